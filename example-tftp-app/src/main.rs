@@ -115,11 +115,12 @@ fn server(addr: SocketAddr, window_size: usize) {
         match header_result {
             Ok((Header::Read(mut read_header), src)) => {
                 println!("Serving {:?}", src);
-                server.host_addr = src;
                 let url = read_header.filename.clone();
                 read_header.filename = get(&url, &mut core);
                 cache = add_and_save(cache, url, read_header.filename.clone()).unwrap();
-                server.clone().handle_read_request(read_header);
+                if let Ok(mut session) = server.new_session(src) {
+                    session.handle_read_request(read_header);
+                }
 		println!("Done");
             },
             _ => {
@@ -130,19 +131,11 @@ fn server(addr: SocketAddr, window_size: usize) {
 }
 
 fn request(local_addr: SocketAddr, host_addr: SocketAddr, url: String, window_size: usize) {
-    let drop_rate = unsafe { tftp::header::DROP_THRESHOLD };
-    unsafe { tftp::header::DROP_THRESHOLD = 0; }
     let mut client = TFTPClient::new(host_addr, local_addr, CLIENT_DOWNLOAD.to_string(), window_size).unwrap();
     let mut dest = url.clone();
     dest.retain(|c| (c.is_alphabetic() && c.is_ascii()) || c == '.');
-    let mut req = client.request_file(url, &dest);
-    unsafe { tftp::header::DROP_THRESHOLD = drop_rate; } 
-    loop {
-        match req.poll() {
-            Err(e) => { panic!(format!("{:?}", e)) },
-            Ok(Async::NotReady) => continue,
-            _ => return
-        }
+    if let Err(e) = client.request_file_blocking(url, &dest) {
+        panic!(format!("{:?}", e))
     }
 }
 
@@ -156,7 +149,6 @@ to run in server mode:
 
 options:
 --help:         display this menu
--d [n]          drop n packets of every 128
 -w [n]          sets window size to n
 "#;
 
@@ -170,7 +162,6 @@ fn main() {
 fn pmain(mut args: Vec<String>) {
     let argn = args.len();
     let mut window_size = 16;
-    let mut drop_freq = 0;
     if argn > 3 || args.contains(&"-s".to_owned()) {
         let end = if argn > 3 { argn - 3 } else { argn };
         for i in 0..argn {
@@ -216,23 +207,6 @@ fn pmain(mut args: Vec<String>) {
                         return;
                     }
                 },
-                "-d"    => {
-                    if args.len() > i + 1 {
-                        if let Ok(w) = args[i + 1].parse::<usize>() {
-                            if w < 127 {
-                                drop_freq = w;      
-                                continue;
-                            }
-                        }
-                        println!("Expected a positive integer < 128 after '-d' flag, got '{}'.", args[i + 1]);
-                        println!("{}", HELP);
-                        return;
-                    } else {
-                        println!("Expected an integer > 0 after '-d' flag.");
-                        println!("{}", HELP);
-                        return;
-                    }
-                },
                 _ => { continue; }
             }
         }
@@ -269,8 +243,6 @@ fn pmain(mut args: Vec<String>) {
 
     let url = args[2].clone();
 
-    unsafe { tftp::header::DROP_THRESHOLD = drop_freq as u64; };
-    unsafe { tftp::header::STOP_AND_WAIT = window_size == 1; };    
     request(local_addr, server_addr, url, window_size);
 }
 /*
@@ -0,0 +1,80 @@
+use std::future::Future;
+use std::io;
+use std::net::UdpSocket;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+use futures;
+use futures::Async;
+use async_io::{ Async as Registered, Timer };
+
+use transport::Transfer;
+
+/// Drives a `SendFile` or `ReceiveFile` as a `std::future::Future`, so that it can be awaited on
+/// any executor: smol, async-std, or anything else. Readiness and timers come from `async-io`,
+/// which runs its own reactor thread when the executor doesn't drive one, so this doesn't assume
+/// tokio-core the way `reactor::ReactorTransfer` does. The transfer is only polled once a datagram
+/// has arrived or the read timeout it set has run out, so the executor is free in between.
+///
+/// The socket is switched to non-blocking mode while this is alive, which a transfer takes the
+/// same way as its read timeout running out. It is switched back when this is dropped.
+pub struct AsyncTransfer<T: Transfer> {
+    transfer: T,
+    /// The transfer's socket, as registered with the reactor.
+    io: Registered<UdpSocket>,
+    /// Fires when the transfer's read timeout runs out, if it set one.
+    timer: Option<Timer>,
+    /// Whether the transfer has been polled at all yet.
+    started: bool,
+}
+
+impl<T: Transfer> AsyncTransfer<T> {
+    pub fn new(transfer: T) -> Result<Self, io::Error> {
+        // This makes every handle to the socket non-blocking, the transfer's included.
+        let io = Registered::new(transfer.socket().try_clone()?)?;
+        Ok(AsyncTransfer { transfer, io, timer: None, started: false })
+    }
+
+    /// Arranges to be woken once the transfer has something to do.
+    fn wait(&mut self, cx: &mut Context) -> Result<(), io::Error> {
+        self.timer = self.transfer.socket().read_timeout()?.map(Timer::after);
+        // Polling registers the waker; anything that's ready already has to be seen to straight away.
+        let timed_out = match self.timer {
+            Some(ref mut timer) => Pin::new(timer).poll(cx).is_ready(),
+            None => false
+        };
+        if timed_out || self.io.poll_readable(cx).is_ready() {
+            cx.waker().wake_by_ref();
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transfer + Unpin> Future for AsyncTransfer<T> {
+    type Output = Result<(), io::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let timed_out = match this.timer {
+            Some(ref mut timer) => Pin::new(timer).poll(cx).is_ready(),
+            None => false
+        };
+        if this.started && !timed_out && this.io.poll_readable(cx).is_pending() {
+            return Poll::Pending
+        }
+        this.started = true;
+        match futures::Future::poll(&mut this.transfer) {
+            Ok(Async::Ready(())) => Poll::Ready(Ok(())),
+            Ok(Async::NotReady) => match this.wait(cx) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e))
+            },
+            Err(e) => Poll::Ready(Err(e))
+        }
+    }
+}
+
+impl<T: Transfer> Drop for AsyncTransfer<T> {
+    fn drop(&mut self) {
+        let _ = self.transfer.socket().set_nonblocking(false);
+    }
+}
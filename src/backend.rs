@@ -0,0 +1,1398 @@
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, BufReader, Read, Write, Seek, Cursor };
+use std::path::{ Path, PathBuf, Component };
+use std::collections::HashMap;
+use std::cmp::{ min, max };
+use std::sync::{ Arc, Mutex, MutexGuard, Weak };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::{ SyncSender, sync_channel };
+use std::thread::{ self, JoinHandle };
+use std::time::SystemTime;
+#[cfg(feature = "memmap")]
+use memmap::{ Mmap, MmapOptions };
+#[cfg(feature = "gzip")]
+use flate2::read::MultiGzDecoder;
+
+/// The whole contents of a file that is being served, e.g. a memory map of it.
+pub type Contents = Box<dyn AsRef<[u8]> + Send>;
+
+/// Where a `SendFile` gets the blocks it sends from: `Contents` that are in memory already, or a
+/// `StreamingSource` that reads each block as it is needed.
+///
+/// Most sources know how long the file is from the start. Those that only find out as they go,
+/// e.g. a `GzipSource` decompressing the file as it is sent, aren't complete until they do: until
+/// then, `len` is how much of the file they have so far, and `fill` gets them more of it.
+pub trait BlockSource: Send {
+    /// The length of the file, in bytes, or as much of it as the source has so far if it isn't
+    /// complete.
+    fn len(&self) -> usize;
+
+    /// The data at `offset` bytes into the file, as much of it as fits in `buf` (less at the end of
+    /// the file). Sources that have to read the data read it into `buf`; ones that have it in
+    /// memory hand it over as it is.
+    fn read_block<'a>(&'a mut self, offset: usize, buf: &'a mut [u8]) -> Result<&'a [u8], io::Error>;
+
+    /// Whether `len` is the length of the whole file.
+    fn is_complete(&self) -> bool {
+        true
+    }
+
+    /// Whether the whole file is in memory, so that sessions at different places in it can read
+    /// from the one source without getting in each other's way; see `SharingBackend`.
+    fn is_shareable(&self) -> bool {
+        false
+    }
+
+    /// Gets the file up to `end` bytes in, or as much of it as there is, returning the new `len`.
+    /// This never waits for data that isn't there yet.
+    fn fill(&mut self, _end: usize) -> Result<usize, io::Error> {
+        Ok(self.len())
+    }
+
+    /// Tells the source that nothing before `offset` is going to be read again, so sources that keep
+    /// what they have read can let go of it.
+    fn release(&mut self, _offset: usize) {}
+}
+
+impl BlockSource for Contents {
+    fn len(&self) -> usize {
+        (**self).as_ref().len()
+    }
+
+    fn read_block<'a>(&'a mut self, offset: usize, buf: &'a mut [u8]) -> Result<&'a [u8], io::Error> {
+        let contents = (**self).as_ref();
+        let offset = min(offset, contents.len());
+        Ok(&contents[offset..min(offset + buf.len(), contents.len())])
+    }
+
+    fn is_shareable(&self) -> bool {
+        true
+    }
+}
+
+impl BlockSource for Box<dyn BlockSource> {
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn read_block<'a>(&'a mut self, offset: usize, buf: &'a mut [u8]) -> Result<&'a [u8], io::Error> {
+        (**self).read_block(offset, buf)
+    }
+
+    fn is_complete(&self) -> bool {
+        (**self).is_complete()
+    }
+
+    fn is_shareable(&self) -> bool {
+        (**self).is_shareable()
+    }
+
+    fn fill(&mut self, end: usize) -> Result<usize, io::Error> {
+        (**self).fill(end)
+    }
+
+    fn release(&mut self, offset: usize) {
+        (**self).release(offset)
+    }
+}
+
+/// Reads the blocks of a file as they are sent, instead of holding all of it in memory. This works
+/// for anything `Read + Seek`, including files that can't be memory mapped, e.g. on some network
+/// filesystems. Blocks are usually asked for in order, so the reader is only sought when they
+/// aren't, e.g. for a retransmission; wrap it in a `BufReader` to read more than a block at a time.
+pub struct StreamingSource<R: Read + Seek + Send> {
+    reader: R,
+    len: usize,
+    /// Where the reader is in the file, if known.
+    position: Option<usize>,
+}
+
+impl<R: Read + Seek + Send> StreamingSource<R> {
+    /// Sends everything `reader` holds, from the start.
+    pub fn new(mut reader: R) -> Result<Self, io::Error> {
+        let len = reader.seek(io::SeekFrom::End(0))? as usize;
+        Ok(StreamingSource { reader, len, position: None })
+    }
+}
+
+impl<R: Read + Seek + Send> BlockSource for StreamingSource<R> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read_block<'a>(&'a mut self, offset: usize, buf: &'a mut [u8]) -> Result<&'a [u8], io::Error> {
+        let len = min(buf.len(), self.len.saturating_sub(offset));
+        // Until the read succeeds, where the reader is isn't known.
+        if self.position.take() != Some(offset) {
+            self.reader.seek(io::SeekFrom::Start(offset as u64))?;
+        }
+        self.reader.read_exact(&mut buf[0..len])?;
+        self.position = Some(offset + len);
+        Ok(&buf[0..len])
+    }
+}
+
+/// Sends a file that is still being written, e.g. a log, or an image that is being generated: its
+/// blocks go out as they are appended, until whoever writes it sets `finished` once all of it has
+/// been. The length of the file is checked again every time the transfer is ready for more of it.
+///
+/// While the writer falls behind, the peer hears nothing, so it has to be patient enough to wait
+/// it out rather than give up on the transfer.
+pub struct GrowingSource {
+    reader: StreamingSource<File>,
+    finished: Arc<AtomicBool>,
+    complete: bool,
+}
+
+impl GrowingSource {
+    pub fn new(file: File, finished: Arc<AtomicBool>) -> Result<Self, io::Error> {
+        let mut source = GrowingSource { reader: StreamingSource::new(file)?, finished, complete: false };
+        source.fill(0)?;
+        Ok(source)
+    }
+}
+
+impl BlockSource for GrowingSource {
+    fn len(&self) -> usize {
+        self.reader.len
+    }
+
+    fn read_block<'a>(&'a mut self, offset: usize, buf: &'a mut [u8]) -> Result<&'a [u8], io::Error> {
+        self.reader.read_block(offset, buf)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    fn fill(&mut self, _end: usize) -> Result<usize, io::Error> {
+        if !self.complete {
+            // Once the writer is finished, the length looked up after that is the final one.
+            let finished = self.finished.load(Ordering::Acquire);
+            self.reader.len = self.reader.reader.metadata()?.len() as usize;
+            self.complete = finished;
+        }
+        Ok(self.reader.len)
+    }
+}
+
+/// How much of a file a `StreamingSource` made by `open_source` or `reader_source` reads at a time.
+const STREAMING_READ_LEN: usize = 64 * 1024;
+
+/// Sends everything `reader` holds, from the start, reading it as it is sent. This is how anything
+/// `Read + Seek` is fed to a `SendFile`, e.g. what a read handler returns, or a file in a backend
+/// that isn't on the local filesystem.
+pub fn reader_source<R: Read + Seek + Send + 'static>(reader: R) -> Result<Box<dyn BlockSource>, io::Error> {
+    Ok(Box::new(StreamingSource::new(BufReader::with_capacity(STREAMING_READ_LEN, reader))?))
+}
+
+/// How much of a file a `WindowedMap` maps at a time. Files no larger than this are mapped whole.
+#[cfg(feature = "memmap")]
+pub const MAP_WINDOW_LEN: usize = 16 * 1024 * 1024;
+
+/// Maps a large file into memory a piece at a time, following the blocks as they are sent, instead
+/// of all of it at once. Otherwise every session sending a multi-gigabyte image would take up that
+/// much address space, and the page cache would churn with several of them going at once.
+#[cfg(feature = "memmap")]
+pub struct WindowedMap {
+    file: File,
+    len: usize,
+    /// How much of the file is mapped at a time.
+    window_len: usize,
+    /// The mapped part of the file, and where in the file it starts.
+    map: Option<(usize, Mmap)>,
+}
+
+#[cfg(feature = "memmap")]
+impl WindowedMap {
+    /// Maps `file` `MAP_WINDOW_LEN` bytes at a time.
+    pub fn new(file: File) -> Result<Self, io::Error> {
+        WindowedMap::with_window_len(file, MAP_WINDOW_LEN)
+    }
+
+    /// Maps `file` `window_len` bytes at a time. The start of it is mapped straight away, so that a
+    /// file that can't be mapped fails here rather than once it is being sent.
+    pub fn with_window_len(file: File, window_len: usize) -> Result<Self, io::Error> {
+        let len = file.metadata()?.len() as usize;
+        let mut map = WindowedMap { file, len, window_len: max(window_len, 2), map: None };
+        if len > 0 {
+            map.map_window(0, 1)?;
+        }
+        Ok(map)
+    }
+
+    /// Maps the window the bytes from `offset` to `end` fall in. Windows start every half a window,
+    /// so a block is always mapped along with the half a window before it, which retransmissions go
+    /// back into.
+    fn map_window(&mut self, offset: usize, end: usize) -> Result<(), io::Error> {
+        let start = offset - offset % (self.window_len / 2);
+        let len = min(max(self.window_len, end - start), self.len - start);
+        // Let go of the old window before mapping the new one.
+        self.map = None;
+        self.map = Some((start, unsafe { MmapOptions::new().offset(start).len(len).map(&self.file)? }));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "memmap")]
+impl BlockSource for WindowedMap {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read_block<'a>(&'a mut self, offset: usize, buf: &'a mut [u8]) -> Result<&'a [u8], io::Error> {
+        let offset = min(offset, self.len);
+        let end = min(offset + buf.len(), self.len);
+        if offset == end {
+            return Ok(&[])
+        }
+        let mapped = match self.map {
+            Some((start, ref map)) => start <= offset && end <= start + map.len(),
+            None => false
+        };
+        if !mapped {
+            self.map_window(offset, end)?;
+        }
+        match self.map {
+            Some((start, ref map)) => Ok(&map[offset - start..end - start]),
+            None => unreachable!()
+        }
+    }
+}
+
+/// Opens `file` to be sent. It is memory mapped if it can be (a window at a time if it is larger
+/// than `MAP_WINDOW_LEN`; see `WindowedMap`), and read as it is sent otherwise, since mapping fails
+/// on some filesystems. Pipes, and files that claim to be empty the way most of /proc does, are
+/// read whole first instead: a transfer has to know where the file ends.
+pub fn open_source(file: File) -> Result<Box<dyn BlockSource>, io::Error> {
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        let mut contents = Vec::new();
+        (&file).read_to_end(&mut contents)?;
+        return Ok(Box::new(Box::new(contents) as Contents))
+    }
+    #[cfg(feature = "memmap")]
+    {
+        if metadata.len() as usize > MAP_WINDOW_LEN {
+            if let Ok(map) = WindowedMap::new(file.try_clone()?) {
+                return Ok(Box::new(map))
+            }
+        } else if let Ok(contents) = map_file(&file) {
+            return Ok(Box::new(contents))
+        }
+    }
+    reader_source(file)
+}
+
+/// Reads a `BlockSource` from start to end, e.g. to decompress a file a backend serves.
+#[cfg(feature = "gzip")]
+struct SourceReader {
+    source: Box<dyn BlockSource>,
+    offset: usize,
+    /// Where data is read into for sources that don't have it in memory, before it is copied out.
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "gzip")]
+impl SourceReader {
+    fn new(source: Box<dyn BlockSource>) -> Self {
+        SourceReader { source, offset: 0, buf: Vec::new() }
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Read for SourceReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.source.fill(self.offset + buf.len())?;
+        self.buf.resize(buf.len(), 0);
+        let len = {
+            let data = self.source.read_block(self.offset, &mut self.buf)?;
+            buf[0..data.len()].copy_from_slice(data);
+            data.len()
+        };
+        self.offset += len;
+        self.source.release(self.offset);
+        Ok(len)
+    }
+}
+
+/// Decompresses a gzip file as it is sent, e.g. "image.bin.gz" served as "image.bin". How long the
+/// file is only becomes clear once all of it has been decompressed, so the source is complete from
+/// then on (see `BlockSource`). It keeps what it has decompressed until the transfer has no more use
+/// for it, i.e. for as long as it may have to be sent again.
+#[cfg(feature = "gzip")]
+pub struct GzipSource<R: Read + Send> {
+    decoder: MultiGzDecoder<R>,
+    /// The decompressed file from `start`, up to as far as it has been decompressed.
+    data: Vec<u8>,
+    start: usize,
+    complete: bool,
+}
+
+#[cfg(feature = "gzip")]
+impl<R: Read + Send> GzipSource<R> {
+    /// Decompresses everything `reader` holds. Files with more than one gzip member, e.g. ones that
+    /// were concatenated, are decompressed whole.
+    pub fn new(reader: R) -> Self {
+        GzipSource { decoder: MultiGzDecoder::new(reader), data: Vec::new(), start: 0, complete: false }
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl<R: Read + Send> BlockSource for GzipSource<R> {
+    fn len(&self) -> usize {
+        self.start + self.data.len()
+    }
+
+    fn read_block<'a>(&'a mut self, offset: usize, buf: &'a mut [u8]) -> Result<&'a [u8], io::Error> {
+        if offset < self.start {
+            return Err(io::Error::new(io::ErrorKind::Other, "That part of the file has already been let go of."))
+        }
+        let offset = min(offset - self.start, self.data.len());
+        Ok(&self.data[offset..min(offset + buf.len(), self.data.len())])
+    }
+
+    fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    fn fill(&mut self, end: usize) -> Result<usize, io::Error> {
+        if !self.complete && self.len() < end {
+            let wanted = end - self.len();
+            let read = (&mut self.decoder).take(wanted as u64).read_to_end(&mut self.data)?;
+            self.complete = read < wanted;
+        }
+        Ok(self.len())
+    }
+
+    fn release(&mut self, offset: usize) {
+        // Moving the rest down only once at least as much is let go of keeps it from costing more
+        // than decompressing did.
+        let released = min(offset.saturating_sub(self.start), self.data.len());
+        if released > 0 && released >= self.data.len() / 2 {
+            self.data.drain(0..released);
+            self.start += released;
+        }
+    }
+}
+
+/// Where the data of an upload goes, e.g. a file, object storage, or a buffer that gets validated.
+/// Anything that is `Write + Seek` is a sink.
+pub trait WriteSink: Send {
+    /// Writes a block of data at `offset` bytes into the file. Blocks can arrive out of order, and
+    /// the same block may be written more than once.
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error>;
+
+    /// Called once the whole file has been written. If the transfer fails, this is never called.
+    fn finalize(&mut self) -> Result<(), io::Error>;
+
+    /// Called before any block is written when the size of the file is known up front, e.g. from
+    /// the "tsize" option, so that room can be made for all of it at once. The peer may be wrong
+    /// about the size, so the file can still turn out shorter or longer.
+    fn allocate(&mut self, _len: u64) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+impl<T: Write + Seek + Send> WriteSink for T {
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        self.seek(io::SeekFrom::Start(offset))?;
+        self.write_all(data)
+    }
+
+    fn finalize(&mut self) -> Result<(), io::Error> {
+        self.flush()
+    }
+}
+
+impl WriteSink for Box<dyn WriteSink> {
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        (**self).write_block(offset, data)
+    }
+
+    fn finalize(&mut self) -> Result<(), io::Error> {
+        (**self).finalize()
+    }
+
+    fn allocate(&mut self, len: u64) -> Result<(), io::Error> {
+        (**self).allocate(len)
+    }
+}
+
+/// How much a `FileSink` holds on to before writing it out, by default.
+pub const WRITE_BUFFER_LEN: usize = 256 * 1024;
+
+/// When a `FileSink` has what it has written made durable, i.e. waits for it to reach the disk
+/// rather than leaving that to the operating system, so that it survives a crash or power loss.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never; what is written reaches the disk whenever the operating system gets around to it.
+    /// This is the default.
+    Never,
+    /// Once the whole file has been written.
+    OnCompletion,
+    /// Every time this many more bytes have been received, and once the whole file has been
+    /// written.
+    EveryBytes(u64),
+    /// After every block. This writes every block out as it arrives, and is by far the slowest.
+    EveryBlock,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self { SyncPolicy::Never }
+}
+
+/// Writes what is received to a file on the local filesystem. This is what `FsBackend` writes to,
+/// and what downloads are saved to.
+///
+/// Blocks that follow on from one another are collected and written out together once there are
+/// enough of them, so that a large file takes a write for every `WRITE_BUFFER_LEN` bytes rather
+/// than one for every block. A block that doesn't follow on, e.g. one that arrived out of order,
+/// writes out what has been collected first.
+pub struct FileSink {
+    file: File,
+    /// The blocks that haven't been written yet, which follow on from one another.
+    buffer: Vec<u8>,
+    /// Where in the file `buffer` goes.
+    buffer_offset: u64,
+    /// The most that is held in `buffer` before it is written out.
+    buffer_len: usize,
+    /// The length the file was given up front, if it was; see `allocate`.
+    allocated: Option<u64>,
+    /// Where the furthest block written so far ends.
+    end: u64,
+    /// When what is written is made durable; see `SyncPolicy`.
+    sync_policy: SyncPolicy,
+    /// The number of bytes received since the file was last made durable.
+    unsynced: u64,
+}
+
+impl FileSink {
+    pub fn new(file: File) -> Self {
+        FileSink::with_buffer_len(file, WRITE_BUFFER_LEN)
+    }
+
+    /// Collects up to `buffer_len` bytes of blocks before writing them out. A `buffer_len` of 0
+    /// writes every block as it arrives.
+    pub fn with_buffer_len(file: File, buffer_len: usize) -> Self {
+        FileSink { file, buffer: Vec::new(), buffer_offset: 0, buffer_len, allocated: None, end: 0, sync_policy: SyncPolicy::default(), unsynced: 0 }
+    }
+
+    /// Sets when what is written is made durable; see `SyncPolicy`.
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        self.sync_policy = sync_policy;
+    }
+
+    /// Writes out the blocks that have been collected, and waits for everything written so far to
+    /// reach the disk.
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.write_buffer()?;
+        self.unsynced = 0;
+        self.file.sync_data()
+    }
+
+    /// Writes out the blocks that have been collected.
+    fn write_buffer(&mut self) -> Result<(), io::Error> {
+        if !self.buffer.is_empty() {
+            self.file.write_block(self.buffer_offset, &self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Writes a block into the buffer, or out to the file if it doesn't fit in with the buffer.
+    fn collect(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        self.end = max(self.end, offset + data.len() as u64);
+        let buffer_end = self.buffer_offset + self.buffer.len() as u64;
+        if !self.buffer.is_empty() && offset >= self.buffer_offset && offset + data.len() as u64 <= buffer_end {
+            // A block that was sent again before it was written out.
+            let start = (offset - self.buffer_offset) as usize;
+            self.buffer[start..start + data.len()].copy_from_slice(data);
+            return Ok(())
+        }
+        if self.buffer.is_empty() || offset != buffer_end {
+            self.write_buffer()?;
+            self.buffer_offset = offset;
+        }
+        if data.len() >= self.buffer_len {
+            return self.file.write_block(offset, data)
+        }
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= self.buffer_len {
+            self.write_buffer()?;
+        }
+        Ok(())
+    }
+}
+
+impl WriteSink for FileSink {
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        self.collect(offset, data)?;
+        self.unsynced += data.len() as u64;
+        let due = match self.sync_policy {
+            SyncPolicy::EveryBlock => true,
+            SyncPolicy::EveryBytes(bytes) => self.unsynced >= bytes,
+            SyncPolicy::Never | SyncPolicy::OnCompletion => false
+        };
+        if due { self.sync() } else { Ok(()) }
+    }
+
+    fn finalize(&mut self) -> Result<(), io::Error> {
+        self.write_buffer()?;
+        // A file that turned out shorter than it was said to be is cut down to what was received.
+        if self.allocated.map_or(false, |allocated| allocated != self.end) {
+            self.file.set_len(self.end)?;
+        }
+        if self.sync_policy != SyncPolicy::Never {
+            self.sync()?;
+        }
+        self.file.flush()
+    }
+
+    /// Sets the length of the file once up front, rather than having it grow with every block
+    /// that is written past its end.
+    fn allocate(&mut self, len: u64) -> Result<(), io::Error> {
+        self.file.set_len(len)?;
+        self.allocated = Some(len);
+        Ok(())
+    }
+}
+
+/// Writes what is received after the first `len` bytes of another sink, e.g. onto the end of a file
+/// that is already there, which is how `OverwritePolicy::Append` works. The blocks are written at
+/// their offset plus `len`, so they can still arrive out of order.
+pub struct AppendSink<W: WriteSink> {
+    sink: W,
+    len: u64,
+}
+
+impl<W: WriteSink> AppendSink<W> {
+    pub fn new(sink: W, len: u64) -> Self {
+        AppendSink { sink, len }
+    }
+}
+
+impl<W: WriteSink> WriteSink for AppendSink<W> {
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        self.sink.write_block(self.len + offset, data)
+    }
+
+    fn finalize(&mut self) -> Result<(), io::Error> {
+        self.sink.finalize()
+    }
+
+    fn allocate(&mut self, len: u64) -> Result<(), io::Error> {
+        self.sink.allocate(self.len + len)
+    }
+}
+
+/// What a transfer hands to the writer thread of a `PipelinedSink`.
+enum SinkMessage {
+    Block(u64, Vec<u8>),
+    Allocate(u64),
+    Finalize,
+}
+
+/// Writes blocks to another sink on a thread of its own, so that the transfer receiving them never
+/// waits on the disk: a slow write or flush would otherwise hold up the ACK, and the peer would take
+/// the delay for a lost packet. Up to `depth` blocks are queued up for the writer; once the queue
+/// is full, writing a block waits for room, so a disk that can't keep up still slows the transfer
+/// down. An error the writer runs into is reported by the next `write_block` or `finalize`.
+pub struct PipelinedSink {
+    messages: Option<SyncSender<SinkMessage>>,
+    writer: Option<JoinHandle<Result<(), io::Error>>>,
+}
+
+impl PipelinedSink {
+    pub fn new<W: WriteSink + 'static>(mut sink: W, depth: usize) -> Self {
+        let (messages, received) = sync_channel(depth);
+        let writer = thread::spawn(move || {
+            for message in received {
+                match message {
+                    SinkMessage::Block(offset, data) => sink.write_block(offset, &data)?,
+                    SinkMessage::Allocate(len) => sink.allocate(len)?,
+                    SinkMessage::Finalize => return sink.finalize()
+                }
+            }
+            // The transfer failed, so the sink mustn't be finalized.
+            Ok(())
+        });
+        PipelinedSink { messages: Some(messages), writer: Some(writer) }
+    }
+
+    fn send(&mut self, message: SinkMessage) -> Result<(), io::Error> {
+        let sent = match self.messages {
+            Some(ref messages) => messages.send(message).is_ok(),
+            None => false
+        };
+        // The writer only stops early when it fails.
+        if sent { Ok(()) } else { self.join() }
+    }
+
+    /// Waits for the writer to finish, returning the error it ran into, if any.
+    fn join(&mut self) -> Result<(), io::Error> {
+        self.messages = None;
+        match self.writer.take() {
+            Some(writer) => match writer.join() {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "The writer thread panicked."))
+            },
+            None => Err(io::Error::new(io::ErrorKind::Other, "The writer thread has already stopped."))
+        }
+    }
+}
+
+impl WriteSink for PipelinedSink {
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        self.send(SinkMessage::Block(offset, data.to_vec()))
+    }
+
+    fn finalize(&mut self) -> Result<(), io::Error> {
+        self.send(SinkMessage::Finalize)?;
+        self.join()
+    }
+
+    fn allocate(&mut self, len: u64) -> Result<(), io::Error> {
+        self.send(SinkMessage::Allocate(len))
+    }
+}
+
+impl Drop for PipelinedSink {
+    fn drop(&mut self) {
+        // Let the writer finish what is queued up, so the file is left alone once this is gone.
+        if self.writer.is_some() {
+            let _ = self.join();
+        }
+    }
+}
+
+/// Something a file can be served from, other than a `Backend`.
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// What a `Backend` knows about a file.
+#[derive(Clone, Debug)]
+pub struct FileInfo {
+    /// The size of the file, in bytes.
+    pub len: u64,
+    /// The time the file was last modified, if known.
+    pub modified: Option<SystemTime>,
+}
+
+/// Where a server gets the files it serves from, and puts the files it receives. Filenames are
+/// passed exactly as they appear in the request.
+///
+/// Errors are reported back to the peer: `io::ErrorKind::NotFound` as FileNotFound,
+/// `io::ErrorKind::PermissionDenied` as AccessViolation and `io::ErrorKind::AlreadyExists` as
+/// FileAlreadyExists; anything else is sent as an undefined error.
+pub trait Backend: Send + Sync {
+    /// Opens `filename` to be read.
+    fn open_read(&self, filename: &str) -> Result<Contents, io::Error>;
+
+    /// Opens `filename` to be sent to a peer. This is `open_read` unless the backend can do better
+    /// than having all of the file in memory at once, e.g. `FsBackend` maps large files a window at
+    /// a time. Backends that can open a file as something `Read + Seek` can hand it over with
+    /// `reader_source`.
+    fn open_source(&self, filename: &str) -> Result<Box<dyn BlockSource>, io::Error> {
+        Ok(Box::new(self.open_read(filename)?))
+    }
+
+    /// Creates `filename` to be written, replacing it if it already exists.
+    fn create_write(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error>;
+
+    /// Looks up information about `filename`.
+    fn metadata(&self, filename: &str) -> Result<FileInfo, io::Error>;
+
+    /// Renames `from` to `to`, which is needed for `OverwritePolicy::Version`. Backends that can't
+    /// rename files don't have to implement this.
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "This backend can't rename files."))
+    }
+
+    /// Opens `filename` to have what is written added onto its end, creating it if it doesn't
+    /// exist; see `AppendSink`. This is needed for `OverwritePolicy::Append`. Backends that can't
+    /// append to files don't have to implement this.
+    fn open_append(&self, _filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "This backend can't append to files."))
+    }
+}
+
+impl<B: Backend + ?Sized> Backend for Arc<B> {
+    fn open_read(&self, filename: &str) -> Result<Contents, io::Error> {
+        (**self).open_read(filename)
+    }
+
+    fn open_source(&self, filename: &str) -> Result<Box<dyn BlockSource>, io::Error> {
+        (**self).open_source(filename)
+    }
+
+    fn create_write(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        (**self).create_write(filename)
+    }
+
+    fn metadata(&self, filename: &str) -> Result<FileInfo, io::Error> {
+        (**self).metadata(filename)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        (**self).rename(from, to)
+    }
+
+    fn open_append(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        (**self).open_append(filename)
+    }
+}
+
+/// What a server does when a write request is for a file that already exists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Replace the file.
+    Overwrite,
+    /// Refuse the request with a FileAlreadyExists error.
+    Reject,
+    /// Keep the old file by renaming it to the first free name of "<filename>.1", "<filename>.2",
+    /// and so on, then write the new one.
+    Version,
+    /// Add what is received onto the end of the file, e.g. for devices that ship their logs by
+    /// uploading the same file again and again with what is new. The file is created if it doesn't
+    /// exist yet. This needs a backend that can append to files; see `Backend::open_append`.
+    Append
+}
+
+impl Default for OverwritePolicy {
+    fn default() -> Self { OverwritePolicy::Overwrite }
+}
+
+/// Serves the files in a folder of the local file system. This is what `TFTPClient` uses by
+/// default, with its data folder. Requests can't reach anything outside of the folder: filenames
+/// containing ".." are refused, as are symbolic links that lead out of it.
+#[derive(Clone, Debug)]
+pub struct FsBackend {
+    pub folder: String,
+    /// If set, the folders leading up to a file that is written are created if they don't exist
+    /// yet, e.g. "configs/sw1" for "configs/sw1/startup.cfg". This is off by default, since it lets
+    /// peers create any number of folders.
+    pub create_dirs: bool,
+    /// When files that are written are made durable; see `SyncPolicy`.
+    pub sync_policy: SyncPolicy,
+}
+
+impl FsBackend {
+    pub fn new(folder: String) -> Self {
+        FsBackend { folder, create_dirs: false, sync_policy: SyncPolicy::default() }
+    }
+
+    /// Joins `filename` onto the (canonical) folder, returning both. A leading '/' is taken to mean
+    /// the folder itself. Filenames with components that would escape it are refused.
+    fn join(&self, filename: &str) -> Result<(PathBuf, PathBuf), io::Error> {
+        let root = Path::new(&self.folder).canonicalize()?;
+        let relative = Path::new(filename.trim_start_matches('/'));
+        let escapes = relative.components().any(|component| match component {
+            Component::Normal(_) | Component::CurDir => false,
+            _ => true
+        });
+        if escapes {
+            return Err(outside_folder())
+        }
+        let path = root.join(relative);
+        Ok((root, path))
+    }
+
+    /// Resolves `filename` to a path inside the folder. Paths that would escape it are refused with
+    /// `io::ErrorKind::PermissionDenied`.
+    fn path(&self, filename: &str) -> Result<PathBuf, io::Error> {
+        let (root, path) = self.join(filename)?;
+
+        // Symbolic links can still lead elsewhere, so check where the path really ends up. A file
+        // that is about to be created doesn't exist yet, so its parent is checked instead. A link
+        // that leads nowhere would be followed when the file is created, wherever it points.
+        let resolved = match (path.canonicalize(), path.parent()) {
+            (Ok(resolved), _) => resolved,
+            (Err(_), _) if fs::symlink_metadata(&path).is_ok() => return Err(outside_folder()),
+            (Err(_), Some(parent)) => parent.canonicalize()?,
+            (Err(e), None) => return Err(e)
+        };
+        if resolved.starts_with(&root) {
+            Ok(path)
+        } else {
+            Err(outside_folder())
+        }
+    }
+
+    /// Creates the folders leading up to `filename`, as long as they end up inside the folder.
+    fn create_parent_dirs(&self, filename: &str) -> Result<(), io::Error> {
+        let (root, path) = self.join(filename)?;
+        let parent = match path.parent() {
+            Some(parent) => parent,
+            None => return Ok(())
+        };
+        // The part of the path that already exists could be a symbolic link leading elsewhere.
+        let mut existing = parent;
+        while !existing.exists() {
+            existing = match existing.parent() {
+                Some(existing) => existing,
+                None => return Err(outside_folder())
+            };
+        }
+        if !existing.canonicalize()?.starts_with(&root) {
+            return Err(outside_folder())
+        }
+        fs::create_dir_all(parent)
+    }
+}
+
+impl Backend for FsBackend {
+    fn open_read(&self, filename: &str) -> Result<Contents, io::Error> {
+        map_file(&File::open(self.path(filename)?)?)
+    }
+
+    fn open_source(&self, filename: &str) -> Result<Box<dyn BlockSource>, io::Error> {
+        open_source(File::open(self.path(filename)?)?)
+    }
+
+    fn create_write(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        if self.create_dirs {
+            self.create_parent_dirs(filename)?;
+        }
+        let file = OpenOptions::new().truncate(true).create(true).write(true).open(self.path(filename)?)?;
+        let mut sink = FileSink::new(file);
+        sink.set_sync_policy(self.sync_policy);
+        Ok(Box::new(sink))
+    }
+
+    fn metadata(&self, filename: &str) -> Result<FileInfo, io::Error> {
+        let metadata = File::open(self.path(filename)?)?.metadata()?;
+        Ok(FileInfo { len: metadata.len(), modified: metadata.modified().ok() })
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        fs::rename(self.path(from)?, self.path(to)?)
+    }
+
+    fn open_append(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        if self.create_dirs {
+            self.create_parent_dirs(filename)?;
+        }
+        let file = OpenOptions::new().create(true).write(true).open(self.path(filename)?)?;
+        let len = file.metadata()?.len();
+        let mut sink = FileSink::new(file);
+        sink.set_sync_policy(self.sync_policy);
+        Ok(Box::new(AppendSink::new(sink, len)))
+    }
+}
+
+fn outside_folder() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Path leads outside of the served folder.")
+}
+
+/// Routes requests to different backends by the start of the filename, e.g. "bios/" to one folder
+/// and "efi/" to another. The prefix is removed before the filename is passed on. If more than one
+/// prefix matches, the longest one wins; filenames that match none go to the fallback, if any, and
+/// are otherwise not found.
+#[derive(Clone, Default)]
+pub struct PrefixBackend {
+    routes: Vec<(String, Arc<dyn Backend>)>,
+    fallback: Option<Arc<dyn Backend>>,
+}
+
+impl PrefixBackend {
+    pub fn new() -> Self {
+        PrefixBackend::default()
+    }
+
+    /// Sends requests for filenames that start with `prefix` to `backend`.
+    pub fn add_route<B: Backend + 'static>(&mut self, prefix: &str, backend: B) {
+        self.routes.push((prefix.trim_start_matches('/').to_string(), Arc::new(backend)));
+    }
+
+    /// Serves filenames that start with `prefix` from `folder`.
+    pub fn add_folder(&mut self, prefix: &str, folder: String) {
+        self.add_route(prefix, FsBackend::new(folder));
+    }
+
+    /// Sets where requests that don't match any prefix go.
+    pub fn set_fallback<B: Backend + 'static>(&mut self, backend: B) {
+        self.fallback = Some(Arc::new(backend));
+    }
+
+    /// Finds the backend for `filename`, and the filename to pass on to it.
+    fn route<'a>(&'a self, filename: &'a str) -> Result<(&'a Arc<dyn Backend>, &'a str), io::Error> {
+        let trimmed = filename.trim_start_matches('/');
+        let route = self.routes.iter()
+            .filter(|&&(ref prefix, _)| trimmed.starts_with(prefix.as_str()))
+            .max_by_key(|&&(ref prefix, _)| prefix.len());
+        match (route, &self.fallback) {
+            (Some(&(ref prefix, ref backend)), _) => Ok((backend, &trimmed[prefix.len()..])),
+            (None, &Some(ref fallback)) => Ok((fallback, filename)),
+            (None, &None) => Err(io::Error::new(io::ErrorKind::NotFound, "No such file."))
+        }
+    }
+}
+
+impl Backend for PrefixBackend {
+    fn open_read(&self, filename: &str) -> Result<Contents, io::Error> {
+        let (backend, filename) = self.route(filename)?;
+        backend.open_read(filename)
+    }
+
+    fn open_source(&self, filename: &str) -> Result<Box<dyn BlockSource>, io::Error> {
+        let (backend, filename) = self.route(filename)?;
+        backend.open_source(filename)
+    }
+
+    fn create_write(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        let (backend, filename) = self.route(filename)?;
+        backend.create_write(filename)
+    }
+
+    fn metadata(&self, filename: &str) -> Result<FileInfo, io::Error> {
+        let (backend, filename) = self.route(filename)?;
+        backend.metadata(filename)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        let (from_backend, from) = self.route(from)?;
+        let (to_backend, to) = self.route(to)?;
+        if !Arc::ptr_eq(from_backend, to_backend) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Can't rename files between backends."))
+        }
+        from_backend.rename(from, to)
+    }
+
+    fn open_append(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        let (backend, filename) = self.route(filename)?;
+        backend.open_append(filename)
+    }
+}
+
+/// Keeps the most recently served files of another backend in memory, so that files requested over
+/// and over again (e.g. a kernel during a PXE boot storm) aren't opened and read every time. A
+/// cached file is only served while the backend reports the same size and modification time for
+/// it; files written through the cache are dropped from it straight away.
+pub struct CachingBackend {
+    backend: Arc<dyn Backend>,
+    /// The most bytes of files to keep. Larger files aren't cached at all.
+    max_bytes: usize,
+    /// The most files to keep.
+    max_entries: usize,
+    cache: Mutex<FileCache>,
+}
+
+#[derive(Default)]
+struct FileCache {
+    files: HashMap<String, CachedFile>,
+    bytes: usize,
+    /// Counts up with every use, to tell which file was used the longest ago.
+    clock: u64,
+}
+
+struct CachedFile {
+    contents: Arc<Vec<u8>>,
+    len: u64,
+    modified: Option<SystemTime>,
+    last_used: u64,
+}
+
+/// File contents that are shared rather than owned, e.g. a cached file being served.
+pub struct SharedContents(pub Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedContents {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl CachingBackend {
+    pub fn new<B: Backend + 'static>(backend: B, max_bytes: usize, max_entries: usize) -> Self {
+        CachingBackend { backend: Arc::new(backend), max_bytes, max_entries, cache: Mutex::new(FileCache::default()) }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<FileCache>, io::Error> {
+        self.cache.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain file cache lock."))
+    }
+
+    fn forget(&self, filename: &str) -> Result<(), io::Error> {
+        let mut cache = self.lock()?;
+        if let Some(file) = cache.files.remove(filename) {
+            cache.bytes -= file.contents.len();
+        }
+        Ok(())
+    }
+}
+
+impl Backend for CachingBackend {
+    fn open_read(&self, filename: &str) -> Result<Contents, io::Error> {
+        let info = self.backend.metadata(filename)?;
+        {
+            let mut cache = self.lock()?;
+            cache.clock += 1;
+            let clock = cache.clock;
+            if let Some(file) = cache.files.get_mut(filename) {
+                if file.len == info.len && file.modified == info.modified {
+                    file.last_used = clock;
+                    return Ok(Box::new(SharedContents(file.contents.clone())))
+                }
+            }
+        }
+
+        let contents = self.backend.open_read(filename)?;
+        if info.len as usize > self.max_bytes || self.max_entries == 0 {
+            return Ok(contents)
+        }
+        let contents = Arc::new(contents.as_ref().as_ref().to_vec());
+        let mut cache = self.lock()?;
+        if let Some(old) = cache.files.remove(filename) {
+            cache.bytes -= old.contents.len();
+        }
+        while cache.files.len() >= self.max_entries || cache.bytes + contents.len() > self.max_bytes {
+            let oldest = match cache.files.iter().min_by_key(|&(_, file)| file.last_used) {
+                Some((oldest, _)) => oldest.clone(),
+                None => break
+            };
+            if let Some(file) = cache.files.remove(&oldest) {
+                cache.bytes -= file.contents.len();
+            }
+        }
+        cache.bytes += contents.len();
+        let last_used = cache.clock;
+        cache.files.insert(filename.to_string(), CachedFile { contents: contents.clone(), len: info.len, modified: info.modified, last_used });
+        Ok(Box::new(SharedContents(contents)))
+    }
+
+    fn open_source(&self, filename: &str) -> Result<Box<dyn BlockSource>, io::Error> {
+        // Files too large to be cached are opened however the backend opens them best.
+        if self.backend.metadata(filename)?.len as usize > self.max_bytes || self.max_entries == 0 {
+            return self.backend.open_source(filename)
+        }
+        Ok(Box::new(self.open_read(filename)?))
+    }
+
+    fn create_write(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        self.forget(filename)?;
+        self.backend.create_write(filename)
+    }
+
+    fn metadata(&self, filename: &str) -> Result<FileInfo, io::Error> {
+        self.backend.metadata(filename)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        self.forget(from)?;
+        self.forget(to)?;
+        self.backend.rename(from, to)
+    }
+
+    fn open_append(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        self.forget(filename)?;
+        self.backend.open_append(filename)
+    }
+}
+
+/// Has all the sessions that are sending the same file at once share its source, e.g. during a PXE
+/// boot storm, instead of every one of them opening (and mapping) the file for itself. Sessions
+/// copy each block out of the shared source as they send it. The source is let go of once the last
+/// session sending from it is done, and a file that has changed since it was opened, by size or
+/// modification time, gets a new source for the sessions that start after the change. Only sources
+/// that have the whole file in memory are shared (see `BlockSource::is_shareable`); every session
+/// gets its own of any other.
+pub struct SharingBackend {
+    backend: Arc<dyn Backend>,
+    /// The sources that are open, by filename, each behind a lock of its own that is held while
+    /// the file is opened. Sources that no session holds any more are cleared out whenever another
+    /// file is opened.
+    sources: Mutex<HashMap<String, SourceSlot>>,
+}
+
+type SourceSlot = Arc<Mutex<Weak<SharedSource>>>;
+
+struct SharedSource {
+    source: Mutex<Box<dyn BlockSource>>,
+    len: usize,
+    /// What the backend said about the file when it was opened.
+    info: FileInfo,
+}
+
+/// A session's share of a `SharingBackend` source.
+struct SharedSourceHandle {
+    shared: Arc<SharedSource>,
+    /// Where blocks are read into for sources that don't have them in memory, before they are
+    /// copied out to the session.
+    buf: Vec<u8>,
+}
+
+impl BlockSource for SharedSourceHandle {
+    fn len(&self) -> usize {
+        self.shared.len
+    }
+
+    fn read_block<'a>(&'a mut self, offset: usize, buf: &'a mut [u8]) -> Result<&'a [u8], io::Error> {
+        let mut source = self.shared.source.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain shared source lock."))?;
+        self.buf.resize(buf.len(), 0);
+        let data = source.read_block(offset, &mut self.buf)?;
+        buf[0..data.len()].copy_from_slice(data);
+        Ok(&buf[0..data.len()])
+    }
+}
+
+impl SharingBackend {
+    pub fn new<B: Backend + 'static>(backend: B) -> Self {
+        SharingBackend { backend: Arc::new(backend), sources: Mutex::new(HashMap::new()) }
+    }
+
+    /// How many sessions are sending `filename` from the same source right now.
+    pub fn sessions(&self, filename: &str) -> usize {
+        let slot = self.sources.lock().ok().and_then(|sources| sources.get(filename).cloned());
+        slot.and_then(|slot| slot.lock().ok().map(|shared| shared.strong_count())).unwrap_or(0)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<HashMap<String, SourceSlot>>, io::Error> {
+        self.sources.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain shared source lock."))
+    }
+
+    fn forget(&self, filename: &str) -> Result<(), io::Error> {
+        self.lock()?.remove(filename);
+        Ok(())
+    }
+}
+
+impl Backend for SharingBackend {
+    fn open_read(&self, filename: &str) -> Result<Contents, io::Error> {
+        self.backend.open_read(filename)
+    }
+
+    fn open_source(&self, filename: &str) -> Result<Box<dyn BlockSource>, io::Error> {
+        let info = self.backend.metadata(filename)?;
+        let slot = {
+            let mut sources = self.lock()?;
+            // A slot someone else has a hold of may be about to have its file opened.
+            sources.retain(|_, slot| Arc::strong_count(slot) > 1 || slot.try_lock().map_or(true, |shared| shared.strong_count() > 0));
+            sources.entry(filename.to_string()).or_insert_with(|| Arc::new(Mutex::new(Weak::new()))).clone()
+        };
+        // The file's own lock is held while it is opened, so that the sessions of a boot storm wait
+        // for the first of them to open it rather than all opening it at once, while other files
+        // are opened alongside it.
+        let mut slot = slot.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain shared source lock."))?;
+        let shared = match slot.upgrade() {
+            Some(ref shared) if shared.info.len == info.len && shared.info.modified == info.modified => shared.clone(),
+            _ => {
+                let source = self.backend.open_source(filename)?;
+                // Sessions at different places in a file can't share a source that only has some of
+                // it at a time, e.g. a `WindowedMap`: they would have it go back and forth between
+                // them.
+                if !source.is_shareable() {
+                    return Ok(source)
+                }
+                let shared = Arc::new(SharedSource { len: source.len(), source: Mutex::new(source), info });
+                *slot = Arc::downgrade(&shared);
+                shared
+            }
+        };
+        Ok(Box::new(SharedSourceHandle { shared, buf: Vec::new() }))
+    }
+
+    fn create_write(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        self.forget(filename)?;
+        self.backend.create_write(filename)
+    }
+
+    fn metadata(&self, filename: &str) -> Result<FileInfo, io::Error> {
+        self.backend.metadata(filename)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        self.forget(from)?;
+        self.forget(to)?;
+        self.backend.rename(from, to)
+    }
+
+    fn open_append(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        self.forget(filename)?;
+        self.backend.open_append(filename)
+    }
+}
+
+/// Serves "<filename>.gz" in place of a file another backend doesn't have, decompressing it as it
+/// is sent (see `GzipSource`), e.g. "image.bin" from "image.bin.gz". Files the backend does have are
+/// served as they are, and so are files that are written. The metadata of a file served this way is
+/// that of the compressed file; its decompressed size isn't known until it has been sent.
+#[cfg(feature = "gzip")]
+pub struct DecompressingBackend {
+    backend: Arc<dyn Backend>,
+}
+
+#[cfg(feature = "gzip")]
+impl DecompressingBackend {
+    pub fn new<B: Backend + 'static>(backend: B) -> Self {
+        DecompressingBackend { backend: Arc::new(backend) }
+    }
+
+    /// Does `open` with `filename`, or with the compressed file in its place if there is no such
+    /// file. If neither exists, the error is about `filename`.
+    fn or_compressed<T, F>(&self, filename: &str, open: F) -> Result<(T, bool), io::Error>
+        where F: Fn(&str) -> Result<T, io::Error> {
+        match open(filename) {
+            Ok(opened) => Ok((opened, false)),
+            Err(e) => if e.kind() == io::ErrorKind::NotFound {
+                open(&format!("{}.gz", filename)).map(|opened| (opened, true)).map_err(|_| e)
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Backend for DecompressingBackend {
+    fn open_read(&self, filename: &str) -> Result<Contents, io::Error> {
+        match self.or_compressed(filename, |filename| self.backend.open_read(filename))? {
+            (compressed, true) => {
+                let mut contents = Vec::new();
+                MultiGzDecoder::new((*compressed).as_ref()).read_to_end(&mut contents)?;
+                Ok(Box::new(contents))
+            },
+            (contents, false) => Ok(contents)
+        }
+    }
+
+    fn open_source(&self, filename: &str) -> Result<Box<dyn BlockSource>, io::Error> {
+        match self.or_compressed(filename, |filename| self.backend.open_source(filename))? {
+            (source, true) => Ok(Box::new(GzipSource::new(SourceReader::new(source)))),
+            (source, false) => Ok(source)
+        }
+    }
+
+    fn create_write(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        self.backend.create_write(filename)
+    }
+
+    fn metadata(&self, filename: &str) -> Result<FileInfo, io::Error> {
+        self.or_compressed(filename, |filename| self.backend.metadata(filename)).map(|(info, _)| info)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        self.backend.rename(from, to)
+    }
+
+    fn open_append(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        self.backend.open_append(filename)
+    }
+}
+
+/// Serves and accepts files kept in memory, e.g. small generated configs, or files for tests.
+/// Clones share the same files, so a clone can be handed to the server while the original is kept
+/// around to look at what was uploaded.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryBackend {
+    pub files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl InMemoryBackend {
+    pub fn new() -> Self {
+        InMemoryBackend::default()
+    }
+
+    /// Adds a file to be served, replacing any file with the same name.
+    pub fn insert(&self, filename: String, contents: Vec<u8>) {
+        if let Ok(mut files) = self.files.lock() {
+            files.insert(filename, contents);
+        }
+    }
+
+    /// Returns a copy of the contents of `filename`, if there is such a file.
+    pub fn get(&self, filename: &str) -> Option<Vec<u8>> {
+        self.files.lock().ok().and_then(|files| files.get(filename).cloned())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<HashMap<String, Vec<u8>>>, io::Error> {
+        self.files.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain file store lock."))
+    }
+}
+
+impl Backend for InMemoryBackend {
+    fn open_read(&self, filename: &str) -> Result<Contents, io::Error> {
+        match self.lock()?.get(filename) {
+            Some(contents) => Ok(Box::new(contents.clone())),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No such file."))
+        }
+    }
+
+    fn create_write(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        Ok(Box::new(InMemoryUpload {
+            filename: filename.to_string(),
+            buffer: Cursor::new(Vec::new()),
+            backend: self.clone()
+        }))
+    }
+
+    fn metadata(&self, filename: &str) -> Result<FileInfo, io::Error> {
+        match self.lock()?.get(filename) {
+            Some(contents) => Ok(FileInfo { len: contents.len() as u64, modified: None }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No such file."))
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        let mut files = self.lock()?;
+        match files.remove(from) {
+            Some(contents) => { files.insert(to.to_string(), contents); Ok(()) },
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No such file."))
+        }
+    }
+
+    fn open_append(&self, filename: &str) -> Result<Box<dyn WriteSink>, io::Error> {
+        let contents = self.get(filename).unwrap_or_default();
+        let len = contents.len() as u64;
+        Ok(Box::new(AppendSink::new(InMemoryUpload {
+            filename: filename.to_string(),
+            buffer: Cursor::new(contents),
+            backend: self.clone()
+        }, len)))
+    }
+}
+
+/// A file being uploaded to an `InMemoryBackend`. The file is only stored once all of it has been
+/// received.
+struct InMemoryUpload {
+    filename: String,
+    buffer: Cursor<Vec<u8>>,
+    backend: InMemoryBackend,
+}
+
+impl WriteSink for InMemoryUpload {
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        self.buffer.write_block(offset, data)
+    }
+
+    fn finalize(&mut self) -> Result<(), io::Error> {
+        let contents = self.buffer.get_ref().clone();
+        self.backend.lock()?.insert(self.filename.clone(), contents);
+        Ok(())
+    }
+}
+
+/// Maps `file` into memory. Empty files can't be mapped, so they get an empty buffer instead.
+#[cfg(feature = "memmap")]
+pub fn map_file(file: &File) -> Result<Contents, io::Error> {
+    if file.metadata()?.len() == 0 {
+        return Ok(Box::new(Vec::new()))
+    }
+    Ok(Box::new(unsafe { MmapOptions::new().map(file)? }))
+}
+
+/// Reads all of `file` into memory, since there is no memory mapping without the "memmap" feature.
+#[cfg(not(feature = "memmap"))]
+pub fn map_file(file: &File) -> Result<Contents, io::Error> {
+    let mut contents = Vec::with_capacity(file.metadata()?.len() as usize);
+    (&*file).read_to_end(&mut contents)?;
+    Ok(Box::new(contents))
+}
+
+/// Reads all of `source`, from the start, so that it can be served. `reader_source` sends it
+/// without reading it all first.
+pub fn read_contents(mut source: Box<dyn ReadSeek>) -> Result<Contents, io::Error> {
+    let mut contents = Vec::new();
+    source.seek(io::SeekFrom::Start(0))?;
+    source.read_to_end(&mut contents)?;
+    Ok(Box::new(contents))
+}
@@ -0,0 +1,141 @@
+use std::io::{ self, Read };
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use md5;
+use sha2::{ Digest, Sha256 };
+
+/// A digest of a file's contents. TFTP itself has no integrity protection, so a caller that knows
+/// what a file should hash to can have the client check it after the transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    Md5([u8; 16]),
+    Sha256([u8; 32]),
+}
+
+impl Checksum {
+    /// Parses a hex encoded MD5 digest, as printed by `md5sum`.
+    pub fn md5_from_hex(hex: &str) -> Result<Checksum, io::Error> {
+        let mut digest = [0u8; 16];
+        parse_hex(hex, &mut digest)?;
+        Ok(Checksum::Md5(digest))
+    }
+
+    /// Parses a hex encoded SHA-256 digest, as printed by `sha256sum`.
+    pub fn sha256_from_hex(hex: &str) -> Result<Checksum, io::Error> {
+        let mut digest = [0u8; 32];
+        parse_hex(hex, &mut digest)?;
+        Ok(Checksum::Sha256(digest))
+    }
+
+    /// Computes the same kind of digest as `self` over `data`.
+    pub fn of_bytes(&self, data: &[u8]) -> Checksum {
+        self.of_reader(data).unwrap()
+    }
+
+    /// Computes the same kind of digest as `self` over the file at `path`.
+    pub fn of_file<P: AsRef<Path>>(&self, path: P) -> Result<Checksum, io::Error> {
+        self.of_reader(File::open(path)?)
+    }
+
+    /// Computes the same kind of digest as `self` over everything `reader` holds.
+    pub fn of_reader<R: Read>(&self, mut reader: R) -> Result<Checksum, io::Error> {
+        let mut buf = [0u8; 64 * 1024];
+        match *self {
+            Checksum::Md5(_) => {
+                let mut context = md5::Context::new();
+                loop {
+                    match reader.read(&mut buf)? {
+                        0 => return Ok(Checksum::Md5(context.compute().0)),
+                        len => context.consume(&buf[..len])
+                    }
+                }
+            },
+            Checksum::Sha256(_) => {
+                let mut hasher = Sha256::new();
+                loop {
+                    match reader.read(&mut buf)? {
+                        0 => break,
+                        len => hasher.update(&buf[..len])
+                    }
+                }
+                let mut digest = [0u8; 32];
+                digest.copy_from_slice(&hasher.finalize());
+                Ok(Checksum::Sha256(digest))
+            }
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match *self {
+            Checksum::Md5(ref digest) => digest,
+            Checksum::Sha256(ref digest) => digest,
+        }
+    }
+}
+
+/// Formats the digest as lower case hex, like `md5sum` and `sha256sum` do.
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex(hex: &str, digest: &mut [u8]) -> Result<(), io::Error> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not a valid digest.", hex));
+    let hex = hex.trim();
+    if !hex.is_ascii() || hex.len() != digest.len() * 2 {
+        return Err(invalid())
+    }
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(())
+}
+
+/// The CRC-32 used by Ethernet and zlib (IEEE 802.3), e.g. for `header::BLOCK_CRC_OPTION`. It is
+/// worked out half a byte at a time from a small table, which is plenty fast for single blocks.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+const CRC32_TABLE: [u32; 16] = [
+    0x00000000, 0x1db71064, 0x3b6e20c8, 0x26d930ac, 0x76dc4190, 0x6b6b51f4, 0x4db26158, 0x5005713c,
+    0xedb88320, 0xf00f9344, 0xd6d6a3e8, 0xcb61b38c, 0x9b64c2b0, 0x86d3d2d4, 0xa00ae278, 0xbdbdf21c,
+];
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(!0)
+    }
+
+    /// The CRC-32 of `data`.
+    pub fn of(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.value()
+    }
+
+    /// Adds `data` to what the CRC covers.
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.0;
+        for &byte in data {
+            crc = CRC32_TABLE[((crc ^ byte as u32) & 0xf) as usize] ^ (crc >> 4);
+            crc = CRC32_TABLE[((crc ^ (byte as u32 >> 4)) & 0xf) as usize] ^ (crc >> 4);
+        }
+        self.0 = crc;
+    }
+
+    /// The CRC of everything added so far.
+    pub fn value(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
@@ -1,213 +1,1931 @@
-use std::net::SocketAddr;
+use std::net::{ SocketAddr, IpAddr, Ipv6Addr };
 use std::fs::*;
-use std::io;
+use std::io::{ self, Cursor, Read, Seek, Write };
+use std::mem;
 use futures::{ Future, Poll, Async };
 use std::net::UdpSocket;
-use std::time::Duration;
-use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant, SystemTime };
+use std::sync::{ Arc, Mutex, MutexGuard, Condvar };
+use std::sync::atomic;
 use error::TFTPError;
 use std::ops::*;
 use std::str::FromStr;
-use std::path::Path;
+use std::path::{ Component, Path };
+use std::thread;
+use std::collections::BTreeMap;
 use futures::prelude::*;
 use futures::future;
 
 use types::*;
 use header::*;
 use send::*;
-use receive::ReceiveFile;
+use receive::{ AckStrategy, ReceiveFile, DEFAULT_DALLY };
+use net_util::{ AccessList, SocketOptions, bind_dual_stack, bind_in_range, disconnect, ephemeral_addr, interface_bind_addr, is_ipv4_mapped, path_mtu_block_size, pin_to_interface, set_socket_options };
+#[cfg(all(unix, feature = "server"))]
+use net_util::{ inetd_socket, systemd_sockets };
+use session::{ ProgressListener, RegisteredTransfer, SessionRegistry, TransferInfo, ConditionalOutcome, TransferControl, TransferObserver, TransferOutcome, TransferProgress, TransferRecord, DEFAULT_HISTORY_LEN };
+#[cfg(feature = "mdns")]
+use std::net::Ipv4Addr;
+#[cfg(feature = "mdns")]
+use mdns::{ MdnsAdvertiser, MdnsService };
+use backend::{ AppendSink, Backend, BlockSource, CachingBackend, SharingBackend, Contents, FileSink, FsBackend, OverwritePolicy, PipelinedSink, ReadSeek, SharedContents, SyncPolicy, WriteSink, open_source, reader_source };
+#[cfg(feature = "gzip")]
+use backend::DecompressingBackend;
+use checksum::Checksum;
+use retry::RetryPolicy;
+use congestion::WindowStrategyFactory;
+use transport::{ Datagram, PollBudget };
 
+/// The number of errors in a row transfers put up with by default; see `TFTPClient::max_attempts`.
 pub const MAX_ATTEMPTS: usize = 8;
 
+/// Lets a server answer request options that this crate doesn't understand itself, such as vendor
+/// specific options. Options the handler doesn't answer are left out of the OACK, i.e. refused.
+pub trait OptionHandler: Send + Sync {
+    /// Returns the value to acknowledge `option` with, or `None` to refuse it. `request` is the
+    /// request that carried the option, which came from `peer`.
+    fn answer_option(&self, request: RequestType, filename: &str, peer: SocketAddr, option: &str, value: &str) -> Option<String>;
+}
+
+/// Settings for serving read requests the way PXE MTFTP servers do. The values normally mirror the
+/// MTFTP options handed out to PXE clients over DHCP (MTFTP-IP, MTFTP-CPORT, and so on); the
+/// server port is simply the port the serving `TFTPClient` is bound to.
+#[derive(Clone, Debug)]
+pub struct MtftpConfig {
+    /// The multicast group and client listen port that data blocks are sent to.
+    pub group: SocketAddr,
+    /// How long to wait after a read request before the first block goes out, giving clients that
+    /// are still listening for an open transfer a chance to see it start from the beginning.
+    pub start_delay: Duration,
+    /// The block size to send. Old PXE firmware only understands 512 byte blocks.
+    pub block_size: usize,
+}
+
+impl MtftpConfig {
+    pub fn new(group: SocketAddr) -> Self {
+        MtftpConfig {
+            group,
+            start_delay: Duration::from_secs(1),
+            block_size: 512,
+        }
+    }
+}
+
+/// Serves read requests with dynamically generated content, instead of files from the backend.
+/// This is implemented for closures with the same signature as `handle_rrq`.
+pub trait ReadRequestHandler: Send + Sync {
+    /// Returns what to send in response to `request` from `peer`, or the error to refuse it with.
+    fn handle_rrq(&self, request: &RWHeader<ReadHeader>, peer: SocketAddr) -> Result<Box<dyn ReadSeek>, ErrorCode>;
+}
+
+impl<F> ReadRequestHandler for F
+    where F: Fn(&RWHeader<ReadHeader>, SocketAddr) -> Result<Box<dyn ReadSeek>, ErrorCode> + Send + Sync {
+    fn handle_rrq(&self, request: &RWHeader<ReadHeader>, peer: SocketAddr) -> Result<Box<dyn ReadSeek>, ErrorCode> {
+        self(request, peer)
+    }
+}
+
+/// Picks the file to serve for a read request based on who is asking, e.g. to hand each PXE client
+/// its own config, or a default per subnet. (The peer's MAC address, if needed, has to be looked up
+/// from its IP address, e.g. in the ARP cache.) This is implemented for closures with the same
+/// signature as `resolve`.
+pub trait FilenameResolver: Send + Sync {
+    /// Returns the filename to open instead of `filename`, or `None` to open it as requested.
+    fn resolve(&self, filename: &str, peer: SocketAddr) -> Option<String>;
+}
+
+impl<F> FilenameResolver for F
+    where F: Fn(&str, SocketAddr) -> Option<String> + Send + Sync {
+    fn resolve(&self, filename: &str, peer: SocketAddr) -> Option<String> {
+        self(filename, peer)
+    }
+}
+
+/// Handles packets whose opcode isn't understood by this crate, which is how experimental protocol
+/// extensions can be built on top of it. See `TFTPClient::set_opcode_handler`.
+pub trait OpcodeHandler: Send + Sync {
+    /// Called with the raw bytes of a packet that arrived on `socket` from `peer`. The opcode is
+    /// `packet[1]`, since the high byte of the opcode field is used for block numbers.
+    fn handle_packet(&self, packet: &[u8], peer: SocketAddr, socket: &mut UdpSocket);
+}
+
+/// Gets to look at every request a server receives before it is served, and can rewrite it (e.g.
+/// map filenames), or refuse it by returning an error. Errors are reported to the peer the same way
+/// `Backend` errors are. Middlewares run in the order they were added, after the access list has
+/// been checked; both methods let everything through by default. See `TFTPClient::add_middleware`.
+pub trait RequestMiddleware: Send + Sync {
+    fn read_request(&self, _request: &mut RWHeader<ReadHeader>, _peer: SocketAddr) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn write_request(&self, _request: &mut RWHeader<WriteHeader>, _peer: SocketAddr) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// Runs the sessions a server starts, one per request. A session blocks until its transfer is
+/// done, so the spawner decides how many transfers can run side by side. See
+/// `TFTPClient::set_spawner`.
+pub trait Spawner: Send + Sync {
+    fn spawn(&self, session: Box<dyn FnOnce() + Send>);
+}
+
+/// Runs every session on a thread of its own. This is what servers use by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadSpawner;
+
+impl Spawner for ThreadSpawner {
+    fn spawn(&self, session: Box<dyn FnOnce() + Send>) {
+        thread::spawn(session);
+    }
+}
+
+/// Runs sessions on a fixed size pool of threads. Sessions beyond the size of the pool wait for a
+/// thread to become free.
+#[cfg(feature = "rayon")]
+impl Spawner for rayon::ThreadPool {
+    fn spawn(&self, session: Box<dyn FnOnce() + Send>) {
+        rayon::ThreadPool::spawn(self, session);
+    }
+}
+
+/// A download being collected in memory. Clones share the same buffer, so the contents can still be
+/// taken out once `ReceiveFile` is done with it.
+#[cfg(feature = "client")]
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+#[cfg(feature = "client")]
+impl WriteSink for SharedBuffer {
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        match self.0.lock() {
+            Ok(mut cursor) => cursor.write_block(offset, data),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Failed to obtain buffer lock."))
+        }
+    }
+
+    fn finalize(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// A reader that is sent from by `send_from_reader`, shared by every attempt at the upload so that
+/// one on a mirror can start over from the beginning.
+#[cfg(feature = "client")]
+struct SharedReader<R>(Arc<Mutex<R>>);
+
+#[cfg(feature = "client")]
+impl<R> SharedReader<R> {
+    fn lock(&self) -> Result<MutexGuard<R>, io::Error> {
+        self.0.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain reader lock."))
+    }
+}
+
+#[cfg(feature = "client")]
+impl<R: Read> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.lock()?.read(buf)
+    }
+}
+
+#[cfg(feature = "client")]
+impl<R: Seek> Seek for SharedReader<R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> Result<u64, io::Error> {
+        self.lock()?.seek(pos)
+    }
+}
+
+/// The number of transfers a server has in progress, shared by all of its sessions.
+#[cfg(feature = "server")]
+#[derive(Default)]
+struct TransferCount {
+    count: Mutex<usize>,
+    finished: Condvar,
+}
+
+/// A transfer counted in a `TransferCount`, for as long as this is alive.
+#[cfg(feature = "server")]
+struct TransferSlot(Arc<TransferCount>);
+
+#[cfg(feature = "server")]
+impl TransferCount {
+    /// Takes a slot if fewer than `max` transfers are in progress. If `wait` is given, waits up to
+    /// that long for a transfer to finish rather than giving up straight away.
+    fn acquire(count: &Arc<TransferCount>, max: usize, wait: Option<Duration>) -> Option<TransferSlot> {
+        let start = Instant::now();
+        let mut current = count.count.lock().ok()?;
+        while *current >= max {
+            let remaining = match wait {
+                Some(wait) if start.elapsed() < wait => wait - start.elapsed(),
+                _ => return None
+            };
+            current = count.finished.wait_timeout(current, remaining).ok()?.0;
+        }
+        *current += 1;
+        Some(TransferSlot(count.clone()))
+    }
+}
+
+#[cfg(feature = "server")]
+impl Drop for TransferSlot {
+    fn drop(&mut self) {
+        if let Ok(mut current) = self.0.count.lock() {
+            *current -= 1;
+        }
+        self.0.finished.notify_one();
+    }
+}
+
+/// Marks a client's socket as taken by one of its transfers (see `TFTPClient::with_own_socket`)
+/// until this is dropped, which it is even if the transfer panics.
+#[cfg(feature = "client")]
+struct SocketClaim<'a>(&'a atomic::AtomicBool);
+
+#[cfg(feature = "client")]
+impl<'a> Drop for SocketClaim<'a> {
+    fn drop(&mut self) {
+        self.0.store(false, atomic::Ordering::Release);
+    }
+}
+
+/// Keeps a client's socket connected to the host's transfer ID for the length of one transfer, so
+/// that datagrams from anyone else are dropped by the OS rather than by the transfer. The socket is
+/// disconnected again when this is dropped, since the next request goes to the host's listening
+/// port, or to another host altogether. Nothing is done where sockets can't be disconnected.
+#[cfg(feature = "client")]
+struct PeerConnection(Option<UdpSocket>);
+
+#[cfg(feature = "client")]
+impl PeerConnection {
+    fn new(socket: &UdpSocket, peer: SocketAddr) -> Result<Self, io::Error> {
+        if cfg!(not(unix)) {
+            return Ok(PeerConnection(None))
+        }
+        let socket = socket.try_clone()?;
+        socket.connect(peer)?;
+        Ok(PeerConnection(Some(socket)))
+    }
+}
+
+#[cfg(feature = "client")]
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        if let Some(ref socket) = self.0 {
+            let _ = disconnect(socket);
+        }
+    }
+}
+
+/// The transfer that serves a request a server has accepted.
+#[cfg(feature = "server")]
+pub enum ServerTransfer {
+    Read(SendFile),
+    Write(ReceiveFile),
+}
+
+#[cfg(feature = "server")]
+impl ServerTransfer {
+    /// The socket of the session the transfer happens on.
+    pub fn socket(&self) -> &UdpSocket {
+        match *self {
+            ServerTransfer::Read(ref send_file) => send_file.socket(),
+            ServerTransfer::Write(ref recv_file) => recv_file.socket()
+        }
+    }
+
+    /// Polls the transfer until it is over, giving up the thread as the transfer's `PollBudget`
+    /// says.
+    pub fn run(&mut self) -> Result<(), io::Error> {
+        let poll_budget = match *self {
+            ServerTransfer::Read(ref send_file) => send_file.poll_budget(),
+            ServerTransfer::Write(ref recv_file) => recv_file.poll_budget()
+        };
+        poll_budget.run(self, ServerTransfer::idle_wait)
+    }
+
+    fn idle_wait(&self) -> Option<Duration> {
+        match *self {
+            ServerTransfer::Read(ref send_file) => send_file.idle_wait(),
+            ServerTransfer::Write(ref recv_file) => recv_file.idle_wait()
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Future for ServerTransfer {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            ServerTransfer::Read(ref mut send_file) => send_file.poll(),
+            ServerTransfer::Write(ref mut recv_file) => recv_file.poll()
+        }
+    }
+}
+
+/// A request accepted by `TFTPClient::accept_request`, along with what is needed to report on it
+/// once its transfer is over.
+#[cfg(feature = "server")]
+pub struct AcceptedRequest {
+    pub transfer: ServerTransfer,
+    direction: RequestType,
+    filename: String,
+    start_time: SystemTime,
+    progress: Arc<TransferProgress>,
+    /// Keeps the transfer listed in `active_transfers` until it is over.
+    _registration: RegisteredTransfer,
+}
+
+/// Configures and creates a `TFTPClient`, for when the defaults `TFTPClient::new` uses won't do.
+/// Everything but the addresses and data folder is optional.
+#[derive(Clone, Debug)]
+pub struct TFTPClientBuilder {
+    host_addr: SocketAddr,
+    socket_addr: SocketAddr,
+    data_folder: String,
+    window_size: usize,
+    max_window_size: Option<usize>,
+    block_size: usize,
+    auto_block_size: bool,
+    block_crc: bool,
+    ack_strategy: Option<AckStrategy>,
+    timeout: Option<Duration>,
+    dally: Duration,
+    mode: RWMode,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_attempts: usize,
+    max_block_retransmissions: Option<usize>,
+    retry_policy: Option<RetryPolicy>,
+    idle_timeout: Option<Duration>,
+    transfer_timeout: Option<Duration>,
+    mirrors: Vec<SocketAddr>,
+    upload_rate_limit: Option<u64>,
+    port_range: Option<RangeInclusive<u16>>,
+    interface: Option<String>,
+    socket_options: SocketOptions,
+    pipeline_depth: Option<usize>,
+    preallocate: bool,
+    sync_policy: SyncPolicy,
+    poll_budget: PollBudget,
+}
+
+impl TFTPClientBuilder {
+    /// Starts configuring a client that talks to `host_addr` from `socket_addr`, keeping its files
+    /// in `data_folder`.
+    pub fn new(host_addr: SocketAddr, socket_addr: SocketAddr, data_folder: String) -> Self {
+        TFTPClientBuilder {
+            host_addr,
+            socket_addr,
+            data_folder,
+            window_size: 1,
+            max_window_size: None,
+            block_size: MAX_DATA_LEN,
+            auto_block_size: false,
+            block_crc: false,
+            ack_strategy: None,
+            timeout: None,
+            dally: DEFAULT_DALLY(),
+            mode: RWMode::Octet,
+            read_timeout: Some(DEFAULT_SOCKET_TIMEOUT()),
+            write_timeout: Some(DEFAULT_SOCKET_TIMEOUT()),
+            max_attempts: MAX_ATTEMPTS,
+            max_block_retransmissions: None,
+            retry_policy: None,
+            idle_timeout: Some(TOTAL_TIMEOUT()),
+            transfer_timeout: None,
+            mirrors: Vec::new(),
+            upload_rate_limit: None,
+            port_range: None,
+            interface: None,
+            socket_options: SocketOptions::default(),
+            pipeline_depth: None,
+            preallocate: false,
+            sync_policy: SyncPolicy::default(),
+            poll_budget: PollBudget::default(),
+        }
+    }
+
+    /// Starts configuring a client that talks to `host_addr` from a port the OS picks; see
+    /// `TFTPClient::for_host`.
+    pub fn for_host(host_addr: SocketAddr, data_folder: String) -> Self {
+        TFTPClientBuilder::new(host_addr, ephemeral_addr(host_addr), data_folder)
+    }
+
+    /// The number of blocks to send before waiting for an ACK. Defaults to 1, i.e. RFC1350's lock
+    /// step.
+    pub fn window_size(mut self, window_size: usize) -> Self {
+        self.window_size = window_size;
+        self
+    }
+
+    /// See `TFTPClient::max_window_size`.
+    pub fn max_window_size(mut self, max_window_size: usize) -> Self {
+        self.max_window_size = Some(max_window_size);
+        self
+    }
+
+    /// See `TFTPClient::block_size`.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// See `TFTPClient::auto_block_size`.
+    pub fn auto_block_size(mut self, auto_block_size: bool) -> Self {
+        self.auto_block_size = auto_block_size;
+        self
+    }
+
+    /// See `TFTPClient::block_crc`.
+    pub fn block_crc(mut self, block_crc: bool) -> Self {
+        self.block_crc = block_crc;
+        self
+    }
+
+    /// See `TFTPClient::ack_strategy`.
+    pub fn ack_strategy(mut self, ack_strategy: AckStrategy) -> Self {
+        self.ack_strategy = Some(ack_strategy);
+        self
+    }
+
+    /// The retransmission interval to ask for; see `TFTPClient::timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// See `TFTPClient::dally`.
+    pub fn dally(mut self, dally: Duration) -> Self {
+        self.dally = dally;
+        self
+    }
+
+    /// See `TFTPClient::mode`.
+    pub fn mode(mut self, mode: RWMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// The read timeout of the client's sockets; `None` means reads block. Defaults to
+    /// `DEFAULT_SOCKET_TIMEOUT`.
+    pub fn read_timeout(mut self, read_timeout: Option<Duration>) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// The write timeout of the client's sockets; `None` means writes block. Defaults to
+    /// `DEFAULT_SOCKET_TIMEOUT`.
+    pub fn write_timeout(mut self, write_timeout: Option<Duration>) -> Self {
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    /// See `TFTPClient::max_attempts`. Defaults to `MAX_ATTEMPTS`.
+    pub fn max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// See `TFTPClient::max_block_retransmissions`.
+    pub fn max_block_retransmissions(mut self, max_block_retransmissions: usize) -> Self {
+        self.max_block_retransmissions = Some(max_block_retransmissions);
+        self
+    }
+
+    /// See `TFTPClient::retry_policy`.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    /// See `TFTPClient::idle_timeout`. Defaults to `TOTAL_TIMEOUT`.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// See `TFTPClient::transfer_timeout`. Defaults to no deadline.
+    pub fn transfer_timeout(mut self, transfer_timeout: Option<Duration>) -> Self {
+        self.transfer_timeout = transfer_timeout;
+        self
+    }
+
+    /// Adds a host to fall back on; see `TFTPClient::mirrors`.
+    pub fn mirror(mut self, mirror: SocketAddr) -> Self {
+        self.mirrors.push(mirror);
+        self
+    }
+
+    /// See `TFTPClient::upload_rate_limit`. Defaults to no limit.
+    pub fn upload_rate_limit(mut self, upload_rate_limit: Option<u64>) -> Self {
+        self.upload_rate_limit = upload_rate_limit;
+        self
+    }
+
+    /// See `TFTPClient::port_range`. If the client's own address has port 0, its port is picked
+    /// from the range too. Defaults to letting the OS pick.
+    pub fn port_range(mut self, port_range: Option<RangeInclusive<u16>>) -> Self {
+        self.port_range = port_range;
+        self
+    }
+
+    /// See `TFTPClient::interface`. The client's own socket is pinned to it as well.
+    pub fn interface(mut self, interface: Option<String>) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    /// See `TFTPClient::socket_options`. The client's own socket gets them as well.
+    pub fn socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// See `TFTPClient::pipeline_depth`. Defaults to writing blocks as they arrive.
+    pub fn pipeline_depth(mut self, pipeline_depth: Option<usize>) -> Self {
+        self.pipeline_depth = pipeline_depth;
+        self
+    }
+
+    /// See `TFTPClient::preallocate`.
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// See `TFTPClient::sync_policy`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// See `TFTPClient::poll_budget`. Defaults to `DEFAULT_POLL_BUDGET` polls.
+    pub fn poll_budget(mut self, poll_budget: PollBudget) -> Self {
+        self.poll_budget = poll_budget;
+        self
+    }
+
+    /// Binds the socket and creates the client.
+    pub fn build(self) -> Result<TFTPClient, io::Error> {
+        send_window_size(self.window_size, self.max_window_size).validate()?;
+        if self.block_size < MIN_BLOCK_SIZE || self.block_size > MAX_BLOCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The block size must be between {} and {}.", MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)))
+        }
+        let socket_addr = match self.interface {
+            Some(ref interface) => interface_bind_addr(self.socket_addr, interface)?,
+            None => self.socket_addr
+        };
+        let udp_socket = match self.port_range {
+            Some(ref ports) if socket_addr.port() == 0 => bind_in_range(ports, |port| UdpSocket::bind(SocketAddr::new(socket_addr.ip(), port)))?,
+            _ => UdpSocket::bind(socket_addr)?
+        };
+        if let Some(ref interface) = self.interface {
+            pin_to_interface(&udp_socket, interface)?;
+        }
+        let mut client = TFTPClient::from_socket(self.host_addr, udp_socket, self.data_folder, self.window_size)?;
+        client.max_window_size = self.max_window_size;
+        client.block_size = self.block_size;
+        client.auto_block_size = self.auto_block_size;
+        client.block_crc = self.block_crc;
+        client.ack_strategy = self.ack_strategy;
+        client.timeout = self.timeout;
+        client.dally = self.dally;
+        client.mode = self.mode;
+        client.read_timeout = self.read_timeout;
+        client.write_timeout = self.write_timeout;
+        client.max_attempts = self.max_attempts;
+        client.max_block_retransmissions = self.max_block_retransmissions;
+        client.retry_policy = self.retry_policy;
+        client.idle_timeout = self.idle_timeout;
+        client.transfer_timeout = self.transfer_timeout;
+        client.mirrors = self.mirrors;
+        client.upload_rate_limit = self.upload_rate_limit;
+        client.port_range = self.port_range;
+        client.interface = self.interface;
+        client.socket_options = self.socket_options;
+        client.pipeline_depth = self.pipeline_depth;
+        client.preallocate = self.preallocate;
+        client.sync_policy = self.sync_policy;
+        client.poll_budget = self.poll_budget;
+        if let Ok(socket) = client.udp_socket.lock() {
+            client.configure_socket(&socket)?;
+        }
+        Ok(client)
+    }
+}
+
 #[derive(Clone)]
 pub struct TFTPClient {
     pub host_addr: SocketAddr,
     data_folder: String,
+    /// The window size to send with. A window size of 1 means stop-and-wait: every block waits for
+    /// its Ack, and received blocks are acknowledged one by one. Otherwise this is the size the
+    /// window starts out at, and it grows from there while no blocks are lost.
     pub window_size: usize,
+    /// How large the window may grow while sending. `None` means `MAX_WINDOW_SIZE`, or
+    /// `window_size` if that is larger. This makes no difference to stop-and-wait.
+    pub max_window_size: Option<usize>,
+    /// The block size to request with the "blksize" option. No option is sent if this is
+    /// MAX_DATA_LEN, the default block size.
+    pub block_size: usize,
+    /// If set, the block size to request is worked out from the MTU of the path to the host
+    /// instead, falling back to `block_size` if the path MTU can't be determined.
+    pub auto_block_size: bool,
+    /// Whether downloads and uploads ask for every block to carry a CRC32 with `BLOCK_CRC_OPTION`,
+    /// so that blocks corrupted on the way are sent again rather than written. Hosts that don't
+    /// support it transfer files as usual. A server always agrees to it when asked.
+    pub block_crc: bool,
+    /// When received files (downloads, and uploads to a server) are acknowledged; see
+    /// `AckStrategy`. `None` means lock step if `window_size` is 1, and a burst at a time
+    /// otherwise.
+    pub ack_strategy: Option<AckStrategy>,
+    /// The retransmission timeout to request with the "utimeout" option. If the host doesn't
+    /// acknowledge it, the usual timers are used.
+    pub timeout: Option<Duration>,
+    /// How long to linger for after receiving a whole file; see `ReceiveFile::set_dally`.
+    pub dally: Duration,
+    /// The transfer mode to make requests with, unless one is given with the request (see
+    /// `request_file_with_mode` and `send_file_with_mode`). Data is sent as it is whatever the mode.
+    pub mode: RWMode,
+    /// The read timeout of the sockets this creates, e.g. for sessions.
+    pub read_timeout: Option<Duration>,
+    /// The write timeout of the sockets this creates, e.g. for sessions.
+    pub write_timeout: Option<Duration>,
+    /// How many errors in a row it takes for a transfer to fail, in either direction, and how many
+    /// times sending a packet is tried. Lossy links, e.g. radio, need more than `MAX_ATTEMPTS`.
+    pub max_attempts: usize,
+    /// If set, a file this sends fails with `TFTPError::RetransmitLimitExceeded` once any one of its
+    /// blocks would have to be sent again more than this many times; see
+    /// `SendFile::set_max_block_retransmissions`.
+    pub max_block_retransmissions: Option<usize>,
+    /// If set, how transfers retransmit and when they give up waiting for the peer, in both
+    /// directions. Otherwise retransmission follows the measured round trip time.
+    pub retry_policy: Option<RetryPolicy>,
+    /// How long the peer may go quiet before a transfer fails, including waiting for the response
+    /// to a request. `None` means waiting forever.
+    pub idle_timeout: Option<Duration>,
+    /// How long a single transfer may take in total before it fails. `None`, the default, means
+    /// there is no deadline, which is what large files on slow links need.
+    pub transfer_timeout: Option<Duration>,
+    /// Hosts to fall back on, in order, when a request to `host_addr` times out or is answered
+    /// with an error. They have to be reachable from `udp_socket`, i.e. be of the same address
+    /// family. Every request starts with `host_addr` again.
+    pub mirrors: Vec<SocketAddr>,
+    /// If set, the sockets this creates for single transfers (server sessions, and downloads and
+    /// uploads running alongside another) are bound to a port from this range rather than one the
+    /// OS picks, so that stateful firewalls can be set up for them.
+    pub port_range: Option<RangeInclusive<u16>>,
+    /// If set, the sockets this creates for single transfers are pinned to the network interface
+    /// with this name (e.g. "eth1"), so that a multi-homed host only talks through it; see
+    /// `pin_to_interface`. `TFTPClientBuilder::interface` pins the client's own socket too.
+    pub interface: Option<String>,
+    /// The TTL, DSCP marking and buffer sizes of the sockets this creates; see `SocketOptions`.
+    /// `TFTPClientBuilder::socket_options` sets them on the client's own socket too.
+    pub socket_options: SocketOptions,
+    /// If set, received files (downloads, and uploads to a server) are written on a thread of their
+    /// own with up to this many blocks queued up for it, so that slow disk writes don't hold up the
+    /// ACKs; see `PipelinedSink`.
+    pub pipeline_depth: Option<usize>,
+    /// Whether received files are given their whole size before any of it is written, rather than
+    /// growing with every block; see `WriteSink::allocate`. Downloads ask the host for the size
+    /// with the "tsize" option while this is set, and a server takes it from the "tsize" option of
+    /// a write request.
+    pub preallocate: bool,
+    /// When downloaded files are made durable, i.e. waited on until they have reached the disk;
+    /// see `SyncPolicy`. Files received as a server are up to the backend, e.g.
+    /// `FsBackend::sync_policy`.
+    pub sync_policy: SyncPolicy,
+    /// How often a transfer gives up the thread it runs on, so that one run from a task on an
+    /// executor doesn't keep the other tasks waiting; see `PollBudget`.
+    pub poll_budget: PollBudget,
+    /// Told how this client's downloads and uploads are going.
+    progress_listener: Option<Arc<dyn ProgressListener>>,
+    /// Pauses and resumes this client's downloads and uploads; see `transfer_control`.
+    control: Arc<TransferControl>,
+    /// Extra options to send along with every request, e.g. vendor specific options.
+    pub request_options: BTreeMap<String, String>,
+    /// Which peers may make requests. Everyone else is refused with an AccessViolation error.
+    pub access_list: AccessList,
+    /// The most transfers a server will have in progress at once, if limited.
+    pub max_concurrent_transfers: Option<usize>,
+    /// If set, requests that arrive while `max_concurrent_transfers` are in progress wait for one
+    /// of them to finish. Otherwise they are refused with an error straight away.
+    pub queue_transfers: bool,
+    #[cfg(feature = "server")]
+    transfers: Arc<TransferCount>,
+    /// Set while one of this client's own transfers is using `udp_socket`; see `with_own_socket`.
+    socket_busy: Arc<atomic::AtomicBool>,
+    /// The transfers this server has in progress, and the ones it has finished.
+    sessions: Arc<SessionRegistry>,
+    /// The number of finished transfers to remember; see `transfer_history`.
+    pub history_len: usize,
+    /// Get told about every request this server handles.
+    observers: Vec<Arc<dyn TransferObserver>>,
+    /// Every request is passed through these, in order, before it is served.
+    middleware: Vec<Arc<dyn RequestMiddleware>>,
+    /// The most bytes per second a server sends to each peer it serves a file to, if limited.
+    pub rate_limit: Option<u64>,
+    /// The most bytes per second `send_file` and friends send, if limited, so that uploads over a
+    /// shared link leave room for everyone else.
+    pub upload_rate_limit: Option<u64>,
+    /// What to do when a write request is for a file that already exists.
+    pub overwrite_policy: OverwritePolicy,
+    /// Where served files are read from and received files are written to.
+    backend: Arc<dyn Backend>,
+    /// If set, rewrites the filenames of read requests before they are opened.
+    filename_resolver: Option<Arc<dyn FilenameResolver>>,
+    /// If set, read requests are answered by this instead of the backend.
+    read_handler: Option<Arc<dyn ReadRequestHandler>>,
+    /// Answers the options of incoming requests that aren't understood by this crate.
+    option_handler: Option<Arc<dyn OptionHandler>>,
+    /// If set, creates the window strategy of every file this sends; otherwise `Aimd` is used.
+    window_strategy: Option<Arc<dyn WindowStrategyFactory>>,
+    /// Handlers for packets with opcodes this crate doesn't understand, keyed by opcode.
+    opcode_handlers: BTreeMap<u8, Arc<dyn OpcodeHandler>>,
+    /// Runs the sessions the server starts.
+    spawner: Arc<dyn Spawner>,
+    /// More sockets that the server takes requests on, besides `udp_socket`.
+    #[cfg(feature = "server")]
+    listeners: Vec<Arc<Mutex<UdpSocket>>>,
+    /// If set, read requests are served MTFTP style: the data is multicast to the configured
+    /// group and request options are ignored.
+    pub mtftp: Option<MtftpConfig>,
+    /// The socket this client sends requests from, or a server takes them on. Downloads and uploads
+    /// that start while another is using it get a socket of their own. Whatever reads from or
+    /// writes to it does so through a handle from `UdpSocket::try_clone`, so the lock is only ever
+    /// held briefly.
     pub udp_socket: Arc<Mutex<UdpSocket>>
 }
 
-unsafe impl Send for TFTPClient {}
-unsafe impl Sync for TFTPClient {}
+unsafe impl Send for TFTPClient {}
+unsafe impl Sync for TFTPClient {}
+
+impl TFTPClient {
+    pub fn new(host_addr: SocketAddr, socket_addr: SocketAddr, data_folder: String, window_size: usize) -> Result<Self, io::Error> {
+        TFTPClient::from_socket(host_addr, UdpSocket::bind(socket_addr)?, data_folder, window_size)
+    }
+
+    /// Creates a client that talks to `host_addr` from a free port the OS picks, on the unspecified
+    /// address of the same family as `host_addr`.
+    pub fn for_host(host_addr: SocketAddr, data_folder: String, window_size: usize) -> Result<Self, io::Error> {
+        TFTPClient::new(host_addr, ephemeral_addr(host_addr), data_folder, window_size)
+    }
+
+    /// Starts configuring a client with a `TFTPClientBuilder`.
+    pub fn builder(host_addr: SocketAddr, socket_addr: SocketAddr, data_folder: String) -> TFTPClientBuilder {
+        TFTPClientBuilder::new(host_addr, socket_addr, data_folder)
+    }
+
+    /// Creates a server that listens on `port` for both IPv4 and IPv6 peers, with a single IPv6
+    /// socket that also accepts IPv4; see `bind_dual_stack`. IPv4 peers show up with IPv4-mapped
+    /// IPv6 addresses (::ffff:a.b.c.d).
+    #[cfg(feature = "server")]
+    pub fn new_dual_stack(port: u16, data_folder: String, window_size: usize) -> Result<Self, io::Error> {
+        let udp_socket = bind_dual_stack(port)?;
+        let host_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+        TFTPClient::from_socket(host_addr, udp_socket, data_folder, window_size)
+    }
+
+    /// Creates a server from the sockets systemd passes on when the process is socket activated.
+    /// The first socket becomes `udp_socket`, the rest are added as listeners, so `serve` takes
+    /// requests on all of them.
+    #[cfg(all(unix, feature = "server"))]
+    pub fn from_systemd(data_folder: String, window_size: usize) -> Result<Self, io::Error> {
+        let mut sockets = systemd_sockets()?.into_iter();
+        let udp_socket = match sockets.next() {
+            Some(udp_socket) => udp_socket,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "No sockets were passed on by systemd."))
+        };
+        let mut server = TFTPClient::from_socket(udp_socket.local_addr()?, udp_socket, data_folder, window_size)?;
+        for udp_socket in sockets {
+            server.configure_socket(&udp_socket)?;
+            server.listeners.push(Arc::new(Mutex::new(udp_socket)));
+        }
+        Ok(server)
+    }
+
+    /// Creates a server from the socket inetd passes on as standard input. Such a server is meant
+    /// to handle the request that caused inetd to start it with `serve_one`, and then exit.
+    #[cfg(all(unix, feature = "server"))]
+    pub fn from_inetd(data_folder: String, window_size: usize) -> Result<Self, io::Error> {
+        let udp_socket = inetd_socket()?;
+        TFTPClient::from_socket(udp_socket.local_addr()?, udp_socket, data_folder, window_size)
+    }
+
+    /// Creates a client that uses `udp_socket` as it is, rather than binding a socket of its own,
+    /// e.g. one with socket options set (SO_BINDTODEVICE, buffer sizes) or one passed on by a
+    /// service manager. Only the read and write timeouts are changed, to `DEFAULT_SOCKET_TIMEOUT`.
+    pub fn from_socket(host_addr: SocketAddr, udp_socket: UdpSocket, data_folder: String, window_size: usize) -> Result<Self, io::Error> {
+        udp_socket.set_read_timeout(Some(DEFAULT_SOCKET_TIMEOUT()))?;
+        udp_socket.set_write_timeout(Some(DEFAULT_SOCKET_TIMEOUT()))?;
+
+        Ok(TFTPClient {
+            window_size,
+            max_window_size: None,
+            block_size: MAX_DATA_LEN,
+            auto_block_size: false,
+            block_crc: false,
+            ack_strategy: None,
+            timeout: None,
+            dally: DEFAULT_DALLY(),
+            mode: RWMode::Octet,
+            read_timeout: Some(DEFAULT_SOCKET_TIMEOUT()),
+            write_timeout: Some(DEFAULT_SOCKET_TIMEOUT()),
+            max_attempts: MAX_ATTEMPTS,
+            max_block_retransmissions: None,
+            retry_policy: None,
+            idle_timeout: Some(TOTAL_TIMEOUT()),
+            transfer_timeout: None,
+            mirrors: Vec::new(),
+            port_range: None,
+            interface: None,
+            socket_options: SocketOptions::default(),
+            pipeline_depth: None,
+            preallocate: false,
+            sync_policy: SyncPolicy::default(),
+            poll_budget: PollBudget::default(),
+            progress_listener: None,
+            control: Arc::new(TransferControl::default()),
+            request_options: BTreeMap::new(),
+            access_list: AccessList::default(),
+            max_concurrent_transfers: None,
+            queue_transfers: false,
+            #[cfg(feature = "server")]
+            transfers: Arc::new(TransferCount::default()),
+            socket_busy: Arc::new(atomic::AtomicBool::new(false)),
+            sessions: Arc::new(SessionRegistry::default()),
+            history_len: DEFAULT_HISTORY_LEN,
+            observers: vec![],
+            middleware: vec![],
+            rate_limit: None,
+            upload_rate_limit: None,
+            overwrite_policy: OverwritePolicy::default(),
+            backend: Arc::new(FsBackend::new(data_folder.clone())),
+            filename_resolver: None,
+            read_handler: None,
+            option_handler: None,
+            window_strategy: None,
+            opcode_handlers: BTreeMap::new(),
+            spawner: Arc::new(ThreadSpawner),
+            #[cfg(feature = "server")]
+            listeners: vec![],
+            mtftp: None,
+            data_folder,
+            host_addr,
+            udp_socket: Arc::new(Mutex::new(udp_socket))
+        })
+    }
+
+    /// Where a transfer writes what it receives to: `sink` itself, or a `PipelinedSink` writing to
+    /// it if `pipeline_depth` is set.
+    fn write_sink<W: WriteSink + 'static>(&self, sink: W) -> Box<dyn WriteSink> {
+        match self.pipeline_depth {
+            Some(depth) => Box::new(PipelinedSink::new(sink, depth)),
+            None => Box::new(sink)
+        }
+    }
+
+    /// Gives a socket this client created the configured socket options, and read and write
+    /// timeouts.
+    fn configure_socket(&self, udp_socket: &UdpSocket) -> Result<(), io::Error> {
+        set_socket_options(udp_socket, &self.socket_options)?;
+        udp_socket.set_read_timeout(self.read_timeout)?;
+        udp_socket.set_write_timeout(self.write_timeout)
+    }
+
+    /// Creates a copy of this client for a single transfer with `peer`. The copy gets its own
+    /// socket bound to an ephemeral port (from `port_range`, if set) on the same interface, since
+    /// RFC1350 requires that each transfer use a freshly chosen transfer ID (port) rather than the
+    /// listening port.
+    pub fn new_session(&self, peer: SocketAddr) -> Result<Self, io::Error> {
+        let local_addr = match self.udp_socket.lock() {
+            Ok(socket) => socket.local_addr()?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Failed to obtain UDP Socket lock."))
+        };
+
+        // IPv4 peers of a dual stack server can only be answered from a socket that accepts IPv4.
+        let dual_stack = local_addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) && is_ipv4_mapped(peer.ip());
+        let bind = |port| if dual_stack {
+            bind_dual_stack(port)
+        } else {
+            UdpSocket::bind(SocketAddr::new(local_addr.ip(), port))
+        };
+        let udp_socket = match self.port_range {
+            Some(ref ports) => bind_in_range(ports, bind)?,
+            None => bind(0)?
+        };
+        if let Some(ref interface) = self.interface {
+            pin_to_interface(&udp_socket, interface)?;
+        }
+        self.configure_socket(&udp_socket)?;
+
+        let mut session = self.clone();
+        session.host_addr = peer;
+        session.udp_socket = Arc::new(Mutex::new(udp_socket));
+        session.socket_busy = Arc::new(atomic::AtomicBool::new(false));
+        Ok(session)
+    }
+
+    /// A handle of its own to `udp_socket` (see `UdpSocket::try_clone`), for whatever reads from or
+    /// writes to it, e.g. a `SendFile` or `ReceiveFile`. Handles never have to wait for, or fail to
+    /// get, the lock on `udp_socket`, which is only held for as long as it takes to make one.
+    fn socket_handle(&self) -> Result<UdpSocket, io::Error> {
+        match self.udp_socket.lock() {
+            Ok(socket) => socket.try_clone(),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Failed to obtain UDP Socket lock."))
+        }
+    }
+
+    //fn connect_to_host(host_addr: SocketAddr) -> impl Future<Item=(), Error=io::Error> { unimplemented!() }
+    //pub fn send_file<P: AsRef<Path>, S: AsRef<Path>>(source: P, filename: S) -> impl Future<Item=i32, Error=io::Error> { unimplemented!() }
+}
+
+/// Downloads and uploads.
+#[cfg(feature = "client")]
+impl TFTPClient {
+
+    /// Requests `filename` from the host, saving it to `destination` in the data folder. If no data
+    /// arrives at all with a large block size, the datagrams are most likely being fragmented and
+    /// dropped along the way, so the request is retried with successively smaller block sizes.
+    pub fn request_file<P: AsRef<Path>, S: AsRef<Path>>(&mut self, filename: P, destination: S) -> impl Future<Item=TransferOutcome, Error=io::Error> {
+        let mode = self.mode;
+        self.request_file_with_mode(filename, destination, mode)
+    }
+
+    /// Like `request_file`, but runs the transfer to completion on the current thread.
+    pub fn request_file_blocking<P: AsRef<Path>, S: AsRef<Path>>(&mut self, filename: P, destination: S) -> Result<TransferOutcome, io::Error> {
+        self.request_file(filename, destination).wait()
+    }
+
+    /// Like `request_file`, but asks for the file in `mode` rather than the client's `mode`.
+    pub fn request_file_with_mode<P: AsRef<Path>, S: AsRef<Path>>(&mut self, filename: P, destination: S, mode: RWMode) -> impl Future<Item=TransferOutcome, Error=io::Error> {
+        let dest_path: &Path = destination.as_ref();
+        let dest = self.data_folder.clone().add("/").add(dest_path.to_str().unwrap());
+        let filename = filename.as_ref().to_str().unwrap().to_string();
+        let sync_policy = self.sync_policy;
+        self.download(filename, mode, move || {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&dest)?;
+            let mut sink = FileSink::new(file);
+            sink.set_sync_policy(sync_policy);
+            Ok(sink)
+        })
+    }
+
+    /// Like `request_file`, but adds the file onto the end of `destination` rather than replacing
+    /// it, e.g. to collect a log that the host keeps adding to; see `AppendSink`. `destination` is
+    /// created if it doesn't exist.
+    pub fn append_file<P: AsRef<Path>, S: AsRef<Path>>(&mut self, filename: P, destination: S) -> impl Future<Item=TransferOutcome, Error=io::Error> {
+        let dest = self.data_folder.clone().add("/").add(destination.as_ref().to_str().unwrap());
+        let filename = filename.as_ref().to_str().unwrap().to_string();
+        let sync_policy = self.sync_policy;
+        let len = metadata(&dest).map(|metadata| metadata.len()).unwrap_or(0);
+        self.download(filename, self.mode, move || {
+            let file = OpenOptions::new().read(true).write(true).create(true).open(&dest)?;
+            // Whatever an attempt that failed part way through added goes again.
+            file.set_len(len)?;
+            let mut sink = FileSink::new(file);
+            sink.set_sync_policy(sync_policy);
+            Ok(AppendSink::new(sink, len))
+        })
+    }
+
+    /// Like `request_file`, but once the file has been saved it is checked against `expected`. If
+    /// it does not match, the future fails with a `TFTPError::ChecksumMismatch`; the file is left
+    /// in place.
+    pub fn request_file_verified<P: AsRef<Path>, S: AsRef<Path>>(&mut self, filename: P, destination: S, expected: Checksum) -> impl Future<Item=TransferOutcome, Error=io::Error> {
+        let dest = self.data_folder.clone().add("/").add(destination.as_ref().to_str().unwrap());
+        self.request_file(filename, destination).and_then(move |outcome| {
+            let actual = expected.of_file(&dest)?;
+            if actual == expected {
+                Ok(outcome)
+            } else {
+                Err(TFTPError::ChecksumMismatch(expected, actual).into())
+            }
+        })
+    }
+
+    /// Downloads `manifest`, then every file it lists (see `parse_manifest`). TFTP has no way to
+    /// list a folder, so this is how sets of files, such as a netboot image and everything it
+    /// loads, are usually fetched. The files are saved relative to the data folder, creating any
+    /// folders that are missing, one after the other; the first one that fails fails the lot.
+    pub fn request_manifest<P: AsRef<Path>>(&mut self, manifest: P) -> impl Future<Item=Vec<(String, TransferOutcome)>, Error=io::Error> {
+        let mut client = self.clone();
+        self.request_file_to_vec(manifest).and_then(move |contents| {
+            let entries = parse_manifest(&String::from_utf8_lossy(&contents))?;
+            let mut outcomes = Vec::with_capacity(entries.len());
+            for (filename, destination) in entries {
+                if let Some(folder) = Path::new(&client.data_folder).join(&destination).parent() {
+                    create_dir_all(folder)?;
+                }
+                // Anything still arriving from the previous transfer would confuse this one.
+                discard_pending(&client.socket_handle()?);
+                let outcome = client.request_file(&filename, &destination).wait()?;
+                outcomes.push((filename, outcome));
+            }
+            Ok(outcomes)
+        })
+    }
+
+    /// Requests `filename` from the host, returning its contents rather than saving it anywhere.
+    pub fn request_file_to_vec<P: AsRef<Path>>(&mut self, filename: P) -> impl Future<Item=Vec<u8>, Error=io::Error> {
+        let filename = filename.as_ref().to_str().unwrap().to_string();
+        let buffer = SharedBuffer::default();
+        let contents = buffer.clone();
+        self.download(filename, self.mode, move || {
+            // Start over if the download is retried.
+            let mut cursor = buffer.0.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain buffer lock."))?;
+            *cursor = Cursor::new(Vec::new());
+            Ok(buffer.clone())
+        }).and_then(move |_| {
+            let mut cursor = contents.0.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain buffer lock."))?;
+            Ok(mem::replace(&mut *cursor, Cursor::new(Vec::new())).into_inner())
+        })
+    }
+
+    /// Requests `filename` from the host and writes it to `writer`, which is handed back once the
+    /// whole file has been written. Blocks can arrive out of order, so the file is collected in
+    /// memory first; this is meant for small files.
+    pub fn request_file_to_writer<P: AsRef<Path>, W: Write>(&mut self, filename: P, writer: W) -> impl Future<Item=W, Error=io::Error> {
+        self.request_file_to_vec(filename).and_then(move |contents| {
+            let mut writer = writer;
+            writer.write_all(&contents)?;
+            writer.flush()?;
+            Ok(writer)
+        })
+    }
+
+    /// Downloads `filename` in the given mode into what `open` returns, falling back to smaller
+    /// block sizes if need be. `open` is called again for every attempt.
+    fn download<W, F>(&self, filename: String, mode: RWMode, open: F) -> impl Future<Item=TransferOutcome, Error=io::Error>
+        where W: WriteSink + 'static, F: Fn() -> Result<W, io::Error> {
+        let client = self.clone();
+        future::lazy(move || {
+            let start = Instant::now();
+            let mut outcome = client.with_own_socket(|client| {
+                client.with_failover(|client| client.download_from_host(&filename, mode, &open))
+            })?;
+            outcome.duration = start.elapsed();
+            Ok(outcome)
+        })
+    }
+
+    /// Downloads `filename` from `host_addr`; see `download`.
+    fn download_from_host<W, F>(&self, filename: &str, mode: RWMode, open: &F) -> Result<TransferOutcome, io::Error>
+        where W: WriteSink + 'static, F: Fn() -> Result<W, io::Error> {
+        let mut block_size = self.request_block_size();
+        loop {
+            match (self.request_file_once(filename.to_string(), mode, open, block_size), fallback_block_size(block_size)) {
+                (Err(ref e), Some(smaller)) if is_no_data_received(e) => {
+                    discard_pending(&self.socket_handle()?);
+                    block_size = smaller;
+                },
+                (result, _) => return result
+            }
+        }
+    }
+
+    /// Runs `transfer` with a client that has a socket to itself, so that any number of transfers
+    /// can run at once: `udp_socket` if no other transfer is using it, otherwise a new socket on an
+    /// ephemeral port (see `new_session`).
+    fn with_own_socket<T, F>(&self, transfer: F) -> Result<T, io::Error>
+        where F: FnOnce(&TFTPClient) -> Result<T, io::Error> {
+        if self.socket_busy.swap(true, atomic::Ordering::AcqRel) {
+            return transfer(&self.new_session(self.host_addr)?)
+        }
+        let _claim = SocketClaim(&self.socket_busy);
+        transfer(self)
+    }
+
+    /// Runs `transfer` against `host_addr`, then against each of the `mirrors` in turn for as long
+    /// as the host it tried timed out or answered with an error.
+    fn with_failover<T, F>(&self, mut transfer: F) -> Result<T, io::Error>
+        where F: FnMut(&TFTPClient) -> Result<T, io::Error> {
+        let mut result = transfer(self);
+        for &mirror in self.mirrors.iter() {
+            match result {
+                Err(ref e) if is_failover_error(e) => discard_pending(&self.socket_handle()?),
+                _ => break
+            }
+            let mut client = self.clone();
+            client.host_addr = mirror;
+            result = transfer(&client);
+        }
+        result
+    }
+
+    /// Makes a single attempt at requesting a file, asking for the given block size. It is written
+    /// to what `open` returns.
+    fn request_file_once<W, F>(&self, filename: String, mode: RWMode, open: &F, block_size: usize) -> Result<TransferOutcome, io::Error>
+        where W: WriteSink + 'static, F: Fn() -> Result<W, io::Error> {
+        let mut read_header = RWHeader::<ReadHeader>::new(filename, mode).unwrap();
+        read_header.blksize = requested_blksize(block_size);
+        read_header.utimeout = self.timeout.map(duration_micros);
+        read_header.block_crc = self.block_crc;
+        if self.progress_listener.is_some() || self.preallocate {
+            read_header.tsize = Some(0);
+        }
+        read_header.options = self.request_options.clone();
+        let requested = read_header.all_options();
+        let socket = self.socket_handle()?;
+        Header::Read(read_header).send(self.host_addr.clone(), &socket)?;
+
+        let addr = await_transfer_id(&socket, self.host_addr.clone(), self.idle_timeout)?;
+        let _connection = PeerConnection::new(&socket, addr)?;
+        let options = negotiate_options(&socket, addr, &requested)?;
+        let mut run = ReceiveFile::new(socket, addr, self.write_sink(open()?), negotiated_block_size(&options))?;
+        run.set_dally(self.dally);
+        run.set_ack_strategy(self.receive_ack_strategy());
+        run.set_timeout(negotiated_timeout(&options));
+        run.set_block_crc(negotiated_block_crc(&options));
+        run.set_max_attempts(self.max_attempts);
+        run.set_retry_policy(self.retry_policy);
+        run.set_poll_budget(self.poll_budget);
+        run.set_idle_timeout(self.idle_timeout);
+        run.set_transfer_timeout(self.transfer_timeout);
+        let total_bytes = options.get("tsize").and_then(|tsize| tsize.parse::<u64>().ok());
+        if let Some(total_bytes) = total_bytes.filter(|_| self.preallocate) {
+            run.set_file_size(total_bytes)?;
+        }
+        let progress = Arc::new(TransferProgress::new(total_bytes, self.progress_listener.clone()));
+        run.set_progress(progress.clone());
+        run.set_control(self.control.clone());
+        if fallback_block_size(block_size).is_some() {
+            run.set_first_block_timeout(Some(FIRST_BLOCK_TIMEOUT()));
+        }
+        run.run()?;
+        Ok(TransferOutcome::new(&progress, negotiated_block_size(&options), options))
+    }
+
+    /// Asks the host for the size of `filename` without downloading it. This sends a request with
+    /// the "tsize" option (RFC2349), and aborts the transfer as soon as the host has answered it.
+    pub fn stat<P: AsRef<Path>>(&mut self, filename: P) -> impl Future<Item=u64, Error=io::Error> {
+        let filename = filename.as_ref().to_str().unwrap().to_string();
+        let client = self.clone();
+        future::lazy(move || {
+            let mut read_header = RWHeader::<ReadHeader>::new(filename, client.mode).unwrap();
+            read_header.tsize = Some(0);
+            let options = client.with_own_socket(|client| client.negotiate_only(read_header, "Only the file size was wanted"))?;
+            match options.get("tsize").and_then(|tsize| tsize.parse::<u64>().ok()) {
+                Some(tsize) => Ok(tsize),
+                None => Err(io::Error::new(io::ErrorKind::Other, "The host did not report the size of the file."))
+            }
+        })
+    }
+
+    /// Finds out which options the host supports, by requesting `filename` (which the host should
+    /// have) with "blksize", "tsize" and "windowsize" set to the largest values this crate can use,
+    /// `BLOCK_CRC_OPTION`, and any `request_options`. The transfer is aborted as soon as the host has answered.
+    /// Returns the options the host acknowledged and the values it picked; a host that doesn't
+    /// support options at all acknowledges none.
+    pub fn probe_options<P: AsRef<Path>>(&mut self, filename: P) -> impl Future<Item=BTreeMap<String, String>, Error=io::Error> {
+        let filename = filename.as_ref().to_str().unwrap().to_string();
+        let client = self.clone();
+        future::lazy(move || {
+            let mut read_header = RWHeader::<ReadHeader>::new(filename, client.mode).unwrap();
+            read_header.blksize = Some(MAX_BLOCK_SIZE);
+            read_header.tsize = Some(0);
+            read_header.block_crc = true;
+            read_header.options = client.request_options.clone();
+            read_header.options.insert("windowsize".to_string(), MAX_WINDOW_SIZE.to_string());
+            client.with_own_socket(|client| client.negotiate_only(read_header, "Only the options were wanted"))
+        })
+    }
+
+    /// Requests `filename` like `request_file` does, unless the copy saved at `destination` already
+    /// matches the host's, in which case nothing is downloaded. First the host is asked for the
+    /// size of the file ("tsize"), and if `hash_option` is given, for its digest with that vendor
+    /// option, which the host should answer with an MD5 or SHA-256 digest in hex. The file is only
+    /// skipped if the sizes and, if the host answered the option, the digests match. A host that
+    /// doesn't report the size, or answers with a digest that isn't MD5 or SHA-256 hex, always
+    /// gets the file downloaded.
+    pub fn request_file_if_changed<P: AsRef<Path>, S: AsRef<Path>>(&mut self, filename: P, destination: S, hash_option: Option<&str>) -> impl Future<Item=ConditionalOutcome, Error=io::Error> {
+        let filename = filename.as_ref().to_str().unwrap().to_string();
+        let destination = destination.as_ref().to_str().unwrap().to_string();
+        let hash_option = hash_option.map(|option| option.to_lowercase());
+        let mut client = self.clone();
+        future::lazy(move || {
+            let local_path = client.data_folder.clone().add("/").add(&destination);
+            if let Ok(metadata) = metadata(&local_path) {
+                let mut read_header = RWHeader::<ReadHeader>::new(filename.clone(), client.mode).unwrap();
+                read_header.tsize = Some(0);
+                read_header.options = client.request_options.clone();
+                if let Some(ref hash_option) = hash_option {
+                    read_header.options.insert(hash_option.clone(), "0".to_string());
+                }
+                let options = client.with_own_socket(|client| client.negotiate_only(read_header, "The local copy may be up to date"))?;
+                let same_size = options.get("tsize").and_then(|tsize| tsize.parse::<u64>().ok()) == Some(metadata.len());
+                let remote_digest = hash_option.and_then(|hash_option| options.get(&hash_option).cloned());
+                let same_digest = match remote_digest {
+                    // A digest that can't be made sense of can't vouch for the local copy.
+                    Some(hex) => match Checksum::md5_from_hex(&hex).or_else(|_| Checksum::sha256_from_hex(&hex)) {
+                        Ok(remote) => remote.of_file(&local_path)? == remote,
+                        Err(_) => false
+                    },
+                    None => true
+                };
+                if same_size && same_digest {
+                    return Ok(ConditionalOutcome::NotModified)
+                }
+            }
+            client.request_file(filename, destination).wait().map(ConditionalOutcome::Downloaded)
+        })
+    }
+
+    /// Sends `read_header` and aborts the transfer with `reason` as soon as the host has answered
+    /// it, returning the options the host acknowledged. Fails if the host refused the request.
+    fn negotiate_only(&self, read_header: RWHeader<ReadHeader>, reason: &str) -> Result<BTreeMap<String, String>, io::Error> {
+        let requested = read_header.all_options();
+        let socket = self.socket_handle()?;
+        Header::Read(read_header).send(self.host_addr.clone(), &socket)?;
+
+        let addr = await_transfer_id(&socket, self.host_addr.clone(), self.idle_timeout)?;
+        let options = negotiate_options(&socket, addr, &requested)?;
+        let mut refused = None;
+        // Without an OACK, the host either started sending the file or refused the request.
+        if options.is_empty() {
+            if let Ok((Header::Error(error_header), _)) = Header::peek(&socket, &mut [0u8; MAX_DATA_LEN + DATA_HEADER_LEN]) {
+                refused = Some(error_header);
+            }
+        }
+        if refused.is_none() {
+            let error_header = ErrorHeader::new(ErrorCode::Undefined, reason.to_string()).unwrap();
+            let _ = Header::Error(error_header).send(addr, &socket);
+        }
+        discard_pending(&socket);
+
+        match refused {
+            Some(error_header) => Err(TFTPError::ErrorReceived(error_header.error_code, error_header.error_message).into()),
+            None => Ok(options)
+        }
+    }
+
+    pub fn send_file<P: AsRef<Path>>(&mut self, filename: P) -> impl Future<Item=TransferOutcome, Error=io::Error> {
+        let mode = self.mode;
+        self.send_file_with_mode(filename, mode)
+    }
+
+    /// Like `send_file`, but runs the transfer to completion on the current thread.
+    pub fn send_file_blocking<P: AsRef<Path>>(&mut self, filename: P) -> Result<TransferOutcome, io::Error> {
+        self.send_file(filename).wait()
+    }
 
-impl TFTPClient {
-    pub fn new(host_addr: SocketAddr, socket_addr: SocketAddr, data_folder: String, window_size: usize) -> Result<Self, io::Error> {
-        let mut udp_socket: UdpSocket = UdpSocket::bind(socket_addr)?;
-        udp_socket.set_read_timeout(Some(Duration::from_secs(4)))?;
-        udp_socket.set_write_timeout(Some(Duration::from_secs(4)))?;
+    /// Like `send_file`, but sends the file in `mode` rather than the client's `mode`.
+    pub fn send_file_with_mode<P: AsRef<Path>>(&mut self, filename: P, mode: RWMode) -> impl Future<Item=TransferOutcome, Error=io::Error> {
+        let filename = filename.as_ref().to_str().unwrap().to_string();
+        let file_src = self.data_folder.clone().add("/").add(&filename);
+        self.upload(filename, mode, move || open_source(File::open(&file_src)?))
+    }
 
-        Ok(TFTPClient {
-            window_size,
-            data_folder,
-            host_addr,
-            udp_socket: Arc::new(Mutex::new(udp_socket))
+    /// Sends the file at `local_path` to the host, to be saved as `remote_name`. Unlike with
+    /// `send_file`, `local_path` doesn't have to be in the data folder; a relative path is relative
+    /// to the working directory. `remote_name` may put the file in a folder on the host, e.g.
+    /// "backups/2024/router1.cfg", as long as the host lets peers create folders (see
+    /// `FsBackend::create_dirs`) or the folder already exists.
+    pub fn send_file_as<P: AsRef<Path>>(&mut self, local_path: P, remote_name: &str) -> impl Future<Item=TransferOutcome, Error=io::Error> {
+        let local_path = local_path.as_ref().to_path_buf();
+        self.upload(remote_name.to_string(), self.mode, move || open_source(File::open(&local_path)?))
+    }
+
+    /// Sends `contents` to the host, to be saved as `filename`.
+    pub fn send_bytes(&mut self, filename: &str, contents: &[u8]) -> impl Future<Item=TransferOutcome, Error=io::Error> {
+        let contents = Arc::new(contents.to_vec());
+        self.upload(filename.to_string(), self.mode, move || Ok(Box::new(SharedContents(contents.clone())) as Contents))
+    }
+
+    /// Sends everything `reader` holds, from the start, to the host, to be saved as `filename`. It
+    /// is read as it is sent, rather than all at once up front.
+    pub fn send_from_reader<R: Read + Seek + Send + 'static>(&mut self, filename: &str, reader: R) -> impl Future<Item=TransferOutcome, Error=io::Error> {
+        // If the upload has to be retried on a mirror, the retry reads the reader from the start again.
+        let reader = Arc::new(Mutex::new(reader));
+        self.upload(filename.to_string(), self.mode, move || reader_source(SharedReader(reader.clone())))
+    }
+
+    /// Sends a write request for `filename` in the given mode, then the contents `open` returns.
+    /// `open` is called again for every host that is tried.
+    /// `filename` has to be a relative path; see `remote_path`.
+    fn upload<F, S>(&self, filename: String, mode: RWMode, mut open: F) -> impl Future<Item=TransferOutcome, Error=io::Error>
+        where F: FnMut() -> Result<S, io::Error>, S: BlockSource + 'static {
+        let client = self.clone();
+        future::lazy(move || {
+            let filename = remote_path(&filename)?;
+            let start = Instant::now();
+            let mut outcome = client.with_own_socket(|client| {
+                client.with_failover(|client| client.upload_to_host(&filename, mode, &mut open))
+            })?;
+            outcome.duration = start.elapsed();
+            Ok(outcome)
         })
     }
 
-    //fn connect_to_host(host_addr: SocketAddr) -> impl Future<Item=(), Error=io::Error> { unimplemented!() }
-    //pub fn send_file<P: AsRef<Path>, S: AsRef<Path>>(source: P, filename: S) -> impl Future<Item=i32, Error=io::Error> { unimplemented!() }
+    /// Uploads to `host_addr`; see `upload`.
+    fn upload_to_host<F, S>(&self, filename: &str, mode: RWMode, open: &mut F) -> Result<TransferOutcome, io::Error>
+        where F: FnMut() -> Result<S, io::Error>, S: BlockSource + 'static {
+        // Only ask the host to create the file once there is something to put in it.
+        let contents = open()?;
+        let mut write_header = RWHeader::<WriteHeader>::new(filename.to_string(), mode).unwrap();
+        write_header.blksize = requested_blksize(self.request_block_size());
+        write_header.utimeout = self.timeout.map(duration_micros);
+        write_header.block_crc = self.block_crc;
+        write_header.options = self.request_options.clone();
+        let requested = write_header.all_options();
+        let socket = self.socket_handle()?;
+        Header::Write(write_header).send(self.host_addr.clone(), &socket)?;
 
-    pub fn request_file<P: AsRef<Path>, S: AsRef<Path>>(&mut self, filename: P, destination: S) -> impl Future<Item=(), Error=io::Error> {
-        let dest_path: &Path = destination.as_ref();
-        let dest = self.data_folder.clone().add("/").add(dest_path.to_str().unwrap());
-        let filename = filename.as_ref().to_str().unwrap().to_string();
+        let addr = await_transfer_id(&socket, self.host_addr.clone(), self.idle_timeout)?;
+        let _connection = PeerConnection::new(&socket, addr)?;
+        let options = negotiate_options(&socket, addr, &requested)?;
+        let file_len = contents.len() as u64;
+        let block_size = negotiated_block_size(&options);
+        let mut run = SendFile::new(socket, addr, contents, send_window_size(self.window_size, self.max_window_size), block_size)?;
+        run.set_timeout(negotiated_timeout(&options));
+        run.set_block_crc(negotiated_block_crc(&options));
+        run.set_max_attempts(self.max_attempts);
+        run.set_max_block_retransmissions(self.max_block_retransmissions);
+        run.set_retry_policy(self.retry_policy);
+        run.set_poll_budget(self.poll_budget);
+        run.set_transfer_timeout(self.transfer_timeout);
+        run.set_idle_timeout(self.idle_timeout);
+        run.set_rate_limit(self.upload_rate_limit);
+        self.apply_window_strategy(&mut run);
+        let progress = Arc::new(TransferProgress::new(Some(file_len), self.progress_listener.clone()));
+        run.set_progress(progress.clone());
+        run.set_control(self.control.clone());
+        run.run()?;
+        Ok(TransferOutcome::new(&progress, block_size, options))
+    }
 
-        let addr = self.host_addr.clone();
-        let mut socket = self.udp_socket.clone();
-        let read_header = Header::Read(RWHeader::<ReadHeader>::new(filename, RWMode::Octet).unwrap());
-        let send_read = future::ok::<u32, u32>(1).then(move |_| {
-            let r = if let Ok(ref mut sock) = socket.try_lock() {
-                match read_header.send(addr, sock) {
-                    Ok(_) => Ok(Async::Ready(())),
-                    Err(e) => Err(e)
-                }
-            } else {
-                Err(io::Error::new(io::ErrorKind::Other, "Failed to obtain UDP Socket lock."))
-            };
-            r
-        });
+    /// The block size to ask for in a new request; see `auto_block_size`.
+    fn request_block_size(&self) -> usize {
+        if self.auto_block_size {
+            path_mtu_block_size(self.host_addr).unwrap_or(self.block_size)
+        } else {
+            self.block_size
+        }
+    }
+}
 
-        let addr = self.host_addr.clone();
-        let socket = self.udp_socket.clone();
-        send_read.and_then(move |_| {
-            let mut run =
-                ReceiveFile::new(socket, addr,
-                                 OpenOptions::new()
-                                     .read(true)
-                                     .write(true)
-                                     .create(true)
-                                     .open(dest)?)?;
-                run.run()
-        })
+impl TFTPClient {
+    /// Sets the listener that gets told how `request_file` and `send_file` transfers are going.
+    /// Downloads also ask the host for the size of the file ("tsize") while one is set.
+    pub fn set_progress_listener<L: ProgressListener + 'static>(&mut self, listener: L) {
+        self.progress_listener = Some(Arc::new(listener));
     }
 
-    pub fn send_file<P: AsRef<Path>>(&mut self, filename: P) -> impl Future<Item=(), Error=io::Error> {
-        let filename = filename.as_ref().to_str().unwrap().to_string();
-        let file_src = self.data_folder.clone().add("/").add(&filename);
-        let addr = self.host_addr.clone();
-        let mut socket = self.udp_socket.clone();
-        let write_header = Header::Write(RWHeader::<WriteHeader>::new(filename, RWMode::Octet).unwrap());
-        let send_read = future::ok::<u32, u32>(1).then(move |_| {
-            let r = if let Ok(ref mut sock) = socket.try_lock() {
-                match write_header.send(addr, sock) {
-                    Ok(_) => Ok(Async::Ready(())),
-                    Err(e) => Err(e)
+    /// A handle to pause and resume the downloads and uploads of this client, and of its clones,
+    /// from another thread. Only the transfer of data is paused, not the request that precedes it.
+    pub fn transfer_control(&self) -> Arc<TransferControl> {
+        self.control.clone()
+    }
+
+    /// Lists the transfers this server (or any session created from it) has in progress.
+    pub fn active_transfers(&self) -> Vec<TransferInfo> {
+        self.sessions.transfers()
+    }
+
+    /// Lists the last `history_len` transfers this server (or any session created from it) has
+    /// finished, including refused requests, oldest first.
+    pub fn transfer_history(&self) -> Vec<TransferRecord> {
+        self.sessions.history()
+    }
+
+    /// Adds an observer to be told about every request this server handles.
+    pub fn add_observer<O: TransferObserver + 'static>(&mut self, observer: O) {
+        self.observers.push(Arc::new(observer));
+    }
+
+    /// Binds another socket for the server to take requests on, e.g. to listen on several
+    /// interfaces or ports at once. Requests arriving on any of them are handled the same way, and
+    /// their transfers happen on the interface the request arrived on.
+    #[cfg(feature = "server")]
+    pub fn add_listener(&mut self, socket_addr: SocketAddr) -> Result<(), io::Error> {
+        let udp_socket = UdpSocket::bind(socket_addr)?;
+        self.configure_socket(&udp_socket)?;
+        self.listeners.push(Arc::new(Mutex::new(udp_socket)));
+        Ok(())
+    }
+
+    /// Advertises this server on the local network as a `_tftp._udp` service named `instance`,
+    /// reachable at `addr` on the port `udp_socket` is bound to, for as long as the returned
+    /// advertiser is kept around. `host` is the host name of the machine, without ".local".
+    #[cfg(feature = "mdns")]
+    pub fn advertise(&self, instance: &str, host: &str, addr: Ipv4Addr) -> Result<MdnsAdvertiser, io::Error> {
+        let port = match self.udp_socket.lock() {
+            Ok(socket) => socket.local_addr()?.port(),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Failed to obtain UDP Socket lock."))
+        };
+        MdnsAdvertiser::start(MdnsService { instance: instance.to_string(), host: host.to_string(), addr, port })
+    }
+
+    /// Adds a middleware to the end of the chain that requests pass through before they are served.
+    pub fn add_middleware<M: RequestMiddleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    /// Sets where the server reads the files it serves from and writes the files it receives to.
+    /// By default this is the data folder.
+    pub fn set_backend<B: Backend + 'static>(&mut self, backend: B) {
+        self.backend = Arc::new(backend);
+    }
+
+    /// Sets what runs the sessions the server starts, e.g. a `rayon::ThreadPool` to bound the
+    /// number of threads, or something that hands them to an async runtime's blocking pool. By
+    /// default every session gets a thread of its own.
+    pub fn set_spawner<S: Spawner + 'static>(&mut self, spawner: S) {
+        self.spawner = Arc::new(spawner);
+    }
+
+    /// Keeps up to `max_entries` of the most recently served files, and no more than `max_bytes` of
+    /// them, in memory; see `CachingBackend`. This wraps the current backend, so set that first.
+    pub fn cache_files(&mut self, max_bytes: usize, max_entries: usize) {
+        self.backend = Arc::new(CachingBackend::new(self.backend.clone(), max_bytes, max_entries));
+    }
+
+    /// Has the sessions that serve the same file at once share one source for it, rather than each
+    /// opening the file for itself; see `SharingBackend`. This wraps the current backend, so set
+    /// that (and `cache_files`, if used) first.
+    pub fn share_sources(&mut self) {
+        self.backend = Arc::new(SharingBackend::new(self.backend.clone()));
+    }
+
+    /// Serves "<filename>.gz" decompressed when a file that is asked for isn't there itself; see
+    /// `DecompressingBackend`. The size of such a file isn't known up front, so a "tsize" option
+    /// asking for it goes unanswered. This wraps the current backend, so set that first, and
+    /// `cache_files` or `share_sources` after.
+    #[cfg(feature = "gzip")]
+    pub fn decompress_files(&mut self) {
+        self.backend = Arc::new(DecompressingBackend::new(self.backend.clone()));
+    }
+
+    /// Sets the resolver that picks the file to serve for each read request. It runs after the
+    /// middleware chain, right before the file is opened.
+    pub fn set_filename_resolver<R: FilenameResolver + 'static>(&mut self, resolver: R) {
+        self.filename_resolver = Some(Arc::new(resolver));
+    }
+
+    /// Sets the handler that answers read requests with dynamically generated content. While one is
+    /// set, the backend is only used for write requests.
+    pub fn set_read_handler<H: ReadRequestHandler + 'static>(&mut self, handler: H) {
+        self.read_handler = Some(Arc::new(handler));
+    }
+
+    /// Sets the handler used to answer request options that aren't understood by this crate. Without
+    /// one, all such options are refused.
+    pub fn set_option_handler<H: OptionHandler + 'static>(&mut self, handler: H) {
+        self.option_handler = Some(Arc::new(handler));
+    }
+
+    /// Sets what creates the `WindowStrategy` of every file this sends, uploads and served files
+    /// alike, in place of `Aimd`.
+    pub fn set_window_strategy<F: WindowStrategyFactory + 'static>(&mut self, factory: F) {
+        self.window_strategy = Some(Arc::new(factory));
+    }
+
+    /// When files this receives are acknowledged; see `ack_strategy`.
+    fn receive_ack_strategy(&self) -> AckStrategy {
+        match self.ack_strategy {
+            Some(ack_strategy) => ack_strategy,
+            None if self.window_size <= 1 => AckStrategy::Lockstep,
+            None => AckStrategy::Burst
+        }
+    }
+
+    /// Gives `send_file` a window strategy of its own, if a factory for them is set.
+    fn apply_window_strategy<D: Datagram>(&self, send_file: &mut SendFile<D>) {
+        if let Some(ref factory) = self.window_strategy {
+            send_file.set_window_strategy(factory.create(send_window_size(self.window_size, self.max_window_size)));
+        }
+    }
+
+    /// Installs a handler for packets with the given opcode that arrive while serving. Only opcodes
+    /// this crate doesn't understand itself can be handled, so `opcode` must be greater than that
+    /// of a NAK (7). Opcodes are a single byte, the low one of the opcode field, since the high
+    /// byte is used for block numbers; so there can be no more than 255 of them.
+    pub fn set_opcode_handler<H: OpcodeHandler + 'static>(&mut self, opcode: u8, handler: H) -> Result<(), io::Error> {
+        if opcode <= OPCODE_NAK {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Opcodes up to and including NAK (7) are handled by this crate."))
+        }
+        self.opcode_handlers.insert(opcode, Arc::new(handler));
+        Ok(())
+    }
+
+    /// Acknowledges the options of a request that is being served, returning the block size to
+    /// use for the transfer. `file_size` is the size of the file being read, if this is a RRQ. The
+    /// OACK is only sent if there are options to acknowledge.
+    #[cfg(feature = "server")]
+    fn acknowledge_options<T: ToRequestType>(&mut self, request: &RWHeader<T>, file_size: Option<u64>) -> Result<BTreeMap<String, String>, io::Error> {
+        let mut options = BTreeMap::new();
+        if let Some(blksize) = request.blksize {
+            options.insert("blksize".to_string(), blksize.to_string());
+        }
+        if let Some(tsize) = request.tsize {
+            options.insert("tsize".to_string(), file_size.unwrap_or(tsize).to_string());
+        }
+        if let Some(utimeout) = request.utimeout {
+            options.insert("utimeout".to_string(), utimeout.to_string());
+        }
+        if request.block_crc {
+            options.insert(BLOCK_CRC_OPTION.to_string(), "1".to_string());
+        }
+        if let Some(ref handler) = self.option_handler {
+            for (option, value) in request.options.iter() {
+                if let Some(answer) = handler.answer_option(T::request_type(), &request.filename, self.host_addr, option, value) {
+                    options.insert(option.clone(), answer);
                 }
-            } else {
-                Err(io::Error::new(io::ErrorKind::Other, "Failed to obtain UDP Socket lock."))
-            };
-            r
-        });
+            }
+        }
+        if options.is_empty() {
+            return Ok(options)
+        }
 
-        let window_size = self.window_size;
-        let addr = self.host_addr.clone();
-        let socket = self.udp_socket.clone();
-        send_read.and_then(move |_| {
-            let mut run =
-                SendFile::new(socket, addr,
-                                 OpenOptions::new()
-                                     .read(true)
-                                     .write(false)
-                                     .create(false)
-                                     .open(file_src)?, window_size)?;
-                run.run()
-        })
+        Header::OAck(OAckHeader::new(options.clone())?).send(self.host_addr.clone(), &self.socket_handle()?)?;
+        Ok(options)
     }
-    
+
     pub fn send_error(&mut self, error: ErrorCode) -> impl Future<Item=(), Error=io::Error> {
-        SendError::new(ErrorHeader::new(error, "<No description supplied>".to_string()).unwrap(), self.host_addr.clone(), self.udp_socket.clone())
+        let mut send_err = SendError::new(ErrorHeader::new(error, "<No description supplied>".to_string()).unwrap(), self.host_addr.clone(), self.udp_socket.clone());
+        send_err.max_attempts = self.max_attempts;
+        send_err
     }
 
     pub fn receive_header(&mut self) -> Result<Option<Header>, io::Error> {
-        if let Ok(ref mut socket) = self.udp_socket.try_lock() {
-            match Header::recv(self.host_addr.clone(), socket) {
-                Ok(r)   => Ok(Some(r)),
-                Err(e)  => {
-                    if let TFTPError::IOError(ioerr) = e {
-                        Err(ioerr)
-                    } else {
-                        Ok(None)
-                    }
+        let mut buf = vec![0u8; recv_buffer_len(self.block_size)];
+        match Header::recv(self.host_addr.clone(), &self.socket_handle()?, &mut buf) {
+            Ok(r)   => Ok(Some(r)),
+            Err(e)  => {
+                if let TFTPError::IOError(ioerr) = e {
+                    Err(ioerr)
+                } else {
+                    Ok(None)
                 }
             }
+        }
+    }
+
+}
+
+/// Serving requests.
+#[cfg(feature = "server")]
+impl TFTPClient {
+    /// Tells the peer why its request can't be served, then returns `err`.
+    fn refuse_request<T>(&mut self, err: io::Error) -> Result<T, io::Error> {
+        let error_code = match err.kind() {
+            io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+            io::ErrorKind::PermissionDenied => ErrorCode::AccessViolation,
+            io::ErrorKind::AlreadyExists => ErrorCode::FileAlreadyExists,
+            _ => ErrorCode::Undefined
+        };
+        self.refuse_request_with(error_code, err)
+    }
+
+    /// Tells the peer why its request can't be served with `error_code`, then returns `err`.
+    pub fn refuse_request_with<T>(&mut self, error_code: ErrorCode, err: io::Error) -> Result<T, io::Error> {
+        let error_header = ErrorHeader::new(error_code, err.to_string().replace('\0', ""))?;
+        let mut send_err = SendError::new(error_header, self.host_addr.clone(), self.udp_socket.clone());
+        send_err.max_attempts = self.max_attempts;
+        loop {
+            match send_err.poll() {
+                Ok(Async::NotReady) => continue,
+                Ok(Async::Ready(_)) | Err(_) => return Err(err)
+            }
+        }
+    }
+
+    /// Refuses the request if the peer isn't let in by the access list.
+    fn check_access(&mut self) -> Result<(), io::Error> {
+        if self.access_list.permits(self.host_addr.ip()) {
+            Ok(())
         } else {
-            Ok(None)
+            let err = io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is not allowed to make requests.", self.host_addr.ip()));
+            self.refuse_request_with(ErrorCode::AccessViolation, err)
+        }
+    }
+
+    /// Applies the overwrite policy to `filename`, which is about to be written.
+    fn make_room_for(&self, filename: &str) -> Result<(), io::Error> {
+        if self.overwrite_policy == OverwritePolicy::Overwrite || self.overwrite_policy == OverwritePolicy::Append || self.backend.metadata(filename).is_err() {
+            return Ok(())
+        }
+        if self.overwrite_policy == OverwritePolicy::Reject {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("'{}' already exists.", filename)))
+        }
+        let mut version = 1;
+        while self.backend.metadata(&format!("{}.{}", filename, version)).is_ok() {
+            version += 1;
+        }
+        self.backend.rename(filename, &format!("{}.{}", filename, version))
+    }
+
+    /// Adds a request to the transfer history and tells the observers how it turned out.
+    fn report_outcome(&self, direction: RequestType, filename: &str, start_time: SystemTime, progress: &TransferProgress, result: &Result<(), io::Error>) {
+        self.sessions.record(TransferRecord {
+            peer: self.host_addr,
+            filename: filename.to_string(),
+            direction: direction.clone(),
+            bytes_transferred: progress.bytes_transferred.load(atomic::Ordering::Relaxed),
+            start_time,
+            duration: start_time.elapsed().unwrap_or(Duration::from_secs(0)),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            send_stats: progress.send_stats(),
+            receive_stats: progress.receive_stats(),
+        }, self.history_len);
+        for observer in self.observers.iter() {
+            match *result {
+                Ok(()) => observer.on_transfer_complete(self.host_addr, direction.clone(), filename),
+                Err(ref e) => observer.on_transfer_failed(self.host_addr, direction.clone(), filename, e)
+            }
+        }
+    }
+
+    /// Accepts a read or write request this session was created for: the request is checked and
+    /// answered, and the transfer that serves it is returned without being run. Observers are told
+    /// about the request, and about its outcome if it is refused; once the transfer is over, pass
+    /// it to `finish_request`. `handle_read_request` and `handle_write_request` do all of this in
+    /// one go.
+    pub fn accept_request(&mut self, header: Header) -> Result<AcceptedRequest, io::Error> {
+        let (direction, filename) = match header {
+            Header::Read(ref read_header) => (RequestType::Read, read_header.filename.clone()),
+            Header::Write(ref write_header) => (RequestType::Write, write_header.filename.clone()),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Only read and write requests can be accepted."))
+        };
+        for observer in self.observers.iter() {
+            observer.on_request(self.host_addr, direction.clone(), &filename);
+        }
+        let start_time = SystemTime::now();
+        let progress = Arc::new(TransferProgress::default());
+        let transfer = match header {
+            Header::Read(read_header) => self.read_transfer(read_header, &progress).map(|(send_file, registration)| (ServerTransfer::Read(send_file), registration)),
+            Header::Write(write_header) => self.write_transfer(write_header, &progress).map(|(recv_file, registration)| (ServerTransfer::Write(recv_file), registration)),
+            _ => unreachable!()
+        };
+        match transfer {
+            Ok((transfer, registration)) => Ok(AcceptedRequest { transfer, direction, filename, start_time, progress, _registration: registration }),
+            Err(e) => {
+                let result = Err(e);
+                self.report_outcome(direction, &filename, start_time, &progress, &result);
+                result.map(|()| unreachable!())
+            }
+        }
+    }
+
+    /// Reports how the transfer of a request from `accept_request` turned out.
+    pub fn finish_request(&self, request: AcceptedRequest, result: &Result<(), io::Error>) {
+        let AcceptedRequest { direction, filename, start_time, progress, .. } = request;
+        self.report_outcome(direction, &filename, start_time, &progress, result);
+    }
+
+    /// Connects the socket of this session to the peer, so that datagrams from anyone else are
+    /// dropped by the OS. The socket of a session is only ever used with the one peer.
+    fn connect_session(&self) -> Result<(), io::Error> {
+        match self.udp_socket.lock() {
+            Ok(socket) => socket.connect(self.host_addr),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Failed to obtain UDP Socket lock."))
         }
     }
 
+    /// Serves a request from start to finish.
+    fn serve_request(&mut self, header: Header) -> Result<(), io::Error> {
+        let mut request = self.accept_request(header)?;
+        let result = request.transfer.run();
+        self.finish_request(request, &result);
+        result
+    }
+
     pub fn handle_write_request(&mut self, write_header: RWHeader<WriteHeader>) -> Result<(), io::Error> {
-        let path = self.data_folder.clone().add("/").add(&write_header.filename);
-        let mut file = OpenOptions::new().truncate(true).create(true).read(true).write(true).open(path)?;
-        let mut recv_file = ReceiveFile::new(self.udp_socket.clone(), self.host_addr.clone(), file)?;
-        recv_file.run()
+        self.serve_request(Header::Write(write_header))
+    }
+
+    fn write_transfer(&mut self, mut write_header: RWHeader<WriteHeader>, progress: &Arc<TransferProgress>) -> Result<(ReceiveFile, RegisteredTransfer), io::Error> {
+        self.connect_session()?;
+        self.check_access()?;
+        for middleware in self.middleware.clone() {
+            if let Err(e) = middleware.write_request(&mut write_header, self.host_addr) {
+                return self.refuse_request(e)
+            }
+        }
+        if let Err(e) = self.make_room_for(&write_header.filename) {
+            return self.refuse_request(e)
+        }
+        let file = match self.overwrite_policy {
+            OverwritePolicy::Append => self.backend.open_append(&write_header.filename),
+            _ => self.backend.create_write(&write_header.filename)
+        };
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => return self.refuse_request(e)
+        };
+        let options = self.acknowledge_options(&write_header, None)?;
+        let transfer = SessionRegistry::register(&self.sessions, self.host_addr.clone(), write_header.filename.clone(), RequestType::Write, progress.clone());
+        let mut recv_file = ReceiveFile::new(self.socket_handle()?, self.host_addr.clone(), self.write_sink(file), negotiated_block_size(&options))?;
+        recv_file.set_progress(transfer.progress.clone());
+        recv_file.set_dally(self.dally);
+        recv_file.set_ack_strategy(self.receive_ack_strategy());
+        recv_file.set_timeout(negotiated_timeout(&options));
+        recv_file.set_block_crc(negotiated_block_crc(&options));
+        recv_file.set_max_attempts(self.max_attempts);
+        recv_file.set_retry_policy(self.retry_policy);
+        recv_file.set_poll_budget(self.poll_budget);
+        recv_file.set_idle_timeout(self.idle_timeout);
+        recv_file.set_transfer_timeout(self.transfer_timeout);
+        if let Some(file_size) = write_header.tsize.filter(|_| self.preallocate) {
+            recv_file.set_file_size(file_size)?;
+        }
+        Ok((recv_file, transfer))
     }
 
     pub fn handle_read_request(&mut self, read_header: RWHeader<ReadHeader>) -> Result<(), io::Error> {
-        let mut file = match File::open(self.data_folder.clone().add("/").add(&read_header.filename)) {
-            Ok(a) => a,
-            Err(e) => {
-                let mut send_err = self.send_error(ErrorCode::FileNotFound);
-                loop {
-                    match send_err.poll() {
-                        Ok(Async::Ready(_)) => return Err(e),
-                        Ok(Async::NotReady) => continue,
-                        Err(e) => panic!(format!("{}", e)),
+        self.serve_request(Header::Read(read_header))
+    }
+
+    fn read_transfer(&mut self, mut read_header: RWHeader<ReadHeader>, progress: &Arc<TransferProgress>) -> Result<(SendFile, RegisteredTransfer), io::Error> {
+        // MTFTP data goes out to a group rather than just the peer.
+        if self.mtftp.is_none() {
+            self.connect_session()?;
+        }
+        self.check_access()?;
+        for middleware in self.middleware.clone() {
+            if let Err(e) = middleware.read_request(&mut read_header, self.host_addr) {
+                return self.refuse_request(e)
+            }
+        }
+        if let Some(filename) = self.filename_resolver.as_ref().and_then(|resolver| resolver.resolve(&read_header.filename, self.host_addr)) {
+            read_header.filename = filename;
+        }
+        let contents = match self.read_handler.clone() {
+            Some(handler) => match handler.handle_rrq(&read_header, self.host_addr.clone()) {
+                Ok(source) => reader_source(source),
+                Err(error_code) =>
+                    return self.refuse_request_with(error_code, io::Error::new(io::ErrorKind::Other, "The read request was refused."))
+            },
+            None => self.backend.open_source(&read_header.filename)
+        };
+        let contents = match contents {
+            Ok(contents) => contents,
+            Err(e) => return self.refuse_request(e)
+        };
+        let transfer = SessionRegistry::register(&self.sessions, self.host_addr.clone(), read_header.filename.clone(), RequestType::Read, progress.clone());
+        if let Some(mtftp) = self.mtftp.clone() {
+            thread::sleep(mtftp.start_delay);
+            let mut send_file = SendFile::new_mtftp(self.socket_handle()?, self.host_addr.clone(), mtftp.group, contents, send_window_size(self.window_size, self.max_window_size), mtftp.block_size)?;
+            send_file.set_rate_limit(self.rate_limit);
+            self.apply_window_strategy(&mut send_file);
+            send_file.set_max_attempts(self.max_attempts);
+            send_file.set_max_block_retransmissions(self.max_block_retransmissions);
+            send_file.set_retry_policy(self.retry_policy);
+            send_file.set_poll_budget(self.poll_budget);
+            send_file.set_transfer_timeout(self.transfer_timeout);
+            send_file.set_idle_timeout(self.idle_timeout);
+            send_file.set_progress(transfer.progress.clone());
+            return Ok((send_file, transfer));
+        }
+        // The size of a file that is only found out as it is sent, e.g. one being decompressed, can't
+        // be told up front.
+        let file_size = if contents.is_complete() {
+            Some(contents.len() as u64)
+        } else {
+            read_header.tsize = None;
+            None
+        };
+        let options = self.acknowledge_options(&read_header, file_size)?;
+        let mut send_file = SendFile::new_server(self.socket_handle()?, self.host_addr.clone(), contents, send_window_size(self.window_size, self.max_window_size), negotiated_block_size(&options))?;
+        send_file.set_timeout(negotiated_timeout(&options));
+        send_file.set_block_crc(negotiated_block_crc(&options));
+        send_file.set_rate_limit(self.rate_limit);
+        self.apply_window_strategy(&mut send_file);
+        send_file.set_max_attempts(self.max_attempts);
+        send_file.set_max_block_retransmissions(self.max_block_retransmissions);
+        send_file.set_retry_policy(self.retry_policy);
+        send_file.set_poll_budget(self.poll_budget);
+        send_file.set_transfer_timeout(self.transfer_timeout);
+        send_file.set_idle_timeout(self.idle_timeout);
+        send_file.set_progress(transfer.progress.clone());
+        Ok((send_file, transfer))
+    }
+
+    /// Handles a request that has already been received on the listening socket. `self` should be
+    /// a session created with `new_session`, so the transfer happens on its own transfer ID.
+    pub fn handle_server_request(mut self, header: Header) {
+        match header {
+            Header::Write(write_header) => {
+                self.handle_write_request(write_header);
+            },
+            Header::Read(read_header) => {
+                self.handle_read_request(read_header);
+            },
+            _ => return
+        }
+    }
+
+    /// Handles a request on a session once there's room for another transfer.
+    fn start_transfer(mut self, header: Header) {
+        let _slot = match self.max_concurrent_transfers {
+            Some(max) => {
+                // Waiting longer than TOTAL_TIMEOUT is pointless, the peer will have given up by then.
+                let wait = if self.queue_transfers { Some(TOTAL_TIMEOUT()) } else { None };
+                match TransferCount::acquire(&self.transfers, max, wait) {
+                    Some(slot) => Some(slot),
+                    None => {
+                        if !self.queue_transfers {
+                            let err = io::Error::new(io::ErrorKind::Other, "Too many transfers in progress.");
+                            let _ = self.refuse_request_with::<()>(ErrorCode::Undefined, err);
+                        }
+                        return
                     }
                 }
-            }
+            },
+            None => None
         };
-        let mut send_file = SendFile::new_server(self.udp_socket.clone(), self.host_addr.clone(), file, self.window_size).unwrap();
-        send_file.run()
+        self.handle_server_request(header)
     }
 
-    pub fn handle_server_request(mut self, src: SocketAddr) {
-        if let Ok(Some(header)) = self.receive_header() {
-            match header {
-                Header::Write(write_header) => {
-                    self.handle_write_request(write_header);
-                },
-                Header::Read(read_header) => {
-                    self.handle_read_request(read_header);
-                },
-                _ => return
+    /// Serves requests arriving on this client's socket, and any added with `add_listener`,
+    /// forever. Every read or write request gets its own session, which is run by the spawner, so
+    /// any number of transfers can be in progress at once.
+    pub fn serve(self) {
+        for listener in self.listeners.iter() {
+            let mut server = self.clone();
+            server.udp_socket = listener.clone();
+            thread::spawn(move || server.listen());
+        }
+        self.listen()
+    }
+
+    /// Waits for a single read or write request on this client's socket and serves it, returning
+    /// once the transfer is over. Anything else that arrives in the meantime is discarded.
+    pub fn serve_one(self) -> Result<(), io::Error> {
+        let socket = self.socket_handle()?;
+        let mut buf = [0u8; MAX_DATA_LEN * 4];
+        loop {
+            let request = {
+                match Header::peek(&socket, &mut buf) {
+                    Ok((Header::Read(_), src)) | Ok((Header::Write(_), src)) =>
+                        Header::recv(src, &socket, &mut buf).ok().map(|header| (header, src)),
+                    Err(TFTPError::IOError(_)) => None,
+                    _ => {
+                        let _ = socket.recv_from(&mut buf);
+                        None
+                    }
+                }
+            };
+            if let Some((header, src)) = request {
+                let mut session = self.new_session(src)?;
+                return match header {
+                    Header::Read(read_header) => session.handle_read_request(read_header),
+                    Header::Write(write_header) => session.handle_write_request(write_header),
+                    _ => Ok(())
+                }
             }
         }
     }
 
-    pub fn serve(mut self) {
-        use rayon::*;
-        use std::thread;
+    /// Takes the next packet off of this client's socket. Read and write requests are returned
+    /// along with where they came from; any other packet is handed to its opcode handler, if there
+    /// is one, or discarded. Errors are those of the socket, e.g. a read timing out.
+    pub fn next_request(&self) -> Result<Option<(Header, SocketAddr)>, io::Error> {
+        self.next_request_on(&mut self.socket_handle()?)
+    }
 
-        let mut pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
-        let self_copy = self.clone();
+    /// Like `next_request`, but takes the packet off of `socket`, a handle to this client's socket.
+    fn next_request_on(&self, socket: &mut UdpSocket) -> Result<Option<(Header, SocketAddr)>, io::Error> {
+        let mut buf = [0u8; MAX_DATA_LEN * 4];
+        match Header::peek(socket, &mut buf) {
+            Ok((Header::Read(_), src)) | Ok((Header::Write(_), src)) => {
+                // Take the request off of the listening socket; the rest of the transfer happens
+                // on the session's own socket.
+                return Ok(Header::recv(src, socket, &mut buf).ok().map(|header| (header, src)))
+            },
+            Ok((Header::Invalid(packet), src)) => {
+                let _ = Header::recv(src, socket, &mut buf);
+                if let Some(handler) = packet.get(1).and_then(|opcode| self.opcode_handlers.get(opcode)) {
+                    handler.handle_packet(&packet, src, socket);
+                }
+            },
+            Ok((_, src)) => {
+                // Nothing but requests should arrive on the listening socket, so discard it.
+                let _ = Header::recv(src, socket, &mut buf);
+            },
+            Err(TFTPError::IOError(e)) => return Err(e),
+            Err(_) => {
+                // A malformed packet; drop it so that it isn't peeked at over and over again.
+                let _ = socket.recv_from(&mut buf);
+            },
+        }
+        Ok(None)
+    }
 
+    /// Serves requests arriving on this client's socket forever.
+    fn listen(self) {
+        let mut socket = match self.socket_handle() {
+            Ok(socket) => socket,
+            Err(_) => return
+        };
         loop {
-            let header_result = if let Ok(ref mut socket) = self.udp_socket.try_lock() {
-                Header::peek(socket)
-            } else {
-                Err(TFTPError::ConnectionClosed)
-            };
-            let mut buf = [0u8; MAX_DATA_LEN * 4];
-            match header_result {
-                Ok((Header::Read(read_header), src)) => {
-                    let mut outgoing_self_copy = self_copy.clone();
-                    outgoing_self_copy.host_addr = src;
-                    pool.install(move || { outgoing_self_copy.handle_server_request(src) });
-                },
-                Ok((Header::Write(write_header), src)) => {
-                    let mut outgoing_self_copy = self_copy.clone();
-                    outgoing_self_copy.host_addr = src;
-                    pool.install(move || { outgoing_self_copy.handle_server_request(src) });
-                },
-                _ => {
-                }, // Ignore everything else
-                Err(e) => {}, // oof
+            if let Ok(Some((header, src))) = self.next_request_on(&mut socket) {
+                if let Ok(session) = self.new_session(src) {
+                    self.spawner.spawn(Box::new(move || { session.start_transfer(header) }));
+                }
             }
-            // Wait for a read or write request
-            // when that is received, move to a new thread and:
-                // send an ack to ithe request
-                // call send_file / receive file accordingly
-            thread::sleep_ms(100);
         }
     }
 
@@ -219,41 +1937,282 @@ impl TFTPClient {
 }
 
 
+/// Waits for the first packet the host sends in response to a request and returns the address it
+/// was sent from. The host answers from a newly chosen port (its transfer ID), so the rest of the
+/// transfer has to be addressed to that port rather than the one the request was sent to. Gives up
+/// after `idle_timeout`, if set.
+#[cfg(feature = "client")]
+fn await_transfer_id(socket: &UdpSocket, host_addr: SocketAddr, idle_timeout: Option<Duration>) -> Result<SocketAddr, io::Error> {
+    let start = Instant::now();
+    let mut buf = [0u8; MAX_DATA_LEN + DATA_HEADER_LEN];
+    while idle_timeout.map_or(true, |idle_timeout| start.elapsed() < idle_timeout) {
+        // The socket may have been left blocking by an earlier transfer, so never wait longer
+        // than what is left of the idle timeout.
+        let remaining = idle_timeout.map_or(DEFAULT_SOCKET_TIMEOUT(), |idle_timeout| idle_timeout.checked_sub(start.elapsed()).unwrap_or_default());
+        socket.set_read_timeout(Some(max(min(remaining, DEFAULT_SOCKET_TIMEOUT()), Duration::from_millis(1))))?;
+        match socket.peek_from(&mut buf) {
+            Ok((_, src)) => {
+                if src.ip() == host_addr.ip() {
+                    return Ok(src)
+                }
+                // Not from the host, so it can't be a response to our request.
+                let _ = socket.recv_from(&mut buf);
+            },
+            Err(e) => {
+                match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => continue,
+                    _ => return Err(e)
+                }
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "The host did not respond to the request."))
+}
+
+/// Handles the option negotiation (RFC2347) part of the host's response to a request. If the host
+/// responded with an OACK it is taken off of the socket and checked against the `requested` options;
+/// an unacceptable OACK is answered with an `ErrorCode::OptionNegotiation` error, aborting the
+/// transfer. If the host refused the requested options with that same error, the transfer is
+/// aborted as well. The returned error then contains a `TFTPError::UnexpectedOption` if the host
+/// acknowledged an option that was never requested (RFC1785), and a
+/// `TFTPError::OptionNegotiationFailed` otherwise.
+///
+/// Returns the options the host accepted; if it did not send an OACK, none were accepted.
+#[cfg(feature = "client")]
+fn negotiate_options(socket: &UdpSocket, tid: SocketAddr, requested: &BTreeMap<String, String>) -> Result<BTreeMap<String, String>, io::Error> {
+    let mut buf = [0u8; MAX_DATA_LEN + DATA_HEADER_LEN];
+    match Header::peek(socket, &mut buf) {
+        Ok((Header::OAck(oack), _)) => {
+            let _ = Header::recv(tid, socket, &mut buf);
+            match check_option_ack(&oack, requested) {
+                Ok(()) => Ok(oack.options),
+                Err(e) => {
+                    let error_header = ErrorHeader::new(ErrorCode::OptionNegotiation, e.to_string()).unwrap();
+                    let _ = Header::Error(error_header).send(tid, socket);
+                    Err(e.into())
+                }
+            }
+        },
+        Ok((Header::Error(error_header), _)) => {
+            match error_header.error_code {
+                ErrorCode::OptionNegotiation => {
+                    let _ = Header::recv(tid, socket, &mut buf);
+                    Err(TFTPError::OptionNegotiationFailed(error_header.error_message).into())
+                },
+                _ => Ok(BTreeMap::new())
+            }
+        },
+        _ => Ok(BTreeMap::new())
+    }
+}
+
+/// Checks that every option in an OACK was requested, and that its value is one we can accept.
+#[cfg(feature = "client")]
+fn check_option_ack(oack: &OAckHeader, requested: &BTreeMap<String, String>) -> Result<(), TFTPError> {
+    for (option, value) in oack.options.iter() {
+        let requested_value = match requested.get(option) {
+            Some(requested_value) => requested_value,
+            None => return Err(TFTPError::UnexpectedOption(option.clone()))
+        };
+        if option == "blksize" {
+            // The host may only pick a block size that is at most the requested one (RFC2348).
+            match (value.parse::<usize>(), requested_value.parse::<usize>()) {
+                (Ok(blksize), Ok(requested_blksize)) if blksize >= MIN_BLOCK_SIZE && blksize <= requested_blksize => {},
+                _ => return Err(TFTPError::OptionNegotiationFailed(format!("Unacceptable blksize '{}'", value)))
+            }
+        } else if option == "utimeout" && value != requested_value {
+            // Like "timeout" (RFC2349), the host has to use the requested value or leave it out.
+            return Err(TFTPError::OptionNegotiationFailed(format!("Unacceptable utimeout '{}'", value)))
+        } else if option == BLOCK_CRC_OPTION && value != requested_value {
+            return Err(TFTPError::OptionNegotiationFailed(format!("Unacceptable {} '{}'", BLOCK_CRC_OPTION, value)))
+        }
+    }
+    Ok(())
+}
+
+/// The value of the "blksize" option to send in a request for the given block size, if any.
+#[cfg(feature = "client")]
+fn requested_blksize(block_size: usize) -> Option<usize> {
+    if block_size == MAX_DATA_LEN { None } else { Some(block_size) }
+}
+
+/// The next block size to try when no data arrives with `block_size`: first one that fits in a
+/// single Ethernet frame even with some tunnel overhead, then the RFC1350 block size.
+#[cfg(feature = "client")]
+fn fallback_block_size(block_size: usize) -> Option<usize> {
+    if block_size > 1428 {
+        Some(1428)
+    } else if block_size > 512 {
+        Some(512)
+    } else {
+        None
+    }
+}
+
+/// Whether a transfer that failed with `err` is worth retrying on a mirror: the host didn't answer,
+/// went away, or refused the request.
+#[cfg(feature = "client")]
+fn is_failover_error(err: &io::Error) -> bool {
+    match err.get_ref().and_then(|e| e.downcast_ref::<TFTPError>()) {
+        Some(&TFTPError::ErrorReceived(..)) | Some(&TFTPError::NoDataReceived) | Some(&TFTPError::PeerUnreachable(_)) => true,
+        _ => err.kind() == io::ErrorKind::TimedOut
+    }
+}
+
+#[cfg(feature = "client")]
+fn is_no_data_received(err: &io::Error) -> bool {
+    match err.get_ref().and_then(|e| e.downcast_ref::<TFTPError>()) {
+        Some(&TFTPError::NoDataReceived) => true,
+        _ => false
+    }
+}
+
+/// Throws away any packets waiting on the socket, e.g. ones left over from an abandoned transfer.
+#[cfg(feature = "client")]
+fn discard_pending(socket: &UdpSocket) {
+    let mut buf = [0u8; 4];
+    let read_timeout = socket.read_timeout().unwrap_or(Some(DEFAULT_SOCKET_TIMEOUT()));
+    let _ = socket.set_read_timeout(Some(Duration::from_millis(100)));
+    while socket.recv_from(&mut buf).is_ok() {}
+    let _ = socket.set_read_timeout(read_timeout);
+}
+
+/// Checks that `filename`, the name to save an upload as, is a relative path that stays inside the
+/// host's folder, e.g. "router1.cfg" or "backups/2024/router1.cfg". It is sent with the folders
+/// separated by '/', whatever the local platform uses.
+#[cfg(feature = "client")]
+fn remote_path(filename: &str) -> Result<String, io::Error> {
+    let mut components = vec![];
+    for component in Path::new(filename).components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_string_lossy()),
+            Component::CurDir => {},
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not a relative path within the host's folder.", filename)))
+        }
+    }
+    if components.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The name to upload as is empty."))
+    }
+    Ok(components.join("/"))
+}
+
+/// Parses a manifest for `TFTPClient::request_manifest` into (filename, destination) pairs. Every
+/// line names a file on the host, optionally followed by the path to save it to; by default it is
+/// saved under its own name, less any leading '/'. Blank lines and lines starting with '#' are
+/// skipped. Destinations have to be relative and may not contain "..", so that a manifest can't
+/// have files written outside the data folder.
+pub fn parse_manifest(manifest: &str) -> Result<Vec<(String, String)>, io::Error> {
+    let mut entries = vec![];
+    for line in manifest.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') { continue }
+        let mut fields = line.split_whitespace();
+        let filename = fields.next().unwrap();
+        let destination = fields.next().unwrap_or(filename.trim_start_matches('/'));
+        if fields.next().is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Manifest line '{}' has too many fields.", line)))
+        }
+        let relative = Path::new(destination).components().all(|component| match component {
+            Component::Normal(_) | Component::CurDir => true,
+            _ => false
+        });
+        if destination.is_empty() || !relative {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Manifest destination '{}' is not a relative path.", destination)))
+        }
+        entries.push((filename.to_string(), destination.to_string()));
+    }
+    Ok(entries)
+}
+
+/// The window a client with the given `window_size` and `max_window_size` sends with.
+fn send_window_size(window_size: usize, max_window_size: Option<usize>) -> WindowSize {
+    match max_window_size {
+        Some(max_window_size) if window_size > 1 => WindowSize::new(window_size, max_window_size),
+        _ => window_size.into()
+    }
+}
+
+/// The block size that was agreed upon, given the options the host accepted.
+fn negotiated_block_size(options: &BTreeMap<String, String>) -> usize {
+    options.get("blksize")
+        .and_then(|blksize| blksize.parse::<usize>().ok())
+        .unwrap_or(MAX_DATA_LEN)
+}
+fn negotiated_timeout(options: &BTreeMap<String, String>) -> Option<Duration> {
+    options.get("utimeout")
+        .and_then(|utimeout| utimeout.parse::<u64>().ok())
+        .map(Duration::from_micros)
+}
+/// Whether blocks carry a CRC32, given the options the host accepted.
+fn negotiated_block_crc(options: &BTreeMap<String, String>) -> bool {
+    options.get(BLOCK_CRC_OPTION).map_or(false, |block_crc| block_crc == "1")
+}
+#[cfg(feature = "client")]
+fn duration_micros(duration: Duration) -> u64 {
+    let micros = duration.as_secs() * 1_000_000 + (duration.subsec_nanos() / 1000) as u64;
+    max(MIN_UTIMEOUT, min(micros, MAX_UTIMEOUT))
+}
+
 /// `TOTAL_TIMEOUT` is the amount of time that, after having not received anything, will mean the
-/// whole file-transfer process will have timed out
+/// whole file-transfer process will have timed out. This is the default `TFTPClient::idle_timeout`.
 #[allow(non_snake_case)]
 pub fn TOTAL_TIMEOUT() -> Duration { Duration::from_secs(10) }
 
+/// `DEFAULT_SOCKET_TIMEOUT` is the read and write timeout sockets get unless configured otherwise.
+#[allow(non_snake_case)]
+pub fn DEFAULT_SOCKET_TIMEOUT() -> Duration { Duration::from_secs(4) }
+
+/// `FIRST_BLOCK_TIMEOUT` is how long a download that could fall back to a smaller block size waits
+/// for the first data packet before giving up on the current block size.
+#[allow(non_snake_case)]
+pub fn FIRST_BLOCK_TIMEOUT() -> Duration { Duration::from_secs(3) }
+
+/// `PAUSE_CHECK_INTERVAL` is how often a paused transfer checks its timeouts.
+#[allow(non_snake_case)]
+pub fn PAUSE_CHECK_INTERVAL() -> Duration { Duration::from_millis(100) }
+
 
 
 
 use std::cmp::*;
 
 
-pub struct SendData {
+pub struct SendData<D: Datagram = UdpSocket> {
     /// The encoded header
     raw_header: RawRequest,
 
     pub send_attempts: usize,
 
+    /// Sending fails once it has failed more than this many times; `MAX_ATTEMPTS` by default.
+    pub max_attempts: usize,
+
     /// UDP Socket handle
-    socket: Arc<Mutex<UdpSocket>>,
+    socket: Arc<D>,
 
     host_addr: SocketAddr,
 
     pub block_number: usize
 }
 
-impl SendData {
-    pub fn new(data: &[u8], block_number: usize, host_addr: SocketAddr, socket: Arc<Mutex<UdpSocket>>) -> Option<SendData> {
-        let data_header = DataHeader::new(data, block_number);
-        Some(SendData { raw_header: data_header.into(), send_attempts: 0, block_number, socket, host_addr })
+impl<D: Datagram> SendData<D> {
+    pub fn new(data: &[u8], block_number: usize, host_addr: SocketAddr, socket: Arc<D>) -> Option<SendData<D>> {
+        Some(SendData { raw_header: DataHeader::encode(data, block_number), send_attempts: 0, max_attempts: MAX_ATTEMPTS, block_number, socket, host_addr })
+    }
+
+    /// Like `new`, but the packet ends with a CRC32 of it; see `BLOCK_CRC_OPTION`.
+    pub fn with_crc(data: &[u8], block_number: usize, host_addr: SocketAddr, socket: Arc<D>) -> Option<SendData<D>> {
+        Some(SendData { raw_header: DataHeader::encode_with_crc(data, block_number), send_attempts: 0, max_attempts: MAX_ATTEMPTS, block_number, socket, host_addr })
     }
 
-    pub fn new_empty(block_number: usize, host_addr: SocketAddr, socket: Arc<Mutex<UdpSocket>>) -> SendData {
+    /// The encoded data packet.
+    pub fn packet(&self) -> &[u8] {
+        self.raw_header.as_ref()
+    }
+
+    pub fn new_empty(block_number: usize, host_addr: SocketAddr, socket: Arc<D>) -> SendData<D> {
         SendData {
-            raw_header: DataHeader::new_empty(block_number).into(),
+            raw_header: DataHeader::encode(&[], block_number),
             send_attempts: 0,
+            max_attempts: MAX_ATTEMPTS,
             host_addr,
             socket,
             block_number
@@ -261,74 +2220,69 @@ impl SendData {
     }
 }
 
-impl Future for SendData {
+impl<D: Datagram> Future for SendData<D> {
     type Item = usize;
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(ref mut socket) = self.socket.try_lock() {
-            match socket.send_to(self.raw_header.as_ref(), self.host_addr) {
-                Ok(bytes_written) => {
-                    if bytes_written != self.raw_header.len() {
-                        Err(io::Error::new(io::ErrorKind::Other, "Failed to send all data in one UDP packet."))
-                    } else {
-                        Ok(Async::Ready(self.block_number))
-                    }
-                },
-                Err(e) => {
-                    self.send_attempts += 1;
-                    if self.send_attempts > MAX_ATTEMPTS {
-                        Err(e)
-                    } else {
-                        Ok(Async::NotReady)
-                    }
+        match self.socket.send_to(self.raw_header.as_ref(), self.host_addr) {
+            Ok(bytes_written) => {
+                if bytes_written != self.raw_header.len() {
+                    Err(io::Error::new(io::ErrorKind::Other, "Failed to send all data in one UDP packet."))
+                } else {
+                    Ok(Async::Ready(self.block_number))
+                }
+            },
+            // Sending again won't bring the peer back.
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => Err(TFTPError::PeerUnreachable(self.host_addr).into()),
+            Err(e) => {
+                self.send_attempts += 1;
+                if self.send_attempts > self.max_attempts {
+                    Err(e)
+                } else {
+                    Ok(Async::NotReady)
                 }
             }
-        } else {
-            Ok(Async::NotReady)
         }
     }
 }
 
-pub struct SendError {
+pub struct SendError<D: Datagram = UdpSocket> {
     pub host_addr: SocketAddr,
-    socket: Arc<Mutex<UdpSocket>>,
+    socket: Arc<D>,
     pub send_attempts: usize,
+    /// Sending fails once it has failed more than this many times; `MAX_ATTEMPTS` by default.
+    pub max_attempts: usize,
     pub raw_header: RawRequest
 }
 
-impl SendError {
-    pub fn new(error: ErrorHeader, host_addr: SocketAddr, socket: Arc<Mutex<UdpSocket>>) -> SendError {
-        SendError { host_addr, socket, send_attempts: 0, raw_header: error.into() }
+impl<D: Datagram> SendError<D> {
+    pub fn new(error: ErrorHeader, host_addr: SocketAddr, socket: Arc<D>) -> SendError<D> {
+        SendError { host_addr, socket, send_attempts: 0, max_attempts: MAX_ATTEMPTS, raw_header: error.into() }
     }
 }
 
-impl Future for SendError {
+impl<D: Datagram> Future for SendError<D> {
     type Item = ();
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut lock = self.socket.try_lock();
-        if let Ok(ref mut socket) = lock {
-            match (*socket).send_to(self.raw_header.as_ref(), self.host_addr) {
-                Ok(bytes_written) => {
-                    if bytes_written != self.raw_header.len() {
-                        Err(io::Error::new(io::ErrorKind::Other, "Failed to send all data in one UDP packet."))
-                    } else {
-                        Ok(Async::Ready(()))
-                    }
-                },
-                Err(e) => {
-                    self.send_attempts += 1;
-                    if self.send_attempts > MAX_ATTEMPTS {
-                        Err(e)
-                    } else {
-                        Ok(Async::NotReady)
-                    }
+        match self.socket.send_to(self.raw_header.as_ref(), self.host_addr) {
+            Ok(bytes_written) => {
+                if bytes_written != self.raw_header.len() {
+                    Err(io::Error::new(io::ErrorKind::Other, "Failed to send all data in one UDP packet."))
+                } else {
+                    Ok(Async::Ready(()))
+                }
+            },
+            Err(e) => {
+                self.send_attempts += 1;
+                if self.send_attempts > self.max_attempts {
+                    Err(e)
+                } else {
+                    Ok(Async::NotReady)
                 }
             }
-        } else {
-            Ok(Async::NotReady)
         }
     }
 }
@@ -0,0 +1,103 @@
+use std::cmp::{ max, min };
+use send::WindowSize;
+
+/// Decides how many blocks a `SendFile` keeps in flight, as Acks come in and blocks get lost. Each
+/// method returns the window from then on, which is kept to between 1 and `WINDOW_SIZE_LIMIT`.
+/// Links differ a lot in what suits them: a datacenter can start big and grow fast, while a
+/// satellite link wants to back off gently rather than halve its window on every loss.
+///
+/// `Aimd` is what transfers use unless they are given a strategy of their own.
+pub trait WindowStrategy: Send {
+    /// How many blocks may be in flight.
+    fn window(&self) -> usize;
+
+    /// The peer has acknowledged `acked` more blocks.
+    fn on_ack(&mut self, acked: usize) -> usize;
+
+    /// The peer repeated the Ack before `block_number`, so that block was lost. `next_block` is the
+    /// first block that hasn't been sent yet.
+    fn on_loss(&mut self, block_number: usize, next_block: usize) -> usize;
+
+    /// No Ack covered `block_number` in time, so it is being resent. `next_block` is the first
+    /// block that hasn't been sent yet.
+    fn on_timeout(&mut self, block_number: usize, next_block: usize) -> usize;
+}
+
+/// Creates a `WindowStrategy` for every file a `TFTPClient` sends, given the window size it is
+/// configured with. This is implemented for closures with the same signature as `create`.
+pub trait WindowStrategyFactory: Send + Sync {
+    fn create(&self, window_size: WindowSize) -> Box<dyn WindowStrategy>;
+}
+
+impl<F> WindowStrategyFactory for F where F: Fn(WindowSize) -> Box<dyn WindowStrategy> + Send + Sync {
+    fn create(&self, window_size: WindowSize) -> Box<dyn WindowStrategy> {
+        self(window_size)
+    }
+}
+
+/// Additive-increase/multiplicative-decrease control of how many blocks a `SendFile` keeps in
+/// flight. The window grows by one block for every window's worth of blocks acknowledged, i.e.
+/// about once a round trip, and is halved on a loss event: the peer repeating the Ack for a block
+/// it already acknowledged, or the retransmission timer going off.
+///
+/// Blocks that were already out when the window was last cut are covered by that cut, so a burst
+/// of losses from one window only halves it once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Aimd {
+    window: usize,
+    max_window: usize,
+    /// Blocks acknowledged since the window last grew.
+    acked: usize,
+    /// Losses of blocks before this one have been reacted to already.
+    recovery_point: usize,
+}
+
+impl Aimd {
+    /// Starts out with `initial_window` blocks in flight, never going past `max_window`. A
+    /// `max_window` of 1 is stop-and-wait.
+    pub fn new(initial_window: usize, max_window: usize) -> Self {
+        let max_window = max(max_window, 1);
+        Aimd { window: min(max(initial_window, 1), max_window), max_window, acked: 0, recovery_point: 0 }
+    }
+}
+
+impl From<WindowSize> for Aimd {
+    fn from(window_size: WindowSize) -> Self {
+        Aimd::new(window_size.initial, window_size.max)
+    }
+}
+
+impl WindowStrategy for Aimd {
+    fn window(&self) -> usize {
+        self.window
+    }
+
+    fn on_ack(&mut self, acked: usize) -> usize {
+        self.acked += acked;
+        while self.acked >= self.window {
+            self.acked -= self.window;
+            if self.window == self.max_window {
+                self.acked = 0;
+                break
+            }
+            self.window += 1;
+        }
+        self.window
+    }
+
+    /// Losses of blocks sent before `next_block` are part of this same event.
+    fn on_loss(&mut self, block_number: usize, next_block: usize) -> usize {
+        if block_number < self.recovery_point {
+            return self.window
+        }
+        self.window = max(self.window / 2, 1);
+        self.acked = 0;
+        self.recovery_point = next_block;
+        self.window
+    }
+
+    /// A timeout is a loss like any other.
+    fn on_timeout(&mut self, block_number: usize, next_block: usize) -> usize {
+        self.on_loss(block_number, next_block)
+    }
+}
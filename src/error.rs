@@ -1,5 +1,10 @@
 use std::io;
+use std::fmt;
+use std::error::Error;
+use std::net::SocketAddr;
 use std::string::FromUtf8Error;
+use checksum::Checksum;
+use header::ErrorCode;
 
 #[derive(Debug)]
 pub enum TFTPError {
@@ -48,9 +53,100 @@ pub enum TFTPError {
     /// The UDP connection suddenly closed
     ConnectionClosed,
 
-    /// Received data from the wrong source address
-    WrongHost,
+    /// Received data from the wrong source address. The offending packet has been taken off of
+    /// the socket, and the address it came from is included.
+    WrongHost(SocketAddr),
 
     /// A string in a header contained invalid unicode.
-    InvalidUnicodeString(FromUtf8Error)
+    InvalidUnicodeString(FromUtf8Error),
+
+    /// Option negotiation failed (RFC2347): either the peer's OACK was unacceptable, or the peer
+    /// rejected the options that were requested. The request can be retried without options.
+    OptionNegotiationFailed(String),
+
+    /// The peer's OACK contained an option that was never requested, which RFC1785 forbids. The
+    /// name of the option is included.
+    UnexpectedOption(String),
+
+    /// Not a single data packet arrived at the start of a transfer. With a large block size, this
+    /// usually means the datagrams are too big to make it to us.
+    NoDataReceived,
+
+    /// The transferred file does not hash to the expected digest. The expected digest comes
+    /// first, followed by the one the file actually has.
+    ChecksumMismatch(Checksum, Checksum),
+
+    /// The peer aborted the transfer with an ERROR packet, whose error code and message are
+    /// included.
+    ErrorReceived(ErrorCode, String),
+
+    /// An ICMP port unreachable message came back for a datagram sent to the peer, whose address
+    /// is included: nothing is listening there anymore. Only sockets connected to the peer find
+    /// out about this.
+    PeerUnreachable(SocketAddr),
+
+    /// A block went unacknowledged after being sent again as many times as it may be; see
+    /// `SendFile::set_max_block_retransmissions`. The block number and the limit are included.
+    RetransmitLimitExceeded(usize, usize)
+}
+
+impl TFTPError {
+    /// Wraps an error from a socket exchanging packets with `peer`, telling apart the one a
+    /// connected socket reports once the peer has gone away.
+    pub fn from_io(err: io::Error, peer: SocketAddr) -> TFTPError {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => TFTPError::PeerUnreachable(peer),
+            _ => TFTPError::IOError(err)
+        }
+    }
+}
+
+impl fmt::Display for TFTPError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TFTPError::IOError(ref e) => write!(f, "I/O error: {}", e),
+            TFTPError::InvalidOpcode(opcode) => write!(f, "Invalid opcode {}", opcode),
+            TFTPError::InvalidFilename(_) => write!(f, "Invalid filename"),
+            TFTPError::EmptyFilename => write!(f, "Empty filename"),
+            TFTPError::EmptyMode => write!(f, "Empty transfer mode"),
+            TFTPError::InvalidMode(_) => write!(f, "Invalid transfer mode"),
+            TFTPError::InvalidHeaderLen => write!(f, "Header too short"),
+            TFTPError::InvalidDataLen => write!(f, "Data packet contains no data"),
+            TFTPError::InvalidString => write!(f, "String contains a null character"),
+            TFTPError::ConnectionClosed => write!(f, "Connection closed"),
+            TFTPError::WrongHost(addr) => write!(f, "Received a packet from unexpected host {}", addr),
+            TFTPError::InvalidUnicodeString(ref e) => write!(f, "Invalid unicode string: {}", e),
+            TFTPError::OptionNegotiationFailed(ref reason) => write!(f, "Option negotiation failed: {}", reason),
+            TFTPError::UnexpectedOption(ref option) => write!(f, "Option '{}' was not requested", option),
+            TFTPError::NoDataReceived => write!(f, "No data was received"),
+            TFTPError::ChecksumMismatch(ref expected, ref actual) => write!(f, "Expected checksum {}, but the file's is {}", expected, actual),
+            TFTPError::ErrorReceived(_, ref message) => write!(f, "Received error from peer: '{}'", message),
+            TFTPError::PeerUnreachable(addr) => write!(f, "Peer {} is unreachable", addr),
+            TFTPError::RetransmitLimitExceeded(block_number, limit) => write!(f, "Block {} was not acknowledged after being retransmitted {} times", block_number, limit)
+        }
+    }
+}
+
+impl Error for TFTPError {}
+
+/// Transfers report errors as `io::Error`s; a `TFTPError` can be retrieved from one of these by
+/// downcasting `io::Error::get_ref`.
+impl From<TFTPError> for io::Error {
+    fn from(err: TFTPError) -> io::Error {
+        match err {
+            TFTPError::IOError(e) => e,
+            err @ TFTPError::PeerUnreachable(_) => io::Error::new(io::ErrorKind::ConnectionRefused, err),
+            err @ TFTPError::RetransmitLimitExceeded(_, _) => io::Error::new(io::ErrorKind::TimedOut, err),
+            err => io::Error::new(io::ErrorKind::Other, err)
+        }
+    }
+}
+
+/// Whether a transfer failed with `err` because the peer went away, in which case retrying is
+/// pointless.
+pub fn is_peer_unreachable(err: &io::Error) -> bool {
+    match err.get_ref().and_then(|e| e.downcast_ref::<TFTPError>()) {
+        Some(&TFTPError::PeerUnreachable(_)) => true,
+        _ => false
+    }
 }
\ No newline at end of file
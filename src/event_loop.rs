@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::{ Duration, Instant };
+use futures::{ Future, Async };
+use mio::{ Events, Poll, PollOpt, Ready, Token };
+use mio::net::UdpSocket;
+
+use client::{ AcceptedRequest, TFTPClient };
+use header::ErrorCode;
+
+/// The token the listening socket is registered with; sessions get the ones after it.
+const LISTENER: Token = Token(0);
+
+/// The most readiness events handled per turn of the loop.
+const EVENT_CAPACITY: usize = 256;
+
+/// A transfer in progress on an `EventLoop`.
+struct Session {
+    /// The session the request arrived for, which is told how the transfer went.
+    server: TFTPClient,
+    request: AcceptedRequest,
+    /// The transfer's socket, as registered with the loop.
+    io: UdpSocket,
+    /// When the read timeout the transfer last set runs out, if it set one.
+    wake_at: Option<Instant>,
+}
+
+/// Serves requests like `TFTPClient::serve`, but runs every transfer on the calling thread rather
+/// than starting a thread (or spawning a task) for each. The listening socket and the socket of
+/// every session are registered with a `mio::Poll`, and a transfer is only polled once a datagram
+/// has arrived for it or the read timeout it set has run out, so the thread sleeps whenever there
+/// is nothing to do. This suits small systems that can't afford a thread per transfer or a tokio
+/// runtime.
+///
+/// The sockets are switched to non-blocking mode, which a transfer takes the same way as its read
+/// timeout running out. Requests beyond `max_concurrent_transfers` are always refused, since there
+/// is no other thread to queue them on. MTFTP start delays hold up the whole loop.
+pub struct EventLoop {
+    server: TFTPClient,
+    /// The server's socket, as registered with the loop.
+    listener: UdpSocket,
+    poll: Poll,
+    sessions: HashMap<Token, Session>,
+    next_token: usize,
+}
+
+impl EventLoop {
+    pub fn new(server: TFTPClient) -> Result<Self, io::Error> {
+        let socket = match server.udp_socket.lock() {
+            Ok(socket) => socket.try_clone()?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Failed to obtain UDP Socket lock."))
+        };
+        // This makes every handle to the socket non-blocking, the server's included.
+        let listener = UdpSocket::from_socket(socket)?;
+        let poll = Poll::new()?;
+        poll.register(&listener, LISTENER, Ready::readable(), PollOpt::level())?;
+        Ok(EventLoop { server, listener, poll, sessions: HashMap::new(), next_token: LISTENER.0 + 1 })
+    }
+
+    /// The number of transfers in progress.
+    pub fn active_transfers(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Serves requests forever.
+    pub fn run(mut self) -> Result<(), io::Error> {
+        loop {
+            self.turn(None)?;
+        }
+    }
+
+    /// Waits until a datagram arrives or a transfer's read timeout runs out, though no longer than
+    /// `timeout` if given, and then takes in new requests and polls the transfers that have
+    /// something to do.
+    pub fn turn(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        let now = Instant::now();
+        let next_wake = self.sessions.values()
+            .filter_map(|session| session.wake_at)
+            .min()
+            .map(|wake_at| if wake_at > now { wake_at - now } else { Duration::from_secs(0) });
+        let timeout = match (timeout, next_wake) {
+            (Some(timeout), Some(next_wake)) => Some(::std::cmp::min(timeout, next_wake)),
+            (timeout, next_wake) => timeout.or(next_wake)
+        };
+
+        let mut events = Events::with_capacity(EVENT_CAPACITY);
+        self.poll.poll(&mut events, timeout)?;
+        let mut ready = Vec::new();
+        for event in events.iter() {
+            if event.token() == LISTENER {
+                self.accept()?;
+            } else {
+                ready.push(event.token());
+            }
+        }
+
+        let now = Instant::now();
+        let due: Vec<Token> = self.sessions.iter()
+            .filter(|&(token, session)| ready.contains(token) || session.wake_at.map_or(false, |wake_at| wake_at <= now))
+            .map(|(&token, _)| token)
+            .collect();
+        for token in due {
+            self.poll_session(token)?;
+        }
+        Ok(())
+    }
+
+    /// Takes every request waiting on the listening socket, and starts a transfer for each.
+    fn accept(&mut self) -> Result<(), io::Error> {
+        loop {
+            let (header, src) = match self.server.next_request() {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(_) => return Ok(())
+            };
+            let mut session = match self.server.new_session(src) {
+                Ok(session) => session,
+                Err(_) => continue
+            };
+            if self.server.max_concurrent_transfers.map_or(false, |max| self.sessions.len() >= max) {
+                let err = io::Error::new(io::ErrorKind::Other, "Too many transfers in progress.");
+                let _ = session.refuse_request_with::<()>(ErrorCode::Undefined, err);
+                continue
+            }
+            // A refused request has already been answered and reported on.
+            let request = match session.accept_request(header) {
+                Ok(request) => request,
+                Err(_) => continue
+            };
+            let io = UdpSocket::from_socket(request.transfer.socket().try_clone()?)?;
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            self.poll.register(&io, token, Ready::readable(), PollOpt::level())?;
+            // Poll the transfer straight away, so it can set its read timeout.
+            self.sessions.insert(token, Session { server: session, request, io, wake_at: Some(Instant::now()) });
+        }
+    }
+
+    /// Polls the transfer of a session, and reports on it if it's over.
+    fn poll_session(&mut self, token: Token) -> Result<(), io::Error> {
+        let result = match self.sessions.get_mut(&token) {
+            Some(session) => match session.request.transfer.poll() {
+                Ok(Async::NotReady) => {
+                    session.wake_at = session.request.transfer.socket().read_timeout()?
+                        .map(|read_timeout| Instant::now() + read_timeout);
+                    return Ok(())
+                },
+                Ok(Async::Ready(())) => Ok(()),
+                Err(e) => Err(e)
+            },
+            None => return Ok(())
+        };
+        if let Some(session) = self.sessions.remove(&token) {
+            self.poll.deregister(&session.io)?;
+            session.server.finish_request(session.request, &result);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EventLoop {
+    fn drop(&mut self) {
+        let _ = self.poll.deregister(&self.listener);
+        if let Ok(socket) = self.server.udp_socket.lock() {
+            let _ = socket.set_nonblocking(false);
+        }
+    }
+}
@@ -1,4 +1,3 @@
-use rand::thread_rng;
 use error::TFTPError;
 use std::cmp;
 use types::*;
@@ -6,21 +5,29 @@ use std::mem;
 use std::marker::PhantomData;
 use std::ascii::AsciiExt;
 use std::net::{ SocketAddr, ToSocketAddrs };
-use std::net::UdpSocket;
+use transport::Datagram;
 use std::io;
+use std::collections::BTreeMap;
+use checksum::Crc32;
 
-/// Since packets are small, just allocate the same amount of memory for each buffer. Increase this
-/// if data is being truncated.
-const BUFF_ALLOCATION_SIZE: usize = MAX_DATA_LEN * 2;
+/// RFC1350's block size. Packets other than data, e.g. errors, are expected to fit in it as well.
+const RFC1350_BLOCK_SIZE: usize = 512;
 
-pub static mut STOP_AND_WAIT: bool = false;
-pub static mut DROP_THRESHOLD: u64 = 0;
+/// How large a buffer has to be for `Header::recv` to receive any packet of a transfer with
+/// `block_size` byte blocks, a CRC32 included.
+pub fn recv_buffer_len(block_size: usize) -> usize {
+    cmp::max(block_size, RFC1350_BLOCK_SIZE) + DATA_HEADER_LEN + CRC_LEN
+}
 
-const OPCODE_RRQ: u8 = 1;
-const OPCODE_WRQ: u8 = 2;
-const OPCODE_DATA: u8 = 3;
-const OPCODE_ACK: u8 = 4;
-const OPCODE_ERROR: u8 = 5;
+pub const OPCODE_RRQ: u8 = 1;
+pub const OPCODE_WRQ: u8 = 2;
+pub const OPCODE_DATA: u8 = 3;
+pub const OPCODE_ACK: u8 = 4;
+pub const OPCODE_ERROR: u8 = 5;
+pub const OPCODE_OACK: u8 = 6;
+/// Asks for a single block to be sent again, because it arrived corrupted. Only sent to peers that
+/// negotiated `BLOCK_CRC_OPTION`.
+pub const OPCODE_NAK: u8 = 7;
 
 pub enum Header {
     Ack(AckHeader),
@@ -28,64 +35,75 @@ pub enum Header {
     Write(RWHeader<WriteHeader>),
     Data(DataHeader),
     Error(ErrorHeader),
+    OAck(OAckHeader),
+    Nak(NakHeader),
     Invalid(Box<[u8]>)
 }
 
 impl Header {
-    pub fn recv(from: SocketAddr, socket: &mut UdpSocket) -> Result<Self, TFTPError> {
-        let mut buf = vec![0u8; BUFF_ALLOCATION_SIZE];
-        match socket.peek_from(buf.as_mut()) {
+    /// Receives a header from `from` into `buf`, which the caller keeps around for the next one; see
+    /// `recv_buffer_len`. A packet too large for `buf` is cut short.
+    pub fn recv<D: Datagram + ?Sized>(from: SocketAddr, socket: &D, buf: &mut [u8]) -> Result<Self, TFTPError> {
+        match socket.peek_from(buf) {
             Ok((bytes_read, src_addr)) => {
 		if from.ip() != src_addr.ip() || from.port() != src_addr.port() {
-                    Err(TFTPError::WrongHost)
+                    let _ = socket.recv_from(buf);
+                    Err(TFTPError::WrongHost(src_addr))
                 } else {
-                    let _ = socket.recv_from(buf.as_mut());
-                    let buf = &buf[0..bytes_read as usize]; 
-                    let res = Ok(match buf[1] {
-                        OPCODE_RRQ => Header::Read(RWHeader::<ReadHeader>::from_raw(&buf)?),
-                        OPCODE_WRQ => Header::Write(RWHeader::<WriteHeader>::from_raw(buf)?),
-                        OPCODE_ACK => Header::Ack(AckHeader::from_raw(buf)?),
-                        OPCODE_ERROR => Header::Error(ErrorHeader::from_raw(buf)?),
-                        OPCODE_DATA => Header::Data(DataHeader::from_raw(buf)?),
-                        _ => Header::Invalid({ 
-                            let mut r = Vec::with_capacity(bytes_read);
-                            (&mut r).clone_from_slice(buf);
-                            r.into_boxed_slice() 
-                        })
-                    });
-                    use rand::Rng;
-                    if (thread_rng().next_u64() & 127) < unsafe { DROP_THRESHOLD } {
-                        Err(TFTPError::IOError(io::Error::new(io::ErrorKind::Other, "Artificial Drop")))
-                    } else {
-                        res
-                    }
+                    let _ = socket.recv_from(buf);
+                    Header::from_raw(&buf[0..bytes_read as usize])
                 }
             },
-            Err(e) => Err(TFTPError::IOError(e))
+            Err(e) => Err(TFTPError::from_io(e, from))
         }
     }
 
-    pub fn peek(socket: &mut UdpSocket) -> Result<(Self, SocketAddr), TFTPError> {
-        let mut buf = vec![0u8; BUFF_ALLOCATION_SIZE];
-        match socket.peek_from(buf.as_mut()) {
+    /// Looks at the next header without taking it off of the socket, using `buf` like `recv` does.
+    pub fn peek<D: Datagram + ?Sized>(socket: &D, buf: &mut [u8]) -> Result<(Self, SocketAddr), TFTPError> {
+        match socket.peek_from(buf) {
             Ok((bytes_read, src_addr)) => {
-                Ok((
-                    match buf[1] {
-                        OPCODE_RRQ => Header::Read(RWHeader::<ReadHeader>::from_raw(&buf)?),
-                        OPCODE_WRQ => Header::Write(RWHeader::<WriteHeader>::from_raw(&buf)?),
-                        OPCODE_ACK => Header::Ack(AckHeader::from_raw(&buf)?),
-                        OPCODE_ERROR => Header::Error(ErrorHeader::from_raw(&buf)?),
-                        OPCODE_DATA => Header::Data(DataHeader::from_raw(&buf)?),
-                        _ => Header::Invalid(buf.into_boxed_slice())
-                    },
-                    src_addr))
+                Ok((Header::from_raw(&buf[0..bytes_read])?, src_addr))
             },
             Err(e) => Err(TFTPError::IOError(e))
         }
     }
 
+    /// Receives a burst of headers from `from`, up to one into each of `bufs`, with as few system
+    /// calls as the socket allows (see `Datagram::recv_from_many`). Only the first is waited for.
+    /// Anything that came from elsewhere is a `TFTPError::WrongHost`, like with `recv`.
+    pub fn recv_many<D: Datagram + ?Sized>(from: SocketAddr, socket: &D, bufs: &mut [Vec<u8>]) -> Result<Vec<Result<Self, TFTPError>>, io::Error> {
+        let received = {
+            let mut bufs: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| buf.as_mut_slice()).collect();
+            socket.recv_from_many(&mut bufs).map_err(|e| io::Error::from(TFTPError::from_io(e, from)))?
+        };
+        Ok(received.into_iter().zip(bufs.iter()).map(|((bytes_read, src_addr), buf)| {
+            if from.ip() != src_addr.ip() || from.port() != src_addr.port() {
+                Err(TFTPError::WrongHost(src_addr))
+            } else {
+                Header::from_raw(&buf[0..bytes_read])
+            }
+        }).collect())
+    }
+
+    /// Decodes a packet of any type.
+    pub fn from_raw(buf: &[u8]) -> Result<Self, TFTPError> {
+        if buf.len() < 2 {
+            return Ok(Header::Invalid(buf.to_vec().into_boxed_slice()))
+        }
+        Ok(match buf[1] {
+            OPCODE_RRQ => Header::Read(RWHeader::<ReadHeader>::from_raw(buf)?),
+            OPCODE_WRQ => Header::Write(RWHeader::<WriteHeader>::from_raw(buf)?),
+            OPCODE_ACK => Header::Ack(AckHeader::from_raw(buf)?),
+            OPCODE_ERROR => Header::Error(ErrorHeader::from_raw(buf)?),
+            OPCODE_DATA => Header::Data(DataHeader::from_raw(buf)?),
+            OPCODE_OACK => Header::OAck(OAckHeader::from_raw(buf)?),
+            OPCODE_NAK => Header::Nak(NakHeader::from_raw(buf)?),
+            _ => Header::Invalid(buf.to_vec().into_boxed_slice())
+        })
+    }
+
     /// Sends a header
-    pub fn send(self, to: SocketAddr, socket: &mut UdpSocket) -> Result<(), io::Error> {
+    pub fn send<D: Datagram + ?Sized>(self, to: SocketAddr, socket: &D) -> Result<(), io::Error> {
         let raw = self.into_raw_request();
         match socket.send_to(raw.as_ref(), to) {
             Ok(bytes_written) => {
@@ -95,10 +113,18 @@ impl Header {
                     Ok(())
                 }
             },
-            Err(e) => Err(e)
+            Err(e) => Err(TFTPError::from_io(e, to).into())
         }
     }
 
+    /// Tells `stray` that it sent a packet to a transfer it is not a part of, by sending it an
+    /// error with the code `ErrorCode::UnknownTransferID`, as specified by RFC1350. This should
+    /// not otherwise disturb the transfer that received the packet.
+    pub fn reject_unknown_tid<D: Datagram + ?Sized>(stray: SocketAddr, socket: &D) -> Result<(), io::Error> {
+        let error_header = ErrorHeader::new(ErrorCode::UnknownTransferID, "Unknown transfer ID".to_string()).unwrap();
+        Header::Error(error_header).send(stray, socket)
+    }
+
     fn into_raw_request(self) -> RawRequest {
         match self {
             Header::Ack(header)     => header.into(),
@@ -106,6 +132,8 @@ impl Header {
             Header::Write(header)   => header.into(),
             Header::Error(header)   => header.into(),
             Header::Data(header)    => header.into(),
+            Header::OAck(header)    => header.into(),
+            Header::Nak(header)     => header.into(),
             Header::Invalid(header) => panic!("Attempted to serialize an invalid header...")
         }
     }
@@ -185,6 +213,12 @@ impl ToRequestType for WriteHeader {
 /// WRQ    -----------------------------------------------
 /// ```
 /// Note: all strings in headers are null-terminated c-style strings, hence the 0 after both strings
+///
+/// The mode may be followed by options (RFC2347), which are encoded as null-terminated option /
+/// value pairs. The options that are understood are "blksize" (RFC2348), "tsize" (RFC2349), the
+/// de-facto "utimeout" (the retransmission timeout in microseconds, as supported by tftpd-hpa) and
+/// this crate's own `BLOCK_CRC_OPTION`; any other options are kept in `options` for the application
+/// to deal with.
 #[derive(Clone, Debug)]
 pub struct RWHeader<T: ToRequestType> {
     /// The name / path of the file to be read / written.
@@ -193,6 +227,24 @@ pub struct RWHeader<T: ToRequestType> {
     /// The mode of data transfer
     pub mode: RWMode,
 
+    /// The requested block size, if any. Must be between MIN_BLOCK_SIZE and MAX_BLOCK_SIZE.
+    pub blksize: Option<usize>,
+
+    /// The transfer size, if any. For a RRQ this should be 0, asking the responder to report the
+    /// size of the file; for a WRQ it is the size of the file that will be sent.
+    pub tsize: Option<u64>,
+
+    /// The requested retransmission timeout in microseconds, if any. Must be between MIN_UTIMEOUT
+    /// and MAX_UTIMEOUT.
+    pub utimeout: Option<u64>,
+
+    /// Whether every data block should carry a CRC32; see `BLOCK_CRC_OPTION`.
+    pub block_crc: bool,
+
+    /// Options that aren't understood by this crate (e.g. vendor specific options), keyed by their
+    /// lower case name. Neither the names nor the values may contain a null character.
+    pub options: BTreeMap<String, String>,
+
     _pd: PhantomData<T>
 }
 
@@ -205,10 +257,33 @@ impl<T: ToRequestType> RWHeader<T> {
         Ok(RWHeader {
             filename,
             mode,
+            blksize: None,
+            tsize: None,
+            utimeout: None,
+            block_crc: false,
+            options: BTreeMap::new(),
             _pd: PhantomData
         })
     }
 
+    /// All of the options carried by this request, in the form they are encoded in.
+    pub fn all_options(&self) -> BTreeMap<String, String> {
+        let mut options = self.options.clone();
+        if let Some(blksize) = self.blksize {
+            options.insert("blksize".to_string(), blksize.to_string());
+        }
+        if let Some(tsize) = self.tsize {
+            options.insert("tsize".to_string(), tsize.to_string());
+        }
+        if let Some(utimeout) = self.utimeout {
+            options.insert("utimeout".to_string(), utimeout.to_string());
+        }
+        if self.block_crc {
+            options.insert(BLOCK_CRC_OPTION.to_string(), "1".to_string());
+        }
+        options
+    }
+
     pub fn into_raw(self) -> RawRequest { self.into() }
 
     pub fn from_raw(src: RawResponse) -> TFTPResult<Self> {
@@ -240,10 +315,11 @@ impl<T: ToRequestType> RWHeader<T> {
 
         let mut mode = Vec::with_capacity(8);
         loop {
-            if src[i] == 0 {
-                break;
-            } else if src.len() <= i {
+            if src.len() <= i {
                 return Err(TFTPError::InvalidMode(Vec::from(src).into_boxed_slice()))
+            } else if src[i] == 0 {
+                i += 1;
+                break;
             }
             mode.push(src[i]);
             i += 1;
@@ -253,6 +329,17 @@ impl<T: ToRequestType> RWHeader<T> {
             return Err(TFTPError::EmptyMode)
         }
 
+        // Options that have invalid values are ignored as if they were never requested (RFC2347).
+        let mut options = parse_options(&src[i..])?;
+        let blksize = options.remove("blksize")
+            .and_then(|blksize| blksize.parse::<usize>().ok())
+            .and_then(|blksize| if blksize >= MIN_BLOCK_SIZE && blksize <= MAX_BLOCK_SIZE { Some(blksize) } else { None });
+        let tsize = options.remove("tsize").and_then(|tsize| tsize.parse::<u64>().ok());
+        let utimeout = options.remove("utimeout")
+            .and_then(|utimeout| utimeout.parse::<u64>().ok())
+            .and_then(|utimeout| if utimeout >= MIN_UTIMEOUT && utimeout <= MAX_UTIMEOUT { Some(utimeout) } else { None });
+        let block_crc = options.remove(BLOCK_CRC_OPTION).map_or(false, |block_crc| block_crc == "1");
+
         match (String::from_utf8(filename), String::from_utf8(mode)) {
             (Err(e), _) => Err(TFTPError::InvalidUnicodeString(e)),
             (_, Err(e)) => Err(TFTPError::InvalidUnicodeString(e)),
@@ -262,6 +349,11 @@ impl<T: ToRequestType> RWHeader<T> {
                         Ok(RWHeader {
                             mode,
                             filename,
+                            blksize,
+                            tsize,
+                            utimeout,
+                            block_crc,
+                            options,
                             _pd: PhantomData
                         }),
                     None => Err(TFTPError::InvalidMode(Vec::from(src).into_boxed_slice()))
@@ -295,15 +387,33 @@ impl<T: ToRequestType> Into<RawRequest> for RWHeader<T> {
         data[i..i + mode_slice.len()].clone_from_slice(mode_slice);
         i += mode_slice.len();
         data[i] = 0;
-        i += 1;
 
+        write_options(&self.all_options(), &mut data);
         data
     }
 }
 
+/// The block size used when no other block size has been negotiated.
 pub const MAX_DATA_LEN: usize = 4 * 1024;
 pub const DATA_HEADER_LEN: usize = 4;
 
+/// The smallest block size that may be negotiated with the "blksize" option (RFC2348).
+pub const MIN_BLOCK_SIZE: usize = 8;
+/// The largest block size that may be negotiated with the "blksize" option (RFC2348).
+pub const MAX_BLOCK_SIZE: usize = 65464;
+
+/// The smallest retransmission timeout, in microseconds, that may be requested with "utimeout".
+pub const MIN_UTIMEOUT: u64 = 10_000;
+/// The largest retransmission timeout, in microseconds, that may be requested with "utimeout".
+pub const MAX_UTIMEOUT: u64 = 255_000_000;
+
+/// A vendor option asking for every data block to end with a CRC32 of the packet, so that blocks
+/// corrupted on the way (which UDP checksums don't always catch) are NAKed rather than written. Its
+/// value is "1". Peers that don't know it leave it out of their OACK, and blocks are sent as usual.
+pub const BLOCK_CRC_OPTION: &'static str = "x-blkcrc32";
+/// The length of the CRC32 at the end of data packets when `BLOCK_CRC_OPTION` was negotiated.
+pub const CRC_LEN: usize = 4;
+
 /// Represents a data header; either sent or received.
 /// With the exception of the first byte being used as the MSB of the block number to extend the
 /// file-size capability of the protocol, this is the format specified by RFC1350:
@@ -317,24 +427,21 @@ pub const DATA_HEADER_LEN: usize = 4;
 #[derive(Clone)]
 pub struct DataHeader {
 
-    /// The data of this data of the request. up to MAX_DATA_LEN bytes.
+    /// The data of this data of the request. up to one block (MAX_BLOCK_SIZE at most) of bytes.
     pub data: Vec<u8>,
     /// How many bytes of [data] are actually being used.
     pub data_len: usize,
-    /// The block number. Each block is MAX_DATA_LEN bytes in size.
+    /// The block number. Each block is the negotiated block size in size.
     pub block_number: usize
 }
 
 impl DataHeader {
 
-    /// Tries to create a new data header to be sent out.
-    /// Returns Some(..) unless block_number * MAX_DATA_LEN goes over the length of data_src.
+    /// Creates a new data header to be sent out. At most MAX_BLOCK_SIZE bytes of data_src are used.
     pub fn new(data_src: &[u8], block_number: usize) -> Self {
-        let data_len = cmp::min(data_src.len(), MAX_DATA_LEN);
-        let mut data = vec![0u8; MAX_DATA_LEN];
-        data[0..data_len].copy_from_slice(&data_src[..]);
+        let data_len = cmp::min(data_src.len(), MAX_BLOCK_SIZE);
         DataHeader {
-            data,
+            data: Vec::from(&data_src[0..data_len]),
             block_number,
             data_len: data_len
         }
@@ -342,7 +449,7 @@ impl DataHeader {
 
     pub fn new_empty(block_number: usize) -> Self {
         DataHeader {
-            data: vec![0u8; MAX_DATA_LEN],
+            data: vec![],
             block_number,
             data_len: 0
         }
@@ -350,6 +457,41 @@ impl DataHeader {
 
     pub fn into_raw(self) -> RawRequest { self.into() }
 
+    /// Encodes a data packet carrying `data_src` (at most MAX_BLOCK_SIZE bytes of it). The data is
+    /// copied straight into the packet, e.g. from a memory map of the file, rather than into a
+    /// `DataHeader` first.
+    pub fn encode(data_src: &[u8], block_number: usize) -> RawRequest {
+        let data_len = cmp::min(data_src.len(), MAX_BLOCK_SIZE);
+        let mut data = Vec::with_capacity(DATA_HEADER_LEN + data_len);
+        data.extend_from_slice(&[(block_number >> 16) as u8, OPCODE_DATA, (block_number >> 8) as u8, block_number as u8]);
+        data.extend_from_slice(&data_src[0..data_len]);
+        data
+    }
+
+    /// Encodes a data packet like `encode`, followed by a CRC32 of the packet, big endian; see
+    /// `BLOCK_CRC_OPTION`.
+    pub fn encode_with_crc(data_src: &[u8], block_number: usize) -> RawRequest {
+        let mut data = DataHeader::encode(data_src, block_number);
+        let crc = Crc32::of(&data);
+        data.extend_from_slice(&[(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]);
+        data
+    }
+
+    /// Takes the CRC32 off of the end of a packet encoded with `encode_with_crc`, returning whether
+    /// it matched. If it didn't, the data can't be trusted.
+    pub fn check_crc(&mut self) -> bool {
+        if self.data_len < CRC_LEN {
+            return false
+        }
+        self.data_len -= CRC_LEN;
+        let trailer = &self.data[self.data_len..self.data_len + CRC_LEN];
+        let expected = (trailer[0] as u32) << 24 | (trailer[1] as u32) << 16 | (trailer[2] as u32) << 8 | trailer[3] as u32;
+        let mut crc = Crc32::new();
+        crc.update(&[(self.block_number >> 16) as u8, OPCODE_DATA, (self.block_number >> 8) as u8, self.block_number as u8]);
+        crc.update(&self.data[0..self.data_len]);
+        crc.value() == expected
+    }
+
     pub fn from_raw(src: RawResponse) -> TFTPResult<Self> {
         debug_assert!(src[1] == OPCODE_DATA);
         if src.len() < 4 {
@@ -364,30 +506,19 @@ impl DataHeader {
         block_number |= (src[3] as u32);
         let block_number = block_number as usize;
 
-        let mut data = vec![0u8; MAX_DATA_LEN];
-        let index = src.len();
-        data[0..cmp::min(index - 4, MAX_DATA_LEN)]
-            .copy_from_slice(&src[4..cmp::min(MAX_DATA_LEN + 4, src.len())]);
+        // Leave room for a CRC32 after the largest block; see `check_crc`.
+        let data = Vec::from(&src[4..cmp::min(MAX_BLOCK_SIZE + CRC_LEN + 4, src.len())]);
         Ok(DataHeader {
+            data_len: data.len(),
             data,
-            block_number,
-            data_len: src.len() - 4
+            block_number
         })
     }
 }
 
 impl Into<RawRequest> for DataHeader {
     fn into(self) -> RawRequest {
-        let block_number = [(self.block_number >> 16) as u8, (self.block_number >> 8) as u8, (self.block_number) as u8];
-        let mut data = vec![0u8; 4 + self.data_len];
-        data[1] = OPCODE_DATA;
-
-        data[0] = block_number[0];
-        data[2] = block_number[1];
-        data[3] = block_number[2];
-        
-        data[4..self.data_len + 4].clone_from_slice(&self.data[0..self.data_len]);
-        data
+        DataHeader::encode(&self.data[0..self.data_len], self.block_number)
     }
 }
 
@@ -436,8 +567,38 @@ impl Into<RawRequest> for AckHeader {
     }
 }
 
-/// Represents all possible error codes defined by RFC1350. Any error code that is greater than 7
-/// will be mapped to ErrorCode::Undefined.
+/// Asks the peer to resend a block that arrived corrupted. It has the same format as an Ack, but
+/// for the opcode, and is only sent to peers that negotiated `BLOCK_CRC_OPTION`:
+/// ```text
+///        1 byte         1 byte     2 bytes
+///        -------------------------------------------------
+/// NAK   | Block # MSB | 07     |   Block # lower 2 bytes  |
+///        -------------------------------------------------
+/// ```
+#[derive(Clone, Debug)]
+pub struct NakHeader { pub block_number: usize }
+
+impl NakHeader {
+    pub fn new(block_number: usize) -> Self { NakHeader { block_number } }
+    pub fn into_raw(self) -> RawRequest { self.into() }
+    pub fn from_raw(src: RawResponse) -> TFTPResult<NakHeader> {
+        debug_assert!(src[1] == OPCODE_NAK);
+        if src.len() < 4 {
+            return Err(TFTPError::InvalidHeaderLen)
+        }
+        let block_number = (src[0] as usize) << 16 | (src[2] as usize) << 8 | src[3] as usize;
+        Ok(NakHeader { block_number })
+    }
+}
+
+impl Into<RawRequest> for NakHeader {
+    fn into(self) -> RawRequest {
+        vec![(self.block_number >> 16) as u8, OPCODE_NAK, (self.block_number >> 8) as u8, self.block_number as u8]
+    }
+}
+
+/// Represents all possible error codes defined by RFC1350, plus the option negotiation error code
+/// defined by RFC2347. Any error code that is greater than 8 will be mapped to ErrorCode::Undefined.
 #[repr(u16)]
 #[derive(Clone, Copy, Debug)]
 pub enum ErrorCode {
@@ -448,13 +609,14 @@ pub enum ErrorCode {
     IllegalOperation = 4,
     UnknownTransferID = 5,
     FileAlreadyExists = 6,
-    NoSuchUser = 7
+    NoSuchUser = 7,
+    OptionNegotiation = 8
 }
 
 
 impl From<u16> for ErrorCode {
     fn from(src: u16) -> Self {
-        if src < 8 {
+        if src < 9 {
             unsafe { mem::transmute::<u16, ErrorCode>(src) }
         } else {
             ErrorCode::Undefined
@@ -537,3 +699,88 @@ impl Into<RawRequest> for ErrorHeader {
         data
     }
 }
+
+
+/// Represents an option acknowledgement (RFC2347), sent in response to a request that carried
+/// options. It contains every option the responder accepted, along with the accepted values.
+/// When encoded, an OACK header has the following format:
+/// ```text
+///         2 bytes  string   1 byte  string   1 byte        string   1 byte  string   1 byte
+///        --------------------------------------------------------------------------------
+/// OACK  | 06    |  opt1  |   0  |  value1  |   0  | ... |  optN  |   0  |  valueN  |   0  |
+///        --------------------------------------------------------------------------------
+/// ```
+/// Option names are case insensitive, so they are always stored in lower case.
+#[derive(Clone, Debug)]
+pub struct OAckHeader {
+    pub options: BTreeMap<String, String>
+}
+
+impl OAckHeader {
+    pub fn new(options: BTreeMap<String, String>) -> Result<OAckHeader, TFTPError> {
+        for (option, value) in options.iter() {
+            if option.contains('\0') || value.contains('\0') {
+                return Err(TFTPError::InvalidString)
+            }
+        }
+        Ok(OAckHeader { options })
+    }
+
+    pub fn from_raw(src: RawResponse) -> TFTPResult<OAckHeader> {
+        if src.len() < 2 {
+            return Err(TFTPError::InvalidHeaderLen)
+        }
+
+        debug_assert!(src[1] == OPCODE_OACK);
+        debug_assert!(src[0] == 0);
+
+        Ok(OAckHeader { options: parse_options(&src[2..])? })
+    }
+
+    pub fn into_raw(self) -> RawRequest { self.into() }
+}
+
+impl Into<RawRequest> for OAckHeader {
+    fn into(self) -> RawRequest {
+        let mut data = vec![0u8, OPCODE_OACK];
+        write_options(&self.options, &mut data);
+        data
+    }
+}
+
+/// Parses a list of null-terminated option / value pairs, as found at the end of requests and in
+/// OACKs.
+fn parse_options(src: &[u8]) -> TFTPResult<BTreeMap<String, String>> {
+    let mut strings = Vec::new();
+    let mut start = 0;
+    for i in 0..src.len() {
+        if src[i] == 0 {
+            match String::from_utf8(Vec::from(&src[start..i])) {
+                Ok(string) => strings.push(string),
+                Err(e) => return Err(TFTPError::InvalidUnicodeString(e))
+            }
+            start = i + 1;
+        }
+    }
+    // Every string must be null-terminated, and every option must have a value.
+    if start != src.len() || strings.len() % 2 != 0 {
+        return Err(TFTPError::InvalidString)
+    }
+
+    let mut options = BTreeMap::new();
+    let mut strings = strings.into_iter();
+    while let (Some(option), Some(value)) = (strings.next(), strings.next()) {
+        options.insert(option.to_lowercase(), value);
+    }
+    Ok(options)
+}
+
+/// Appends options to `data` as null-terminated option / value pairs.
+fn write_options(options: &BTreeMap<String, String>, data: &mut Vec<u8>) {
+    for (option, value) in options.iter() {
+        data.extend_from_slice(option.as_ref());
+        data.push(0);
+        data.extend_from_slice(value.as_ref());
+        data.push(0);
+    }
+}
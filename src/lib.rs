@@ -8,14 +8,27 @@
 ///
 /// This library aims implement all of RFC1350, and to provide simple means to use and extend
 /// it.
+#[cfg(feature = "memmap")]
 extern crate memmap;
 extern crate futures;
+#[cfg(feature = "local-ip")]
 extern crate local_ip;
+#[cfg(feature = "tokio-core")]
 extern crate tokio_core;
 extern crate bit_set;
 extern crate bit_vec;
+#[cfg(feature = "rayon")]
 extern crate rayon;
 extern crate rand;
+extern crate libc;
+extern crate md5;
+extern crate sha2;
+#[cfg(feature = "mio")]
+extern crate mio;
+#[cfg(feature = "async-io")]
+extern crate async_io;
+#[cfg(feature = "gzip")]
+extern crate flate2;
 //#[macro_use] extern crate lazy_static;
 
 
@@ -26,13 +39,31 @@ pub mod receive;
 pub mod send;
 pub mod header;
 pub mod types;
+pub mod net_util;
+pub mod backend;
+pub mod throttle;
+pub mod congestion;
+pub mod retry;
+pub mod timer_wheel;
+pub mod session;
+pub mod checksum;
+pub mod transport;
+#[cfg(all(feature = "tokio", unix))]
+pub mod reactor;
+#[cfg(feature = "async-io")]
+pub mod async_transfer;
+#[cfg(all(feature = "event-loop", unix))]
+pub mod event_loop;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+#[cfg(feature = "mdns")]
+pub mod mdns;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "client", feature = "server"))]
 mod tests {
     use super::*;
     use std::net::SocketAddr;
     use futures::*;
-    use tokio_core::reactor::Core;
     use super::client::*;
     use std::net::*;
     use std::thread::spawn;
@@ -50,13 +81,8 @@ mod tests {
 
         let p = spawn(move || { server.serve() });
         let q = spawn(move || {
-            let mut r = client.request_file(Path::new("test.md"), Path::new("oof.md"));
-            loop {
-                match r.poll() {
-                    Ok(Async::Ready(_)) => return,
-                    Err(e) => { panic!(e.to_string()) },
-                    Ok(Async::NotReady) => continue,
-                }
+            if let Err(e) = client.request_file_blocking(Path::new("test.md"), Path::new("oof.md")) {
+                panic!(e.to_string())
             }
         });
 
@@ -74,17 +100,1529 @@ mod tests {
 
         let p = spawn(move || { server.serve() });
         let q = spawn(move || {
-            let mut r = client.send_file(Path::new("woah.jpeg"));
-            loop {
-                match r.poll() {
-                    Ok(Async::Ready(_)) => return,
-                    Err(e) => { eprintln!("{}", e.to_string()); break; },
-                    Ok(Async::NotReady) => continue,
-                }
+            if let Err(e) = client.send_file_blocking(Path::new("woah.jpeg")) {
+                eprintln!("{}", e.to_string());
             }
         });
 
         q.join();   
         println!("oof");
     }
+
+    #[test]
+    fn test_oack_round_trip() {
+        use super::header::*;
+        use std::collections::BTreeMap;
+
+        let mut options = BTreeMap::new();
+        options.insert("blksize".to_string(), "1428".to_string());
+        options.insert("tsize".to_string(), "0".to_string());
+        let raw = OAckHeader::new(options.clone()).unwrap().into_raw();
+        assert_eq!(OAckHeader::from_raw(&raw).unwrap().options, options);
+
+        // Option names are case insensitive, and every option needs a value.
+        assert_eq!(OAckHeader::from_raw(b"\x00\x06BLKSIZE\x001428\x00").unwrap().options["blksize"], "1428");
+        assert!(OAckHeader::from_raw(b"\x00\x06blksize\x00").is_err());
+    }
+
+    #[test]
+    fn test_data_encode() {
+        use super::header::*;
+
+        let block: Vec<u8> = (0..600u32).map(|i| i as u8).collect();
+        let raw = DataHeader::encode(&block[..512], 0x01_0203);
+        assert_eq!(raw[..4], [0x01, OPCODE_DATA, 0x02, 0x03]);
+        let data = DataHeader::from_raw(&raw).unwrap();
+        assert_eq!((data.block_number, &data.data[..]), (0x01_0203, &block[..512]));
+        assert_eq!(raw, DataHeader::new(&block[..512], 0x01_0203).into_raw());
+        assert_eq!(DataHeader::encode(&[], 7).len(), DATA_HEADER_LEN);
+    }
+
+    #[test]
+    fn test_request_options() {
+        use super::header::*;
+
+        let mut request = RWHeader::<ReadHeader>::new("test.md".to_string(), RWMode::Octet).unwrap();
+        request.blksize = Some(MAX_BLOCK_SIZE);
+        request.options.insert("x-token".to_string(), "abc".to_string());
+        let raw = request.into_raw();
+        let request = RWHeader::<ReadHeader>::from_raw(&raw).unwrap();
+        assert_eq!(request.blksize, Some(MAX_BLOCK_SIZE));
+        assert_eq!(request.options.len(), 1);
+        assert_eq!(request.options["x-token"], "abc");
+
+        // Out of range block sizes and timeouts are ignored rather than rejected.
+        let raw = b"\x00\x01test.md\x00octet\x00blksize\x0070000\x00";
+        assert_eq!(RWHeader::<ReadHeader>::from_raw(raw).unwrap().blksize, None);
+        let raw = b"\x00\x01test.md\x00octet\x00utimeout\x0050000\x00";
+        assert_eq!(RWHeader::<ReadHeader>::from_raw(raw).unwrap().utimeout, Some(50000));
+        let raw = b"\x00\x01test.md\x00octet\x00utimeout\x00500\x00";
+        assert_eq!(RWHeader::<ReadHeader>::from_raw(raw).unwrap().utimeout, None);
+    }
+
+    #[test]
+    fn test_opcode_handler() {
+        use std::time::Duration;
+
+        struct Echo;
+        impl OpcodeHandler for Echo {
+            fn handle_packet(&self, packet: &[u8], peer: SocketAddr, socket: &mut UdpSocket) {
+                let _ = socket.send_to(packet, peer);
+            }
+        }
+
+        let host_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 22712);
+        let mut server = TFTPClient::new(host_addr, host_addr, "data/server_data".to_string(), 1).unwrap();
+        assert!(server.set_opcode_handler(header::OPCODE_OACK, Echo).is_err());
+        server.set_opcode_handler(0x20, Echo).unwrap();
+        spawn(move || { server.serve() });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(4))).unwrap();
+        socket.send_to(b"\x00\x20hello", host_addr).unwrap();
+        let mut buf = [0u8; 16];
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"\x00\x20hello");
+    }
+
+    #[test]
+    fn test_in_memory_backend() {
+        use super::backend::InMemoryBackend;
+        use std::fs;
+
+        let host_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 22713);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 32713);
+
+        let backend = InMemoryBackend::new();
+        let mut client = TFTPClient::new(host_addr, client_addr, "data/client_data".to_string(), 1).unwrap();
+        let mut server = TFTPClient::new(client_addr, host_addr, "data/server_data".to_string(), 1).unwrap();
+        server.set_backend(backend.clone());
+        spawn(move || { server.serve() });
+
+        client.send_file(Path::new("woah.jpeg")).wait().unwrap();
+        // The upload is only stored once the server has received all of it.
+        for _ in 0..50 {
+            if backend.get("woah.jpeg").is_some() { break }
+            ::std::thread::sleep(::std::time::Duration::from_millis(100));
+        }
+        assert_eq!(backend.get("woah.jpeg"), Some(fs::read("data/client_data/woah.jpeg").unwrap()));
+
+        let contents = client.request_file_to_vec(Path::new("woah.jpeg")).wait().unwrap();
+        assert_eq!(Some(contents), backend.get("woah.jpeg"));
+    }
+
+    #[test]
+    #[cfg(all(feature = "event-loop", unix))]
+    fn test_event_loop() {
+        use super::backend::InMemoryBackend;
+        use super::event_loop::EventLoop;
+
+        let host_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 22717);
+        let client_addr = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+
+        let backend = InMemoryBackend::new();
+        let image: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        backend.insert("boot.img".to_string(), image.clone());
+        let mut server = TFTPClient::new(client_addr(32717), host_addr, "data/server_data".to_string(), 1).unwrap();
+        server.set_backend(backend.clone());
+        spawn(move || EventLoop::new(server).unwrap().run());
+
+        // Two downloads and an upload, all served by the one thread.
+        let downloads: Vec<_> = (0..2).map(|i| spawn(move || {
+            let mut client = TFTPClient::new(host_addr, client_addr(32718 + i), "data/client_data".to_string(), 1).unwrap();
+            client.request_file_to_vec(Path::new("boot.img")).wait().unwrap()
+        })).collect();
+        let mut client = TFTPClient::new(host_addr, client_addr(32720), "data/client_data".to_string(), 1).unwrap();
+        client.send_bytes("upload.img", &image[..70_000]).wait().unwrap();
+
+        for download in downloads {
+            assert!(download.join().unwrap() == image);
+        }
+        for _ in 0..50 {
+            if backend.get("upload.img").is_some() { break }
+            ::std::thread::sleep(::std::time::Duration::from_millis(100));
+        }
+        assert!(backend.get("upload.img") == Some(image[..70_000].to_vec()));
+    }
+
+    #[test]
+    fn test_read_handler() {
+        use super::backend::{ InMemoryBackend, ReadSeek };
+        use super::header::{ RWHeader, ReadHeader };
+        use std::io::Cursor;
+
+        let host_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 22721);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 32721);
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        let backend = InMemoryBackend::new();
+        let mut client = TFTPClient::new(host_addr, client_addr, "data/client_data".to_string(), 1).unwrap();
+        let mut server = TFTPClient::new(client_addr, host_addr, "data/server_data".to_string(), 1).unwrap();
+        server.set_backend(backend.clone());
+        let generated = data.clone();
+        server.set_read_handler(move |_request: &RWHeader<ReadHeader>, _peer: SocketAddr| {
+            Ok(Box::new(Cursor::new(generated.clone())) as Box<dyn ReadSeek>)
+        });
+        spawn(move || { server.serve() });
+
+        assert!(client.request_file_to_vec(Path::new("generated.bin")).wait().unwrap() == data);
+
+        // Not from the start: the whole reader is sent all the same.
+        let mut reader = Cursor::new(data.clone());
+        reader.set_position(5_000);
+        client.send_from_reader("reader.bin", reader).wait().unwrap();
+        for _ in 0..50 {
+            if backend.get("reader.bin").is_some() { break }
+            ::std::thread::sleep(::std::time::Duration::from_millis(100));
+        }
+        assert!(backend.get("reader.bin") == Some(data));
+    }
+
+    #[test]
+    fn test_transfer_id() {
+        use super::backend::InMemoryBackend;
+        use super::header::{ recv_buffer_len, AckHeader, Header, RWHeader, RWMode, ReadHeader, MAX_DATA_LEN };
+        use std::time::Duration;
+
+        let host_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 22741);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 32741);
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        let backend = InMemoryBackend::new();
+        backend.insert("tid.bin".to_string(), data.clone());
+        let mut server = TFTPClient::new(client_addr, host_addr, "data/server_data".to_string(), 1).unwrap();
+        server.set_backend(backend);
+        let client = UdpSocket::bind(client_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        spawn(move || { server.serve() });
+
+        // The request is answered, and the file sent, from a port of its own rather than the one
+        // the request went to.
+        let request = RWHeader::<ReadHeader>::new("tid.bin".to_string(), RWMode::Octet).unwrap();
+        Header::Read(request).send(host_addr, &client).unwrap();
+        let mut buf = vec![0u8; recv_buffer_len(MAX_DATA_LEN)];
+        let (_, tid) = Header::peek(&client, &mut buf).unwrap();
+        assert_eq!(tid.ip(), host_addr.ip());
+        assert!(tid.port() != host_addr.port());
+
+        let mut received = Vec::new();
+        let mut next_block = 0;
+        while received.len() < data.len() {
+            let block = match Header::recv(tid, &client, &mut buf) {
+                Ok(Header::Data(block)) => block,
+                // The server's answer to the request.
+                Ok(Header::Ack(AckHeader { block_number: 0 })) => continue,
+                _ => panic!("Expected data from the transfer's port.")
+            };
+            if block.block_number == next_block {
+                received.extend_from_slice(&block.data[..block.data_len]);
+                next_block += 1;
+            }
+            Header::Ack(AckHeader::new(block.block_number)).send(tid, &client).unwrap();
+        }
+        assert!(received == data);
+    }
+
+    #[test]
+    fn test_unknown_transfer_id() {
+        use super::backend::{ Contents, SharedContents };
+        use super::header::{ recv_buffer_len, AckHeader, ErrorCode, ErrorHeader, Header };
+        use super::send::SendFile;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        // The sockets of a server's sessions are connected to their peer, so strays never reach
+        // them; a transfer on a socket that isn't has to turn them away itself.
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 22742);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 32742);
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+
+        let client = UdpSocket::bind(client_addr).unwrap();
+        let stranger = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stranger.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let server = UdpSocket::bind(server_addr).unwrap();
+        let sender = SendFile::new_server(server, client_addr, Box::new(SharedContents(Arc::new(data.clone()))) as Contents, 1, 512).unwrap();
+        let sender = spawn(move || sender.run());
+
+        let mut buf = vec![0u8; recv_buffer_len(512)];
+        let mut received = Vec::new();
+        let mut next_block = 0;
+        while received.len() < data.len() {
+            let block = match Header::recv(server_addr, &client, &mut buf) {
+                Ok(Header::Data(block)) => block,
+                // The server's answer to the request.
+                Ok(Header::Ack(AckHeader { block_number: 0 })) => continue,
+                _ => panic!("Expected data from the server.")
+            };
+            if block.block_number == next_block {
+                received.extend_from_slice(&block.data[..block.data_len]);
+                next_block += 1;
+            }
+            // An Ack from anyone else, while the server waits on ours, is turned away with an
+            // error, and the transfer carries on as if it never arrived.
+            if block.block_number == 0 {
+                Header::Ack(AckHeader::new(0)).send(server_addr, &stranger).unwrap();
+                match Header::recv(server_addr, &stranger, &mut buf) {
+                    Ok(Header::Error(ErrorHeader { error_code: ErrorCode::UnknownTransferID, .. })) => {},
+                    _ => panic!("Expected the stranger to be told its transfer ID is unknown.")
+                }
+            }
+            Header::Ack(AckHeader::new(block.block_number)).send(server_addr, &client).unwrap();
+        }
+        sender.join().unwrap().unwrap();
+        assert!(received == data);
+    }
+
+    #[test]
+    fn test_request_middleware() {
+        use super::backend::InMemoryBackend;
+        use super::client::RequestMiddleware;
+        use super::header::{ RWHeader, ReadHeader };
+        use std::{ fs, io };
+
+        struct Aliases;
+        impl RequestMiddleware for Aliases {
+            fn read_request(&self, request: &mut RWHeader<ReadHeader>, _peer: SocketAddr) -> Result<(), io::Error> {
+                match request.filename.as_str() {
+                    "alias.bin" => { request.filename = "image.bin".to_string(); Ok(()) },
+                    "secret.bin" => Err(io::Error::new(io::ErrorKind::PermissionDenied, "No.")),
+                    _ => Ok(())
+                }
+            }
+        }
+
+        let host_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 22716);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 32716);
+
+        let backend = InMemoryBackend::new();
+        backend.insert("image.bin".to_string(), vec![7u8; 10_000]);
+        backend.insert("secret.bin".to_string(), vec![1u8; 10]);
+        let mut client = TFTPClient::new(host_addr, client_addr, "data/client_data".to_string(), 1).unwrap();
+        let mut server = TFTPClient::new(client_addr, host_addr, "data/server_data".to_string(), 1).unwrap();
+        server.set_backend(backend);
+        server.add_middleware(Aliases);
+        let history = server.clone();
+        spawn(move || { server.serve() });
+
+        client.request_file(Path::new("alias.bin"), Path::new("middleware.bin")).wait().unwrap();
+        assert_eq!(fs::read("data/client_data/middleware.bin").unwrap(), vec![7u8; 10_000]);
+        let _ = fs::remove_file("data/client_data/middleware.bin");
+        assert!(client.request_file(Path::new("secret.bin"), Path::new("middleware.bin")).wait().is_err());
+        let _ = fs::remove_file("data/client_data/middleware.bin");
+
+        for _ in 0..50 {
+            if history.transfer_history().len() == 2 { break }
+            ::std::thread::sleep(::std::time::Duration::from_millis(100));
+        }
+        let mut records = history.transfer_history();
+        records.sort_by_key(|record| record.filename.clone());
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].filename.as_str(), records[0].bytes_transferred, records[0].error.is_none()), ("alias.bin", 10_000, true));
+        assert_eq!((records[1].filename.as_str(), records[1].error.is_some()), ("secret.bin", true));
+    }
+
+    #[test]
+    fn test_fs_backend_jail() {
+        use super::backend::{ Backend, FsBackend };
+        use std::io::ErrorKind;
+
+        let backend = FsBackend::new("data/server_data".to_string());
+        assert!(backend.open_read("test.md").is_ok());
+        assert!(backend.open_read("/test.md").is_ok());
+        // data/test.md exists, but is outside of the folder.
+        for filename in ["../test.md", "/../test.md", "./../server_data/../test.md"].iter() {
+            assert_eq!(backend.open_read(filename).err().map(|e| e.kind()), Some(ErrorKind::PermissionDenied));
+            assert_eq!(backend.create_write(filename).err().map(|e| e.kind()), Some(ErrorKind::PermissionDenied));
+        }
+
+        let mut backend = backend;
+        assert!(backend.create_write("configs/sw1/startup.cfg").is_err());
+        backend.create_dirs = true;
+        assert!(backend.create_write("configs/sw1/startup.cfg").is_ok());
+        assert_eq!(backend.create_write("../configs/startup.cfg").err().map(|e| e.kind()), Some(ErrorKind::PermissionDenied));
+        let _ = ::std::fs::remove_dir_all("data/server_data/configs");
+
+        // A link to a file that doesn't exist yet mustn't have it created outside of the folder.
+        #[cfg(unix)]
+        {
+            let _ = ::std::fs::remove_file("data/server_data/dangling.cfg");
+            ::std::os::unix::fs::symlink("../dangling.cfg", "data/server_data/dangling.cfg").unwrap();
+            assert_eq!(backend.create_write("dangling.cfg").err().map(|e| e.kind()), Some(ErrorKind::PermissionDenied));
+            assert_eq!(backend.open_append("dangling.cfg").err().map(|e| e.kind()), Some(ErrorKind::PermissionDenied));
+            ::std::fs::remove_file("data/server_data/dangling.cfg").unwrap();
+            assert!(!::std::path::Path::new("data/dangling.cfg").exists());
+        }
+    }
+
+    #[test]
+    fn test_prefix_backend() {
+        use super::backend::{ Backend, InMemoryBackend, PrefixBackend };
+        use std::io::ErrorKind;
+
+        let (bios, efi) = (InMemoryBackend::new(), InMemoryBackend::new());
+        bios.insert("boot.0".to_string(), vec![1]);
+        efi.insert("boot.0".to_string(), vec![2]);
+        efi.insert("x64/boot.0".to_string(), vec![3]);
+        let mut backend = PrefixBackend::new();
+        backend.add_route("bios/", bios);
+        backend.add_route("/efi/", efi.clone());
+        backend.add_route("efi/x64/", efi);
+
+        let read = |filename: &str| backend.open_read(filename).map(|contents| contents.as_ref().as_ref().to_vec());
+        assert_eq!(read("bios/boot.0").unwrap(), vec![1]);
+        assert_eq!(read("/efi/boot.0").unwrap(), vec![2]);
+        // "efi/x64/" is the longer match, so this is "boot.0" rather than "x64/boot.0".
+        assert_eq!(read("efi/x64/boot.0").unwrap(), vec![2]);
+        assert_eq!(read("boot.0").err().map(|e| e.kind()), Some(ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn test_caching_backend() {
+        use super::backend::{ Backend, CachingBackend, InMemoryBackend };
+
+        let files = InMemoryBackend::new();
+        files.insert("kernel".to_string(), vec![1; 100]);
+        files.insert("initrd".to_string(), vec![2; 100]);
+        files.insert("huge".to_string(), vec![3; 1000]);
+        let backend = CachingBackend::new(files.clone(), 150, 2);
+        let read = |filename: &str| backend.open_read(filename).unwrap().as_ref().as_ref().to_vec();
+
+        assert_eq!(read("kernel"), vec![1; 100]);
+        assert_eq!(read("huge"), vec![3; 1000]);
+        // The in-memory backend has no modification times, so a change that keeps the size the
+        // same is only seen once the file has left the cache.
+        files.insert("kernel".to_string(), vec![5; 100]);
+        assert_eq!(read("kernel"), vec![1; 100]);
+        // Only one of the two fits, so this pushes the kernel out.
+        assert_eq!(read("initrd"), vec![2; 100]);
+        assert_eq!(read("kernel"), vec![5; 100]);
+        // A file that changed size is read again.
+        files.insert("kernel".to_string(), vec![4; 50]);
+        assert_eq!(read("kernel"), vec![4; 50]);
+    }
+
+    #[test]
+    fn test_sharing_backend() {
+        use super::backend::{ reader_source, Backend, BlockSource, InMemoryBackend, SharingBackend };
+        use std::io::Cursor;
+
+        let files = InMemoryBackend::new();
+        let kernel: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        files.insert("kernel".to_string(), kernel.clone());
+        let backend = SharingBackend::new(files.clone());
+        let mut buf = [0u8; 512];
+
+        // Sessions sending the same file share a source, and read from it independently.
+        let mut first = backend.open_source("kernel").unwrap();
+        let mut second = backend.open_source("kernel").unwrap();
+        assert_eq!(backend.sessions("kernel"), 2);
+        assert_eq!(second.read_block(512, &mut buf).unwrap(), &kernel[512..]);
+        assert_eq!(first.read_block(0, &mut buf).unwrap(), &kernel[..512]);
+
+        // A file that changed gets a source of its own, and sources go once nobody sends from them.
+        files.insert("kernel".to_string(), vec![1; 10]);
+        let mut third = backend.open_source("kernel").unwrap();
+        assert_eq!(third.read_block(0, &mut buf).unwrap(), &[1; 10]);
+        assert_eq!(first.len(), 1000);
+        assert_eq!(backend.sessions("kernel"), 1);
+        drop(third);
+        assert_eq!(backend.sessions("kernel"), 0);
+
+        // Only sources with the whole file in memory can be read from at different places at once.
+        assert!(files.open_source("kernel").unwrap().is_shareable());
+        assert!(!reader_source(Cursor::new(kernel)).unwrap().is_shareable());
+    }
+
+    #[test]
+    fn test_checksum() {
+        use super::checksum::Checksum;
+
+        let md5 = Checksum::md5_from_hex("900150983cd24fb0d6963f7d28e17f72").unwrap();
+        let sha256 = Checksum::sha256_from_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad").unwrap();
+        assert_eq!(md5.of_bytes(b"abc"), md5);
+        assert_eq!(sha256.of_bytes(b"abc"), sha256);
+        assert!(md5.of_bytes(b"abd") != md5);
+        assert_eq!(sha256.to_string(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        assert!(Checksum::md5_from_hex("900150983cd24fb0d6963f7d28e17f7").is_err());
+        assert!(Checksum::md5_from_hex("x00150983cd24fb0d6963f7d28e17f72").is_err());
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = "# netboot\npxelinux.0\n\n/boot/vmlinuz kernel/vmlinuz\n  initrd.img  \n";
+        let entries = parse_manifest(manifest).unwrap();
+        assert_eq!(entries, vec![
+            ("pxelinux.0".to_string(), "pxelinux.0".to_string()),
+            ("/boot/vmlinuz".to_string(), "kernel/vmlinuz".to_string()),
+            ("initrd.img".to_string(), "initrd.img".to_string()),
+        ]);
+
+        assert!(parse_manifest("a.bin ../a.bin").is_err());
+        assert!(parse_manifest("a.bin /etc/a.bin").is_err());
+        assert!(parse_manifest("a.bin b.bin c.bin").is_err());
+    }
+
+    #[test]
+    fn test_bind_in_range() {
+        use super::net_util::bind_in_range;
+
+        let localhost = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let bind = |port| UdpSocket::bind(SocketAddr::new(localhost, port));
+        let taken = bind(0).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        // Whichever port it starts at, the only free one is what it ends up with.
+        for _ in 0..4 {
+            let socket = bind_in_range(&(port..=port.wrapping_add(1)), bind).unwrap();
+            assert_eq!(socket.local_addr().unwrap().port(), port.wrapping_add(1));
+        }
+        assert_eq!(bind_in_range(&(port..=port), bind).unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        use super::retry::RetryPolicy;
+        use std::time::Duration;
+
+        let policy = RetryPolicy::new(Duration::from_millis(500), 2.0, Duration::from_secs(3), 5);
+        let intervals: Vec<Duration> = (0..5).map(|attempt| policy.interval(attempt)).collect();
+        assert_eq!(intervals, vec![Duration::from_millis(500), Duration::from_secs(1), Duration::from_secs(2),
+                                   Duration::from_secs(3), Duration::from_secs(3)]);
+        assert_eq!(RetryPolicy::fixed(Duration::from_secs(1), 5).interval(4), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_access_list() {
+        use super::net_util::{ AccessList, Cidr };
+
+        let mut access_list = AccessList::default();
+        assert!(access_list.permits("192.0.2.1".parse().unwrap()));
+
+        access_list.allow.push("10.0.0.0/8".parse().unwrap());
+        access_list.allow.push("fd00::/8".parse().unwrap());
+        access_list.deny.push("10.1.2.0/24".parse().unwrap());
+        assert!(access_list.permits("10.200.0.1".parse().unwrap()));
+        assert!(access_list.permits("fd12::1".parse().unwrap()));
+        assert!(access_list.permits("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!access_list.permits("10.1.2.3".parse().unwrap()));
+        assert!(!access_list.permits("192.0.2.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert_eq!("10.0.0.1".parse::<Cidr>().unwrap().prefix_len, 32);
+    }
+
+    #[test]
+    fn test_overwrite_policy() {
+        use super::backend::{ InMemoryBackend, OverwritePolicy };
+        use std::thread::JoinHandle;
+        use std::time::Duration;
+
+        // A server on a port of its own that serves `requests` requests with `overwrite_policy`,
+        // and a client of it. Joining the server waits for everything it received to be written.
+        let serve = |backend: &InMemoryBackend, overwrite_policy, requests| -> (TFTPClient, JoinHandle<()>) {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let host_addr = socket.local_addr().unwrap();
+            let mut client = TFTPClient::new(host_addr, "127.0.0.1:0".parse().unwrap(), "data/client_data".to_string(), 1).unwrap();
+            client.dally = Duration::from_millis(10);
+            let mut server = TFTPClient::from_socket(host_addr, socket, "data/server_data".to_string(), 1).unwrap();
+            server.set_backend(backend.clone());
+            server.overwrite_policy = overwrite_policy;
+            server.dally = Duration::from_millis(10);
+            (client, spawn(move || for _ in 0..requests { let _ = server.clone().serve_one(); }))
+        };
+
+        let backend = InMemoryBackend::new();
+        backend.insert("woah.jpeg".to_string(), b"old".to_vec());
+        backend.insert("woah.jpeg.1".to_string(), b"older".to_vec());
+        let (mut client, server) = serve(&backend, OverwritePolicy::Version, 1);
+        client.send_file(Path::new("woah.jpeg")).wait().unwrap();
+        server.join().unwrap();
+        assert_eq!(backend.get("woah.jpeg.1"), Some(b"older".to_vec()));
+        assert_eq!(backend.get("woah.jpeg.2"), Some(b"old".to_vec()));
+        assert!(backend.get("woah.jpeg").is_some());
+
+        let (mut client, server) = serve(&backend, OverwritePolicy::Reject, 1);
+        assert!(client.send_file(Path::new("woah.jpeg")).wait().is_err());
+        server.join().unwrap();
+
+        // Appending adds onto what's there, and creates files that aren't.
+        backend.insert("device.log".to_string(), b"booted\n".to_vec());
+        let (mut client, server) = serve(&backend, OverwritePolicy::Append, 4);
+        client.send_bytes("device.log", b"link up\n").wait().unwrap();
+        client.send_bytes("other.log", b"booted\n").wait().unwrap();
+        // Downloads can be appended as well.
+        let path = Path::new("data/client_data/appended.log");
+        let _ = std::fs::remove_file(path);
+        client.append_file("other.log", "appended.log").wait().unwrap();
+        client.append_file("device.log", "appended.log").wait().unwrap();
+        server.join().unwrap();
+        assert_eq!(backend.get("device.log"), Some(b"booted\nlink up\n".to_vec()));
+        assert_eq!(backend.get("other.log"), Some(b"booted\n".to_vec()));
+        assert_eq!(std::fs::read(path).unwrap(), b"booted\nbooted\nlink up\n".to_vec());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_aimd() {
+        use super::congestion::{ Aimd, WindowStrategy };
+
+        let mut aimd = Aimd::new(2, 8);
+        // One more block in flight for every window's worth acknowledged...
+        aimd.on_ack(2);
+        assert_eq!(aimd.window(), 3);
+        aimd.on_ack(2);
+        assert_eq!(aimd.window(), 3);
+        aimd.on_ack(1);
+        assert_eq!(aimd.window(), 4);
+        // ...up to the maximum.
+        aimd.on_ack(100);
+        assert_eq!(aimd.window(), 8);
+        aimd.on_ack(100);
+        assert_eq!(aimd.window(), 8);
+
+        // Blocks 0 to 19 are out. Losing one halves the window, but losing others from the same
+        // flight doesn't, until a block sent after the cut is lost as well.
+        aimd.on_loss(12, 20);
+        assert_eq!(aimd.window(), 4);
+        aimd.on_loss(15, 20);
+        assert_eq!(aimd.window(), 4);
+        aimd.on_loss(20, 24);
+        assert_eq!(aimd.window(), 2);
+        aimd.on_loss(24, 26);
+        aimd.on_loss(26, 27);
+        assert_eq!(aimd.window(), 1);
+
+        // Stop-and-wait never grows.
+        let mut stop_and_wait = Aimd::new(1, 1);
+        stop_and_wait.on_ack(10);
+        assert_eq!(stop_and_wait.window(), 1);
+    }
+
+    #[test]
+    fn test_window_strategy() {
+        use super::backend::{ Contents, SharedContents };
+        use super::congestion::WindowStrategy;
+        use super::send::SendFile;
+        use super::transport::MemoryDatagram;
+        use std::sync::Arc;
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+
+        // Keeps the window where it is, counting the Acks it's told about.
+        struct Fixed(usize, Arc<AtomicUsize>);
+        impl WindowStrategy for Fixed {
+            fn window(&self) -> usize { self.0 }
+            fn on_ack(&mut self, acked: usize) -> usize { self.1.fetch_add(acked, Ordering::SeqCst); self.0 }
+            fn on_loss(&mut self, _: usize, _: usize) -> usize { self.0 }
+            fn on_timeout(&mut self, _: usize, _: usize) -> usize { self.0 }
+        }
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let acked = Arc::new(AtomicUsize::new(0));
+        let contents = Box::new(SharedContents(Arc::new(data.clone()))) as Contents;
+        let mut sender = SendFile::new_server(server, client_addr, contents, 1, 512).unwrap();
+        sender.set_window_strategy(Box::new(Fixed(3, acked.clone())));
+        let (received, stats) = receive_from(sender, client);
+        assert_eq!(stats.window_size, 3);
+        assert!(acked.load(Ordering::SeqCst) >= 20);
+        assert!(received == data);
+    }
+
+    #[test]
+    fn test_timer_wheel() {
+        use super::timer_wheel::TimerWheel;
+        use std::time::{ Duration, Instant };
+
+        let mut wheel = TimerWheel::new();
+        let start = Instant::now();
+        // On every level, including deadlines that wrap around a slot of the level above.
+        let deadlines: Vec<u64> = vec![3, 3, 63, 64, 65, 200, 4_100, 300_000, 30 * 86_400_000];
+        for (i, &deadline) in deadlines.iter().enumerate().rev() {
+            wheel.insert(start + Duration::from_millis(deadline), i);
+        }
+        assert_eq!(wheel.len(), deadlines.len());
+        assert!(wheel.next_expiry().unwrap() <= start + Duration::from_millis(4));
+
+        // Timers never go off early, and go off within a millisecond of their deadline.
+        let mut expired = vec![];
+        for &deadline in deadlines.iter() {
+            assert!(wheel.expire(start + Duration::from_millis(deadline - 1)).is_empty());
+            expired.extend(wheel.expire(start + Duration::from_millis(deadline + 1)));
+            assert!(expired.iter().any(|&i| deadlines[i] == deadline));
+        }
+        expired.sort();
+        assert_eq!(expired, (0..deadlines.len()).collect::<Vec<_>>());
+        assert!(wheel.is_empty() && wheel.next_expiry().is_none());
+
+        // Deadlines that have passed go off on the next call.
+        wheel.insert(start, deadlines.len());
+        assert_eq!(wheel.expire(start + Duration::from_millis(deadlines[deadlines.len() - 1] + 1)), vec![deadlines.len()]);
+    }
+
+    #[test]
+    fn test_window_size() {
+        use super::send::{ WindowSize, MAX_WINDOW_SIZE, WINDOW_SIZE_LIMIT };
+
+        assert_eq!(WindowSize::from(1), WindowSize::fixed(1));
+        assert_eq!(WindowSize::from(16), WindowSize::new(16, MAX_WINDOW_SIZE));
+        assert_eq!(WindowSize::from(1000), WindowSize::new(1000, 1000));
+        assert!(WindowSize::new(16, 64).validate().is_ok());
+        assert!(WindowSize::from(0).validate().is_err());
+        assert!(WindowSize::new(64, 16).validate().is_err());
+        assert!(WindowSize::new(16, WINDOW_SIZE_LIMIT + 1).validate().is_err());
+
+        let host_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 69);
+        let client = TFTPClient::builder(host_addr, SocketAddr::new(host_addr.ip(), 0), "data/client_data".to_string());
+        let err = client.window_size(32).max_window_size(8).build().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        use super::throttle::TokenBucket;
+        use std::time::{ Duration, Instant };
+
+        let mut bucket = TokenBucket::new(100_000, 10_000);
+        let start = Instant::now();
+        // The bucket starts out full, so the first 10KB can go straight away...
+        bucket.take(10_000);
+        assert!(start.elapsed() < Duration::from_millis(50));
+        // ...but the next 20KB have to wait for 200ms worth of tokens.
+        bucket.take(10_000);
+        bucket.take(10_000);
+        assert!(start.elapsed() >= Duration::from_millis(190));
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    struct SharedSink(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl backend::WriteSink for SharedSink {
+        fn write_block(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+            let mut buf = self.0.lock().unwrap();
+            let end = offset as usize + data.len();
+            if buf.len() < end { buf.resize(end, 0) }
+            buf[offset as usize..end].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    /// Serves `data` from `server` to `client`, and returns what the client received.
+    fn transfer_over<D: transport::Datagram + Send + Sync + 'static>(server: D, client: D, data: &[u8]) -> Vec<u8> {
+        use super::backend::{ Contents, SharedContents };
+        use super::send::SendFile;
+        use std::sync::Arc;
+
+        let client_addr = client.local_addr().unwrap();
+        let contents = Box::new(SharedContents(Arc::new(data.to_vec()))) as Contents;
+        receive_from(SendFile::new_server(server, client_addr, contents, 1, 512).unwrap(), client).0
+    }
+
+    /// Runs `sender` to `client`, and returns what the client received along with what the sender
+    /// did to get it there.
+    fn receive_from<D: transport::Datagram + Send + Sync + 'static>(mut sender: super::send::SendFile<D>, client: D) -> (Vec<u8>, super::session::SendStats) {
+        use super::receive::ReceiveFile;
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let server_addr = sender.socket().local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sender = spawn(move || {
+            let poll_budget = sender.poll_budget();
+            poll_budget.run(&mut sender, |sender| sender.idle_wait()).map(|()| sender.stats())
+        });
+        let mut receiver = ReceiveFile::new(client, server_addr, SharedSink(received.clone()), 512).unwrap();
+        receiver.set_dally(Duration::from_millis(10));
+        receiver.set_stop_and_wait(true);
+        receiver.run().unwrap();
+        let stats = sender.join().unwrap().unwrap();
+        let received = received.lock().unwrap().clone();
+        (received, stats)
+    }
+
+    /// Waits for `count` Acks of `block_number` to arrive at `socket` from `from`, ignoring anything
+    /// else.
+    fn await_acks<D: transport::Datagram>(socket: &D, from: SocketAddr, block_number: usize, count: usize) {
+        use super::header::{ AckHeader, Header };
+
+        let mut buf = [0u8; 1024];
+        let mut acks = 0;
+        while acks < count {
+            match Header::recv(from, socket, &mut buf) {
+                Ok(Header::Ack(AckHeader { block_number: acked })) if acked == block_number => acks += 1,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_block_crc() {
+        use super::backend::Contents;
+        use super::checksum::Crc32;
+        use super::header::{ DataHeader, RWHeader, ReadHeader, Header, RWMode };
+        use super::receive::ReceiveFile;
+        use super::send::SendFile;
+        use super::transport::{ FaultProfile, FaultyDatagram, MemoryDatagram };
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        assert_eq!(Crc32::of(b"123456789"), 0xCBF43926);
+        let packet = DataHeader::encode_with_crc(b"firmware", 0x10203);
+        // Any bit but those of the opcode, which would make it some other kind of packet.
+        for bit in (0..packet.len() * 8).filter(|bit| bit / 8 != 1) {
+            let mut corrupted = packet.clone();
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            match Header::from_raw(&corrupted) {
+                Ok(Header::Data(mut data_header)) => assert!(!data_header.check_crc()),
+                _ => panic!("Not a data packet")
+            }
+        }
+        match Header::from_raw(&packet) {
+            Ok(Header::Data(mut data_header)) => {
+                assert!(data_header.check_crc());
+                assert_eq!(&data_header.data[..data_header.data_len], b"firmware");
+            },
+            _ => panic!("Not a data packet")
+        }
+
+        let mut request = RWHeader::<ReadHeader>::new("image.bin".to_string(), RWMode::Octet).unwrap();
+        request.block_crc = true;
+        match Header::from_raw(&request.into_raw()) {
+            Ok(Header::Read(request)) => assert!(request.block_crc && request.options.is_empty()),
+            _ => panic!("Not a read request")
+        }
+
+        // Corrupted blocks are NAKed and sent again, rather than written.
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let server = FaultyDatagram::new(server, FaultProfile { corrupt: 0.1, ..FaultProfile::default() }, 3).unwrap();
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        let contents = Box::new(data.clone()) as Contents;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sender = spawn(move || {
+            let mut sender = SendFile::new_server(server, client_addr, contents, 8, 512)?;
+            sender.set_block_crc(true);
+            sender.set_transfer_timeout(Some(Duration::from_secs(20)));
+            while !sender.poll()?.is_ready() {}
+            Ok::<_, ::std::io::Error>(sender.socket().stats())
+        });
+        let mut receiver = ReceiveFile::new(client, server_addr, SharedSink(received.clone()), 512).unwrap();
+        receiver.set_block_crc(true);
+        receiver.set_dally(Duration::from_millis(10));
+        receiver.set_transfer_timeout(Some(Duration::from_secs(20)));
+        receiver.run().unwrap();
+        assert!(sender.join().unwrap().unwrap().corrupted > 0);
+        assert!(*received.lock().unwrap() == data);
+    }
+
+    #[test]
+    fn test_reorder_window() {
+        use super::header::{ DataHeader, Header };
+        use super::receive::ReceiveFile;
+        use super::transport::MemoryDatagram;
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut receiver = ReceiveFile::new(client, server_addr, SharedSink(received.clone()), 512).unwrap();
+        receiver.set_reorder_window(4);
+        receiver.set_dally(Duration::from_millis(10));
+        let receiver = spawn(move || receiver.run());
+
+        // Block 100 can't be part of the same window as block 1, which is still missing, so it's
+        // dropped rather than written, and the transfer carries on from block 0.
+        Header::Data(DataHeader::new(&[1; 512], 0)).send(client_addr, &server).unwrap();
+        Header::Data(DataHeader::new(&[9; 512], 100)).send(client_addr, &server).unwrap();
+        await_acks(&server, client_addr, 0, 3);
+        Header::Data(DataHeader::new(&[2; 512], 1)).send(client_addr, &server).unwrap();
+        Header::Data(DataHeader::new(&[3; 10], 2)).send(client_addr, &server).unwrap();
+        receiver.join().unwrap().unwrap();
+        assert_eq!(received.lock().unwrap().len(), 1034);
+    }
+
+    #[test]
+    fn test_ack_strategy() {
+        use super::header::{ AckHeader, DataHeader, Header };
+        use super::receive::{ AckStrategy, ReceiveFile };
+        use super::transport::MemoryDatagram;
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut receiver = ReceiveFile::new(client, server_addr, SharedSink(received.clone()), 512).unwrap();
+        receiver.set_ack_strategy(AckStrategy::EveryN(4));
+        receiver.set_timeout(Some(Duration::from_millis(100)));
+        receiver.set_dally(Duration::from_millis(10));
+        let receiver = spawn(move || receiver.run());
+        let mut buf = [0u8; 1024];
+
+        // The ACK for the write request, then at most one for every 4 blocks until all 8 are.
+        assert!(match Header::recv(client_addr, &server, &mut buf) { Ok(Header::Ack(_)) => true, _ => false });
+        for block_number in 0..8 {
+            Header::Data(DataHeader::new(&[block_number as u8; 512], block_number)).send(client_addr, &server).unwrap();
+        }
+        let mut acks = Vec::new();
+        while acks.last() != Some(&7) {
+            if let Ok(Header::Ack(AckHeader { block_number })) = Header::recv(client_addr, &server, &mut buf) {
+                acks.push(block_number);
+            }
+        }
+        assert!(acks.len() <= 2 && acks[0] >= 3);
+        Header::Data(DataHeader::new(&[8; 10], 8)).send(client_addr, &server).unwrap();
+        receiver.join().unwrap().unwrap();
+        assert_eq!(received.lock().unwrap().len(), 8 * 512 + 10);
+    }
+
+    #[test]
+    fn test_duplicate_blocks() {
+        use super::header::{ AckHeader, DataHeader, Header };
+        use super::receive::{ AckStrategy, ReceiveFile, DUPLICATE_BURST };
+        use super::session::{ ReceiveStats, TransferProgress };
+        use super::transport::MemoryDatagram;
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let progress = Arc::new(TransferProgress::new(None, None));
+        let mut receiver = ReceiveFile::new(client, server_addr, SharedSink(received.clone()), 512).unwrap();
+        receiver.set_ack_strategy(AckStrategy::EveryN(8));
+        receiver.set_timeout(Some(Duration::from_millis(500)));
+        receiver.set_dally(Duration::from_millis(10));
+        receiver.set_progress(progress.clone());
+        let receiver = spawn(move || receiver.run());
+        let mut buf = [0u8; 1024];
+
+        // A single block isn't acknowledged with EveryN(8), but a burst of it arriving again is.
+        assert!(match Header::recv(client_addr, &server, &mut buf) { Ok(Header::Ack(_)) => true, _ => false });
+        Header::Data(DataHeader::new(&[1; 512], 0)).send(client_addr, &server).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        for _ in 0..DUPLICATE_BURST {
+            Header::Data(DataHeader::new(&[2; 512], 0)).send(client_addr, &server).unwrap();
+        }
+        assert!(match Header::recv(client_addr, &server, &mut buf) { Ok(Header::Ack(AckHeader { block_number: 0 })) => true, _ => false });
+        Header::Data(DataHeader::new(&[3; 10], 1)).send(client_addr, &server).unwrap();
+        receiver.join().unwrap().unwrap();
+
+        // The duplicates weren't written over the block that arrived first.
+        assert_eq!(received.lock().unwrap()[..512], [1; 512][..]);
+        let stats = progress.receive_stats().unwrap();
+        assert_eq!(stats, ReceiveStats { blocks_received: 2 + DUPLICATE_BURST, duplicates: DUPLICATE_BURST, duplicate_acks: 1 });
+    }
+
+    #[test]
+    fn test_receive_stream() {
+        use super::receive::ReceiveFile;
+        use super::send::SendFile;
+        use super::transport::MemoryDatagram;
+        use super::backend::{ Contents, SharedContents };
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let data = (0..10_000).map(|i| i as u8).collect::<Vec<u8>>();
+
+        let sender = SendFile::new_server(server, client_addr, Box::new(SharedContents(Arc::new(data.clone()))) as Contents, 4, 512).unwrap();
+        let sender = spawn(move || sender.run());
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut receiver = ReceiveFile::new(client, server_addr, SharedSink(written.clone()), 512).unwrap();
+        receiver.set_dally(Duration::from_millis(10));
+
+        // The ranges follow on from one another, and add up to the whole file.
+        let mut streamed = Vec::new();
+        for range in receiver.into_stream() {
+            let (offset, bytes) = range.unwrap();
+            assert_eq!(offset, streamed.len() as u64);
+            streamed.extend_from_slice(&bytes);
+        }
+        sender.join().unwrap().unwrap();
+        assert!(streamed == data);
+        assert!(*written.lock().unwrap() == data);
+    }
+
+    #[test]
+    fn test_receive_stream_buffer_limit() {
+        use super::header::{ DataHeader, Header };
+        use super::receive::{ ReceiveFile, STREAM_BUFFER_LIMIT };
+        use super::transport::MemoryDatagram;
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let block_size = 65464;
+        let mut receiver = ReceiveFile::new(client, server_addr, SharedSink(Arc::new(Mutex::new(Vec::new()))), block_size).unwrap();
+        receiver.set_dally(Duration::from_millis(10));
+        let stream = receiver.into_stream();
+        let receiver = spawn(move || Iterator::map(stream, |range| range.unwrap().1.len()).sum::<usize>());
+
+        // A block just past what the stream would buffer is dropped, even though it is within the
+        // default reorder window.
+        let past_limit = STREAM_BUFFER_LIMIT / block_size + 1;
+        Header::Data(DataHeader::new(&vec![1; block_size], 0)).send(client_addr, &server).unwrap();
+        Header::Data(DataHeader::new(&vec![9; block_size], past_limit)).send(client_addr, &server).unwrap();
+        await_acks(&server, client_addr, 0, 3);
+        Header::Data(DataHeader::new(&[2; 10], 1)).send(client_addr, &server).unwrap();
+        assert_eq!(receiver.join().unwrap(), block_size + 10);
+    }
+
+    #[test]
+    fn test_pipelined_sink() {
+        use super::backend::{ PipelinedSink, WriteSink };
+        use std::sync::{ Arc, Mutex };
+
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut sink = PipelinedSink::new(SharedSink(written.clone()), 2);
+        for i in 0..10u8 {
+            sink.write_block(i as u64 * 4, &[i; 4]).unwrap();
+        }
+        sink.finalize().unwrap();
+        assert_eq!(written.lock().unwrap().len(), 40);
+        assert_eq!(written.lock().unwrap()[36..], [9; 4]);
+
+        // Writing to a full slice fails once it runs out of room, which has to reach the transfer.
+        let mut sink = PipelinedSink::new(std::io::Cursor::new(vec![0u8; 8].into_boxed_slice()), 1);
+        let failed = (0..10u64).any(|i| sink.write_block(i * 4, &[1; 4]).is_err());
+        assert!(failed || sink.finalize().is_err());
+    }
+
+    #[test]
+    fn test_file_sink() {
+        use super::backend::{ FileSink, SyncPolicy, WriteSink };
+        use std::fs::{ self, OpenOptions };
+
+        let path = std::env::temp_dir().join(format!("tftp-file-sink-{}", std::process::id()));
+        let open = || OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        // The file has its whole size from the start, and blocks can still arrive out of order.
+        let mut sink = FileSink::new(open());
+        sink.allocate(2048).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2048);
+        for &block in [1u64, 0, 3, 2].iter() {
+            sink.write_block(block * 512, &[block as u8; 512]).unwrap();
+        }
+        sink.finalize().unwrap();
+        assert_eq!(fs::read(&path).unwrap()[1024..1536], [2; 512]);
+
+        // A peer that said the file was larger than it is leaves no padding behind.
+        let mut sink = FileSink::new(open());
+        sink.allocate(4096).unwrap();
+        sink.write_block(0, &[1; 1000]).unwrap();
+        sink.finalize().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![1; 1000]);
+
+        // Blocks that follow on from one another are held on to until there are enough of them.
+        let mut sink = FileSink::with_buffer_len(open(), 1024);
+        sink.write_block(0, &[1; 512]).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        // Sent again before it was written out, then a block out of order, which writes it out.
+        sink.write_block(0, &[2; 512]).unwrap();
+        sink.write_block(1024, &[3; 512]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![2; 512]);
+        sink.write_block(512, &[4; 512]).unwrap();
+        sink.finalize().unwrap();
+        let written = fs::read(&path).unwrap();
+        assert_eq!((written.len(), written[0], written[512], written[1024]), (1536, 2, 4, 3));
+
+        // Making what has been written durable writes out what has been collected along with it.
+        let mut sink = FileSink::new(open());
+        sink.set_sync_policy(SyncPolicy::EveryBytes(1024));
+        sink.write_block(0, &[5; 512]).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        sink.write_block(512, &[6; 512]).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 1024);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_poll_budget() {
+        use super::transport::PollBudget;
+        use std::cell::Cell;
+        use std::time::{ Duration, Instant };
+
+        // Done on its tenth poll.
+        let mut polls = 0;
+        let mut transfer = future::poll_fn(move || -> Poll<(), std::io::Error> {
+            polls += 1;
+            Ok(if polls == 10 { Async::Ready(()) } else { Async::NotReady })
+        });
+        let given_up = Cell::new(0);
+        let start = Instant::now();
+        PollBudget::new(4).run(&mut transfer, |_| { given_up.set(given_up.get() + 1); Some(Duration::from_millis(20)) }).unwrap();
+        assert_eq!(given_up.get(), 2);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_server_transfer_poll_budget() {
+        use super::receive::ReceiveFile;
+        use super::transport::PollBudget;
+        use std::sync::{ Arc, Mutex };
+        use std::time::{ Duration, Instant };
+
+        fn cpu_time() -> Duration {
+            let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+            Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+        }
+
+        // The peer never sends anything, and the session's socket doesn't wait for it to, so only
+        // the poll budget keeps the transfer from spinning until it gives up.
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let mut receiver = ReceiveFile::new(socket, peer.local_addr().unwrap(), SharedSink(Arc::new(Mutex::new(Vec::new()))), 512).unwrap();
+        receiver.set_poll_budget(PollBudget::new(1));
+        receiver.set_idle_timeout(Some(Duration::from_millis(500)));
+        let mut transfer = ServerTransfer::Write(receiver);
+
+        let (start, cpu_start) = (Instant::now(), cpu_time());
+        assert!(transfer.run().is_err());
+        assert!(cpu_time() - cpu_start < start.elapsed() / 2);
+    }
+
+    #[test]
+    fn test_memory_transfer() {
+        use super::transport::MemoryDatagram;
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        assert!(transfer_over(server, client, &data) == data);
+    }
+
+    #[test]
+    fn test_streaming_source() {
+        use super::backend::{ BlockSource, StreamingSource };
+        use super::send::SendFile;
+        use super::transport::MemoryDatagram;
+        use std::io::Cursor;
+
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut source = StreamingSource::new(Cursor::new(data.clone())).unwrap();
+        let mut buf = vec![0u8; 512];
+        assert_eq!(source.len(), data.len());
+        // In order, going back for a retransmission, and the short block at the end.
+        for &offset in [0, 512, 1024, 512, 99_840].iter() {
+            let expected = &data[offset..std::cmp::min(offset + 512, data.len())];
+            assert_eq!(source.read_block(offset, &mut buf).unwrap(), expected);
+        }
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let sender = SendFile::new_server(server, client_addr, source, 1, 512).unwrap();
+        assert!(receive_from(sender, client).0 == data);
+    }
+
+    #[test]
+    #[cfg(feature = "memmap")]
+    fn test_windowed_map() {
+        use super::backend::{ BlockSource, WindowedMap };
+        use std::fs::{ self, File };
+        use std::io::Write;
+
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("tftp-windowed-map-{}", std::process::id()));
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let mut map = WindowedMap::with_window_len(File::open(&path).unwrap(), 64 * 1024).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut buf = vec![0u8; 1468];
+        assert_eq!(map.len(), data.len());
+        // In order across several windows, going back for a retransmission, then the end.
+        let offsets: Vec<usize> = (0..120).map(|block| block * 1468).chain(vec![1468 * 30, 299_000, 300_000]).collect();
+        for offset in offsets {
+            let expected = &data[offset..std::cmp::min(offset + 1468, data.len())];
+            assert_eq!(map.read_block(offset, &mut buf).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_decompressing_backend() {
+        use super::backend::{ Backend, BlockSource, DecompressingBackend, InMemoryBackend };
+        use super::receive::ReceiveFile;
+        use super::send::SendFile;
+        use super::transport::MemoryDatagram;
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let files = InMemoryBackend::new();
+        files.insert("image.bin.gz".to_string(), encoder.finish().unwrap());
+        files.insert("plain".to_string(), vec![1; 10]);
+        let backend = DecompressingBackend::new(files);
+
+        // Files that are there are served as they are; the rest only once decompressed.
+        assert!(backend.open_source("plain").unwrap().is_complete());
+        assert!(backend.open_source("missing").err().unwrap().kind() == std::io::ErrorKind::NotFound);
+        assert!(backend.open_read("image.bin").unwrap().as_ref().as_ref() == &data[..]);
+        let source = backend.open_source("image.bin").unwrap();
+        assert!(!source.is_complete());
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sender = spawn(move || SendFile::new_server(server, client_addr, source, 1, 512)?.run());
+        let mut receiver = ReceiveFile::new(client, server_addr, SharedSink(received.clone()), 512).unwrap();
+        receiver.set_dally(Duration::from_millis(10));
+        receiver.set_stop_and_wait(true);
+        receiver.run().unwrap();
+        sender.join().unwrap().unwrap();
+        assert!(*received.lock().unwrap() == data);
+    }
+
+    #[test]
+    fn test_growing_source() {
+        use super::backend::{ BlockSource, GrowingSource };
+        use super::receive::ReceiveFile;
+        use super::send::SendFile;
+        use super::transport::MemoryDatagram;
+        use std::fs::{ self, File, OpenOptions };
+        use std::io::Write;
+        use std::sync::{ Arc, Mutex };
+        use std::sync::atomic::{ AtomicBool, Ordering };
+        use std::time::Duration;
+
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("tftp-growing-source-{}", std::process::id()));
+        File::create(&path).unwrap().write_all(&data[..1000]).unwrap();
+        let finished = Arc::new(AtomicBool::new(false));
+        let source = GrowingSource::new(File::open(&path).unwrap(), finished.clone()).unwrap();
+        assert_eq!((source.len(), source.is_complete()), (1000, false));
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sender = spawn(move || SendFile::new_server(server, client_addr, source, 1, 512)?.run());
+        // The rest of the file is written while it is being sent.
+        let writer = {
+            let (data, path) = (data.clone(), path.clone());
+            spawn(move || {
+                let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+                for chunk in data[1000..].chunks(7_000) {
+                    std::thread::sleep(Duration::from_millis(20));
+                    file.write_all(chunk).unwrap();
+                }
+                finished.store(true, Ordering::Release);
+            })
+        };
+        let mut receiver = ReceiveFile::new(client, server_addr, SharedSink(received.clone()), 512).unwrap();
+        receiver.set_dally(Duration::from_millis(10));
+        receiver.set_stop_and_wait(true);
+        receiver.run().unwrap();
+        sender.join().unwrap().unwrap();
+        writer.join().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(*received.lock().unwrap() == data);
+    }
+
+    #[test]
+    fn test_send_stats() {
+        use super::send::SendFile;
+        use super::session::TransferProgress;
+        use super::transport::MemoryDatagram;
+        use super::backend::{ Contents, SharedContents };
+        use std::sync::Arc;
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let data = vec![7u8; 10_000];
+        let progress = Arc::new(TransferProgress::new(Some(data.len() as u64), None));
+
+        let mut sender = SendFile::new_server(server, client_addr, Box::new(SharedContents(Arc::new(data.clone()))) as Contents, 1, 512).unwrap();
+        sender.set_progress(progress.clone());
+        let (_, stats) = receive_from(sender, client);
+
+        // 19 full blocks and a short one, plus whatever the peer's repeated Acks had sent again.
+        assert_eq!(progress.send_stats(), Some(stats));
+        assert_eq!(stats.blocks_sent, 20 + stats.retransmissions);
+        assert_eq!(stats.retransmissions, progress.report().retransmissions);
+        assert!(stats.bytes_sent >= data.len() + 20 * super::header::DATA_HEADER_LEN);
+        assert_eq!((stats.timeouts, stats.window_size), (0, 1));
+        // Every block is measured once it's acknowledged, unless it was sent again.
+        assert!(stats.rtt_samples <= 20 && stats.rtt_samples + stats.retransmissions >= 20);
+    }
+
+    #[test]
+    fn test_batched_io() {
+        use super::transport::Datagram;
+        use std::time::Duration;
+
+        let localhost = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+        let (sender, receiver) = (UdpSocket::bind(localhost).unwrap(), UdpSocket::bind(localhost).unwrap());
+        let packets: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 10 + i as usize]).collect();
+        let bufs: Vec<&[u8]> = packets.iter().map(|packet| packet.as_slice()).collect();
+        assert_eq!(Datagram::send_to_many(&sender, &bufs, receiver.local_addr().unwrap()).unwrap(), 5);
+
+        // However many come in a burst, they all arrive in order.
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut received = vec![];
+        while received.len() < 5 {
+            let mut bufs = vec![vec![0u8; 64]; 5 - received.len()];
+            let burst = {
+                let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| buf.as_mut_slice()).collect();
+                Datagram::recv_from_many(&receiver, &mut slices).unwrap()
+            };
+            for (buf, (len, src)) in bufs.into_iter().zip(burst) {
+                assert_eq!(src, sender.local_addr().unwrap());
+                received.push(buf[..len].to_vec());
+            }
+        }
+        assert!(received == packets);
+    }
+
+    #[test]
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn test_uring_transfer() {
+        use super::uring::UringDatagram;
+
+        let localhost = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+        let server = UringDatagram::new(UdpSocket::bind(localhost).unwrap());
+        let client = UringDatagram::new(UdpSocket::bind(localhost).unwrap());
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        assert!(transfer_over(server, client, &data) == data);
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        use super::transport::{ Datagram, FaultProfile, FaultyDatagram, MemoryDatagram };
+        use std::time::Duration;
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let profile = FaultProfile { drop: 0.2, duplicate: 0.2, reorder: 0.2, delay: 0.2, delay_time: Duration::from_millis(5), corrupt: 0.0 };
+
+        // The same seed does the same things to the same datagrams.
+        let run = |seed| {
+            let (a, b) = MemoryDatagram::pair(server_addr, client_addr);
+            let a = FaultyDatagram::new(a, profile.clone(), seed).unwrap();
+            b.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+            for n in 0..100u8 {
+                a.send_to(&[n], client_addr).unwrap();
+            }
+            let mut buf = [0u8; 1];
+            // Held back datagrams go out while the sender waits for a reply.
+            a.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+            assert!(a.recv_from(&mut buf).is_err());
+            let mut arrivals = vec![];
+            while let Ok(_) = b.recv_from(&mut buf) {
+                arrivals.push(buf[0]);
+            }
+            (arrivals, a.stats())
+        };
+        let (arrivals, stats) = run(7);
+        assert_eq!((arrivals.clone(), stats), run(7));
+        assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.reordered > 0 && stats.delayed > 0);
+        assert_eq!(arrivals.len(), 100 - stats.dropped + stats.duplicated);
+        assert!(arrivals.windows(2).any(|pair| pair[0] > pair[1]));
+
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let profile = FaultProfile { drop: 0.05, duplicate: 0.05, reorder: 0.05, delay: 0.05, delay_time: Duration::from_millis(5), corrupt: 0.0 };
+        let server = FaultyDatagram::new(server, profile.clone(), 1).unwrap();
+        let client = FaultyDatagram::new(client, profile, 2).unwrap();
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        assert!(transfer_over(server, client, &data) == data);
+    }
+
+    #[test]
+    fn test_retransmit_timer() {
+        use super::backend::{ Contents, SharedContents };
+        use super::header::{ AckHeader, Header, OPCODE_DATA };
+        use super::retry::RetryPolicy;
+        use super::send::SendFile;
+        use super::transport::{ Datagram, MemoryDatagram };
+        use std::sync::Arc;
+        use std::sync::atomic::{ AtomicBool, Ordering };
+        use std::time::{ Duration, Instant };
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let done = Arc::new(AtomicBool::new(false));
+        let sender_done = done.clone();
+        let sender = spawn(move || {
+            let contents = Box::new(SharedContents(Arc::new(vec![7u8; 100]))) as Contents;
+            let mut send_file = SendFile::new_server(server, client_addr, contents, 1, 512)?;
+            send_file.set_retry_policy(Some(RetryPolicy::fixed(Duration::from_millis(50), 3)));
+            let result = send_file.run();
+            sender_done.store(true, Ordering::SeqCst);
+            result
+        });
+
+        // Packets that aren't Acks keep arriving faster than the retransmission timeout, so the
+        // sender's socket never times out; the window has to be resent on the timer regardless.
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let start = Instant::now();
+        let mut data_sent = 0;
+        let mut buf = [0u8; 1024];
+        while !done.load(Ordering::SeqCst) && start.elapsed() < Duration::from_secs(5) {
+            client.send_to(&[0, 99, 0, 0], server_addr).unwrap();
+            while let Ok((_, _)) = client.recv_from(&mut buf) {
+                if buf[1] == OPCODE_DATA { data_sent += 1 }
+            }
+        }
+        assert_eq!(sender.join().unwrap().unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(data_sent, 4);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // Without a retry policy or a negotiated timeout, the timer follows the round trip time.
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let sender = spawn(move || {
+            let contents = Box::new(SharedContents(Arc::new(vec![7u8; 100]))) as Contents;
+            SendFile::new_server(server, client_addr, contents, 1, 512)?.run()
+        });
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut data_sent = 0;
+        while data_sent < 2 {
+            client.recv_from(&mut buf).unwrap();
+            if buf[1] == OPCODE_DATA { data_sent += 1 }
+        }
+        Header::Ack(AckHeader::new(0)).send(server_addr, &client).unwrap();
+        sender.join().unwrap().unwrap();
+        // A peer that has gone away fails the transfer once the idle timeout runs out, however long
+        // the retry policy would go on for.
+        let (server, _client) = MemoryDatagram::pair(server_addr, client_addr);
+        let contents = Box::new(SharedContents(Arc::new(vec![7u8; 100]))) as Contents;
+        let mut send_file = SendFile::new_server(server, client_addr, contents, 1, 512).unwrap();
+        send_file.set_retry_policy(Some(RetryPolicy::fixed(Duration::from_millis(20), 1000)));
+        send_file.set_idle_timeout(Some(Duration::from_millis(200)));
+        let start = Instant::now();
+        assert_eq!(send_file.run().unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_block_retransmission_limit() {
+        use super::backend::{ Contents, SharedContents };
+        use super::error::TFTPError;
+        use super::header::OPCODE_DATA;
+        use super::retry::RetryPolicy;
+        use super::send::SendFile;
+        use super::transport::{ Datagram, MemoryDatagram };
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 69);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000);
+        let (server, client) = MemoryDatagram::pair(server_addr, client_addr);
+        let contents = Box::new(SharedContents(Arc::new(vec![7u8; 100]))) as Contents;
+        let mut send_file = SendFile::new_server(server, client_addr, contents, 1, 512).unwrap();
+        // The retry policy would go on for much longer, but the block runs out of retransmissions.
+        send_file.set_retry_policy(Some(RetryPolicy::fixed(Duration::from_millis(20), 100)));
+        send_file.set_max_block_retransmissions(Some(2));
+        let err = send_file.run().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        match err.get_ref().and_then(|e| e.downcast_ref::<TFTPError>()) {
+            Some(&TFTPError::RetransmitLimitExceeded(0, 2)) => {},
+            _ => panic!("Unexpected error {:?}", err)
+        }
+
+        // The Ack for the request, and block 0 sent once and retransmitted twice.
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let mut buf = [0u8; 1024];
+        let mut data_sent = 0;
+        while let Ok((_, _)) = client.recv_from(&mut buf) {
+            if buf[1] == OPCODE_DATA { data_sent += 1 }
+        }
+        assert_eq!(data_sent, 3);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_peer_unreachable() {
+        use super::error::{ TFTPError, is_peer_unreachable };
+        use super::receive::ReceiveFile;
+        use std::sync::{ Arc, Mutex };
+        use std::time::{ Duration, Instant };
+
+        // Nothing is listening on the port of a socket that has been closed.
+        let localhost = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+        let peer_addr = UdpSocket::bind(localhost).unwrap().local_addr().unwrap();
+        let socket = UdpSocket::bind(localhost).unwrap();
+        socket.connect(peer_addr).unwrap();
+
+        let start = Instant::now();
+        let receiver = ReceiveFile::new(socket, peer_addr, SharedSink(Arc::new(Mutex::new(Vec::new()))), 512).unwrap();
+        let err = receiver.run().unwrap_err();
+        assert!(is_peer_unreachable(&err));
+        match err.get_ref().and_then(|e| e.downcast_ref::<TFTPError>()) {
+            Some(&TFTPError::PeerUnreachable(addr)) => assert_eq!(addr, peer_addr),
+            _ => panic!("Expected PeerUnreachable, got {:?}", err)
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_socket_options() {
+        use super::net_util::SocketOptions;
+
+        let host_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 69);
+        let localhost = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+        let options = SocketOptions { ttl: Some(7), dscp: Some(46), recv_buffer_size: Some(1 << 20), send_buffer_size: Some(1 << 20) };
+        let client = TFTPClient::builder(host_addr, localhost, "data/client_data".to_string())
+            .socket_options(options)
+            .build().unwrap();
+        assert_eq!(client.udp_socket.lock().unwrap().ttl().unwrap(), 7);
+        // Sessions get the options too.
+        let session = client.new_session(host_addr).unwrap();
+        assert_eq!(session.udp_socket.lock().unwrap().ttl().unwrap(), 7);
+
+        let options = SocketOptions { dscp: Some(64), ..SocketOptions::default() };
+        let err = TFTPClient::builder(host_addr, localhost, "data/client_data".to_string()).socket_options(options).build().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[cfg(feature = "async-io")]
+    fn test_async_transfer() {
+        use super::async_transfer::AsyncTransfer;
+        use super::backend::{ Contents, SharedContents };
+        use super::receive::ReceiveFile;
+        use super::send::SendFile;
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let localhost = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+        let (server, client) = (UdpSocket::bind(localhost).unwrap(), UdpSocket::bind(localhost).unwrap());
+        let (server_addr, client_addr) = (server.local_addr().unwrap(), client.local_addr().unwrap());
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let contents = Box::new(SharedContents(Arc::new(data.clone()))) as Contents;
+        let sender = spawn(move || async_io::block_on(AsyncTransfer::new(SendFile::new_server(server, client_addr, contents, 1, 512)?)?));
+        let mut receiver = ReceiveFile::new(client, server_addr, SharedSink(received.clone()), 512).unwrap();
+        receiver.set_dally(Duration::from_millis(10));
+        receiver.set_stop_and_wait(true);
+        async_io::block_on(AsyncTransfer::new(receiver).unwrap()).unwrap();
+        sender.join().unwrap().unwrap();
+        assert!(*received.lock().unwrap() == data);
+    }
 }
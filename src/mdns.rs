@@ -0,0 +1,256 @@
+use std::io;
+use std::net::{ SocketAddr, UdpSocket, IpAddr, Ipv4Addr };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// The name TFTP servers are advertised under (DNS-SD).
+pub const SERVICE_TYPE: &'static str = "_tftp._udp.local";
+/// The name DNS-SD browsers ask for to find out which kinds of service there are.
+const SERVICE_ENUMERATION: &'static str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set in the class of records that are unique to this host, so that caches replace old copies.
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// The TTL of records naming this host, as recommended by RFC6762.
+const HOST_TTL: u32 = 120;
+/// The TTL of other records, as recommended by RFC6762.
+const OTHER_TTL: u32 = 4500;
+
+/// A TFTP server to advertise.
+#[derive(Clone, Debug)]
+pub struct MdnsService {
+    /// The name of this particular server, e.g. "Lab TFTP".
+    pub instance: String,
+    /// The host name of the machine, without ".local".
+    pub host: String,
+    /// The address peers should send requests to.
+    pub addr: Ipv4Addr,
+    /// The port the server is listening on.
+    pub port: u16,
+}
+
+impl MdnsService {
+    fn instance_name(&self) -> String {
+        format!("{}.{}", self.instance, SERVICE_TYPE)
+    }
+
+    fn host_name(&self) -> String {
+        format!("{}.local", self.host)
+    }
+
+    /// Builds a response with all of the service's records. A `ttl` of 0 tells others to forget
+    /// them.
+    fn response(&self, ttl: Option<u32>) -> Vec<u8> {
+        let (host_ttl, other_ttl) = ttl.map(|ttl| (ttl, ttl)).unwrap_or((HOST_TTL, OTHER_TTL));
+        let instance_name = self.instance_name();
+        let host_name = self.host_name();
+
+        // ID 0, flags QR and AA, no questions, 5 answers.
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 5, 0, 0, 0, 0];
+        let mut rdata = vec![];
+        write_name(&mut rdata, SERVICE_TYPE);
+        write_record(&mut packet, SERVICE_ENUMERATION, TYPE_PTR, CLASS_IN, other_ttl, &rdata);
+
+        rdata.clear();
+        write_name(&mut rdata, &instance_name);
+        write_record(&mut packet, SERVICE_TYPE, TYPE_PTR, CLASS_IN, other_ttl, &rdata);
+
+        rdata.clear();
+        // Priority and weight 0, then the port.
+        rdata.extend_from_slice(&[0, 0, 0, 0, (self.port >> 8) as u8, self.port as u8]);
+        write_name(&mut rdata, &host_name);
+        write_record(&mut packet, &instance_name, TYPE_SRV, CLASS_IN | CACHE_FLUSH, host_ttl, &rdata);
+
+        // No key / value pairs, which is written as a single empty string.
+        write_record(&mut packet, &instance_name, TYPE_TXT, CLASS_IN | CACHE_FLUSH, other_ttl, &[0]);
+        write_record(&mut packet, &host_name, TYPE_A, CLASS_IN | CACHE_FLUSH, host_ttl, &self.addr.octets());
+        packet
+    }
+
+    /// Checks whether `packet` is a query that asks about any of this service's records.
+    fn is_asked_about(&self, packet: &[u8]) -> bool {
+        // Only queries (QR not set) are answered.
+        if packet.len() < 12 || packet[2] & 0x80 != 0 {
+            return false
+        }
+        let questions = (packet[4] as usize) << 8 | packet[5] as usize;
+        let names = [
+            (SERVICE_ENUMERATION.to_string(), TYPE_PTR),
+            (SERVICE_TYPE.to_string(), TYPE_PTR),
+            (self.instance_name(), TYPE_SRV),
+            (self.instance_name(), TYPE_TXT),
+            (self.host_name(), TYPE_A)
+        ];
+        let mut pos = 12;
+        for _ in 0..questions {
+            let (name, next) = match read_name(packet, pos) {
+                Some(name) => name,
+                None => return false
+            };
+            if next + 4 > packet.len() {
+                return false
+            }
+            let qtype = (packet[next] as u16) << 8 | packet[next + 1] as u16;
+            let asked = names.iter().any(|&(ref ours, rtype)| {
+                ours.eq_ignore_ascii_case(&name) && (qtype == rtype || qtype == TYPE_ANY)
+            });
+            if asked {
+                return true
+            }
+            pos = next + 4;
+        }
+        false
+    }
+}
+
+/// Advertises a TFTP server on the local network with multicast DNS, so that it can be found by
+/// browsing for `_tftp._udp` services. The advertisement stops, and is withdrawn, when this is
+/// dropped.
+pub struct MdnsAdvertiser {
+    service: MdnsService,
+    socket: UdpSocket,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MdnsAdvertiser {
+    /// Announces `service`, then answers queries about it in the background.
+    pub fn start(service: MdnsService) -> Result<Self, io::Error> {
+        let too_long = service.instance.len() > 63 || service.host.len() > 63;
+        if too_long || service.instance.is_empty() || service.host.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Instance and host names must be 1 to 63 bytes long."))
+        }
+
+        let socket = bind_mdns_socket()?;
+        socket.join_multicast_v4(&MDNS_GROUP, &service.addr)?;
+        socket.set_multicast_ttl_v4(255)?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let group = SocketAddr::new(IpAddr::V4(MDNS_GROUP), MDNS_PORT);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (service, socket, stop) = (service.clone(), socket.try_clone()?, stop.clone());
+            thread::spawn(move || {
+                let response = service.response(None);
+                // RFC6762 asks for at least two announcements, a second apart.
+                let _ = socket.send_to(&response, group);
+                let mut announcements = 1;
+                let mut buf = [0u8; 9000];
+                while !stop.load(Ordering::Relaxed) {
+                    match socket.recv_from(&mut buf) {
+                        Ok((len, _)) if service.is_asked_about(&buf[..len]) => {
+                            let _ = socket.send_to(&response, group);
+                        },
+                        Ok(_) => {},
+                        Err(_) => if announcements < 2 {
+                            let _ = socket.send_to(&response, group);
+                            announcements += 1;
+                        }
+                    }
+                }
+            })
+        };
+        Ok(MdnsAdvertiser { service, socket, stop, thread: Some(thread) })
+    }
+
+    pub fn service(&self) -> &MdnsService {
+        &self.service
+    }
+}
+
+impl Drop for MdnsAdvertiser {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let goodbye = self.service.response(Some(0));
+        let _ = self.socket.send_to(&goodbye, SocketAddr::new(IpAddr::V4(MDNS_GROUP), MDNS_PORT));
+    }
+}
+
+/// Binds to the mDNS port with SO_REUSEADDR set, since other responders on the machine (e.g. Avahi)
+/// are normally bound to it already.
+#[cfg(unix)]
+fn bind_mdns_socket() -> Result<UdpSocket, io::Error> {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 { return Err(io::Error::last_os_error()) }
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    let value: libc::c_int = 1;
+    for &option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT].iter() {
+        let result = unsafe {
+            libc::setsockopt(fd, libc::SOL_SOCKET, option, &value as *const _ as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if result != 0 { return Err(io::Error::last_os_error()) }
+    }
+
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_port = MDNS_PORT.to_be();
+    let result = unsafe {
+        libc::bind(fd, &addr as *const _ as *const libc::sockaddr, mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+    };
+    if result != 0 { return Err(io::Error::last_os_error()) }
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn bind_mdns_socket() -> Result<UdpSocket, io::Error> {
+    UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), MDNS_PORT))
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+fn write_record(buf: &mut Vec<u8>, name: &str, rtype: u16, class: u16, ttl: u32, rdata: &[u8]) {
+    write_name(buf, name);
+    buf.extend_from_slice(&[(rtype >> 8) as u8, rtype as u8, (class >> 8) as u8, class as u8]);
+    buf.extend_from_slice(&[(ttl >> 24) as u8, (ttl >> 16) as u8, (ttl >> 8) as u8, ttl as u8]);
+    buf.extend_from_slice(&[(rdata.len() >> 8) as u8, rdata.len() as u8]);
+    buf.extend_from_slice(rdata);
+}
+
+/// Reads the (possibly compressed) name at `pos` in `packet`, returning it and the position right
+/// after it.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = vec![];
+    let mut end = None;
+    // Compression pointers could form a loop, so only follow so many of them.
+    for _ in 0..128 {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)))
+        } else if len & 0xc0 == 0xc0 {
+            let offset = (len & 0x3f) << 8 | *packet.get(pos + 1)? as usize;
+            end = end.or(Some(pos + 2));
+            pos = offset;
+        } else {
+            let label = packet.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+    }
+    None
+}
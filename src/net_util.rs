@@ -0,0 +1,542 @@
+use std::net::{ SocketAddr, UdpSocket, IpAddr, Ipv4Addr, Ipv6Addr };
+use std::cmp::{ min, max };
+use std::io;
+use std::str::FromStr;
+use std::ops::RangeInclusive;
+use rand::{ thread_rng, Rng };
+use header::{ DATA_HEADER_LEN, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE };
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+
+/// The address to bind a socket to for talking to `host_addr`: the unspecified address of the same
+/// family, with port 0 so that the OS picks a free port.
+pub fn ephemeral_addr(host_addr: SocketAddr) -> SocketAddr {
+    match host_addr {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    }
+}
+
+/// Binds a socket with `bind` on the first free port in `ports`, e.g. so that a firewall only has
+/// to let a known range through. The search starts at a random port in the range, so that
+/// transfers don't all pile onto the lowest ports. Fails with `io::ErrorKind::AddrInUse` if every
+/// port in the range is taken.
+pub fn bind_in_range<F>(ports: &RangeInclusive<u16>, bind: F) -> Result<UdpSocket, io::Error>
+    where F: Fn(u16) -> Result<UdpSocket, io::Error> {
+    let (first, last) = (*ports.start() as u32, *ports.end() as u32);
+    if first > last {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The port range is empty."))
+    }
+    let len = last - first + 1;
+    let offset = thread_rng().next_u32() % len;
+    for i in 0..len {
+        match bind((first + (offset + i) % len) as u16) {
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            result => return result
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, format!("No port between {} and {} is free.", first, last)))
+}
+
+/// Finds the MTU of the path to `host_addr` as far as the kernel knows it: the MTU of the interface
+/// the route goes through, lowered by any "fragmentation needed" messages that routers along the
+/// path have sent back. Returns `None` if the MTU could not be determined on this platform.
+#[cfg(target_os = "linux")]
+pub fn path_mtu(host_addr: SocketAddr) -> Option<usize> {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, mtu_discover, pmtudisc_do, mtu) = if host_addr.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO, libc::IP_MTU)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO, libc::IPV6_MTU)
+    };
+
+    let socket = UdpSocket::bind(ephemeral_addr(host_addr)).ok()?;
+    let fd = socket.as_raw_fd();
+    // Datagrams must be sent with the DF flag set, otherwise the kernel doesn't track the path MTU.
+    let value: libc::c_int = pmtudisc_do;
+    let result = unsafe {
+        libc::setsockopt(fd, level, mtu_discover, &value as *const _ as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result != 0 { return None }
+
+    // The MTU can only be queried once the socket has a route, i.e. is connected.
+    socket.connect(host_addr).ok()?;
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(fd, level, mtu, &mut value as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if result != 0 || value <= 0 { None } else { Some(value as usize) }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn path_mtu(_host_addr: SocketAddr) -> Option<usize> { None }
+
+/// The largest block size for which data packets sent to `host_addr` fit in a single IP datagram,
+/// i.e. will not be fragmented. Returns `None` if the path MTU could not be determined.
+pub fn path_mtu_block_size(host_addr: SocketAddr) -> Option<usize> {
+    let ip_header_len = if host_addr.is_ipv4() { IPV4_HEADER_LEN } else { IPV6_HEADER_LEN };
+    path_mtu(host_addr).map(|mtu| {
+        let block_size = mtu.saturating_sub(ip_header_len + UDP_HEADER_LEN + DATA_HEADER_LEN);
+        max(MIN_BLOCK_SIZE, min(block_size, MAX_BLOCK_SIZE))
+    })
+}
+
+/// The address to bind a socket to so that it uses the network interface called `interface` (e.g.
+/// "eth1"). On Linux that is `socket_addr` as it is, since `pin_to_interface` does the job. On other
+/// platforms an unspecified IP in `socket_addr` is replaced by the interface's address of the same
+/// family, which pins the socket to the interface as far as the platform allows.
+#[cfg(target_os = "linux")]
+pub fn interface_bind_addr(socket_addr: SocketAddr, _interface: &str) -> Result<SocketAddr, io::Error> {
+    Ok(socket_addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn interface_bind_addr(socket_addr: SocketAddr, interface: &str) -> Result<SocketAddr, io::Error> {
+    if !socket_addr.ip().is_unspecified() {
+        return Ok(socket_addr)
+    }
+    Ok(SocketAddr::new(interface_addr(interface, socket_addr.is_ipv6())?, socket_addr.port()))
+}
+
+/// Pins `socket` to the network interface called `interface` with SO_BINDTODEVICE, so that it only
+/// sends and receives through that interface, whatever the routing table says. Elsewhere this does
+/// nothing; the socket is expected to be bound to `interface_bind_addr` instead.
+#[cfg(target_os = "linux")]
+pub fn pin_to_interface(socket: &UdpSocket, interface: &str) -> Result<(), io::Error> {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_BINDTODEVICE,
+                         interface.as_ptr() as *const libc::c_void, interface.len() as libc::socklen_t)
+    };
+    if result != 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_to_interface(_socket: &UdpSocket, _interface: &str) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Socket options for the sockets a client or server creates; see `set_socket_options`. Options
+/// that are `None` are left at the OS defaults.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// The TTL of outgoing IPv4 datagrams, or the hop limit of outgoing IPv6 ones.
+    pub ttl: Option<u32>,
+    /// The DSCP value (0 to 63) to mark outgoing datagrams with, so that the network can give TFTP
+    /// traffic a class of service of its own. It goes in the ToS byte (IPv4) or traffic class
+    /// (IPv6), with the ECN bits left clear.
+    pub dscp: Option<u8>,
+    /// The size of the socket's receive buffer (SO_RCVBUF) in bytes. Large windows on links with a
+    /// high bandwidth-delay product need more than the default. The OS may round the size or cap it
+    /// (on Linux, at net.core.rmem_max).
+    pub recv_buffer_size: Option<usize>,
+    /// The size of the socket's send buffer (SO_SNDBUF) in bytes, capped the same way.
+    pub send_buffer_size: Option<usize>,
+}
+
+/// Sets `options` on `socket`. On an IPv6 socket the IPv4 TTL and ToS are set as well, where the
+/// platform allows it, for the IPv4 peers of a dual stack socket.
+#[cfg(unix)]
+pub fn set_socket_options(socket: &UdpSocket, options: &SocketOptions) -> Result<(), io::Error> {
+    use libc;
+
+    let ipv6 = socket.local_addr()?.is_ipv6();
+    if let Some(ttl) = options.ttl {
+        if ipv6 {
+            set_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, ttl as libc::c_int)?;
+            let _ = socket.set_ttl(ttl);
+        } else {
+            socket.set_ttl(ttl)?;
+        }
+    }
+    if let Some(dscp) = options.dscp {
+        if dscp > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "DSCP values go from 0 to 63."))
+        }
+        let tos = (dscp as libc::c_int) << 2;
+        if ipv6 {
+            set_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?;
+            let _ = set_int_option(socket, libc::IPPROTO_IP, libc::IP_TOS, tos);
+        } else {
+            set_int_option(socket, libc::IPPROTO_IP, libc::IP_TOS, tos)?;
+        }
+    }
+    if let Some(size) = options.recv_buffer_size {
+        set_int_option(socket, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        set_int_option(socket, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)?;
+    }
+    Ok(())
+}
+
+/// Only the TTL of IPv4 sockets can be set on this platform; asking for anything else is an error.
+#[cfg(not(unix))]
+pub fn set_socket_options(socket: &UdpSocket, options: &SocketOptions) -> Result<(), io::Error> {
+    if options.dscp.is_some() || options.recv_buffer_size.is_some() || options.send_buffer_size.is_some()
+        || (options.ttl.is_some() && socket.local_addr()?.is_ipv6()) {
+        return Err(io::Error::new(io::ErrorKind::Other, "These socket options can't be set on this platform."))
+    }
+    if let Some(ttl) = options.ttl {
+        socket.set_ttl(ttl)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_int_option(socket: &UdpSocket, level: ::libc::c_int, name: ::libc::c_int, value: ::libc::c_int) -> Result<(), io::Error> {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, name, &value as *const _ as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result != 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+/// Looks up the first IPv4 (or, if `ipv6` is set, IPv6) address of the network interface called
+/// `interface`.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn interface_addr(interface: &str, ipv6: bool) -> Result<IpAddr, io::Error> {
+    use libc;
+    use std::ffi::CStr;
+    use std::ptr;
+
+    let mut addrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    let mut found = None;
+    let mut next = addrs;
+    while !next.is_null() && found.is_none() {
+        let ifaddr = unsafe { &*next };
+        next = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null() || unsafe { CStr::from_ptr(ifaddr.ifa_name) }.to_bytes() != interface.as_bytes() {
+            continue
+        }
+        found = match (unsafe { (*ifaddr.ifa_addr).sa_family } as i32, ipv6) {
+            (libc::AF_INET, false) => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))))
+            },
+            (libc::AF_INET6, true) => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+            },
+            _ => None
+        };
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    found.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Interface '{}' has no address of the right family.", interface)))
+}
+
+#[cfg(not(unix))]
+fn interface_addr(_interface: &str, _ipv6: bool) -> Result<IpAddr, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "Binding to an interface by name is not supported on this platform; bind to its address instead."))
+}
+
+/// Binds a UDP socket to `port` on all IPv6 addresses, with IPV6_V6ONLY turned off so that IPv4
+/// datagrams are received on it too, from IPv4-mapped IPv6 addresses.
+#[cfg(unix)]
+pub fn bind_dual_stack(port: u16) -> Result<UdpSocket, io::Error> {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    // The option has to be set before the socket is bound, which std doesn't allow for.
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM, 0) };
+    if fd < 0 { return Err(io::Error::last_os_error()) }
+    // Owning the descriptor straight away closes it again if anything below fails.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    let value: libc::c_int = 0;
+    let result = unsafe {
+        libc::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, &value as *const _ as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result != 0 { return Err(io::Error::last_os_error()) }
+
+    let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    addr.sin6_port = port.to_be();
+    let result = unsafe {
+        libc::bind(fd, &addr as *const _ as *const libc::sockaddr, mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t)
+    };
+    if result != 0 { return Err(io::Error::last_os_error()) }
+    Ok(socket)
+}
+
+/// Binds a UDP socket to `port` on all IPv6 addresses. Whether IPv4 datagrams are received on it
+/// too is up to the platform's default.
+#[cfg(not(unix))]
+pub fn bind_dual_stack(port: u16) -> Result<UdpSocket, io::Error> {
+    UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port))
+}
+
+/// Takes over the sockets passed on by systemd socket activation (LISTEN_FDS), in the order they
+/// are listed in the socket unit. Returns an empty list if this process wasn't socket activated.
+#[cfg(unix)]
+pub fn systemd_sockets() -> Result<Vec<UdpSocket>, io::Error> {
+    use std::env;
+    use std::process;
+
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let for_us = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(process::id());
+    if !for_us { return Ok(vec![]) }
+    let count = env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<i32>().ok()).unwrap_or(0);
+    // The sockets are meant for this process only, not for any it starts.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).map(|fd| unsafe { datagram_socket(fd) }).collect()
+}
+
+/// Takes over the socket inetd passes on as standard input when it starts a UDP service in "wait"
+/// mode.
+#[cfg(unix)]
+pub fn inetd_socket() -> Result<UdpSocket, io::Error> {
+    unsafe { datagram_socket(0) }
+}
+
+/// Wraps `fd` in a `UdpSocket`, after checking that it really is a datagram socket. The caller must
+/// own `fd`.
+#[cfg(unix)]
+unsafe fn datagram_socket(fd: i32) -> Result<UdpSocket, io::Error> {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    let mut socket_type: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut socket_type as *mut _ as *mut libc::c_void, &mut len) != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    if socket_type != libc::SOCK_DGRAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("File descriptor {} is not a datagram socket.", fd)))
+    }
+    Ok(UdpSocket::from_raw_fd(fd))
+}
+
+/// Undoes `UdpSocket::connect`, so that the socket takes datagrams from anyone again and can send
+/// them anywhere.
+#[cfg(unix)]
+pub fn disconnect(socket: &UdpSocket) -> Result<(), io::Error> {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let mut addr: libc::sockaddr = unsafe { mem::zeroed() };
+    addr.sa_family = libc::AF_UNSPEC as libc::sa_family_t;
+    let result = unsafe { libc::connect(socket.as_raw_fd(), &addr, mem::size_of::<libc::sockaddr>() as libc::socklen_t) };
+    // Some BSDs dissolve the association but report EAFNOSUPPORT anyway.
+    if result != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EAFNOSUPPORT) {
+            return Err(err)
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn disconnect(_socket: &UdpSocket) -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "UDP sockets can't be disconnected on this platform."))
+}
+
+/// Writes `addr` into `storage` the way the OS expects socket addresses, returning its length.
+#[cfg(unix)]
+pub fn write_sockaddr(addr: &SocketAddr, storage: &mut ::libc::sockaddr_storage) -> ::libc::socklen_t {
+    use libc;
+    use std::mem;
+
+    match *addr {
+        SocketAddr::V4(ref addr) => {
+            let sin = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr { s_addr: u32::from(*addr.ip()).to_be() };
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        },
+        SocketAddr::V6(ref addr) => {
+            let sin6 = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
+
+/// The inverse of `write_sockaddr`.
+#[cfg(unix)]
+pub fn read_sockaddr(storage: &::libc::sockaddr_storage) -> Result<SocketAddr, io::Error> {
+    use libc;
+    use std::net::{ SocketAddrV4, SocketAddrV6 };
+
+    match storage.ss_family as i32 {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)), u16::from_be(sin.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(sin6.sin6_addr.s6_addr), u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+        },
+        family => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported address family {}.", family)))
+    }
+}
+
+/// Sends each of `bufs` to `addr` as a datagram of its own with a single sendmmsg(2), returning how
+/// many were sent.
+#[cfg(target_os = "linux")]
+pub fn send_mmsg(socket: &UdpSocket, bufs: &[&[u8]], addr: SocketAddr) -> Result<usize, io::Error> {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    if bufs.is_empty() { return Ok(0) }
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let name_len = write_sockaddr(&addr, &mut name);
+    let name = &mut name as *mut _ as *mut libc::c_void;
+    let mut iovs: Vec<libc::iovec> = bufs.iter()
+        .map(|buf| libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: buf.len() })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovs.iter_mut().map(|iov| {
+        let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+        msg.msg_hdr.msg_name = name;
+        msg.msg_hdr.msg_namelen = name_len;
+        msg.msg_hdr.msg_iov = iov;
+        msg.msg_hdr.msg_iovlen = 1;
+        msg
+    }).collect();
+    let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as libc::c_uint, 0) };
+    if sent < 0 { return Err(io::Error::last_os_error()) }
+    Ok(sent as usize)
+}
+
+/// Receives a burst of datagrams, up to one into each of `bufs`, with a single recvmmsg(2). Only
+/// the first is waited for, within the socket's read timeout. Returns the length and source of
+/// each datagram received.
+#[cfg(target_os = "linux")]
+pub fn recv_mmsg(socket: &UdpSocket, bufs: &mut [&mut [u8]]) -> Result<Vec<(usize, SocketAddr)>, io::Error> {
+    use libc;
+    use std::mem;
+    use std::ptr;
+    use std::os::unix::io::AsRawFd;
+
+    if bufs.is_empty() { return Ok(vec![]) }
+    let mut names: Vec<libc::sockaddr_storage> = bufs.iter().map(|_| unsafe { mem::zeroed() }).collect();
+    let mut iovs: Vec<libc::iovec> = bufs.iter_mut()
+        .map(|buf| libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovs.iter_mut().zip(names.iter_mut()).map(|(iov, name)| {
+        let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+        msg.msg_hdr.msg_name = name as *mut _ as *mut libc::c_void;
+        msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_hdr.msg_iov = iov;
+        msg.msg_hdr.msg_iovlen = 1;
+        msg
+    }).collect();
+    let received = unsafe {
+        libc::recvmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as libc::c_uint, libc::MSG_WAITFORONE, ptr::null_mut())
+    };
+    if received < 0 { return Err(io::Error::last_os_error()) }
+    msgs.iter().zip(names.iter()).take(received as usize)
+        .map(|(msg, name)| Ok((msg.msg_len as usize, read_sockaddr(name)?)))
+        .collect()
+}
+
+/// Checks whether `ip` is an IPv4 address mapped into IPv6 (::ffff:a.b.c.d).
+pub fn is_ipv4_mapped(ip: IpAddr) -> bool {
+    unmap_ipv4(ip) != ip
+}
+
+/// A block of IP addresses in CIDR notation, e.g. "10.0.0.0/8" or "fd00::/8".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, io::Error> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "CIDR prefix length is too long for the address."))
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+
+    /// Checks whether `ip` is in this block. IPv4 addresses mapped into IPv6 (::ffff:a.b.c.d), which
+    /// is how IPv4 peers show up on dual stack sockets, count as the IPv4 address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, unmap_ipv4(ip)) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) =>
+                prefix_matches(u32::from(addr) as u128, u32::from(ip) as u128, 32, self.prefix_len),
+            (IpAddr::V6(addr), IpAddr::V6(ip)) =>
+                prefix_matches(u128::from(addr), u128::from(ip), 128, self.prefix_len),
+            _ => false
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = io::Error;
+
+    /// Parses "address/prefix length". A lone address is a block of just that address.
+    fn from_str(s: &str) -> Result<Self, io::Error> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not a valid CIDR block.", s));
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().and_then(|addr| addr.parse::<IpAddr>().ok()).ok_or_else(&invalid)?;
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|_| invalid())?,
+            None => if addr.is_ipv4() { 32 } else { 128 }
+        };
+        Cidr::new(addr, prefix_len)
+    }
+}
+
+fn unmap_ipv4(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(v6.to_ipv4().unwrap()),
+            _ => ip
+        },
+        _ => ip
+    }
+}
+
+fn prefix_matches(addr: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 { return true }
+    let shift = (bits - prefix_len) as u32;
+    addr >> shift == ip >> shift
+}
+
+/// Which peers a server will talk to. A peer is let in if it isn't in any denied block, and either
+/// no allowed blocks are given or it is in one of them. The default lets everyone in.
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+}
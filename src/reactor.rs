@@ -0,0 +1,97 @@
+use std::io;
+use std::net::UdpSocket;
+use std::time::Instant;
+use futures::{ Future, Poll, Async };
+use futures::task;
+use mio;
+use tokio_core::reactor::{ Handle, PollEvented, Timeout };
+
+pub use transport::Transfer;
+
+/// Drives a `SendFile` or `ReceiveFile` on a tokio-core reactor. Left to itself, a transfer blocks
+/// in its socket reads and has to be polled over and over; this registers the socket with the
+/// reactor instead, and only polls the transfer once a datagram has arrived or the read timeout
+/// the transfer set has run out, so the reactor thread is free in between.
+///
+/// The socket is switched to non-blocking mode while this is alive, which a transfer takes the
+/// same way as its read timeout running out. It is switched back when this is dropped.
+pub struct ReactorTransfer<T: Transfer> {
+    transfer: T,
+    /// The transfer's socket, as registered with the reactor.
+    io: PollEvented<mio::net::UdpSocket>,
+    /// Another handle to the transfer's socket, to peek at it without disturbing the transfer.
+    socket: UdpSocket,
+    /// Fires when the transfer's read timeout runs out, if it set one.
+    timer: Option<Timeout>,
+    handle: Handle,
+    /// Whether the transfer has been polled at all yet.
+    started: bool,
+}
+
+impl<T: Transfer> ReactorTransfer<T> {
+    pub fn new(transfer: T, handle: &Handle) -> Result<Self, io::Error> {
+        let socket = transfer.socket().try_clone()?;
+        // This makes every handle to the socket non-blocking, the transfer's included.
+        let io = PollEvented::new(mio::net::UdpSocket::from_socket(socket.try_clone()?)?, handle)?;
+        Ok(ReactorTransfer { transfer, io, socket, timer: None, handle: handle.clone(), started: false })
+    }
+
+    /// Whether a datagram is waiting to be read.
+    fn readable(&self) -> bool {
+        let mut buf = [0u8; 4];
+        self.socket.peek_from(&mut buf).is_ok()
+    }
+
+    /// Arranges to be polled again once the transfer has something to do.
+    fn wait(&mut self) -> Result<(), io::Error> {
+        // Readiness is only reported when a datagram arrives, so anything already waiting has to be
+        // checked for by hand, both before and after asking the reactor for the next one.
+        if !self.readable() {
+            self.io.need_read();
+        }
+        if self.readable() {
+            task::current().notify();
+        }
+        self.timer = match self.socket.read_timeout()? {
+            Some(read_timeout) => {
+                let mut timer = Timeout::new_at(Instant::now() + read_timeout, &self.handle)?;
+                // Registers the timer with the current task.
+                if let Async::Ready(()) = timer.poll()? {
+                    task::current().notify();
+                }
+                Some(timer)
+            },
+            None => None
+        };
+        Ok(())
+    }
+}
+
+impl<T: Transfer> Future for ReactorTransfer<T> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let timed_out = match self.timer {
+            Some(ref mut timer) => timer.poll()?.is_ready(),
+            None => false
+        };
+        if self.started && !timed_out && !self.io.poll_read().is_ready() {
+            return Ok(Async::NotReady)
+        }
+        self.started = true;
+        match self.transfer.poll()? {
+            Async::Ready(()) => Ok(Async::Ready(())),
+            Async::NotReady => {
+                self.wait()?;
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl<T: Transfer> Drop for ReactorTransfer<T> {
+    fn drop(&mut self) {
+        let _ = self.socket.set_nonblocking(false);
+    }
+}
@@ -1,32 +1,40 @@
 use std::net::SocketAddr;
 use bit_set::BitSet;
 use bit_vec::BitVec;
-use std::fs::File;
 use std::io::{ self, Seek, Read, Write };
 use std::path::Path;
-use futures::{ Future, Poll, Async };
+use futures::{ Future, Poll, Async, Stream };
 use std::net::UdpSocket;
+use transport::{ Datagram, PollBudget };
 use std::time::Duration;
 use std::sync::{ Arc, Mutex };
-use memmap::{ MmapOptions, MmapMut };
+use backend::WriteSink;
+use retry::RetryPolicy;
+use session::{ ReceiveStats, TransferControl, TransferProgress };
+use std::sync::atomic::Ordering;
 use std::time::Instant;
-use std::collections::{ BinaryHeap, HashMap };
-use error::TFTPError;
+use std::collections::{ BTreeMap, BinaryHeap, HashMap, VecDeque };
+use error::{ TFTPError, is_peer_unreachable };
 use std::ops::*;
+use std::cmp::{ min, max };
+use std::mem;
+use std::thread;
 
 use types::*;
 use header::*;
 use client::*;
+use send::WINDOW_SIZE_LIMIT;
 
 
-pub struct ReceiveFile {
-    /// The file that backs file_map.
-    file: File,
+pub struct ReceiveFile<D: Datagram = UdpSocket> {
+    /// Where the received data is written to.
+    file: Box<dyn WriteSink>,
 
-    file_map: MmapMut,
+    /// The size of each block (except the last one), in bytes.
+    block_size: usize,
 
-    /// The highest block number that has been received. If this is surpassed, then [file_map] must
-    /// be increased in size. If it is `None` that means no blocks have been received yet.
+    /// The highest block number that has been received. If it is `None` that means no blocks have
+    /// been received yet.
     highest_block: Option<usize>,
 
     received_last_block: bool,
@@ -37,42 +45,180 @@ pub struct ReceiveFile {
     /// The highest block that has been received, along with all blocks before it.
     consec_recv: Option<usize>,
 
-    socket: Arc<Mutex<UdpSocket>>,
+    /// The socket the transfer is done through; it belongs to this transfer alone.
+    socket: Arc<D>,
+
+    /// What packets are received into, sized for the block size; see `recv_buffer_len`.
+    recv_buf: Vec<u8>,
 
     host_addr: SocketAddr,
 
     /// The number of errors that have occured sequentially (i.e. one after the other)
     error_count: usize,
 
+    /// The transfer fails once more than this many errors have occured in a row.
+    max_attempts: usize,
+
     /// The average time between packets from the server.
     packet_time: Duration,
 
     /// The time at which the last data packet was received.
-    last_time: Instant
+    last_time: Instant,
+
+    /// How long to linger after sending the final ACK, re-ACKing the last block if the peer
+    /// retransmits it (i.e. if our final ACK was lost).
+    dally: Duration,
+
+    /// When the blocks that arrive are acknowledged; see `AckStrategy`.
+    ack_strategy: AckStrategy,
+
+    /// The last block that was acknowledged along with all the blocks before it, if any.
+    acked: Option<usize>,
+
+    /// When the blocks that arrived since the last ACK are to be acknowledged, with
+    /// `AckStrategy::Delayed`.
+    ack_due: Option<Instant>,
+
+    /// The time at which the transfer started.
+    start_time: Instant,
+
+    /// If no data at all has been received this long after the start of the transfer, it fails
+    /// with `TFTPError::NoDataReceived`.
+    first_block_timeout: Option<Duration>,
+
+    /// The transfer fails if nothing arrives from the peer for this long. `None` means it waits
+    /// forever.
+    idle_timeout: Option<Duration>,
+
+    /// The transfer fails if it isn't done this long after it started. `None` means there is no
+    /// deadline.
+    transfer_timeout: Option<Duration>,
+
+    /// If set, this is used as the retransmission timeout instead of the average time between
+    /// packets (e.g. when it was negotiated with the "utimeout" option).
+    timeout: Option<Duration>,
+
+    /// Where to report how far along the transfer is, if anywhere.
+    progress: Option<Arc<TransferProgress>>,
+
+    /// If set, decides when the last ACK is resent and when to give up; see `set_retry_policy`.
+    retry_policy: Option<RetryPolicy>,
+
+    /// The number of times in a row the last ACK has been resent without any data arriving.
+    timeouts: usize,
+
+    /// If set, the transfer holds off while this is paused.
+    control: Option<Arc<TransferControl>>,
+
+    /// How often `run` gives up the thread.
+    poll_budget: PollBudget,
+
+    /// Whether every block ends with a CRC32, as negotiated with `BLOCK_CRC_OPTION`.
+    block_crc: bool,
+
+    /// How far past the first missing block a block may be and still be taken; see
+    /// `set_reorder_window`.
+    reorder_window: usize,
+
+    /// What the transfer has done so far; see `stats`.
+    stats: ReceiveStats,
+
+    /// The number of blocks in a row that had already arrived, since the last new one or the last
+    /// ACK sent because of them.
+    duplicates_in_row: usize,
+
+    /// Set when the last read came back empty without the socket waiting out its timeout, i.e. the
+    /// socket doesn't block: how long `run` should sleep for rather than poll again straight away.
+    idle_wait: Option<Duration>,
+
+    /// The time at which the final ACK was sent. If this is `Some`, the whole file has been received
+    /// and we are dallying.
+    dally_start: Option<Instant>
 }
 
-impl ReceiveFile {
-    pub fn receive(socket: Arc<Mutex<UdpSocket>>, host_addr: SocketAddr, file: File) -> Result<Self, io::Error> {
-        let mut r = ReceiveFile::new(socket, host_addr, file)?;
+/// When a `ReceiveFile` acknowledges the blocks it receives. An ACK always covers every block up to
+/// the first one still missing, and it is sent again whenever the peer goes quiet, whatever the
+/// strategy; the strategies differ in how often it goes out while blocks keep arriving.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AckStrategy {
+    /// Every block, as soon as it arrives: RFC 1350's lock step, for a peer that sends one block at
+    /// a time.
+    Lockstep,
+    /// Every burst of blocks that arrives together, e.g. a window, once it has been dealt with.
+    /// This is the default.
+    Burst,
+    /// Every time this many more blocks have arrived. This suits a peer with a fixed window of
+    /// that many blocks (RFC 7440) that only moves on once the whole window is acknowledged.
+    EveryN(usize),
+    /// At most this long after a block arrives, along with whatever else arrives in the meantime,
+    /// so that a peer sending with a large window gets fewer ACKs.
+    Delayed(Duration),
+}
+
+impl Default for AckStrategy {
+    fn default() -> Self { AckStrategy::Burst }
+}
+
+/// How many blocks past the first missing one `ReceiveFile` takes by default: as many as the
+/// largest window a peer can send with.
+pub const DEFAULT_REORDER_WINDOW: usize = WINDOW_SIZE_LIMIT;
+
+/// How many blocks that have already arrived it takes in a row for `ReceiveFile` to send its last
+/// ACK again straight away. The peer only sends blocks again once it has given up waiting for an
+/// ACK, so a run of them means our ACKs are being lost, and waiting for the next one to be due
+/// would only have it send even more.
+pub const DUPLICATE_BURST: usize = 3;
+
+/// How many bytes of blocks that arrived ahead of a missing one a `ReceiveStream` holds on to at
+/// most. Its reorder window is cut down to fit, so that blocks past it are dropped and sent again
+/// by the peer rather than buffered.
+pub const STREAM_BUFFER_LIMIT: usize = 16 * 1024 * 1024;
+
+/// The default amount of time `ReceiveFile` will dally for after sending the final ACK.
+#[allow(non_snake_case)]
+pub fn DEFAULT_DALLY() -> Duration { Duration::from_secs(2) }
+
+impl<D: Datagram> ReceiveFile<D> {
+    pub fn receive<W: WriteSink + 'static>(socket: D, host_addr: SocketAddr, file: W, block_size: usize) -> Result<Self, io::Error> {
+        let mut r = ReceiveFile::new(socket, host_addr, file, block_size)?;
         r.init()
     }
 
-    pub fn new(socket: Arc<Mutex<UdpSocket>>, host_addr: SocketAddr, mut file: File) -> Result<Self, io::Error> {
-        // If file is empty some strange error related to mmap happens, so write a single null byte!
-        file.write(&[0])?;
-        let file_map = unsafe { MmapOptions::new().map_mut(&file)? };
+    pub fn new<W: WriteSink + 'static>(socket: D, host_addr: SocketAddr, file: W, block_size: usize) -> Result<Self, io::Error> {
         let mut r = ReceiveFile {
-            file,
-            file_map,
-            socket,
+            file: Box::new(file),
+            socket: Arc::new(socket),
+            recv_buf: vec![0u8; recv_buffer_len(block_size)],
             host_addr,
+            block_size,
             consec_recv: None,
             received: BitSet::new(),
             received_last_block: false,
             highest_block: None,
             error_count: 0,
+            max_attempts: MAX_ATTEMPTS,
             packet_time: Duration::new(1, 0),
-            last_time: Instant::now()
+            last_time: Instant::now(),
+            start_time: Instant::now(),
+            first_block_timeout: None,
+            idle_timeout: Some(TOTAL_TIMEOUT()),
+            transfer_timeout: None,
+            timeout: None,
+            progress: None,
+            retry_policy: None,
+            timeouts: 0,
+            control: None,
+            poll_budget: PollBudget::default(),
+            block_crc: false,
+            reorder_window: DEFAULT_REORDER_WINDOW,
+            stats: ReceiveStats::default(),
+            duplicates_in_row: 0,
+            idle_wait: None,
+            dally: DEFAULT_DALLY(),
+            ack_strategy: AckStrategy::default(),
+            acked: None,
+            ack_due: None,
+            dally_start: None
         };
         r.init()
     }
@@ -88,167 +234,368 @@ impl ReceiveFile {
 	}*/
     }
 
+    /// Sets how long to linger for after the final ACK has been sent; see `DEFAULT_DALLY`.
+    pub fn set_dally(&mut self, dally: Duration) {
+        self.dally = dally;
+    }
+
+    /// Acknowledges every block as soon as it arrives, i.e. `AckStrategy::Lockstep`, or every burst
+    /// of them otherwise. This should be set when the peer sends with a window size of 1.
+    pub fn set_stop_and_wait(&mut self, stop_and_wait: bool) {
+        self.ack_strategy = if stop_and_wait { AckStrategy::Lockstep } else { AckStrategy::Burst };
+    }
+
+    /// Sets when the blocks that arrive are acknowledged; see `AckStrategy`.
+    pub fn set_ack_strategy(&mut self, ack_strategy: AckStrategy) {
+        self.ack_strategy = ack_strategy;
+    }
+
+    /// Sets how long to wait for the first data packet before failing with
+    /// `TFTPError::NoDataReceived`. `None` means the usual idle timeout applies.
+    pub fn set_first_block_timeout(&mut self, first_block_timeout: Option<Duration>) {
+        self.first_block_timeout = first_block_timeout;
+    }
+
+    /// Sets how long the peer may go quiet before the transfer fails; see `TOTAL_TIMEOUT`.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Sets how long the whole transfer may take; by default there is no limit.
+    pub fn set_transfer_timeout(&mut self, transfer_timeout: Option<Duration>) {
+        self.transfer_timeout = transfer_timeout;
+    }
+
+    /// Sets how often `run` gives up the thread; see `PollBudget`.
+    pub fn set_poll_budget(&mut self, poll_budget: PollBudget) {
+        self.poll_budget = poll_budget;
+    }
+
+    /// How often `run` gives up the thread; see `set_poll_budget`.
+    pub fn poll_budget(&self) -> PollBudget {
+        self.poll_budget
+    }
+
+    /// How long the transfer would have waited on its socket, if its last poll found nothing to
+    /// read without the socket waiting; see `PollBudget::run`.
+    pub fn idle_wait(&self) -> Option<Duration> {
+        self.idle_wait
+    }
+
+    /// Checks the CRC32 at the end of every block, NAKing blocks that arrive corrupted instead of
+    /// writing them; this has to have been negotiated with `BLOCK_CRC_OPTION`.
+    pub fn set_block_crc(&mut self, block_crc: bool) {
+        self.block_crc = block_crc;
+    }
+
+    /// Tells the sink how large the file is going to be before any of it arrives, e.g. from the
+    /// "tsize" option, so that it can make room for all of it at once; see `WriteSink::allocate`.
+    pub fn set_file_size(&mut self, file_size: u64) -> Result<(), io::Error> {
+        self.file.allocate(file_size)
+    }
+
+    /// Drops blocks that are `reorder_window` or more blocks past the first one still missing,
+    /// re-sending the last ACK instead to tell the peer where the transfer is at. A peer can't have
+    /// sent them in the same window as the missing block, and writing them would make the file
+    /// however large the peer likes. Defaults to `DEFAULT_REORDER_WINDOW`.
+    pub fn set_reorder_window(&mut self, reorder_window: usize) {
+        self.reorder_window = reorder_window;
+    }
+
+    /// Sets the retransmission timeout; see `timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how many errors in a row it takes for the transfer to fail; see `MAX_ATTEMPTS`.
+    pub fn set_max_attempts(&mut self, max_attempts: usize) {
+        self.max_attempts = max_attempts;
+    }
+
+    /// Resends the last ACK according to `retry_policy` when no data arrives, failing once it runs
+    /// out of attempts. A timeout negotiated with the peer (see `set_timeout`) is kept to, without
+    /// backing off.
+    pub fn set_retry_policy(&mut self, retry_policy: Option<RetryPolicy>) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// The retry policy in effect, taking a negotiated timeout into account.
+    fn effective_retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry_policy.map(|policy| match self.timeout {
+            Some(timeout) => RetryPolicy::fixed(timeout, policy.max_attempts),
+            None => policy
+        })
+    }
+
+    /// Reports the progress of the transfer to `progress` from now on.
+    pub fn set_progress(&mut self, progress: Arc<TransferProgress>) {
+        self.progress = Some(progress);
+    }
+
+    /// What the transfer has done so far. This is also handed to the `TransferProgress` once the
+    /// transfer is over, successfully or not.
+    pub fn stats(&self) -> ReceiveStats {
+        self.stats
+    }
+
+    /// The socket the transfer is done through.
+    pub fn socket(&self) -> &D {
+        &self.socket
+    }
+
+    /// Holds off on reading and acknowledging data whenever `control` is paused.
+    pub fn set_control(&mut self, control: Arc<TransferControl>) {
+        self.control = Some(control);
+    }
+
     fn init(mut self) -> Result<Self, io::Error> {
         self.send_ack(0)?;
         Ok(self)
     }
 
     pub fn run(mut self) -> Result<(), io::Error> {
-        loop {
-            let r = self.poll();
-            match r {
-                Ok(Async::NotReady) => continue,
-                Ok(Async::Ready(())) => return Ok(()),
-                Err(e) => return Err(e)
-            }
-        }
+        let poll_budget = self.poll_budget;
+        poll_budget.run(&mut self, |transfer| transfer.idle_wait)
     }
 
-    pub fn handle_data(&mut self, data: DataHeader) -> Result<Option<()>, io::Error> {
-	if unsafe { STOP_AND_WAIT } { self.send_ack(data.block_number)?; }
+    /// Hands the file over as it arrives, in order: the transfer becomes a `ReceiveStream` of the
+    /// byte ranges that are complete, i.e. that every block before them has arrived too. Each range
+    /// comes out as soon as the block that completes it arrives, so the file can be put to use
+    /// before the transfer is over. The data is still written to the sink as well.
+    ///
+    /// Blocks that arrive ahead of a missing one are kept in memory until it shows up, so the
+    /// reorder window is limited to `STREAM_BUFFER_LIMIT` bytes worth of blocks.
+    pub fn into_stream(mut self) -> ReceiveStream<D> {
+        self.reorder_window = max(min(self.reorder_window, STREAM_BUFFER_LIMIT / self.block_size), 1);
+        let ranges = Arc::new(Mutex::new(VecDeque::new()));
+        let file = mem::replace(&mut self.file, Box::new(io::Cursor::new(Vec::new())));
+        self.file = Box::new(InOrderSink { file, next: 0, pending: BTreeMap::new(), ranges: ranges.clone() });
+        ReceiveStream { transfer: self, ranges, done: false }
+    }
+
+    pub fn handle_data(&mut self, mut data: DataHeader) -> Result<Option<()>, io::Error> {
+        if self.block_crc && !data.check_crc() {
+            // Ask for the block again straight away, rather than waiting for the window to be resent.
+            let _ = Header::Nak(NakHeader::new(data.block_number)).send(self.host_addr.clone(), &*self.socket);
+            return Ok(Some(()))
+        }
+        let first_missing = self.consec_recv.map_or(0, |consec_recv| consec_recv + 1);
+        if data.block_number >= first_missing + self.reorder_window {
+            if let Some(consec_recv) = self.consec_recv {
+                let _ = self.send_ack(consec_recv);
+            }
+            return Ok(Some(()))
+        }
+	// A lost ACK is made up for by the peer's retransmission, so a failed send isn't fatal.
+	if self.ack_strategy == AckStrategy::Lockstep { let _ = self.send_ack(data.block_number); }
 	self.last_time = Instant::now();
-        if let Some(highest_block) = self.highest_block.take() {
-            self.highest_block = Some(data.block_number);
-            let new_len = (MAX_DATA_LEN * (data.block_number as usize) + data.data_len) as u64;
-            if highest_block < data.block_number || self.file_map.len() < new_len as usize {
-                self.file_map.flush()?;
-                let current_len = self.file_map.len();
-                let new_len = (MAX_DATA_LEN * (data.block_number as usize) + data.data_len) as u64;
-                self.file.set_len(new_len)?;
-                self.file.flush()?;
-                self.file_map = unsafe {
-                    MmapOptions::new().len(new_len as usize).map_mut(&self.file)?
-                };
+        self.timeouts = 0;
+        // Blocks can arrive out of order (e.g. retransmissions), so this only ever goes up.
+        self.highest_block = Some(match self.highest_block {
+            Some(highest_block) => max(highest_block, data.block_number),
+            None => data.block_number
+        });
+
+        let is_new = self.received.insert(data.block_number as usize);
+        self.stats.blocks_received += 1;
+        if !is_new {
+            self.stats.duplicates += 1;
+            self.duplicates_in_row += 1;
+            if self.duplicates_in_row >= DUPLICATE_BURST {
+                self.duplicates_in_row = 0;
+                if let Some(consec_recv) = self.consec_recv {
+                    self.stats.duplicate_acks += 1;
+                    let _ = self.acknowledge(consec_recv);
+                }
             }
         } else {
-            self.highest_block = Some(data.block_number);
-            self.file_map.flush()?;
-            let current_len = self.file_map.len();
-            let new_len = (MAX_DATA_LEN * data.block_number + data.data_len) as u64;
-            self.file.set_len(new_len)?;
-            self.file.flush()?;
-            self.file_map = unsafe {
-                MmapOptions::new().len(new_len as usize).map_mut(&self.file)?
-            };
+            self.duplicates_in_row = 0;
         }
 
-        self.received.insert(data.block_number as usize);
-
         // This means it is the last data header.
-        if data.data_len < MAX_DATA_LEN {
+        if data.data_len < self.block_size {
             self.received_last_block = true;
-            if data.data_len > 0 {
-                let start = data.block_number * MAX_DATA_LEN;
-                let end = start + data.data_len;
-                self.file_map[start..end]
-                    .copy_from_slice(&data.data[0..data.data_len]); 
+        }
+
+        let data_len = min(data.data_len, self.block_size);
+        if let Some(ref progress) = self.progress {
+            if is_new {
+                progress.bytes_transferred.fetch_add(data_len, Ordering::Relaxed);
+            } else {
+                progress.retransmissions.fetch_add(1, Ordering::Relaxed);
             }
-        } else {
-            let start = data.block_number * MAX_DATA_LEN;
-            let end = start + MAX_DATA_LEN;
-            self.file_map[start..end]
-                .copy_from_slice(&data.data);
+            progress.notify();
+        }
+        // A duplicate is already in the file.
+        if is_new && data_len > 0 {
+            self.file.write_block((data.block_number * self.block_size) as u64, &data.data[0..data_len])?;
         }
         Ok(Some(()))
     }
 
+    /// Moves `consec_recv` along over the blocks that have arrived, and acknowledges it when the ACK
+    /// strategy has it acknowledged.
+    fn update_acks(&mut self) {
+        if self.consec_recv.is_none() && self.received.contains(0) {
+            self.consec_recv = Some(0);
+        }
+        let mut consec_recv = match self.consec_recv {
+            Some(consec_recv) => consec_recv,
+            None => return
+        };
+        while self.received.contains(consec_recv + 1) {
+            consec_recv += 1;
+        }
+        self.consec_recv = Some(consec_recv);
+        let is_new = self.acked.map_or(true, |acked| consec_recv > acked);
+        let due = match self.ack_strategy {
+            AckStrategy::Lockstep | AckStrategy::Burst => true,
+            AckStrategy::EveryN(n) => self.acked.map_or(consec_recv + 1, |acked| consec_recv - acked) >= max(n, 1),
+            AckStrategy::Delayed(delay) => {
+                if is_new && self.ack_due.is_none() {
+                    self.ack_due = Some(Instant::now() + delay);
+                }
+                self.ack_due.map_or(false, |ack_due| Instant::now() >= ack_due)
+            }
+        };
+        if due {
+            let _ = self.acknowledge(consec_recv);
+        }
+    }
+
+    /// Acknowledges `consec_recv`, and everything before it.
+    fn acknowledge(&mut self, consec_recv: usize) -> Result<(), io::Error> {
+        self.acked = Some(consec_recv);
+        self.ack_due = None;
+        self.send_ack(consec_recv)
+    }
+
     /// # Returns
-    /// Ok(None): if the socket can't be borrowed (it is already being used)
-    ///
-    /// Ok(Some(())): if the ack was successfully sent
+    /// Ok(()): if the ack was successfully sent
     ///
     /// Err(<io::Error>): If there was an I/O error at any point.
-    fn send_ack(&mut self, block_number: usize) -> Result<Option<()>, io::Error> {
-	if let Ok(ref mut socket) = self.socket.try_lock() {
-            Header::Ack(AckHeader::new(block_number))
-                .send(self.host_addr.clone(), socket)?;
-            Ok(Some(()))
-        } else {
-            Ok(None)
-        }
+    fn send_ack(&mut self, block_number: usize) -> Result<(), io::Error> {
+        Header::Ack(AckHeader::new(block_number))
+            .send(self.host_addr.clone(), &*self.socket)
     }
 
     fn receive_header(&mut self) -> Result<Option<Vec<Header>>, io::Error> {
-        if let Ok(ref mut socket) = self.socket.clone().try_lock() {
-	    socket.set_read_timeout(Some(self.packet_time.clone()))?;
-            match Header::recv(self.host_addr.clone(), socket) {
-                Ok(r)   => { 
+        let socket = &*self.socket.clone();
+        let read_timeout = match self.effective_retry_policy() {
+            Some(policy) => policy.interval(self.timeouts),
+            None => self.timeout.unwrap_or(self.packet_time)
+        };
+        // Don't wait past when a delayed ACK is due.
+        let read_timeout = match self.ack_due {
+            Some(ack_due) => min(read_timeout, max(ack_due.saturating_duration_since(Instant::now()), Duration::from_millis(1))),
+            None => read_timeout
+        };
+	    socket.set_read_timeout(Some(read_timeout))?;
+        self.idle_wait = None;
+        let waited_from = Instant::now();
+        match Header::recv(self.host_addr.clone(), socket, &mut self.recv_buf) {
+            Ok(r)   => { 
 		    self.update_average();
 		    let mut headers = vec![r];
 	            socket.set_read_timeout(Some(Duration::new(0, 250000)))?;
-		    while let Ok(header) = Header::recv(self.host_addr.clone(), socket) {
-			headers.push(header);
-		    }
-                    Ok(Some(headers))
-                },
-                Err(e)  => {
-                    if let TFTPError::IOError(ioerr) = e {
-                        Err(ioerr)
-                    } else {
-                        Ok(None)
+                loop {
+                    match Header::recv(self.host_addr.clone(), socket, &mut self.recv_buf) {
+                        Ok(header) => headers.push(header),
+                        Err(TFTPError::WrongHost(stray)) => {
+                            let _ = Header::reject_unknown_tid(stray, socket);
+                        },
+                        Err(_) => break
                     }
                 }
+                Ok(Some(headers))
+            },
+            Err(TFTPError::WrongHost(stray)) => {
+                let _ = Header::reject_unknown_tid(stray, socket);
+                Ok(None)
+            },
+            Err(e @ TFTPError::PeerUnreachable(_)) => Err(e.into()),
+            Err(e)  => {
+                if let TFTPError::IOError(ioerr) = e {
+                    let timed_out = ioerr.kind() == io::ErrorKind::WouldBlock || ioerr.kind() == io::ErrorKind::TimedOut;
+                    if timed_out && waited_from.elapsed() < read_timeout / 2 {
+                        self.idle_wait = Some(read_timeout);
+                    }
+                    Err(ioerr)
+                } else {
+                    Ok(None)
+                }
             }
-        } else {
-            Ok(None)
         }
     }
 
-    fn send_error(&mut self, error_header: ErrorHeader) -> Result<(), io::Error> {
-        if let Ok(ref mut socket) = self.socket.try_lock() {
-            match Header::Error(ErrorHeader { error_code: 0u16.into(), error_message: "Giving up 😞".to_string() })
-                    .send(self.host_addr.clone(), socket) {
-                Err(e) => Err(e),
-                _ => Ok(())
+    /// Waits until the dally period is over, re-ACKing the last block every time it is received
+    /// again. Since the transfer is already complete at this point, errors are not reported.
+    fn dally(&mut self, dally_start: Instant) -> Poll<(), io::Error> {
+        if dally_start.elapsed() >= self.dally {
+            return Ok(Async::Ready(()))
+        }
+
+        let last_block = self.highest_block.unwrap();
+        match self.receive_header() {
+            Ok(Some(headers)) => {
+                for header in headers {
+                    if let Header::Data(data_header) = header {
+                        if data_header.block_number == last_block {
+                            let _ = self.send_ack(last_block);
+                        }
+                    }
+                }
+                Ok(Async::NotReady)
+            },
+            Ok(None) => Ok(Async::NotReady),
+            Err(e) => {
+                match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Ok(Async::NotReady),
+                    _ => Ok(Async::Ready(()))
+                }
             }
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other, "Failed to obtain UDP Socket lock"))
         }
     }
 
     fn fail(&mut self, err: io::Error) -> Poll<(), io::Error> {
-        for i in 0..MAX_ATTEMPTS {
-            if let Ok(ref mut socket) = self.socket.try_lock() {
-                match Header::Error(ErrorHeader { error_code: 0u16.into(), error_message: "Giving up 😞".to_string() })
-                    .send(self.host_addr.clone(), socket) {
-                    Err(e) => continue,
-                    _ => return Err(err)
-                }
+        for i in 0..self.max_attempts {
+            match Header::Error(ErrorHeader { error_code: 0u16.into(), error_message: "Giving up 😞".to_string() })
+                .send(self.host_addr.clone(), &*self.socket) {
+                Err(e) => continue,
+                _ => return Err(err)
             }
         }
         Err(err)
     }
 }
 
-impl Future for ReceiveFile {
-    type Item = ();
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+impl<D: Datagram> ReceiveFile<D> {
+    fn poll_transfer(&mut self) -> Poll<(), io::Error> {
         use header::Header::*;
 
-	if self.consec_recv.is_none() {
-        	if self.received.contains(0) {
-                	self.consec_recv = Some(0);
-            	}
-       	}
-	if self.consec_recv.is_some() {
-        	    let mut consec_recv = self.consec_recv.clone().unwrap();
-            	    let original = consec_recv;
-            	    loop {
-                        if self.received.contains(consec_recv + 1) {
-                            consec_recv += 1;
-                            continue;
-                        } else {
-                            break;
-                        }
-                    }
-                    self.consec_recv = Some(consec_recv);
-	            if original <= consec_recv {
-	    	        let _ = self.send_ack(consec_recv);
-	            }
+        if let Some(dally_start) = self.dally_start {
+            return self.dally(dally_start);
+        }
+
+        if let Some(control) = self.control.clone() {
+            if control.is_paused() {
+                // The peer's retransmissions queue up in the socket, and are dealt with on resuming.
+                if self.idle_timeout.map_or(false, |idle_timeout| self.last_time.elapsed() > idle_timeout) {
+                    return self.fail(io::Error::new(io::ErrorKind::TimedOut, "The transfer was paused for too long."));
+                }
+                if self.transfer_timeout.map_or(false, |transfer_timeout| self.start_time.elapsed() > transfer_timeout) {
+                    return self.fail(io::Error::new(io::ErrorKind::TimedOut, "The transfer did not finish in time."));
+                }
+                if !control.wait_for_resume(PAUSE_CHECK_INTERVAL()) {
+                    self.last_time = Instant::now();
+                    self.timeouts = 0;
+                }
+                return Ok(Async::NotReady)
+            }
         }
-	
+
+        self.update_acks();
+
 
         if self.received_last_block {
             let mut contains_all = true;
@@ -257,17 +604,29 @@ impl Future for ReceiveFile {
                 if !contains_all { break }
             }
             if contains_all {
-                // Send a several ACKS to let the server know we're done here
-                for i in 0..4 {
-                    self.send_ack(self.highest_block.unwrap())?;
+                // Let the server know we're done here, then stick around in case the ACK is lost.
+                if let Err(e) = self.file.finalize() {
+                    return self.fail(e)
                 }
-                return Ok(Async::Ready(()))
+                // The peer may already have the ACK and be gone, which a connected socket reports here.
+                let _ = self.send_ack(self.highest_block.unwrap());
+                self.dally_start = Some(Instant::now());
+                return Ok(Async::NotReady)
+            }
+        }
+
+        if let Some(first_block_timeout) = self.first_block_timeout {
+            if self.highest_block.is_none() && self.start_time.elapsed() > first_block_timeout {
+                return self.fail(TFTPError::NoDataReceived.into());
             }
         }
 
-        if self.last_time.elapsed() > TOTAL_TIMEOUT() {
+        if self.idle_timeout.map_or(false, |idle_timeout| self.last_time.elapsed() > idle_timeout) {
             return self.fail(io::Error::new(io::ErrorKind::TimedOut, "TFTP connection appears to be dead."));
         }
+        if self.transfer_timeout.map_or(false, |transfer_timeout| self.start_time.elapsed() > transfer_timeout) {
+            return self.fail(io::Error::new(io::ErrorKind::TimedOut, "The transfer did not finish in time."));
+        }
         
         let prev_error_count = self.error_count;
         self.error_count = 0;
@@ -289,43 +648,39 @@ impl Future for ReceiveFile {
                     	    Ok(Some(())) => {}
                         }
 		    } else if let Header::Error(error_header) = header {
-		 	return Err(io::Error::new(io::ErrorKind::Other,
-                                          format!("Received error from server: '{}'", error_header.error_message)))
+		 	return Err(TFTPError::ErrorReceived(error_header.error_code, error_header.error_message).into())
  		    }
 		}
-	        if self.consec_recv.is_none() {
-        	    if self.received.contains(0) {
-                	self.consec_recv = Some(0);
-            	    }
-        	}
-	        if self.consec_recv.is_some() {
-        	    let mut consec_recv = self.consec_recv.clone().unwrap();
-            	    let original = consec_recv;
-            	    loop {
-                        if self.received.contains(consec_recv + 1) {
-                            consec_recv += 1;
-                            continue;
-                        } else {
-                            break;
-                        }
-                    }
-                    self.consec_recv = Some(consec_recv);
-	            if original <= consec_recv {
-	    	        let _ = self.send_ack(consec_recv);
-	            }
-                }
+                self.update_acks();
 		return Ok(Async::NotReady)
             },
 
 	   Ok(None) => { return Ok(Async::NotReady) },
 
             Err(e) => {
+                // There's nobody left to finish the transfer with.
+                if is_peer_unreachable(&e) {
+                    return Err(e)
+                }
                 if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock {
-                    
-		   if self.last_time.elapsed() > Duration::new(1, 0) {
+                    if let Some(policy) = self.effective_retry_policy() {
+                        if self.last_time.elapsed() >= policy.interval(self.timeouts) {
+                            self.timeouts += 1;
+                            if self.timeouts > policy.max_attempts {
+                                return self.fail(io::Error::new(io::ErrorKind::TimedOut, "No data arrived after retransmitting the maximum number of times."));
+                            }
+                            self.last_time = Instant::now();
+                            if let Some(block_number) = self.consec_recv {
+                                self.acknowledge(block_number)?;
+                            }
+                        }
+                        return Ok(Async::NotReady);
+                    }
+
+		   if self.last_time.elapsed() > self.timeout.unwrap_or(Duration::new(1, 0)) {
 		    	self.last_time = Instant::now();
 			if let Some(&block_number) = self.consec_recv.as_ref() {
-			self.send_ack(block_number)?;
+			self.acknowledge(block_number)?;
 		    } else {
 			    self.error_count += 1;
 		    }}
@@ -333,7 +688,7 @@ impl Future for ReceiveFile {
                 }
 
                 self.error_count = prev_error_count + 1;
-                if self.error_count > MAX_ATTEMPTS {
+                if self.error_count > self.max_attempts {
                     return self.fail(e)
                 } else {
                     return Ok(Async::NotReady)
@@ -342,3 +697,125 @@ impl Future for ReceiveFile {
         }
     }
 }
+
+impl<D: Datagram> Future for ReceiveFile<D> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.poll_transfer();
+        if let Ok(Async::NotReady) = result {
+            return result
+        }
+        if let Some(ref progress) = self.progress {
+            if let Ok(mut receive_stats) = progress.receive_stats.lock() {
+                *receive_stats = Some(self.stats());
+            }
+        }
+        result
+    }
+}
+
+/// A `ReceiveFile` that yields the file as it arrives, as the offset of each range of bytes along
+/// with the bytes themselves; see `ReceiveFile::into_stream`. The ranges come in order and without
+/// gaps, and the stream ends once the whole file has been received. Like `ReceiveFile`, this is
+/// polled rather than woken, so it is easiest to use as an `Iterator`.
+pub struct ReceiveStream<D: Datagram = UdpSocket> {
+    transfer: ReceiveFile<D>,
+    ranges: Arc<Mutex<VecDeque<(u64, Vec<u8>)>>>,
+    done: bool,
+}
+
+impl<D: Datagram> ReceiveStream<D> {
+    fn next_range(&self) -> Option<(u64, Vec<u8>)> {
+        self.ranges.lock().ok().and_then(|mut ranges| ranges.pop_front())
+    }
+}
+
+impl<D: Datagram> Stream for ReceiveStream<D> {
+    type Item = (u64, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(range) = self.next_range() {
+            return Ok(Async::Ready(Some(range)))
+        }
+        if self.done {
+            return Ok(Async::Ready(None))
+        }
+        // The transfer still dallies once the last range is out, so it is only done after that.
+        if self.transfer.poll()?.is_ready() {
+            self.done = true;
+        }
+        match self.next_range() {
+            Some(range) => Ok(Async::Ready(Some(range))),
+            None if self.done => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<D: Datagram> Iterator for ReceiveStream<D> {
+    type Item = Result<(u64, Vec<u8>), io::Error>;
+
+    /// Waits for the next range, or for the transfer to be over.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.poll() {
+                Ok(Async::Ready(range)) => return range.map(Ok),
+                Ok(Async::NotReady) => match self.transfer.idle_wait {
+                    Some(wait) => thread::sleep(wait),
+                    None => thread::yield_now()
+                },
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e))
+                }
+            }
+        }
+    }
+}
+
+/// Passes blocks through to `file`, and queues up every range of bytes that has become complete
+/// for a `ReceiveStream`. Blocks that arrive ahead of a missing one are held on to until it shows
+/// up; there are no more of them than the reorder window lets through, which `into_stream` keeps
+/// within `STREAM_BUFFER_LIMIT`.
+struct InOrderSink {
+    file: Box<dyn WriteSink>,
+    /// The offset of the first byte that hasn't been queued up.
+    next: u64,
+    pending: BTreeMap<u64, Vec<u8>>,
+    ranges: Arc<Mutex<VecDeque<(u64, Vec<u8>)>>>,
+}
+
+impl WriteSink for InOrderSink {
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        self.file.write_block(offset, data)?;
+        if offset < self.next {
+            return Ok(())
+        }
+        if offset > self.next {
+            self.pending.insert(offset, data.to_vec());
+            return Ok(())
+        }
+        let start = self.next;
+        let mut range = data.to_vec();
+        self.next += data.len() as u64;
+        while let Some(data) = self.pending.remove(&self.next) {
+            self.next += data.len() as u64;
+            range.extend_from_slice(&data);
+        }
+        if let Ok(mut ranges) = self.ranges.lock() {
+            ranges.push_back((start, range));
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), io::Error> {
+        self.file.finalize()
+    }
+
+    fn allocate(&mut self, len: u64) -> Result<(), io::Error> {
+        self.file.allocate(len)
+    }
+}
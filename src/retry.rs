@@ -0,0 +1,41 @@
+use std::cmp::min;
+use std::time::Duration;
+use client::MAX_ATTEMPTS;
+
+/// How a transfer retransmits when nothing arrives from the peer: the first retransmission happens
+/// after `initial_interval`, and every one after that waits `multiplier` times longer than the one
+/// before, up to `max_interval`. The transfer fails once `max_attempts` retransmissions in a row
+/// have gone unanswered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub initial_interval: Duration,
+    pub multiplier: f64,
+    pub max_interval: Duration,
+    pub max_attempts: usize,
+}
+
+impl RetryPolicy {
+    pub fn new(initial_interval: Duration, multiplier: f64, max_interval: Duration, max_attempts: usize) -> Self {
+        RetryPolicy { initial_interval, multiplier, max_interval, max_attempts }
+    }
+
+    /// Retransmits every `interval`, without backing off.
+    pub fn fixed(interval: Duration, max_attempts: usize) -> Self {
+        RetryPolicy::new(interval, 1.0, interval, max_attempts)
+    }
+
+    /// How long to wait before retransmission number `attempt` (counting from 0).
+    pub fn interval(&self, attempt: usize) -> Duration {
+        let initial = self.initial_interval.as_secs() as f64 + self.initial_interval.subsec_nanos() as f64 / 1e9;
+        let max_interval = self.max_interval.as_secs() as f64 + self.max_interval.subsec_nanos() as f64 / 1e9;
+        let secs = (initial * self.multiplier.max(1.0).powi(min(attempt, 64) as i32)).min(max_interval);
+        Duration::new(secs as u64, (secs.fract() * 1e9) as u32)
+    }
+}
+
+/// Retransmits after 1, 2, 4, ... seconds, up to 16, giving up after `MAX_ATTEMPTS` tries.
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(Duration::from_secs(1), 2.0, Duration::from_secs(16), MAX_ATTEMPTS)
+    }
+}
@@ -1,73 +1,141 @@
 use std::net::{ SocketAddr, ToSocketAddrs };
 use bit_set::BitSet;
 use bit_vec::BitVec;
-use std::fs::File;
 use std::io::{ self, Seek };
 use futures::{ Future, Poll, Async };
 use std::net::UdpSocket;
+use transport::{ Datagram, PollBudget };
 use std::time::Duration;
-use std::sync::{ Arc, Mutex };
-use memmap::{ Mmap, MmapOptions };
+use std::sync::Arc;
+use backend::BlockSource;
+use throttle::TokenBucket;
+use congestion::{ Aimd, WindowStrategy };
+use retry::RetryPolicy;
+use session::{ SendStats, TransferControl, TransferProgress };
+use timer_wheel::TimerWheel;
+use std::sync::atomic;
 use std::time::Instant;
-use std::collections::{ BinaryHeap, HashMap };
+use std::collections::{ HashMap, VecDeque };
 use error::TFTPError;
 use std::ops::*;
 use std::cmp::*;
 use header::*;
 use client::*;
 
+/// How large the window grows by default, unless a larger window is asked for to start with.
 pub const MAX_WINDOW_SIZE: usize = 256;
 
-#[derive(Clone)]
-struct BlockData {
-    pub time_sent: Instant,
-    pub block_number: usize
+/// The largest window there can be: the most the "windowsize" option (RFC 7440) allows.
+pub const WINDOW_SIZE_LIMIT: usize = 65535;
+
+/// How many blocks a `SendFile` sends before waiting for an Ack: `initial` to start with, growing
+/// to `max` while no blocks are lost (see `congestion::Aimd`, unless another `WindowStrategy` is
+/// used). A `max` of 1 is stop-and-wait.
+///
+/// A plain window size converts into one that starts out at that size and grows up to
+/// `MAX_WINDOW_SIZE`, or beyond if it is larger than that; a window size of 1 stays at 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowSize {
+    pub initial: usize,
+    pub max: usize,
 }
 
-impl PartialEq<BlockData> for BlockData {
-    fn eq(&self, other: &BlockData) -> bool {
-        self.time_sent == other.time_sent && self.block_number == other.block_number
+impl WindowSize {
+    pub fn new(initial: usize, max: usize) -> Self {
+        WindowSize { initial, max }
     }
-}
 
-impl Eq for BlockData {}
+    /// A window that stays at `size` blocks.
+    pub fn fixed(size: usize) -> Self {
+        WindowSize::new(size, size)
+    }
 
-impl PartialOrd<BlockData> for BlockData {
-    fn partial_cmp(&self, other: &BlockData) -> Option<Ordering> {
-        self.time_sent.partial_cmp(&other.time_sent)
+    /// Checks that the window starts out at 1 block or more, and can grow from there to no more
+    /// than `WINDOW_SIZE_LIMIT`.
+    pub fn validate(&self) -> Result<(), io::Error> {
+        if self.initial == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The window size must be at least 1."))
+        }
+        if self.max < self.initial {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The maximum window size can't be smaller than the initial one."))
+        }
+        if self.max > WINDOW_SIZE_LIMIT {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The window size can't be larger than {}.", WINDOW_SIZE_LIMIT)))
+        }
+        Ok(())
     }
 }
 
-impl Ord for BlockData {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.time_sent.cmp(&other.time_sent)
+impl From<usize> for WindowSize {
+    fn from(window_size: usize) -> Self {
+        match window_size {
+            0 | 1 => WindowSize::fixed(window_size),
+            _ => WindowSize::new(window_size, max(window_size, MAX_WINDOW_SIZE))
+        }
     }
 }
 
-pub struct SendFile {
-    /// The file!
-    file: File,
+/// The number of blocks `file_len` bytes make: the whole blocks, plus the final short block once the
+/// whole file is known. The final block is empty if the file is a multiple of the block size, since
+/// a short block is what ends the transfer.
+fn block_count(file_len: usize, block_size: usize, complete: bool) -> Result<usize, io::Error> {
+    if file_len >= (1 << 24) * block_size { return Err(io::Error::new(io::ErrorKind::Other, "File is too large to be sent with this block size.")) }
+    Ok(file_len / block_size + if complete { 1 } else { 0 })
+}
+
+/// The most packets (Acks, mostly) read from the peer in one go.
+const RECV_BURST: usize = 8;
+
+/// `FILL_INTERVAL` is how often a source that isn't complete, e.g. a `GrowingSource`, is checked for
+/// more of the file while there's room in the window for it.
+#[allow(non_snake_case)]
+fn FILL_INTERVAL() -> Duration { Duration::from_millis(50) }
 
-    /// A file backed buffer, allows the file to be indexed like an array!
-    file_map: Mmap,
+/// The shortest retransmission timeout measured from the round trip time, so that a very fast link
+/// doesn't have blocks sent again while their Acks are still on the way.
+#[allow(non_snake_case)]
+pub fn MIN_RETRANSMIT_TIMEOUT() -> Duration { Duration::from_millis(100) }
 
-    /// The exact length, in bytes, of file_map
+pub struct SendFile<D: Datagram = UdpSocket> {
+    /// Where the blocks of the file are read from, e.g. a memory map of it.
+    source: Box<dyn BlockSource>,
+
+    /// Where blocks are read into, for sources that don't have them in memory already.
+    block_buf: Vec<u8>,
+
+    /// The exact length, in bytes, of the file, or as much of it as the source has so far if it
+    /// isn't complete (see `BlockSource`).
     file_len: usize,
 
-    /// The UDP socket to send data through
-    socket: Arc<Mutex<UdpSocket>>,
+    /// The socket to send data through. This is shared with the `SendData` of each block.
+    socket: Arc<D>,
 
     /// The host address to send data to
     host_addr: SocketAddr,
 
+    /// The address data blocks are sent to. This is `host_addr` except in MTFTP mode, where the
+    /// blocks go to a multicast group.
+    data_addr: SocketAddr,
+
     /// Blocks that are awaiting Acks. This includes blocks that haven't actually been sent yet!
     blocks_pending_acks: BitSet,
 
-    /// The total number of blocks in the file.
+    /// The total number of blocks in the file. While the source isn't complete, this is only the
+    /// whole blocks it has so far: the short block that ends the transfer can't be told apart until
+    /// it is.
     num_blocks: usize,
 
-    /// Window size
-    window_size: usize,
+    /// The first block the peer hasn't acknowledged. The source has been told that the blocks
+    /// before it won't be read again, so Acks from before it are stale.
+    acknowledged: usize,
+
+    /// The size of each block (except the last one), in bytes.
+    block_size: usize,
+
+    /// Decides how many blocks are in flight at once; see `set_window_strategy`. When a window size
+    /// of 1 is asked for, blocks are sent one at a time, each waiting for its Ack, i.e. the window
+    /// never grows past 1.
+    congestion: Box<dyn WindowStrategy>,
 
     /// the current window range
     ///  lower bound (first) is inclusive, upper bound is exclusive
@@ -76,132 +144,409 @@ pub struct SendFile {
     /// The number of consecutive errors that have occured...
     err_counter: usize,
 
+    /// The transfer fails once more than this many errors have occured in a row, and so does
+    /// sending a single packet.
+    max_attempts: usize,
+
+    /// How many times each block that is out has been sent again, by block number.
+    block_retransmissions: HashMap<usize, usize>,
+
+    /// If set, the transfer fails once a block would be sent again more than this many times; see
+    /// `set_max_block_retransmissions`.
+    max_block_retransmissions: Option<usize>,
+
     /// For all blocks that have been sent and have not yet received an Ack, this hashmap contains
-    /// the time at which it was sent. This is in done to allow the calculation of [average_rtt]
+    /// the time at which it was first sent. This is in done to allow the calculation of [average_rtt]
     send_times: HashMap<usize, Instant>,
 
+    /// Blocks that have been sent more than once and not yet acknowledged. There's no telling which
+    /// transmission an Ack for one of these answers, so they don't go into [average_rtt] (Karn's
+    /// algorithm).
+    retransmitted: BitSet,
+
     /// The exponential moving average of the round trip time
     average_rtt: Duration,
 
     /// The number of consecutive timeouts encountered
     timeouts: usize,
+
+    /// When each block that is out gets resent if no Ack covers it by then, by block number. Only
+    /// set if there is a retransmission timeout; see `retransmit_timeout`.
+    retransmit_deadlines: HashMap<usize, Instant>,
+
+    /// The deadlines in `retransmit_deadlines`, in the order they go off. Deadlines that were
+    /// moved or acknowledged in the meantime are ignored when they do.
+    retransmit_timers: TimerWheel<(usize, Instant)>,
+
+    /// Packets that arrived in the same burst as the last one dealt with, to be dealt with next.
+    received: VecDeque<Result<Header, TFTPError>>,
+
+    /// Where bursts of packets are received into.
+    recv_bufs: Vec<Vec<u8>>,
+
+    /// If set, the window is resent whenever no Ack arrives for this long (e.g. negotiated with the
+    /// "utimeout" option). Otherwise we wait for Acks indefinitely.
+    timeout: Option<Duration>,
+
+    /// If set, limits the rate at which data blocks are sent.
+    rate_limit: Option<TokenBucket>,
+
+    /// Where to report how far along the transfer is, if anywhere.
+    progress: Option<Arc<TransferProgress>>,
+
+    /// The highest block number that has been sent so far, if any.
+    highest_sent: Option<usize>,
+
+    /// If set, decides when the window is resent and when to give up; see `set_retry_policy`.
+    retry_policy: Option<RetryPolicy>,
+
+    /// The time at which the transfer started.
+    start_time: Instant,
+
+    /// The transfer fails if it isn't done this long after it started. `None` means there is no
+    /// deadline.
+    transfer_timeout: Option<Duration>,
+
+    /// The transfer fails if nothing arrives from the peer for this long while blocks are waiting
+    /// to be acknowledged. `None` means it waits forever.
+    idle_timeout: Option<Duration>,
+
+    /// The time at which something last arrived from the peer, or at which nothing was left waiting
+    /// on it.
+    last_heard: Instant,
+
+    /// If set, the transfer holds off while this is paused.
+    control: Option<Arc<TransferControl>>,
+
+    /// How often `run` gives up the thread.
+    poll_budget: PollBudget,
+
+    /// Whether every block ends with a CRC32, as negotiated with `BLOCK_CRC_OPTION`.
+    block_crc: bool,
+
+    /// What the transfer has done so far; see `stats`.
+    stats: SendStats,
+
+    /// Set when the last read came back empty without the socket waiting out its timeout, i.e. the
+    /// socket doesn't block: how long `run` should sleep for rather than poll again straight away.
+    idle_wait: Option<Duration>,
+
+    /// Whether the first window has been sent. That waits for the first poll, so that everything
+    /// set up after the transfer is created, e.g. `set_block_crc`, applies to it as well.
+    started: bool,
 }
 
-impl SendFile {
-    pub fn new(socket: Arc<Mutex<UdpSocket>>, host_addr: SocketAddr, file: File, window_size: usize) -> Result<Self, io::Error> {
-	if window_size <= 1 { unsafe { STOP_AND_WAIT = true } }
-        let file_map = unsafe { MmapOptions::new().map(&file)? };
-        let file_len: usize = file_map.len();
-        if file_len > (1 << 24) * MAX_DATA_LEN { return Err(io::Error::new(io::ErrorKind::Other, "Files greater than 8GB in size cannot be sent.")) }
-        // The number of whole blocks, plus another block if there is extra
-        let num_blocks: usize = file_len / MAX_DATA_LEN + (if file_len & (MAX_DATA_LEN - 1) == 0 { 0 } else { 1 });
-	let window_size = if window_size <= 1 { 1 } else { 2 };
-        let mut r = SendFile {
-            file,
-            file_map,
-            file_len,
-            socket,
-            host_addr,
-            num_blocks,
-            window_size: window_size,
-            err_counter: 0,
-            window_range: (0, window_size),
-            blocks_pending_acks: BitSet::from_bit_vec(BitVec::from_elem(num_blocks, true)),
-            send_times: HashMap::with_capacity(window_size),
-            average_rtt: Duration::from_secs(1),
-            timeouts: 0
-        };
-        r.init(window_size)
+impl<D: Datagram> SendFile<D> {
+    pub fn new<S: BlockSource + 'static, W: Into<WindowSize>>(socket: D, host_addr: SocketAddr, source: S, window_size: W, block_size: usize) -> Result<Self, io::Error> {
+        SendFile::create(socket, host_addr, Box::new(source), window_size.into(), block_size)?.init()
     }
 
     // TODO: Fix this when done
-    pub fn new_server(socket: Arc<Mutex<UdpSocket>>, host_addr: SocketAddr, file: File, window_size: usize) -> Result<Self, io::Error> {
-	if window_size <= 1 { unsafe { STOP_AND_WAIT = true } }
-        let file_map = unsafe { MmapOptions::new().map(&file)? };
-        let file_len: usize = file_map.len();
-        if file_len > (1 << 24) * MAX_DATA_LEN { return Err(io::Error::new(io::ErrorKind::Other, "Files greater than 8GB in size cannot be sent.")) }
-        // The number of whole blocks, plus another block if there is extra
-        let num_blocks: usize = file_len / MAX_DATA_LEN + (if file_len & (MAX_DATA_LEN - 1) == 0 { 0 } else { 1 });
-	let window_size = if window_size <= 1 { 1 } else { 2 };
-        let mut r = SendFile {
-            file,
-            file_map,
+    pub fn new_server<S: BlockSource + 'static, W: Into<WindowSize>>(socket: D, host_addr: SocketAddr, source: S, window_size: W, block_size: usize) -> Result<Self, io::Error> {
+        SendFile::create(socket, host_addr, Box::new(source), window_size.into(), block_size)?.server_init()
+    }
+
+    /// Serves a read request MTFTP style: Acks are still expected from `host_addr`, but the data
+    /// blocks are sent to `data_addr` (usually a multicast group) so that every client listening
+    /// there receives the file as well.
+    pub fn new_mtftp<S: BlockSource + 'static, W: Into<WindowSize>>(socket: D, host_addr: SocketAddr, data_addr: SocketAddr, source: S, window_size: W, block_size: usize) -> Result<Self, io::Error> {
+        let mut r = SendFile::create(socket, host_addr, Box::new(source), window_size.into(), block_size)?;
+        r.data_addr = data_addr;
+        r.server_init()
+    }
+
+    fn create(socket: D, host_addr: SocketAddr, source: Box<dyn BlockSource>, window: WindowSize, block_size: usize) -> Result<Self, io::Error> {
+        window.validate()?;
+        let file_len: usize = source.len();
+        let num_blocks = block_count(file_len, block_size, source.is_complete())?;
+        let window_size = window.initial;
+        Ok(SendFile {
+            source,
+            block_buf: vec![0u8; block_size],
             file_len,
-            socket,
+            socket: Arc::new(socket),
             host_addr,
+            data_addr: host_addr,
             num_blocks,
-            window_size: window_size,
+            acknowledged: 0,
+            block_size,
+            congestion: Box::new(Aimd::from(window)),
             err_counter: 0,
-            window_range: (0, window_size),
+            max_attempts: MAX_ATTEMPTS,
+            block_retransmissions: HashMap::new(),
+            max_block_retransmissions: None,
+            window_range: (0, min(window_size, num_blocks)),
             blocks_pending_acks: BitSet::from_bit_vec(BitVec::from_elem(num_blocks, true)),
             send_times: HashMap::with_capacity(window_size),
+            retransmitted: BitSet::with_capacity(num_blocks),
             average_rtt: Duration::from_secs(1),
-            timeouts: 0
-        };
-       
-        r.server_init(window_size) 
+            timeouts: 0,
+            retransmit_deadlines: HashMap::with_capacity(window_size),
+            retransmit_timers: TimerWheel::new(),
+            received: VecDeque::new(),
+            recv_bufs: vec![vec![0u8; MAX_DATA_LEN + DATA_HEADER_LEN]; RECV_BURST],
+            timeout: None,
+            rate_limit: None,
+            progress: None,
+            highest_sent: None,
+            retry_policy: None,
+            start_time: Instant::now(),
+            transfer_timeout: None,
+            idle_timeout: Some(TOTAL_TIMEOUT()),
+            last_heard: Instant::now(),
+            control: None,
+            poll_budget: PollBudget::default(),
+            block_crc: false,
+            stats: SendStats::default(),
+            idle_wait: None,
+            started: false
+        })
     }
 
-    fn server_init(mut self, window_size: usize) -> Result<Self, io::Error> {
-        let mut a = Header::Ack(AckHeader::new(0));
-        if let Ok(ref mut s) = self.socket.try_lock() {
-            s.set_read_timeout(Some(self.average_rtt.mul(2)))?;
-            match a.send(self.host_addr.clone(), s) {
-                Ok(()) => {},
-                Err(e) => return Err(e)
+    /// Sets the retransmission timeout; see `timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.reset_retransmit_timers();
+    }
+
+    /// Sets how many errors in a row it takes for the transfer to fail; see `MAX_ATTEMPTS`.
+    pub fn set_max_attempts(&mut self, max_attempts: usize) {
+        self.max_attempts = max_attempts;
+    }
+
+    /// Fails the transfer with `TFTPError::RetransmitLimitExceeded` once any one block would have to
+    /// be sent again more than `max_block_retransmissions` times, whatever made it go missing. By
+    /// default there is no such limit, and only errors in a row count (see `set_max_attempts`).
+    pub fn set_max_block_retransmissions(&mut self, max_block_retransmissions: Option<usize>) {
+        self.max_block_retransmissions = max_block_retransmissions;
+    }
+
+    /// Resends the window according to `retry_policy` when no Ack arrives, failing once it runs out
+    /// of attempts. A timeout negotiated with the peer (see `set_timeout`) is kept to, without
+    /// backing off.
+    pub fn set_retry_policy(&mut self, retry_policy: Option<RetryPolicy>) {
+        self.retry_policy = retry_policy;
+        self.reset_retransmit_timers();
+    }
+
+    /// Sets how long the whole transfer may take; by default there is no limit.
+    pub fn set_transfer_timeout(&mut self, transfer_timeout: Option<Duration>) {
+        self.transfer_timeout = transfer_timeout;
+    }
+
+    /// Sets how long the peer may go quiet before the transfer fails; see `TOTAL_TIMEOUT`. Time
+    /// spent with every block sent acknowledged, e.g. waiting on the writer of a `GrowingSource`,
+    /// doesn't count.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Sets how often `run` gives up the thread; see `PollBudget`.
+    pub fn set_poll_budget(&mut self, poll_budget: PollBudget) {
+        self.poll_budget = poll_budget;
+    }
+
+    /// How often `run` gives up the thread; see `set_poll_budget`.
+    pub fn poll_budget(&self) -> PollBudget {
+        self.poll_budget
+    }
+
+    /// How long the transfer would have waited on its socket, if its last poll found nothing to
+    /// read without the socket waiting; see `PollBudget::run`.
+    pub fn idle_wait(&self) -> Option<Duration> {
+        self.idle_wait
+    }
+
+    /// The retry policy in effect, taking a negotiated timeout into account.
+    fn effective_retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry_policy.map(|policy| match self.timeout {
+            Some(timeout) => RetryPolicy::fixed(timeout, policy.max_attempts),
+            None => policy
+        })
+    }
+
+    /// How long to wait for an Ack before resending a block: the retry policy's interval for the
+    /// current attempt, or the timeout negotiated with the peer. Without either, it is twice the
+    /// average round trip time (but no less than `MIN_RETRANSMIT_TIMEOUT`), doubled for every
+    /// timeout in a row.
+    fn retransmit_timeout(&self) -> Duration {
+        match self.effective_retry_policy() {
+            Some(policy) => policy.interval(self.timeouts),
+            None => self.timeout.unwrap_or_else(|| {
+                max(self.average_rtt.mul(2), MIN_RETRANSMIT_TIMEOUT()).mul(1 << min(self.err_counter, 6) as u32)
+            })
+        }
+    }
+
+    /// Resends `block_number` if no Ack covers it within the retransmission timeout.
+    fn set_retransmit_timer(&mut self, block_number: usize, sent_at: Instant) {
+        let deadline = sent_at + self.retransmit_timeout();
+        self.retransmit_deadlines.insert(block_number, deadline);
+        self.retransmit_timers.insert(deadline, (block_number, deadline));
+    }
+
+    /// Restarts the retransmission timers of the blocks that are out, e.g. those sent on creating
+    /// the transfer, once the timeout has changed.
+    fn reset_retransmit_timers(&mut self) {
+        self.retransmit_deadlines.clear();
+        let now = Instant::now();
+        for block_number in self.window_range.0..self.next_unsent() {
+            self.set_retransmit_timer(block_number, now);
+        }
+    }
+
+    /// The blocks that are out and whose retransmission timer has gone off, soonest first.
+    fn expired_blocks(&mut self) -> Vec<usize> {
+        let (window_start, next_unsent) = (self.window_range.0, self.next_unsent());
+        let mut expired = Vec::new();
+        for (block_number, deadline) in self.retransmit_timers.expire(Instant::now()) {
+            if block_number >= window_start && block_number < next_unsent && self.retransmit_deadlines.get(&block_number) == Some(&deadline) {
+                self.retransmit_deadlines.remove(&block_number);
+                expired.push(block_number);
+            }
+        }
+        expired
+    }
+
+    /// Resends the blocks whose retransmission timer has gone off that are still in the window. The
+    /// timer of the first block that is yet to be acknowledged going off is what counts as a
+    /// timeout, and the transfer fails if that has happened too many times in a row; blocks that
+    /// were sent after it are part of the same timeout.
+    fn retransmit(&mut self, expired: Vec<usize>) -> Result<(), io::Error> {
+        if expired.contains(&self.window_range.0) {
+            self.stats.timeouts += 1;
+            self.congestion.on_timeout(self.window_range.0, self.next_unsent());
+            self.window_range.1 = min(self.window_range.0 + self.window(), self.num_blocks);
+            match self.effective_retry_policy() {
+                // Giving up is left to `poll`.
+                Some(policy) => {
+                    self.timeouts += 1;
+                    if self.timeouts > policy.max_attempts {
+                        return Ok(())
+                    }
+                },
+                None => {
+                    self.err_counter += 1;
+                    if self.err_counter > self.max_attempts {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "No Ack arrived after retransmitting the maximum number of times."))
+                    }
+                }
             }
-        } else { unreachable!()}
-        self.send_window()?; 
+        }
+        // Blocks the window has shrunk away from go out again when it gets back to them.
+        let window_end = self.window_range.1;
+        self.send_block_numbers(expired.into_iter().filter(|&block_number| block_number < window_end).collect())
+    }
+
+    /// The first block that hasn't been sent yet.
+    fn next_unsent(&self) -> usize {
+        self.highest_sent.map_or(0, |highest_sent| highest_sent + 1)
+    }
+
+    /// How many blocks may be in flight, as the window strategy has it.
+    fn window(&self) -> usize {
+        min(max(self.congestion.window(), 1), WINDOW_SIZE_LIMIT)
+    }
+
+    /// Adapts the window with `strategy` from now on, instead of `Aimd`. The window that is out
+    /// already is left as it is; the strategy's window applies once it moves along.
+    pub fn set_window_strategy(&mut self, strategy: Box<dyn WindowStrategy>) {
+        self.congestion = strategy;
+        if let Some(ref progress) = self.progress {
+            progress.window_size.store(self.window(), atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Ends every block with a CRC32 of the packet, and resends blocks the peer NAKs; this has to
+    /// have been negotiated with `BLOCK_CRC_OPTION`.
+    pub fn set_block_crc(&mut self, block_crc: bool) {
+        self.block_crc = block_crc;
+    }
+
+    /// Limits the rate at which data is sent to `bytes_per_sec`, or lifts the limit if `None`.
+    /// Blocks are paced out one by one rather than sending a whole window at once.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        // Allow bursts of up to 50ms worth of data, but at least one whole block.
+        let block_len = (self.block_size + DATA_HEADER_LEN) as u64;
+        self.rate_limit = bytes_per_sec.map(|rate| TokenBucket::new(rate, max(rate / 20, block_len)));
+    }
+
+    /// Reports the progress of the transfer to `progress` from now on.
+    pub fn set_progress(&mut self, progress: Arc<TransferProgress>) {
+        progress.window_size.store(self.window(), atomic::Ordering::Relaxed);
+        self.progress = Some(progress);
+    }
+
+    /// What the transfer has done so far. This is also handed to the `TransferProgress` once the
+    /// transfer is over, successfully or not.
+    pub fn stats(&self) -> SendStats {
+        SendStats { window_size: self.window(), ..self.stats }
+    }
+
+    /// The socket the transfer is done through.
+    pub fn socket(&self) -> &D {
+        &self.socket
+    }
+
+    /// Holds off on sending data whenever `control` is paused.
+    pub fn set_control(&mut self, control: Arc<TransferControl>) {
+        self.control = Some(control);
+    }
+
+    fn server_init(mut self) -> Result<Self, io::Error> {
+        let mut a = Header::Ack(AckHeader::new(0));
+        self.socket.set_read_timeout(Some(self.average_rtt.mul(2)))?;
+        match a.send(self.host_addr.clone(), &*self.socket) {
+            Ok(()) => {},
+            Err(e) => return Err(e)
+        }
         Ok(self)
     }
 
-    fn init(mut self, window_size: usize) -> Result<Self, io::Error> {
-        // Receive an Ack for the write request... Try several times to receive an Ack
+    fn init(mut self) -> Result<Self, io::Error> {
+        // Receive an Ack for the write request, waiting as long as for an Ack to a block. Block 0
+        // hasn't been sent yet, so the timer only limits the wait.
+        self.set_retransmit_timer(0, Instant::now());
         match self.receive_header() {
             Ok(Some(Header::Ack(ack))) => { /* cool */ },
+            Ok(Some(Header::Error(err_header))) => return Err(TFTPError::ErrorReceived(err_header.error_code, err_header.error_message).into()),
             _ =>return Err(io::Error::new(io::ErrorKind::InvalidData, "Did not receive an ACK for the write request."))
         }
-        self.send_window()?;
-
         Ok(self)
     }
 
     pub fn run(mut self) -> Result<(), io::Error> {
-        loop {
-            let r = self.poll();
-            match r {
-                Ok(Async::NotReady) => continue,
-                Ok(Async::Ready(())) => return Ok(()),
-                Err(e) => return Err(e)
-            }
-        }
+        let poll_budget = self.poll_budget;
+        poll_budget.run(&mut self, |transfer| transfer.idle_wait)
     }
 
-    pub fn get_block_n(&self, block_number: usize) -> Option<SendData> {
-        if block_number >= self.num_blocks { return None }
+    pub fn get_block_n(&mut self, block_number: usize) -> Result<Option<SendData<D>>, io::Error> {
+        if block_number >= self.num_blocks { return Ok(None) }
 
-        let mut data = [0u8; MAX_DATA_LEN];
-        if block_number == self.num_blocks - 1 {
-            let tail_len = self.file_len - block_number * MAX_DATA_LEN;
-            data[0..tail_len]
-                .clone_from_slice(&self.file_map[block_number * MAX_DATA_LEN .. self.file_len]);
-            SendData::new(&data[0..(self.file_len - block_number * MAX_DATA_LEN)], block_number, self.host_addr.clone(), self.socket.clone())
+        let start = block_number * self.block_size;
+        let len = min(self.block_size, self.file_len - start);
+        let max_attempts = self.max_attempts;
+        let data = self.source.read_block(start, &mut self.block_buf[0..len])?;
+        let block = if self.block_crc {
+            SendData::with_crc(data, block_number, self.data_addr.clone(), self.socket.clone())
         } else {
-            data[..].clone_from_slice(&self.file_map[block_number * MAX_DATA_LEN..block_number * (MAX_DATA_LEN) + MAX_DATA_LEN]);
-            SendData::new(&data, block_number, self.host_addr.clone(), self.socket.clone())
-        }
+            SendData::new(data, block_number, self.data_addr.clone(), self.socket.clone())
+        };
+        Ok(block.map(|mut block| { block.max_attempts = max_attempts; block }))
     }
 
-    fn send_data(&mut self, mut to_send: SendData) -> Result<(), io::Error> {
+    fn send_data(&mut self, mut to_send: SendData<D>) -> Result<(), io::Error> {
         let time_sent = Instant::now();
         match to_send.poll() {
             Ok(Async::Ready(block_number)) => {
-                *self.send_times.entry(block_number).or_insert(time_sent) = time_sent;
+                self.send_times.entry(block_number).or_insert(time_sent);
+                self.stats.blocks_sent += 1;
+                self.stats.bytes_sent += to_send.packet().len();
                 Ok(())
             },
             // Failed to send again... There is a maximum number of times that a packet can be sent so try it again.
             Ok(Async::NotReady) => {
-                if to_send.send_attempts < MAX_ATTEMPTS {
+                if to_send.send_attempts < self.max_attempts {
                     Ok(())
                 } else {
                     Err(io::Error::new(io::ErrorKind::Other, "Failed to send packet too many times consecutively."))
@@ -212,38 +557,48 @@ impl SendFile {
     }
 
     fn handle_ack(&mut self, ack_header: AckHeader) -> Poll<(), io::Error> {
+        if ack_header.block_number + 1 < self.acknowledged {
+            return Ok(Async::NotReady)
+        }
         if ack_header.block_number < self.window_range.0 {
-		for i in ack_header.block_number + 1..self.window_range.0 {
-			self.blocks_pending_acks.insert(i);
-		}
-		self.window_range.0 = ack_header.block_number + 1;
-		self.window_range.1 = self.window_range.0 + self.window_size;
-	} else {
-        
-        // If the whole window we sent last time was received, increase it!
-        if !unsafe { STOP_AND_WAIT } { 
-	if ack_header.block_number + 1 == self.window_range.1 {
-    	    self.window_size <<= 1;
-            if self.window_size == 0 { self.window_size == 1; }
-            else if self.window_size > MAX_WINDOW_SIZE { self.window_size = MAX_WINDOW_SIZE; }
-        } else { // otherwise make it smaller..
-            self.window_size >>= 1;
-            if self.window_size == 0 { self.window_size == 1; }
-        }}
-	}
+            // The peer repeated an Ack, so the block after it never arrived.
+            self.congestion.on_loss(ack_header.block_number + 1, self.next_unsent());
+            for i in ack_header.block_number + 1..self.window_range.0 {
+                self.blocks_pending_acks.insert(i);
+            }
+            self.window_range.0 = ack_header.block_number + 1;
+        } else {
+            self.congestion.on_ack(ack_header.block_number + 1 - self.window_range.0);
+        }
 
+        if ack_header.block_number >= self.window_range.0 {
+            self.timeouts = 0;
+        }
+        // Every block the Ack covers that was only sent once gives a round trip time.
         for block_number in self.window_range.0..=(ack_header.block_number as usize) {
             self.blocks_pending_acks.remove(block_number);
-            if let Some(instant) = self.send_times.remove(&(ack_header.block_number as usize)) {
-                self.update_average_rtt(instant.elapsed());
+            let retransmitted = self.retransmitted.remove(block_number);
+            self.retransmit_deadlines.remove(&block_number);
+            self.block_retransmissions.remove(&block_number);
+            if let Some(instant) = self.send_times.remove(&block_number) {
+                if !retransmitted {
+                    self.update_average_rtt(instant.elapsed());
+                }
             }
         }
 
         use std::cmp::min;
         let new_lower = ack_header.block_number + 1;
-        self.window_range = (new_lower, min(new_lower + self.window_size, self.num_blocks));
+        self.window_range = (new_lower, min(new_lower + self.window(), self.num_blocks));
+        self.acknowledged = new_lower;
+        self.source.release(new_lower * self.block_size);
+        if let Some(ref progress) = self.progress {
+            progress.bytes_transferred.store(min(new_lower * self.block_size, self.file_len), atomic::Ordering::Relaxed);
+            progress.window_size.store(self.window(), atomic::Ordering::Relaxed);
+            progress.notify();
+        }
         
-        if self.window_range.0 == self.num_blocks {
+        if self.window_range.0 == self.num_blocks && self.source.is_complete() {
             Ok(Async::Ready(()))
         } else {
             self.send_window()?;
@@ -251,68 +606,258 @@ impl SendFile {
         }
     }
 
+    /// Resends a block the peer NAKed because it arrived corrupted. Corruption isn't congestion, so
+    /// the window is left as it is. NAKs for blocks that are acknowledged already, or that haven't
+    /// been sent, are ignored.
+    fn handle_nak(&mut self, nak_header: NakHeader) -> Poll<(), io::Error> {
+        let block_number = nak_header.block_number;
+        if !self.block_crc || block_number < self.window_range.0 || block_number >= self.next_unsent() {
+            return Ok(Async::NotReady)
+        }
+        self.send_block_numbers(vec![block_number])?;
+        Ok(Async::NotReady)
+    }
+
     fn send_window(&mut self) -> Result<(), io::Error> {
-	for block_number in self.window_range.0..self.window_range.1 {
-	    if let Some(block) = self.get_block_n(block_number) {
+        self.fill_window()?;
+        let window = (self.window_range.0..self.window_range.1).collect();
+        self.send_block_numbers(window)
+    }
+
+    /// Sends whatever blocks a source that isn't complete has got since they last went out, as far
+    /// as there's room in the window for.
+    fn send_appended(&mut self) -> Result<(), io::Error> {
+        self.fill_window()?;
+        let appended = (max(self.next_unsent(), self.window_range.0)..self.window_range.1).collect();
+        self.send_block_numbers(appended)
+    }
+
+    /// Fills the window with as many blocks as the source has for it.
+    fn fill_window(&mut self) -> Result<(), io::Error> {
+        // A byte past the window tells whether its last block is a whole one.
+        let window_end = self.window_range.0 + self.window();
+        self.fill_source(window_end * self.block_size + 1)?;
+        self.window_range.1 = min(window_end, self.num_blocks);
+        Ok(())
+    }
+
+    /// Gets the file from a source that isn't complete up to `end` bytes in, and makes the blocks
+    /// that brings in ready to be sent.
+    fn fill_source(&mut self, end: usize) -> Result<(), io::Error> {
+        if self.source.is_complete() {
+            return Ok(())
+        }
+        self.file_len = self.source.fill(end)?;
+        let num_blocks = block_count(self.file_len, self.block_size, self.source.is_complete())?;
+        for block_number in self.num_blocks..num_blocks {
+            self.blocks_pending_acks.insert(block_number);
+        }
+        self.num_blocks = num_blocks;
+        Ok(())
+    }
+
+    /// Sends the given blocks, whether for the first time or again, and sets their retransmission
+    /// timers.
+    fn send_block_numbers(&mut self, block_numbers: Vec<usize>) -> Result<(), io::Error> {
+        if let Some(highest_sent) = self.highest_sent {
+            let max_block_retransmissions = self.max_block_retransmissions;
+            for &block_number in block_numbers.iter().filter(|&&block_number| block_number <= highest_sent) {
+                let retransmissions = self.block_retransmissions.entry(block_number).or_insert(0);
+                *retransmissions += 1;
+                if let Some(max) = max_block_retransmissions.filter(|&max| *retransmissions > max) {
+                    return Err(TFTPError::RetransmitLimitExceeded(block_number, max).into())
+                }
+            }
+        }
+        let mut blocks: Vec<SendData<D>> = Vec::with_capacity(block_numbers.len());
+        for block_number in block_numbers {
+            blocks.extend(self.get_block_n(block_number)?);
+        }
+        let block_numbers: Vec<usize> = blocks.iter().map(|block| block.block_number).collect();
+        if self.rate_limit.is_some() {
+            for block in blocks {
+                if let Some(ref mut rate_limit) = self.rate_limit {
+                    rate_limit.take(block.packet().len());
+                }
                 self.send_data(block)?;
             }
+        } else {
+            self.send_blocks(blocks)?;
+        }
+        for &block_number in &block_numbers {
+            match self.highest_sent {
+                Some(highest_sent) if block_number <= highest_sent => {
+                    self.retransmitted.insert(block_number);
+                    self.stats.retransmissions += 1;
+                    if let Some(ref progress) = self.progress {
+                        progress.retransmissions.fetch_add(1, atomic::Ordering::Relaxed);
+                    }
+                },
+                _ => self.highest_sent = Some(block_number)
+            }
+        }
+        // Blocks that couldn't be sent just now are covered by their timer as well.
+        let sent_at = Instant::now();
+        for block_number in block_numbers {
+            self.set_retransmit_timer(block_number, sent_at);
+        }
+        Ok(())
+    }
+
+    /// Sends `blocks` with as few system calls as the socket allows (see
+    /// `Datagram::send_to_many`). If that fails, the rest are sent one at a time, which is where
+    /// errors are dealt with.
+    fn send_blocks(&mut self, mut blocks: Vec<SendData<D>>) -> Result<(), io::Error> {
+        while !blocks.is_empty() {
+            let time_sent = Instant::now();
+            let sent = {
+                let packets: Vec<&[u8]> = blocks.iter().map(|block| block.packet()).collect();
+                self.socket.send_to_many(&packets, self.data_addr)
+            };
+            match sent {
+                Ok(sent) if sent > 0 => for block in blocks.drain(..sent) {
+                    self.send_times.entry(block.block_number).or_insert(time_sent);
+                    self.stats.blocks_sent += 1;
+                    self.stats.bytes_sent += block.packet().len();
+                },
+                _ => {
+                    for block in blocks {
+                        self.send_data(block)?;
+                    }
+                    return Ok(())
+                }
+            }
         }
         Ok(())
     }
 
     fn handle_error(&mut self, err_header: ErrorHeader) -> Poll<(), io::Error> {
-        Err(io::Error::new(io::ErrorKind::Other, err_header.error_message))
+        Err(TFTPError::ErrorReceived(err_header.error_code, err_header.error_message).into())
     }
 
     fn receive_header(&mut self) -> Result<Option<Header>, io::Error> {
-        if let Ok(ref mut socket) = self.socket.clone().try_lock() {
-            socket.set_read_timeout(None)?;  
-    	    match Header::recv(self.host_addr.clone(), socket) {
-                Ok(r)   => { self.err_counter = 0; Ok(Some(r)) },
-                Err(e)  => {
-                    if self.err_counter > MAX_ATTEMPTS {
-                        if let TFTPError::IOError(ioerr) = e {
-                            Err(ioerr)
-                        } else {
-                            Ok(None)
+        let socket = &*self.socket.clone();
+        // Don't wait past the next retransmission timer or the deadline, so that they go off on time.
+        let read_timeout = self.retransmit_timers.next_expiry().map(|retransmit_at| {
+            max(retransmit_at.saturating_duration_since(Instant::now()), Duration::from_millis(1))
+        });
+        let read_timeout = match self.transfer_timeout {
+            Some(transfer_timeout) => {
+                let remaining = max(transfer_timeout.checked_sub(self.start_time.elapsed()).unwrap_or_default(), Duration::from_millis(1));
+                Some(read_timeout.map_or(remaining, |read_timeout| min(read_timeout, remaining)))
+            },
+            None => read_timeout
+        };
+        // Nor past checking a source that isn't complete for more of the file.
+        let read_timeout = if self.source.is_complete() {
+            read_timeout
+        } else {
+            Some(read_timeout.map_or(FILL_INTERVAL(), |read_timeout| min(read_timeout, FILL_INTERVAL())))
+        };
+        self.idle_wait = None;
+        let received = match self.received.pop_front() {
+            Some(received) => received,
+            None => {
+                socket.set_read_timeout(read_timeout)?;
+                let waited_from = Instant::now();
+                match Header::recv_many(self.host_addr.clone(), socket, &mut self.recv_bufs) {
+                    Ok(received) => {
+                        self.received.extend(received);
+                        match self.received.pop_front() {
+                            Some(received) => received,
+                            None => return Ok(None)
                         }
-                    } else {
-                        if let TFTPError::IOError(ioerr) = e {
-                            match ioerr.kind() { 
-                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => self.send_window()?,
-                                _ => {}
-                            }
+                    },
+                    Err(e) => {
+                        let wait = read_timeout.unwrap_or(self.average_rtt);
+                        let timed_out = e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut;
+                        if timed_out && waited_from.elapsed() < wait / 2 {
+                            self.idle_wait = Some(wait);
                         }
-                        self.err_counter += 1;
+                        Err(TFTPError::IOError(e))
+                    }
+                }
+            }
+        };
+        match received {
+            Ok(r)   => { self.err_counter = 0; self.last_heard = Instant::now(); Ok(Some(r)) },
+            Err(TFTPError::WrongHost(stray)) => {
+                let _ = Header::reject_unknown_tid(stray, socket);
+                Ok(None)
+            },
+            Err(e @ TFTPError::PeerUnreachable(_)) => Err(e.into()),
+            // The retransmission timers are seen to on the next poll.
+            Err(TFTPError::IOError(ref ioerr)) if ioerr.kind() == io::ErrorKind::WouldBlock || ioerr.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(e)  => {
+                if self.err_counter > self.max_attempts {
+                    if let TFTPError::IOError(ioerr) = e {
+                        Err(ioerr)
+                    } else {
                         Ok(None)
                     }
+                } else {
+                    self.err_counter += 1;
+                    Ok(None)
                 }
             }
-        } else {
-            Ok(None)
         }
     }
 
     fn update_average_rtt(&mut self, rtt: Duration) {
         // hopefully this will be compiles and optimized to 5 bit shifts and one subtract op.
         self.average_rtt = rtt.div(16) + self.average_rtt.mul(15).div(16);
-        if let Ok(ref mut s) = self.socket.try_lock() {
-            s.set_read_timeout(Some(self.average_rtt.clone()));
-        }
+        self.stats.rtt_samples += 1;
     }
 }
 
-impl Future for SendFile {
-    type Item = ();
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if self.window_range.0 == self.num_blocks && self.blocks_pending_acks.is_empty() {
+impl<D: Datagram> SendFile<D> {
+    fn poll_transfer(&mut self) -> Poll<(), io::Error> {
+        if self.transfer_timeout.map_or(false, |transfer_timeout| self.start_time.elapsed() > transfer_timeout) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "The transfer did not finish in time."))
+        }
+        if let Some(policy) = self.effective_retry_policy() {
+            if self.timeouts > policy.max_attempts {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "No Ack arrived after retransmitting the maximum number of times."))
+            }
+        }
+        if self.window_range.0 >= self.next_unsent() {
+            // Nothing is waiting on the peer.
+            self.last_heard = Instant::now();
+        } else if self.idle_timeout.map_or(false, |idle_timeout| self.last_heard.elapsed() > idle_timeout) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "TFTP connection appears to be dead."))
+        }
+        if let Some(control) = self.control.clone() {
+            if control.is_paused() {
+                // Acks queue up in the socket, and are dealt with on resuming.
+                if !control.wait_for_resume(PAUSE_CHECK_INTERVAL()) {
+                    self.timeouts = 0;
+                    self.err_counter = 0;
+                    self.last_heard = Instant::now();
+                }
+                return Ok(Async::NotReady)
+            }
+        }
+        if !self.started {
+            self.started = true;
+            self.send_window()?;
+        }
+        if self.window_range.0 == self.num_blocks && self.blocks_pending_acks.is_empty() && self.source.is_complete() {
             return Ok(Async::Ready(()));
         } else {
+            if !self.source.is_complete() && self.next_unsent() < self.window_range.0 + self.window() {
+                self.send_appended()?;
+            }
+            // Running out of retransmissions fails the transfer, whereas a read that times out below
+            // only means there is nothing to do yet.
+            let expired = self.expired_blocks();
+            if !expired.is_empty() {
+                self.retransmit(expired)?;
+            }
             match self.receive_header() {
                 Ok(Some(Header::Ack(ack_header))) => self.handle_ack(ack_header),
 
+                Ok(Some(Header::Nak(nak_header))) => self.handle_nak(nak_header),
+
                 Ok(Some(Header::Error(err_header))) => self.handle_error(err_header),
 
                 // This means either a header type we don't want was received, or a tftp error occured
@@ -333,3 +878,20 @@ impl Future for SendFile {
     }
 }
 
+impl<D: Datagram> Future for SendFile<D> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.poll_transfer();
+        if let Ok(Async::NotReady) = result {
+            return result
+        }
+        if let Some(ref progress) = self.progress {
+            if let Ok(mut send_stats) = progress.send_stats.lock() {
+                *send_stats = Some(self.stats());
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,331 @@
+use std::io;
+use std::net::SocketAddr;
+use std::collections::{ BTreeMap, HashMap, VecDeque };
+use std::sync::{ Arc, Condvar, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, SystemTime };
+
+use header::RequestType;
+
+/// The number of finished transfers a server remembers by default.
+pub const DEFAULT_HISTORY_LEN: usize = 256;
+
+/// How far along a transfer is. This is kept up to date by the `SendFile` or `ReceiveFile` doing
+/// the transfer, and can be read from other threads while it runs.
+#[derive(Default)]
+pub struct TransferProgress {
+    /// The number of bytes of the file that have made it to the receiver so far.
+    pub bytes_transferred: AtomicUsize,
+    /// The current window size of the sender. This stays 0 while receiving, since the window is
+    /// up to the peer.
+    pub window_size: AtomicUsize,
+    /// The number of data blocks that were sent again (when sending), or that arrived again (when
+    /// receiving).
+    pub retransmissions: AtomicUsize,
+    /// The size of the file, if known.
+    pub total_bytes: Option<u64>,
+    /// How sending the file went, once it is over; see `SendStats`.
+    pub send_stats: Mutex<Option<SendStats>>,
+    /// How receiving the file went, once it is over; see `ReceiveStats`.
+    pub receive_stats: Mutex<Option<ReceiveStats>>,
+    /// Told about every bit of progress, if set.
+    listener: Option<Arc<dyn ProgressListener>>,
+}
+
+impl TransferProgress {
+    pub fn new(total_bytes: Option<u64>, listener: Option<Arc<dyn ProgressListener>>) -> Self {
+        TransferProgress { total_bytes, listener, ..TransferProgress::default() }
+    }
+
+    /// A snapshot of the progress so far.
+    pub fn report(&self) -> ProgressReport {
+        ProgressReport {
+            bytes_transferred: self.bytes_transferred.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes,
+            retransmissions: self.retransmissions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// How sending the file went, if it was sent and is over.
+    pub fn send_stats(&self) -> Option<SendStats> {
+        self.send_stats.lock().ok().and_then(|send_stats| *send_stats)
+    }
+
+    /// How receiving the file went, if it was received and is over.
+    pub fn receive_stats(&self) -> Option<ReceiveStats> {
+        self.receive_stats.lock().ok().and_then(|receive_stats| *receive_stats)
+    }
+
+    /// Tells the listener, if there is one, how far along the transfer is. This is called by the
+    /// transfer whenever it has made progress.
+    pub fn notify(&self) {
+        if let Some(ref listener) = self.listener {
+            listener.on_progress(&self.report());
+        }
+    }
+}
+
+/// A snapshot of how far along a transfer is.
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressReport {
+    pub bytes_transferred: usize,
+    /// The size of the file, if known. Downloads only know it if the host answered the "tsize"
+    /// option.
+    pub total_bytes: Option<u64>,
+    /// See `TransferProgress::retransmissions`.
+    pub retransmissions: usize,
+}
+
+/// What a `SendFile` did to get a file across, which says a lot about the link to the peer: a
+/// lossy link takes retransmissions and timeouts, and keeps the window small.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SendStats {
+    /// The number of data packets sent, retransmissions included.
+    pub blocks_sent: usize,
+    /// The number of bytes of data packets sent, headers included.
+    pub bytes_sent: usize,
+    /// The number of data packets that were sent again.
+    pub retransmissions: usize,
+    /// The number of times no Ack arrived in time, so that the window was sent again.
+    pub timeouts: usize,
+    /// The number of round trip times measured. Blocks that were sent again aren't measured, since
+    /// there's no telling which transmission their Ack is for.
+    pub rtt_samples: usize,
+    /// The size the window was at in the end.
+    pub window_size: usize,
+}
+
+/// What a `ReceiveFile` got to receive a file. Blocks that arrive more than once mean the peer
+/// sent them again, usually because our ACKs were lost or came too late.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReceiveStats {
+    /// The number of data packets that arrived, duplicates included.
+    pub blocks_received: usize,
+    /// The number of data packets for blocks that had already arrived. These aren't written again.
+    pub duplicates: usize,
+    /// The number of times so many duplicates arrived in a row that the last ACK was sent again
+    /// straight away; see `DUPLICATE_BURST`.
+    pub duplicate_acks: usize,
+}
+
+/// Gets told how a transfer is going, e.g. to show a progress bar. This is implemented for closures
+/// that take a `&ProgressReport`.
+pub trait ProgressListener: Send + Sync {
+    /// Called from the thread doing the transfer, so this should return quickly.
+    fn on_progress(&self, progress: &ProgressReport);
+}
+
+impl<F> ProgressListener for F where F: Fn(&ProgressReport) + Send + Sync {
+    fn on_progress(&self, progress: &ProgressReport) {
+        self(progress)
+    }
+}
+
+/// Gets told about the requests a server handles, e.g. to kick off something else once a device
+/// has fetched its boot image. All methods do nothing by default.
+pub trait TransferObserver: Send + Sync {
+    /// Called when a request arrives, before anything else is done with it.
+    fn on_request(&self, _peer: SocketAddr, _direction: RequestType, _filename: &str) {}
+
+    /// Called once the whole file has been transferred.
+    fn on_transfer_complete(&self, _peer: SocketAddr, _direction: RequestType, _filename: &str) {}
+
+    /// Called if the request was refused, or the transfer failed part way through.
+    fn on_transfer_failed(&self, _peer: SocketAddr, _direction: RequestType, _filename: &str, _error: &io::Error) {}
+}
+
+/// A snapshot of a transfer that a server has in progress.
+#[derive(Clone, Debug)]
+pub struct TransferInfo {
+    pub peer: SocketAddr,
+    pub filename: String,
+    /// `Read` if the peer is downloading the file, `Write` if it is uploading it.
+    pub direction: RequestType,
+    pub bytes_transferred: usize,
+    /// The window size the file is being sent with, or `None` if it is being received.
+    pub window_size: Option<usize>,
+    pub start_time: SystemTime,
+}
+
+/// A transfer that a server has finished, successfully or not.
+#[derive(Clone, Debug)]
+pub struct TransferRecord {
+    pub peer: SocketAddr,
+    /// The filename as it was requested.
+    pub filename: String,
+    /// `Read` if the peer downloaded the file, `Write` if it uploaded it.
+    pub direction: RequestType,
+    pub bytes_transferred: usize,
+    /// When the request arrived.
+    pub start_time: SystemTime,
+    pub duration: Duration,
+    /// Why the transfer failed or was refused, or `None` if the whole file was transferred.
+    pub error: Option<String>,
+    /// How sending the file went, if the peer downloaded it and it got as far as being sent.
+    pub send_stats: Option<SendStats>,
+    /// How receiving the file went, if the peer uploaded it and it got as far as being received.
+    pub receive_stats: Option<ReceiveStats>,
+}
+
+/// How a transfer made with `TFTPClient` went.
+#[derive(Clone, Debug, Default)]
+pub struct TransferOutcome {
+    /// The size of the file.
+    pub bytes: usize,
+    /// The number of data blocks the file took up.
+    pub blocks: usize,
+    /// See `TransferProgress::retransmissions`.
+    pub retransmits: usize,
+    /// How long the whole transfer took, including any attempts with smaller block sizes or on
+    /// mirrors.
+    pub duration: Duration,
+    /// The options the host acknowledged, as it acknowledged them.
+    pub negotiated_options: BTreeMap<String, String>,
+    /// How sending the file went, if it was an upload.
+    pub send_stats: Option<SendStats>,
+    /// How receiving the file went, if it was a download.
+    pub receive_stats: Option<ReceiveStats>,
+}
+
+impl TransferOutcome {
+    /// The outcome of a transfer that has just finished, as far as `progress` tracked it. The
+    /// duration is left for the caller to fill in.
+    pub fn new(progress: &TransferProgress, block_size: usize, negotiated_options: BTreeMap<String, String>) -> Self {
+        let report = progress.report();
+        TransferOutcome {
+            bytes: report.bytes_transferred,
+            // The last block is always shorter than the block size, even if that means it's empty.
+            blocks: report.bytes_transferred / block_size + 1,
+            retransmits: report.retransmissions,
+            duration: Duration::from_secs(0),
+            negotiated_options,
+            send_stats: progress.send_stats(),
+            receive_stats: progress.receive_stats(),
+        }
+    }
+}
+
+/// What `TFTPClient::request_file_if_changed` did.
+#[derive(Clone, Debug)]
+pub enum ConditionalOutcome {
+    /// The file was downloaded, since the local copy was missing or differed from the host's.
+    Downloaded(TransferOutcome),
+    /// The local copy already matched the host's, so nothing was downloaded.
+    NotModified,
+}
+
+/// Lets transfers be paused from another thread, e.g. by a UI that lets the user make room for
+/// something more urgent, and resumed later. A paused transfer neither sends nor acknowledges data,
+/// so to the peer it looks like a stalled link: a pause only survives as long as the peer keeps
+/// retransmitting, and a paused download still fails once its own idle timeout runs out.
+#[derive(Default)]
+pub struct TransferControl {
+    paused: Mutex<bool>,
+    resumed: Condvar,
+}
+
+impl TransferControl {
+    pub fn pause(&self) {
+        *self.paused.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.paused.lock().unwrap() = false;
+        self.resumed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    /// Waits until the transfer is resumed, but for no longer than `timeout`. Returns whether it is
+    /// still paused.
+    pub fn wait_for_resume(&self, timeout: Duration) -> bool {
+        let paused = self.paused.lock().unwrap();
+        if !*paused { return false }
+        let (paused, _) = self.resumed.wait_timeout(paused, timeout).unwrap();
+        *paused
+    }
+}
+
+struct ActiveTransfer {
+    peer: SocketAddr,
+    filename: String,
+    direction: RequestType,
+    start_time: SystemTime,
+    progress: Arc<TransferProgress>,
+}
+
+/// Keeps track of the transfers a server has in progress. It is shared by all of the server's
+/// sessions.
+#[derive(Default)]
+pub struct SessionRegistry {
+    next_id: AtomicUsize,
+    transfers: Mutex<HashMap<usize, ActiveTransfer>>,
+    history: Mutex<VecDeque<TransferRecord>>,
+}
+
+/// A transfer listed in a `SessionRegistry`, for as long as this is alive.
+pub struct RegisteredTransfer {
+    id: usize,
+    registry: Arc<SessionRegistry>,
+    pub progress: Arc<TransferProgress>,
+}
+
+impl SessionRegistry {
+    /// Lists a transfer until the returned guard is dropped. `progress` is what the transfer keeps
+    /// up to date.
+    pub fn register(registry: &Arc<SessionRegistry>, peer: SocketAddr, filename: String, direction: RequestType, progress: Arc<TransferProgress>) -> RegisteredTransfer {
+        let id = registry.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut transfers) = registry.transfers.lock() {
+            transfers.insert(id, ActiveTransfer { peer, filename, direction, start_time: SystemTime::now(), progress: progress.clone() });
+        }
+        RegisteredTransfer { id, registry: registry.clone(), progress }
+    }
+
+    /// Lists the transfers in progress right now.
+    pub fn transfers(&self) -> Vec<TransferInfo> {
+        let transfers = match self.transfers.lock() {
+            Ok(transfers) => transfers,
+            Err(_) => return vec![]
+        };
+        transfers.values().map(|transfer| TransferInfo {
+            peer: transfer.peer,
+            filename: transfer.filename.clone(),
+            direction: transfer.direction.clone(),
+            bytes_transferred: transfer.progress.bytes_transferred.load(Ordering::Relaxed),
+            window_size: match transfer.direction {
+                RequestType::Read => Some(transfer.progress.window_size.load(Ordering::Relaxed)),
+                RequestType::Write => None
+            },
+            start_time: transfer.start_time,
+        }).collect()
+    }
+
+    /// Adds a finished transfer to the history, forgetting the oldest ones so that no more than
+    /// `max_len` are kept.
+    pub fn record(&self, record: TransferRecord, max_len: usize) {
+        if let Ok(mut history) = self.history.lock() {
+            history.push_back(record);
+            while history.len() > max_len {
+                history.pop_front();
+            }
+        }
+    }
+
+    /// Lists the transfers in the history, oldest first.
+    pub fn history(&self) -> Vec<TransferRecord> {
+        match self.history.lock() {
+            Ok(history) => history.iter().cloned().collect(),
+            Err(_) => vec![]
+        }
+    }
+}
+
+impl Drop for RegisteredTransfer {
+    fn drop(&mut self) {
+        if let Ok(mut transfers) = self.registry.transfers.lock() {
+            transfers.remove(&self.id);
+        }
+    }
+}
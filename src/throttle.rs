@@ -0,0 +1,48 @@
+use std::cmp::max;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+/// Limits the rate at which data is sent. Tokens (bytes) trickle in at `rate` per second, up to
+/// `capacity`, and every packet has to take as many tokens as it is long before it may be sent. The
+/// capacity is the largest burst that can go out at full speed.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    /// Bytes per second.
+    rate: u64,
+    capacity: f64,
+    tokens: f64,
+    last_fill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(rate: u64, capacity: u64) -> Self {
+        TokenBucket {
+            rate: max(rate, 1),
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            last_fill: Instant::now(),
+        }
+    }
+
+    /// Waits until `amount` tokens are available, then takes them.
+    pub fn take(&mut self, amount: usize) {
+        self.fill();
+        let amount = amount as f64;
+        if self.tokens < amount {
+            let wait = (amount - self.tokens) / self.rate as f64;
+            thread::sleep(Duration::new(wait as u64, (wait.fract() * 1e9) as u32));
+            self.fill();
+        }
+        // Sleeping can take a little less time than asked for, in which case this goes slightly
+        // negative and the next packet makes up for it.
+        self.tokens -= amount;
+    }
+
+    fn fill(&mut self) {
+        let elapsed = self.last_fill.elapsed();
+        self.last_fill = Instant::now();
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity);
+    }
+}
@@ -0,0 +1,127 @@
+use std::cmp::max;
+use std::mem;
+use std::time::{ Duration, Instant };
+
+/// How many bits of a deadline each level of the wheel sorts by, i.e. each level has 64 slots.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+/// Enough levels for any deadline a u64 of milliseconds can hold.
+const LEVELS: usize = 11;
+
+/// A hierarchical timer wheel with a resolution of a millisecond. Every level has 64 slots, each
+/// spanning 64 times as long as those of the level below: a slot of the first level is a
+/// millisecond, one of the second is 64 milliseconds, and so on. A timer goes into the lowest level
+/// that its deadline is within reach of, and moves down a level whenever its slot comes up, so
+/// setting and expiring timers takes the same time however many of them there are.
+///
+/// Timers can't be cancelled. Whoever sets them ignores the ones that don't matter any more when
+/// they expire.
+pub struct TimerWheel<T> {
+    start: Instant,
+    /// The milliseconds since `start` that timers have been expired up to.
+    elapsed: u64,
+    /// The slots of every level, one level after the other. Timers are kept with their deadline in
+    /// milliseconds since `start`.
+    slots: Vec<Vec<(u64, T)>>,
+    /// A bit for each slot of a level that has timers in it.
+    occupied: [u64; LEVELS],
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new() -> Self {
+        TimerWheel {
+            start: Instant::now(),
+            elapsed: 0,
+            slots: (0..LEVELS * SLOTS).map(|_| Vec::new()).collect(),
+            occupied: [0; LEVELS],
+            len: 0,
+        }
+    }
+
+    /// The number of timers that haven't expired.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Sets a timer that expires with `item` at `deadline`, rounded up to the next millisecond. A
+    /// deadline that has passed already expires on the next call to `expire`.
+    pub fn insert(&mut self, deadline: Instant, item: T) {
+        let deadline = max(self.millis_at(deadline, true), self.elapsed);
+        self.insert_at(deadline, item);
+        self.len += 1;
+    }
+
+    /// Takes out every timer that is due by `now`, soonest first.
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let now = self.millis_at(now, false);
+        let mut expired = Vec::new();
+        while let Some((level, slot, slot_start)) = self.next_slot() {
+            if slot_start > now {
+                break
+            }
+            self.elapsed = slot_start;
+            self.occupied[level] &= !(1 << slot);
+            let timers = mem::replace(&mut self.slots[level * SLOTS + slot], Vec::new());
+            for (deadline, item) in timers {
+                if level == 0 {
+                    expired.push(item);
+                } else {
+                    // Everything in the slot is within reach of a lower level now.
+                    self.insert_at(deadline, item);
+                }
+            }
+        }
+        self.elapsed = max(self.elapsed, now);
+        self.len -= expired.len();
+        expired
+    }
+
+    /// When the next timer is due, or a little before then if it has yet to move down to the first
+    /// level. Waiting until then and calling `expire` never misses a timer.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.next_slot().map(|(_, _, slot_start)| self.start + Duration::from_millis(slot_start))
+    }
+
+    fn insert_at(&mut self, deadline: u64, item: T) {
+        // The highest bit in which the deadline differs from now decides the level.
+        let significant = 63 - ((deadline ^ self.elapsed) | (SLOTS as u64 - 1)).leading_zeros();
+        let level = (significant / SLOT_BITS) as usize;
+        let slot = ((deadline >> (level as u32 * SLOT_BITS)) as usize) & (SLOTS - 1);
+        self.slots[level * SLOTS + slot].push((deadline, item));
+        self.occupied[level] |= 1 << slot;
+    }
+
+    /// The first slot with timers in it, as its level, its index and when it starts. Timers on a
+    /// level are all later than those on the levels below, and never in a slot that has passed.
+    fn next_slot(&self) -> Option<(usize, usize, u64)> {
+        for level in 0..LEVELS {
+            let shift = level as u32 * SLOT_BITS;
+            let current = ((self.elapsed >> shift) as usize) & (SLOTS - 1);
+            let ahead = self.occupied[level] & (!0u64 << current);
+            if ahead != 0 {
+                let slot = ahead.trailing_zeros() as usize;
+                // The part of now above this level, which the slot shares.
+                let above = self.elapsed.checked_shr(shift + SLOT_BITS).and_then(|above| above.checked_shl(shift + SLOT_BITS)).unwrap_or(0);
+                return Some((level, slot, above | ((slot as u64) << shift)))
+            }
+        }
+        None
+    }
+
+    fn millis_at(&self, instant: Instant, round_up: bool) -> u64 {
+        let since = instant.saturating_duration_since(self.start);
+        let millis = since.as_secs() * 1000 + (since.subsec_nanos() / 1_000_000) as u64;
+        if round_up && since.subsec_nanos() % 1_000_000 != 0 { millis + 1 } else { millis }
+    }
+}
+
+impl<T> Default for TimerWheel<T> {
+    fn default() -> Self {
+        TimerWheel::new()
+    }
+}
@@ -0,0 +1,556 @@
+use std::cmp::{ min, max };
+use std::collections::VecDeque;
+use std::io;
+use std::net::{ SocketAddr, UdpSocket };
+use std::sync::{ Arc, Condvar, Mutex, MutexGuard };
+use std::thread;
+use std::time::{ Duration, Instant };
+#[cfg(unix)]
+use libc;
+use futures::Future;
+#[cfg(target_os = "linux")]
+use net_util;
+use receive::ReceiveFile;
+use send::SendFile;
+
+/// Something datagrams can be sent and received through. Transfers do all of their I/O through one
+/// of these, which is a `UdpSocket` unless something else is asked for, e.g. a `MemoryDatagram` in
+/// tests that shouldn't depend on real sockets.
+///
+/// The methods behave like the `UdpSocket` methods of the same name: in particular, a read that
+/// runs past the read timeout fails with `WouldBlock` or `TimedOut`.
+pub trait Datagram {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, io::Error>;
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error>;
+
+    /// Like `recv_from`, but leaves the datagram to be read again.
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error>;
+
+    /// Sets how long reads wait for a datagram to arrive; `None` means they wait forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error>;
+
+    fn read_timeout(&self) -> Result<Option<Duration>, io::Error>;
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error>;
+
+    /// Sends each of `bufs` to `addr` as a datagram of its own, returning how many were sent. This
+    /// is `send_to` for each in turn unless the transport can do better, e.g. a `UdpSocket` on
+    /// Linux sends them all with a single sendmmsg(2). An error is only returned if nothing was
+    /// sent.
+    fn send_to_many(&self, bufs: &[&[u8]], addr: SocketAddr) -> Result<usize, io::Error> {
+        for (sent, buf) in bufs.iter().enumerate() {
+            if let Err(e) = self.send_to(buf, addr) {
+                return if sent == 0 { Err(e) } else { Ok(sent) }
+            }
+        }
+        Ok(bufs.len())
+    }
+
+    /// Receives up to one datagram into each of `bufs`, returning the length and source of each
+    /// one received. The first is waited for like `recv_from` does, but no more are waited for
+    /// after that. This receives a single datagram unless the transport can do better, e.g. a
+    /// `UdpSocket` on Linux receives a whole burst with a single recvmmsg(2).
+    fn recv_from_many(&self, bufs: &mut [&mut [u8]]) -> Result<Vec<(usize, SocketAddr)>, io::Error> {
+        match bufs.first_mut() {
+            Some(buf) => Ok(vec![self.recv_from(buf)?]),
+            None => Ok(vec![])
+        }
+    }
+}
+
+impl Datagram for UdpSocket {
+    #[cfg(unix)]
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
+        match UdpSocket::send_to(self, buf, addr) {
+            // Some platforms, e.g. macOS, insist on `send` once the socket is connected to the peer.
+            Err(ref e) if e.raw_os_error() == Some(libc::EISCONN) => UdpSocket::send(self, buf),
+            result => result
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        UdpSocket::peek_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>, io::Error> {
+        UdpSocket::read_timeout(self)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        UdpSocket::local_addr(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_to_many(&self, bufs: &[&[u8]], addr: SocketAddr) -> Result<usize, io::Error> {
+        net_util::send_mmsg(self, bufs, addr)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_from_many(&self, bufs: &mut [&mut [u8]]) -> Result<Vec<(usize, SocketAddr)>, io::Error> {
+        net_util::recv_mmsg(self, bufs)
+    }
+}
+
+/// A locked transport can be used just like the transport itself.
+impl<'a, D: Datagram + ?Sized> Datagram for MutexGuard<'a, D> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
+        (**self).send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        (**self).recv_from(buf)
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        (**self).peek_from(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>, io::Error> {
+        (**self).read_timeout()
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        (**self).local_addr()
+    }
+
+    fn send_to_many(&self, bufs: &[&[u8]], addr: SocketAddr) -> Result<usize, io::Error> {
+        (**self).send_to_many(bufs, addr)
+    }
+
+    fn recv_from_many(&self, bufs: &mut [&mut [u8]]) -> Result<Vec<(usize, SocketAddr)>, io::Error> {
+        (**self).recv_from_many(bufs)
+    }
+}
+
+/// A transport behind a mutex is locked for every call, waiting for the lock if need be.
+impl<D: Datagram + ?Sized> Datagram for Mutex<D> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
+        lock(self)?.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        lock(self)?.recv_from(buf)
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        lock(self)?.peek_from(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        lock(self)?.set_read_timeout(timeout)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>, io::Error> {
+        lock(self)?.read_timeout()
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        lock(self)?.local_addr()
+    }
+
+    fn send_to_many(&self, bufs: &[&[u8]], addr: SocketAddr) -> Result<usize, io::Error> {
+        lock(self)?.send_to_many(bufs, addr)
+    }
+
+    fn recv_from_many(&self, bufs: &mut [&mut [u8]]) -> Result<Vec<(usize, SocketAddr)>, io::Error> {
+        lock(self)?.recv_from_many(bufs)
+    }
+}
+
+fn lock<'a, D: ?Sized>(socket: &'a Mutex<D>) -> Result<MutexGuard<'a, D>, io::Error> {
+    socket.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain UDP Socket lock."))
+}
+
+/// A transfer that does all of its I/O through one socket, which is what lets it be driven by an
+/// event loop: see `reactor::ReactorTransfer` and `async_transfer::AsyncTransfer`.
+pub trait Transfer: Future<Item=(), Error=io::Error> {
+    fn socket(&self) -> &UdpSocket;
+}
+
+/// How long a transfer's `run` keeps the thread to itself. A transfer is polled over and over until
+/// it is done, which, when `run` is called from a task on an executor, would keep every other task
+/// on that thread waiting; so every so often the thread is given up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PollBudget {
+    /// How many times in a row the transfer is polled before the thread is given up.
+    pub polls: usize,
+}
+
+impl PollBudget {
+    pub fn new(polls: usize) -> Self {
+        PollBudget { polls: max(polls, 1) }
+    }
+
+    /// Polls `transfer` until it is done. Whenever it has been polled `polls` times in a row, the
+    /// thread is yielded; if the transfer found nothing to read without its socket waiting for
+    /// anything (`idle_wait`), polling it again straight away would only spin, so the thread sleeps
+    /// for as long as the transfer would have waited instead, i.e. its retransmission timeout.
+    pub fn run<F, W>(&self, transfer: &mut F, idle_wait: W) -> Result<(), io::Error>
+            where F: Future<Item=(), Error=io::Error>, W: Fn(&F) -> Option<Duration> {
+        let mut polls = 0;
+        loop {
+            if transfer.poll()?.is_ready() {
+                return Ok(())
+            }
+            polls += 1;
+            if polls >= self.polls {
+                polls = 0;
+                match idle_wait(transfer) {
+                    Some(wait) => thread::sleep(wait),
+                    None => thread::yield_now()
+                }
+            }
+        }
+    }
+}
+
+impl Default for PollBudget {
+    fn default() -> Self {
+        PollBudget::new(DEFAULT_POLL_BUDGET)
+    }
+}
+
+/// The number of polls in a row a transfer's `run` does by default; see `PollBudget`.
+pub const DEFAULT_POLL_BUDGET: usize = 64;
+
+impl Transfer for SendFile {
+    fn socket(&self) -> &UdpSocket { SendFile::socket(self) }
+}
+
+impl Transfer for ReceiveFile {
+    fn socket(&self) -> &UdpSocket { ReceiveFile::socket(self) }
+}
+
+/// The datagrams waiting to be read by a `MemoryDatagram`, along with who sent them.
+struct Inbox {
+    queue: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+    arrived: Condvar,
+}
+
+impl Inbox {
+    fn new() -> Arc<Inbox> {
+        Arc::new(Inbox { queue: Mutex::new(VecDeque::new()), arrived: Condvar::new() })
+    }
+}
+
+/// One end of an in-memory link between two addresses; see `MemoryDatagram::pair`. Datagrams are
+/// delivered instantly, in order, and are never lost, so transfers over these behave the same way
+/// every time. Datagrams sent to any address other than the other end are silently dropped, just
+/// like UDP datagrams sent to an address nobody is listening on.
+pub struct MemoryDatagram {
+    addr: SocketAddr,
+    peer_addr: SocketAddr,
+    inbox: Arc<Inbox>,
+    peer_inbox: Arc<Inbox>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl MemoryDatagram {
+    /// Creates two ends of a link, the first with the address `a` and the second with `b`.
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (MemoryDatagram, MemoryDatagram) {
+        let (a_inbox, b_inbox) = (Inbox::new(), Inbox::new());
+        (
+            MemoryDatagram { addr: a, peer_addr: b, inbox: a_inbox.clone(), peer_inbox: b_inbox.clone(), read_timeout: Mutex::new(None) },
+            MemoryDatagram { addr: b, peer_addr: a, inbox: b_inbox, peer_inbox: a_inbox, read_timeout: Mutex::new(None) }
+        )
+    }
+
+    /// Waits for a datagram to arrive, then copies it into `buf`, removing it from the inbox if
+    /// `remove` is set.
+    fn read(&self, buf: &mut [u8], remove: bool) -> Result<(usize, SocketAddr), io::Error> {
+        let timeout = self.read_timeout()?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut queue = self.inbox.queue.lock().unwrap();
+        loop {
+            if let Some(&(ref data, src)) = queue.front() {
+                let len = min(data.len(), buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                if remove {
+                    queue.pop_front();
+                }
+                return Ok((len, src))
+            }
+            queue = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "Timed out waiting for a datagram."))
+                    }
+                    self.inbox.arrived.wait_timeout(queue, deadline - now).unwrap().0
+                },
+                None => self.inbox.arrived.wait(queue).unwrap()
+            };
+        }
+    }
+}
+
+impl Datagram for MemoryDatagram {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
+        if addr == self.peer_addr {
+            self.peer_inbox.queue.lock().unwrap().push_back((buf.to_vec(), self.addr));
+            self.peer_inbox.arrived.notify_all();
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        self.read(buf, true)
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        self.read(buf, false)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        if timeout == Some(Duration::new(0, 0)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot set a 0 duration timeout."))
+        }
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>, io::Error> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        Ok(self.addr)
+    }
+}
+
+/// What a `FaultyDatagram` does to the datagrams sent through it. Each chance is a probability from
+/// 0 to 1, rolled separately for every datagram.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultProfile {
+    /// The chance that a datagram is never sent.
+    pub drop: f64,
+    /// The chance that a datagram is sent twice.
+    pub duplicate: f64,
+    /// The chance that a datagram is held back, and sent after the datagram that follows it (or
+    /// when the sender next reads, if nothing follows it before then).
+    pub reorder: f64,
+    /// The chance that a datagram is held back for `delay_time`.
+    pub delay: f64,
+    pub delay_time: Duration,
+    /// The chance that a bit of a datagram is flipped on the way, e.g. by a flaky bridge that
+    /// doesn't check UDP checksums.
+    pub corrupt: f64,
+}
+
+impl FaultProfile {
+    /// Drops datagrams with the chance `drop`, and leaves them alone otherwise.
+    pub fn lossy(drop: f64) -> Self {
+        FaultProfile { drop, ..FaultProfile::default() }
+    }
+}
+
+/// How many datagrams a `FaultyDatagram` has done something to so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    pub sent: usize,
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+    pub delayed: usize,
+    pub corrupted: usize,
+}
+
+/// A xorshift64* generator. Faults have to come out the same for the same seed on every platform
+/// and with every version of `rand`, so this doesn't use `rand`.
+struct FaultRng(u64);
+
+impl FaultRng {
+    fn new(seed: u64) -> Self {
+        // The state must never be 0.
+        FaultRng(if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed })
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns true with the chance `p`.
+    fn roll(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+struct FaultState {
+    rng: FaultRng,
+    /// Datagrams held back to be sent after the next one.
+    reordered: Vec<(Vec<u8>, SocketAddr)>,
+    /// Datagrams held back until a certain time.
+    delayed: Vec<(Instant, Vec<u8>, SocketAddr)>,
+    stats: FaultStats,
+}
+
+/// Wraps another transport, and drops, duplicates, reorders, delays and corrupts the datagrams sent
+/// through it as laid out by a `FaultProfile`. The faults are decided by a generator seeded with `seed`, so
+/// the same seed and the same sequence of datagrams always give the same faults, which makes
+/// loss-recovery reproducible in tests. Only datagrams being sent are interfered with; to disturb
+/// both directions of a transfer, wrap both ends.
+///
+/// Held back datagrams are sent once they are due by whichever call on this comes next; reads wait
+/// in short enough steps that this happens on time.
+pub struct FaultyDatagram<D: Datagram> {
+    inner: D,
+    profile: FaultProfile,
+    state: Mutex<FaultState>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl<D: Datagram> FaultyDatagram<D> {
+    pub fn new(inner: D, profile: FaultProfile, seed: u64) -> Result<Self, io::Error> {
+        let read_timeout = inner.read_timeout()?;
+        Ok(FaultyDatagram {
+            inner,
+            profile,
+            state: Mutex::new(FaultState { rng: FaultRng::new(seed), reordered: vec![], delayed: vec![], stats: FaultStats::default() }),
+            read_timeout: Mutex::new(read_timeout),
+        })
+    }
+
+    /// What has been done to the datagrams sent so far.
+    pub fn stats(&self) -> FaultStats {
+        self.state.lock().unwrap().stats
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Sends the datagrams that are held back until the next one is sent, and the delayed datagrams
+    /// that are due, returning when the next delayed datagram will be due.
+    fn release(&self, state: &mut FaultState, reordered: bool) -> Result<Option<Instant>, io::Error> {
+        if reordered {
+            for (data, addr) in state.reordered.drain(..) {
+                self.inner.send_to(&data, addr)?;
+            }
+        }
+        let now = Instant::now();
+        let mut i = 0;
+        while i < state.delayed.len() {
+            if state.delayed[i].0 <= now {
+                let (_, data, addr) = state.delayed.remove(i);
+                self.inner.send_to(&data, addr)?;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(state.delayed.iter().map(|&(due, _, _)| due).min())
+    }
+
+    /// Reads with `read`, sending held back datagrams when they are due in the meantime.
+    fn read<F>(&self, buf: &mut [u8], read: F) -> Result<(usize, SocketAddr), io::Error>
+        where F: Fn(&D, &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        let timeout = self.read_timeout()?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let next_due = {
+                let mut state = self.state.lock().unwrap();
+                self.release(&mut state, true)?
+            };
+            let next_due = match next_due {
+                Some(next_due) => next_due,
+                None => return read(&self.inner, buf)
+            };
+            let until = match deadline {
+                Some(deadline) => min(deadline, next_due),
+                None => next_due
+            };
+            let now = Instant::now();
+            let wait = if until > now { until - now } else { Duration::from_millis(1) };
+            self.inner.set_read_timeout(Some(wait))?;
+            let result = read(&self.inner, buf);
+            self.inner.set_read_timeout(timeout)?;
+            match result {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                        return result
+                    }
+                },
+                result => return result
+            }
+        }
+    }
+}
+
+impl<D: Datagram> Datagram for FaultyDatagram<D> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
+        let mut state = self.state.lock().unwrap();
+        self.release(&mut state, false)?;
+        state.stats.sent += 1;
+        let mut corrupted;
+        // Only rolled for when asked for, so that other profiles fault the same as they always have.
+        let buf = if self.profile.corrupt > 0.0 && !buf.is_empty() && state.rng.roll(self.profile.corrupt) {
+            state.stats.corrupted += 1;
+            let bit = state.rng.next() as usize % (buf.len() * 8);
+            corrupted = buf.to_vec();
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            &corrupted[..]
+        } else {
+            buf
+        };
+        if state.rng.roll(self.profile.drop) {
+            state.stats.dropped += 1;
+        } else if state.rng.roll(self.profile.delay) {
+            state.stats.delayed += 1;
+            state.delayed.push((Instant::now() + self.profile.delay_time, buf.to_vec(), addr));
+        } else if state.rng.roll(self.profile.reorder) {
+            state.stats.reordered += 1;
+            state.reordered.push((buf.to_vec(), addr));
+            return Ok(buf.len())
+        } else {
+            self.inner.send_to(buf, addr)?;
+            if state.rng.roll(self.profile.duplicate) {
+                state.stats.duplicated += 1;
+                self.inner.send_to(buf, addr)?;
+            }
+        }
+        self.release(&mut state, true)?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        self.read(buf, |inner, buf| inner.recv_from(buf))
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        self.read(buf, |inner, buf| inner.peek_from(buf))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.inner.set_read_timeout(timeout)?;
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>, io::Error> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.inner.local_addr()
+    }
+}
@@ -0,0 +1,330 @@
+use std::io;
+use std::mem;
+use std::net::{ SocketAddr, UdpSocket };
+use std::os::unix::io::{ AsRawFd, RawFd };
+use std::ptr;
+use std::sync::{ Mutex, MutexGuard };
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::time::Duration;
+use libc;
+
+use net_util::write_sockaddr;
+use transport::Datagram;
+
+/// The number of sends that are queued up before they have to be submitted.
+pub const DEFAULT_RING_ENTRIES: u32 = 64;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_OP_SENDMSG: u8 = 9;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets { head: u32, tail: u32, ring_mask: u32, ring_entries: u32, flags: u32, dropped: u32, array: u32, resv1: u32, resv2: u64 }
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets { head: u32, tail: u32, ring_mask: u32, ring_entries: u32, overflow: u32, cqes: u32, flags: u32, resv1: u32, resv2: u64 }
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// A submission queue entry, as laid out by the kernel.
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    msg_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+/// A completion queue entry, as laid out by the kernel.
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A memory mapped part of a ring, unmapped when dropped.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> Result<Self, io::Error> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if ptr == libc::MAP_FAILED { return Err(io::Error::last_os_error()) }
+        Ok(Mapping { ptr: ptr as *mut u8, len })
+    }
+
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        self.ptr.offset(offset as isize) as *mut T
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// A datagram waiting to be sent, along with everything the kernel reads to send it. It is boxed
+/// so that none of it moves while the kernel might be looking at it.
+struct PendingSend {
+    buf: Vec<u8>,
+    addr: libc::sockaddr_storage,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+}
+
+/// An io_uring instance that sends datagrams.
+struct Ring {
+    fd: RawFd,
+    // The mappings are only held on to so that they stay mapped.
+    _sq: Mapping,
+    _cq: Mapping,
+    sqes: Mapping,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_array: *mut u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    entries: u32,
+    pending: Vec<Box<PendingSend>>,
+}
+
+impl Ring {
+    fn new(entries: u32) -> Result<Self, io::Error> {
+        let mut params = Params::default();
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params) } as RawFd;
+        if fd < 0 { return Err(io::Error::last_os_error()) }
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>();
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * mem::size_of::<Sqe>();
+        let maps = Mapping::new(fd, sq_len, IORING_OFF_SQ_RING).and_then(|sq| {
+            let cq = Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?;
+            let sqes = Mapping::new(fd, sqes_len, IORING_OFF_SQES)?;
+            Ok((sq, cq, sqes))
+        });
+        let (sq, cq, sqes) = match maps {
+            Ok(maps) => maps,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(e)
+            }
+        };
+        unsafe {
+            Ok(Ring {
+                fd,
+                sq_tail: sq.at(params.sq_off.tail),
+                sq_mask: *sq.at::<u32>(params.sq_off.ring_mask),
+                sq_array: sq.at(params.sq_off.array),
+                cq_head: cq.at(params.cq_off.head),
+                cq_tail: cq.at(params.cq_off.tail),
+                cq_mask: *cq.at::<u32>(params.cq_off.ring_mask),
+                cqes: cq.at(params.cq_off.cqes),
+                entries: params.sq_entries,
+                pending: Vec::with_capacity(params.sq_entries as usize),
+                _sq: sq,
+                _cq: cq,
+                sqes,
+            })
+        }
+    }
+
+    /// Queues up a send of `buf` to `addr` on `fd`. It isn't handed to the kernel until `submit`.
+    fn push(&mut self, fd: RawFd, buf: &[u8], addr: &SocketAddr) {
+        let mut send = Box::new(PendingSend { buf: buf.to_vec(), addr: unsafe { mem::zeroed() }, iov: unsafe { mem::zeroed() }, msg: unsafe { mem::zeroed() } });
+        let addr_len = write_sockaddr(addr, &mut send.addr);
+        send.iov.iov_base = send.buf.as_mut_ptr() as *mut libc::c_void;
+        send.iov.iov_len = send.buf.len();
+        send.msg.msg_name = &mut send.addr as *mut _ as *mut libc::c_void;
+        send.msg.msg_namelen = addr_len;
+        send.msg.msg_iov = &mut send.iov;
+        send.msg.msg_iovlen = 1;
+
+        let sqe = Sqe {
+            opcode: IORING_OP_SENDMSG,
+            fd,
+            addr: &send.msg as *const libc::msghdr as u64,
+            len: 1,
+            user_data: self.pending.len() as u64,
+            ..Sqe::default()
+        };
+        unsafe {
+            // Nothing else submits to this ring, so the tail can't change under our feet.
+            let tail = (*self.sq_tail).load(Ordering::Relaxed);
+            let index = tail & self.sq_mask;
+            ptr::write(self.sqes.at::<Sqe>(0).offset(index as isize), sqe);
+            *self.sq_array.offset(index as isize) = index;
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.pending.push(send);
+    }
+
+    /// Hands every queued send to the kernel with a single system call, and waits for them all to
+    /// complete. Returns the first error any of them ran into.
+    fn submit(&mut self) -> Result<(), io::Error> {
+        let mut to_submit = self.pending.len() as u32;
+        let mut completed = 0;
+        let mut result = Ok(());
+        while completed < self.pending.len() {
+            let submitted = unsafe {
+                libc::syscall(libc::SYS_io_uring_enter, self.fd, to_submit, 1u32, IORING_ENTER_GETEVENTS, ptr::null::<libc::c_void>(), 0usize)
+            };
+            if submitted < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted { continue }
+                // The kernel may still be reading the sends, so they can never be freed.
+                for send in self.pending.drain(..) {
+                    mem::forget(send);
+                }
+                return Err(err)
+            }
+            to_submit -= submitted as u32;
+            unsafe {
+                let mut head = (*self.cq_head).load(Ordering::Relaxed);
+                let tail = (*self.cq_tail).load(Ordering::Acquire);
+                while head != tail {
+                    let cqe = &*self.cqes.offset((head & self.cq_mask) as isize);
+                    if cqe.res < 0 && result.is_ok() {
+                        result = Err(io::Error::from_raw_os_error(-cqe.res));
+                    }
+                    head = head.wrapping_add(1);
+                    completed += 1;
+                }
+                (*self.cq_head).store(head, Ordering::Release);
+            }
+        }
+        self.pending.clear();
+        result
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        let _ = self.submit();
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// A UDP socket whose sends go through io_uring. Sends are queued up rather than made one system
+/// call at a time, and handed to the kernel all at once when the socket is next read from (which
+/// is what `SendFile` does after sending a window), when the ring is full, or when this is dropped.
+/// A whole window of blocks then costs a single system call.
+///
+/// Since sends complete later, `send_to` always reports success; an error sending is returned by
+/// the read that submits it instead. If the kernel doesn't support io_uring, or it is disabled,
+/// sends go straight to the socket as usual. Only the sends are batched: a file that is mapped whole
+/// or held in memory (e.g. a cached copy) is read without any system calls, but the blocks of a
+/// streaming, growing or windowed source still take reads, or remaps, of their own.
+pub struct UringDatagram {
+    // Dropped first, so that whatever is still queued is sent before the socket is closed.
+    ring: Option<Mutex<Ring>>,
+    socket: UdpSocket,
+}
+
+unsafe impl Send for UringDatagram {}
+unsafe impl Sync for UringDatagram {}
+
+impl UringDatagram {
+    pub fn new(socket: UdpSocket) -> Self {
+        UringDatagram::with_entries(socket, DEFAULT_RING_ENTRIES)
+    }
+
+    /// Queues up to `entries` sends (rounded up to a power of two by the kernel) at a time.
+    pub fn with_entries(socket: UdpSocket, entries: u32) -> Self {
+        UringDatagram { ring: Ring::new(entries).ok().map(Mutex::new), socket }
+    }
+
+    /// Whether sends go through io_uring, rather than straight to the socket.
+    pub fn is_uring(&self) -> bool {
+        self.ring.is_some()
+    }
+
+    /// Sends everything that has been queued up.
+    pub fn flush(&self) -> Result<(), io::Error> {
+        match self.ring {
+            Some(ref ring) => lock(ring)?.submit(),
+            None => Ok(())
+        }
+    }
+
+    /// Sends everything that has been queued up, and gives back the socket.
+    pub fn into_inner(self) -> Result<UdpSocket, io::Error> {
+        self.flush()?;
+        Ok(self.socket)
+    }
+}
+
+fn lock<'a>(ring: &'a Mutex<Ring>) -> Result<MutexGuard<'a, Ring>, io::Error> {
+    ring.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain io_uring lock."))
+}
+
+impl Datagram for UringDatagram {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
+        let ring = match self.ring {
+            Some(ref ring) => ring,
+            None => return self.socket.send_to(buf, addr)
+        };
+        let mut ring = lock(ring)?;
+        if ring.pending.len() as u32 >= ring.entries {
+            ring.submit()?;
+        }
+        ring.push(self.socket.as_raw_fd(), buf, &addr);
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        self.flush()?;
+        self.socket.recv_from(buf)
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        self.flush()?;
+        self.socket.peek_from(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>, io::Error> {
+        self.socket.read_timeout()
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+}